@@ -1,19 +1,40 @@
 use std::collections::HashMap;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use super::soap;
-use crate::errors::{self, AddAnyPortError, AddPortError, GetExternalIpError, RemovePortError, RequestError};
+use futures::stream::{self, Stream, StreamExt};
 
-use crate::common::{self, messages, parsing, parsing::RequestReponse};
-use crate::PortMappingProtocol;
+#[cfg(feature = "aio")]
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+#[cfg(feature = "aio")]
+use super::HyperTransport;
+use super::Transport;
+use crate::errors::{
+    self, AddAnyPortError, AddPortError, GetDnsServersError, GetExternalIpError, GetExternalIpv6Error,
+    GetGenericPortMappingEntryError, GetSpecificPortMappingEntryError, RemovePortError, RenewPortError, RequestError,
+    SearchError,
+};
+
+use crate::common::parsing::{
+    self, ActiveConnection, DeviceInfo, DiscoveryInfo, DslLinkInfo, MappingResult, MappingWarning, RequestReponse,
+    ServiceInfo, StatusInfo,
+};
+use crate::common::{self, messages, AnyPort, Capabilities, ConnectionService, GatewayOptions, PruneResults};
+use crate::soap::{self, Action};
+use crate::transport::HttpRequest;
+use crate::{PortMappingProtocol, RemoteHost};
 
 /// This structure represents a gateway found by the search functions.
 #[derive(Clone, Debug)]
 pub struct Gateway {
-    /// Socket address of the gateway
-    pub addr: SocketAddrV4,
+    /// Socket address of the gateway, an IPv6 one if its description was found at an IPv6 location
+    pub addr: SocketAddr,
     /// Root url of the device
     pub root_url: String,
     /// Control url of the device
@@ -22,27 +43,558 @@ pub struct Gateway {
     pub control_schema_url: String,
     /// Control schema for all actions
     pub control_schema: HashMap<String, Vec<String>>,
+    /// Options for the requests made to the gateway
+    pub options: GatewayOptions,
+    /// Headers of the search response, or `None` if the gateway was not found by a search
+    pub discovery_info: Option<DiscoveryInfo>,
+    /// Names of the root device from its description, or `None` if the description was not fetched
+    pub device_info: Option<DeviceInfo>,
+    /// HTTP client sending the requests
+    pub transport: Arc<dyn Transport>,
+    // Shared by the clones, so they all learn the service type answered by the gateway.
+    pub(crate) connection_service: Arc<Mutex<ConnectionService>>,
+    // Whether `AddAnyPortMapping` worked, shared by the clones like the service type.
+    pub(crate) add_any_port_support: Arc<Mutex<Option<bool>>>,
+    // What the description tells, fetched once for the clones.
+    pub(crate) capabilities: Arc<Mutex<Option<Capabilities>>>,
 }
 
 impl Gateway {
-    async fn perform_request(&self, header: &str, body: &str, ok: &str) -> Result<RequestReponse, RequestError> {
-        let url = format!("{}", self);
-        let text = soap::send_async(&url, soap::Action::new(header), body).await?;
-        parsing::parse_response(text, ok)
+    /// Create a gateway from a known address and control url, without searching for it.
+    ///
+    /// The control url can be a path (`/ctl/IPConn`) or a full url on `addr`, and is normalized to a path.
+    /// Because the control schema is not fetched, the standard `WANIPConnection:1` arguments are assumed.
+    #[cfg(feature = "aio")]
+    pub fn new(addr: SocketAddr, control_url: String) -> Result<Gateway, SearchError> {
+        Gateway::with_transport(addr, control_url, Arc::new(HyperTransport::default()))
+    }
+
+    /// Create a gateway from the url of its description, such as `http://192.168.1.1:5000/rootDesc.xml`.
+    ///
+    /// The description and control schema are fetched like for a gateway found by `search_gateway` with
+    /// `options`, but without a search: this reconnects at once to a gateway found before, whose
+    /// `DiscoveryInfo` is then `None`. The url must have an IPv4 address, or an IPv6 address in brackets.
+    #[cfg(feature = "aio")]
+    pub async fn from_location(location: &str, options: common::SearchOptions) -> Result<Gateway, SearchError> {
+        super::search::gateway_from_location(location, &options).await
+    }
+
+    /// Create a gateway like `Gateway::new`, sending its requests through `transport`.
+    pub fn with_transport(
+        addr: SocketAddr,
+        control_url: String,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Gateway, SearchError> {
+        Ok(Gateway {
+            addr,
+            root_url: String::new(),
+            control_url: parsing::parse_control_url(&addr, &control_url)?,
+            control_schema_url: String::new(),
+            control_schema: messages::default_control_schema(),
+            options: GatewayOptions::default(),
+            discovery_info: None,
+            device_info: None,
+            transport,
+            connection_service: Default::default(),
+            add_any_port_support: Default::default(),
+            capabilities: Default::default(),
+        })
+    }
+
+    /// Convert a gateway found by the blocking `search_gateway`, sending its requests through `transport`.
+    ///
+    /// This is how the async API is used without tokio: search for the gateway on a blocking thread of the
+    /// runtime, then convert it.
+    pub fn from_blocking(gateway: crate::Gateway, transport: Arc<dyn Transport>) -> Gateway {
+        Gateway {
+            addr: gateway.addr,
+            root_url: gateway.root_url,
+            control_url: gateway.control_url,
+            control_schema_url: gateway.control_schema_url,
+            control_schema: gateway.control_schema,
+            options: gateway.options,
+            discovery_info: gateway.discovery_info,
+            device_info: gateway.device_info,
+            transport,
+            connection_service: gateway.connection_service,
+            add_any_port_support: gateway.add_any_port_support,
+            capabilities: gateway.capabilities,
+        }
+    }
+
+    async fn perform_request(&self, action: Action<'_>, body: &str) -> Result<RequestReponse, RequestError> {
+        self.perform_request_at(&self.control_url, None, action, body).await
+    }
+
+    // Actions of the connection service are sent with its service type, which is probed until confirmed.
+    async fn perform_request_at(
+        &self,
+        control_url: &str,
+        deadline: Option<Instant>,
+        action: Action<'_>,
+        body: &str,
+    ) -> Result<RequestReponse, RequestError> {
+        if action.service_type != messages::SERVICE_TYPE {
+            return self.send_action(control_url, deadline, action, body).await;
+        }
+
+        let (service_type, other) = {
+            let connection_service = self.connection_service.lock().unwrap_or_else(|e| e.into_inner());
+            (connection_service.service_type, connection_service.other(action.name))
+        };
+        let result = self
+            .send_connection_action(control_url, deadline, service_type, action, body)
+            .await;
+        match (&result, other) {
+            (Err(e), Some(other)) if parsing::is_service_type_rejected(e) => {
+                common::record_retry(&self.options, action.name);
+                match self
+                    .send_connection_action(control_url, deadline, other, action, body)
+                    .await
+                {
+                    Err(ref e) if parsing::is_service_type_rejected(e) => result,
+                    retry => retry,
+                }
+            }
+            _ => result,
+        }
+    }
+
+    async fn send_connection_action(
+        &self,
+        control_url: &str,
+        deadline: Option<Instant>,
+        service_type: &'static str,
+        action: Action<'_>,
+        body: &str,
+    ) -> Result<RequestReponse, RequestError> {
+        let body = messages::rebind_service_type(body, service_type);
+        let result = self
+            .send_action(control_url, deadline, Action::new(service_type, action.name), &body)
+            .await;
+        if parsing::is_service_type_accepted(&result) {
+            self.connection_service
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .confirm(service_type);
+        }
+        result
+    }
+
+    async fn send_action(
+        &self,
+        control_url: &str,
+        deadline: Option<Instant>,
+        action: Action<'_>,
+        body: &str,
+    ) -> Result<RequestReponse, RequestError> {
+        match self
+            .send_request(control_url, deadline, &action.header(), action, body)
+            .await
+        {
+            // Some gateways only understand the header without quotes.
+            Err(ref e) if parsing::is_soap_action_rejected(e, action.name) => {
+                common::record_retry(&self.options, action.name);
+                self.send_request(control_url, deadline, &action.unquoted_header(), action, body)
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    async fn send_request(
+        &self,
+        control_url: &str,
+        deadline: Option<Instant>,
+        header: &str,
+        action: Action<'_>,
+        body: &str,
+    ) -> Result<RequestReponse, RequestError> {
+        let result = async {
+            let url = format!("http://{}{}", self.addr, control_url);
+            let mut request = HttpRequest::post(url, header, body.to_string(), &self.options);
+            request.timeout = common::time_left(deadline)?;
+            let response = self.transport.send(request).await?;
+            let body = common::filter_response(&self.options, response.body);
+            parsing::parse_http_response(response.status, body, &action.response_name())
+        }
+        .await;
+        common::record_request(&self.options, action.name, &result);
+        result
+    }
+
+    /// Type of the connection service the actions are sent to.
+    ///
+    /// A gateway found by a search uses the connection service of its description, such as
+    /// `urn:schemas-upnp-org:service:WANPPPConnection:1` for DSL gateways. One created with `Gateway::new` starts
+    /// with `urn:schemas-upnp-org:service:WANIPConnection:1`: when the gateway rejects an action every connection
+    /// service has, with UPnP error 401 (Invalid Action) or an HTTP 404, and accepts it from the other one, the
+    /// service type that worked is kept, for this gateway and its clones.
+    /// A gateway found with `SearchOptions::prefer_igd_v2` starts with
+    /// `urn:schemas-upnp-org:service:WANIPConnection:2` when it has it.
+    pub fn service_type(&self) -> &'static str {
+        self.connection_service
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .service_type
+    }
+
+    /// Whether the gateway implements `AddAnyPortMapping`, or `None` until `add_any_port` tried it.
+    ///
+    /// This is `Some(false)` once the gateway answered that it does not implement the action, and right away
+    /// if the control schema does not list it, as `add_any_port` then falls back to `AddPortMapping` without
+    /// asking. The answer is kept for this gateway and its clones, so later calls go straight to the fallback.
+    pub fn supports_add_any_port(&self) -> Option<bool> {
+        if !self.control_schema.contains_key("AddAnyPortMapping") {
+            return Some(false);
+        }
+        *self.add_any_port_support.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// What the gateway can do, from its device description and control schema
+    ///
+    /// The description and control schema are fetched on the first call only, and the result is kept for this
+    /// gateway and its clones. The service version and `AddAnyPortMapping` support follow what the gateway
+    /// answered since.
+    pub async fn capabilities(&self) -> Result<Capabilities, RequestError> {
+        let cached = self.capabilities.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let mut capabilities = match cached {
+            Some(capabilities) => capabilities,
+            None => {
+                let services = self.services().await?;
+                let max_lease_duration = self.max_lease_duration().await?;
+                let capabilities = Capabilities::from_description(&self.control_schema, &services, max_lease_duration);
+                *self.capabilities.lock().unwrap_or_else(|e| e.into_inner()) = Some(capabilities.clone());
+                capabilities
+            }
+        };
+        capabilities.update(self.service_type(), self.supports_add_any_port());
+        Ok(capabilities)
+    }
+
+    /// Check that a TCP connection to the gateway can be opened within `timeout`.
+    ///
+    /// This is a pure connectivity probe, independent of UPnP, to fail fast before sending actions to a gateway
+    /// that may be gone, for example one loaded from a cache. A `true` result does not tell the gateway still
+    /// answers UPnP requests at its control url.
+    #[cfg(feature = "aio")]
+    pub async fn is_reachable(&self, timeout: Duration) -> bool {
+        matches!(
+            tokio::time::timeout(timeout, TcpStream::connect(self.addr)).await,
+            Ok(Ok(..))
+        )
+    }
+
+    /// Address of this host on the network that reaches the gateway, the internal client of its mappings.
+    ///
+    /// For a gateway found by a search, this is the address its response was sent to, see
+    /// `DiscoveryInfo::local_ip`. Otherwise it is the source address the system would route packets to the
+    /// gateway from, found without sending any, so it picks the right interface on a host with several. For a
+    /// gateway reached over IPv6, it is the IPv4 address routing to the SSDP multicast group instead.
+    pub fn local_ip(&self) -> io::Result<Ipv4Addr> {
+        match self.discovery_info.as_ref().and_then(|info| info.local_ip) {
+            Some(ip) => Ok(ip),
+            None => common::local_ipv4_for(self.addr),
+        }
+    }
+
+    /// Send a SOAP envelope, usually built with `soap::build_envelope`, to the control url.
+    ///
+    /// This is an escape hatch for actions the other methods do not cover. On success the `{action}Response`
+    /// element is returned, and UPnP errors reported by the gateway are returned as `RequestError::ErrorCode`.
+    pub async fn send_envelope(
+        &self,
+        service_type: &str,
+        action: &str,
+        envelope: &str,
+    ) -> Result<xmltree::Element, RequestError> {
+        let response = self
+            .perform_request(Action::new(service_type, action), envelope)
+            .await?;
+        Ok(response.into_xml())
     }
 
     /// Get the external IP address of the gateway in a tokio compatible way
+    ///
+    /// Returns `GetExternalIpError::NotConnected` while the gateway has no external address yet.
     pub async fn get_external_ip(&self) -> Result<Ipv4Addr, GetExternalIpError> {
         let result = self
             .perform_request(
-                messages::GET_EXTERNAL_IP_HEADER,
+                messages::GET_EXTERNAL_IP_ACTION,
                 &messages::format_get_external_ip_message(),
-                "GetExternalIPAddressResponse",
             )
             .await;
         parsing::parse_get_external_ip_response(result)
     }
 
+    /// Get the state of the WAN connection of the gateway.
+    ///
+    /// This tells a gateway whose WAN link is down, which can still map ports, from one refusing to. `GetStatusInfo`
+    /// is required by IGD, but some gateways lack it and return a `RequestError::ErrorCode`.
+    pub async fn get_status_info(&self) -> Result<StatusInfo, RequestError> {
+        let result = self
+            .perform_request(
+                messages::GET_STATUS_INFO_ACTION,
+                &messages::format_get_status_info_message(),
+            )
+            .await;
+        parsing::parse_get_status_info_response(result)
+    }
+
+    /// Wait until the gateway is connected to the internet, and return its external IP address.
+    ///
+    /// The gateway is polled every `interval`. It is connected once `GetStatusInfo` reports `Connected` and its
+    /// external address is not `0.0.0.0`, so a gateway behind another NAT, with a private external address, is
+    /// connected too. For gateways without `GetStatusInfo`, the external address must also not be private, as
+    /// some report a LAN address while the WAN link comes up. Request errors that are retriable are ignored, as
+    /// the gateway may be booting too. If the timeout expires first, `GetExternalIpError::Timeout` is returned.
+    #[cfg(feature = "aio")]
+    pub async fn wait_for_connection(
+        &self,
+        timeout: Duration,
+        interval: Duration,
+    ) -> Result<Ipv4Addr, GetExternalIpError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.connected_ip().await {
+                Ok(Some(ip)) => return Ok(ip),
+                Ok(None) => {}
+                Err(GetExternalIpError::RequestError(ref e)) if e.is_retriable() => {}
+                Err(e) => return Err(e),
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(GetExternalIpError::Timeout);
+            }
+            tokio::time::sleep(interval.min(left)).await;
+        }
+    }
+
+    // The external IP address if the gateway is connected.
+    #[cfg(feature = "aio")]
+    async fn connected_ip(&self) -> Result<Option<Ipv4Addr>, GetExternalIpError> {
+        let connected = match self.get_status_info().await {
+            Ok(status) if !status.is_connected() => return Ok(None),
+            Ok(_) => true,
+            // Gateways without `GetStatusInfo`, which the address alone tells.
+            Err(RequestError::ErrorCode {
+                code: 401 | 602 | 606, ..
+            }) => false,
+            Err(e) => return Err(GetExternalIpError::RequestError(e)),
+        };
+        match self.get_external_ip().await {
+            Ok(ip) if connected => Ok(Some(ip).filter(|ip| !ip.is_unspecified())),
+            Ok(ip) => Ok(Some(ip).filter(|ip| common::is_connected_address(*ip))),
+            Err(GetExternalIpError::NotConnected) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get the external IPv6 address of a dual-stack gateway.
+    ///
+    /// IGD has no standard action for this: `WANIPv6FirewallControl:1` only opens pinholes, and
+    /// `WANIPConnection:2` reports IPv4 addresses. This calls the `X_AVM_DE_GetExternalIPv6Address` action of the
+    /// connection service, which AVM FRITZ!Box gateways provide. Other gateways, and gateways without an IPv6
+    /// connection, return `GetExternalIpv6Error::NotSupported`.
+    pub async fn get_external_ipv6(&self) -> Result<Ipv6Addr, GetExternalIpv6Error> {
+        let result = self
+            .perform_request(
+                messages::GET_EXTERNAL_IPV6_ACTION,
+                &messages::format_get_external_ipv6_message(),
+            )
+            .await;
+        parsing::parse_get_external_ipv6_response(result)
+    }
+
+    /// Check whether the gateway lets this client perform actions, before mapping any port.
+    ///
+    /// This calls the harmless `GetExternalIPAddress` action and returns `false` when the gateway answers with
+    /// error 606, which usually means UPnP is disabled in the router settings, or the client is not on its
+    /// allowed list. Other errors are returned as is.
+    pub async fn check_authorized(&self) -> Result<bool, RequestError> {
+        let result = self
+            .perform_request(
+                messages::GET_EXTERNAL_IP_ACTION,
+                &messages::format_get_external_ip_message(),
+            )
+            .await;
+        parsing::parse_check_authorized_response(result)
+    }
+
+    /// Read a state variable of the connection service, such as `PortMappingNumberOfEntries`.
+    ///
+    /// This uses the deprecated `QueryStateVariable` action, which many gateways still answer even for variables
+    /// without a getter. Unknown variables are usually reported as `RequestError::ErrorCode { code: 404, .. }`.
+    pub async fn query_state_variable(&self, name: &str) -> Result<String, RequestError> {
+        let result = self
+            .perform_request(
+                messages::QUERY_STATE_VARIABLE_ACTION,
+                &messages::format_query_state_variable_message(name),
+            )
+            .await;
+        parsing::parse_query_state_variable_response(result)
+    }
+
+    /// Get the DNS servers the gateway obtained for its WAN connection.
+    ///
+    /// Gateways without the optional `GetDNSServers` action return `GetDnsServersError::NotSupported`.
+    pub async fn get_dns_servers(&self) -> Result<Vec<Ipv4Addr>, GetDnsServersError> {
+        let result = self
+            .perform_request(
+                messages::GET_DNS_SERVERS_ACTION,
+                &messages::format_get_dns_servers_message(),
+            )
+            .await;
+        parsing::parse_get_dns_servers_response(result)
+    }
+
+    /// Get the line attributes of a DSL modem, from its `WANDSLLinkConfig:1` service.
+    ///
+    /// The service is looked up in the device description, so `RequestError::UnsupportedAction` is returned when
+    /// the gateway has no such service, or was created with `Gateway::new`.
+    pub async fn get_dsl_link_info(&self) -> Result<DslLinkInfo, RequestError> {
+        let control_url = self
+            .find_service_control_url(messages::WAN_DSL_LINK_CONFIG)
+            .await?
+            .ok_or_else(|| RequestError::UnsupportedAction("GetDSLLinkInfo".to_string()))?;
+        let link_info = self.perform_dsl_action(&control_url, "GetDSLLinkInfo").await;
+        let auto_config = self.perform_dsl_action(&control_url, "GetAutoConfig").await;
+        let modulation_type = self.perform_dsl_action(&control_url, "GetModulationType").await;
+        parsing::parse_dsl_link_info(link_info, auto_config, modulation_type)
+    }
+
+    async fn perform_dsl_action(&self, control_url: &str, action: &str) -> Result<RequestReponse, RequestError> {
+        self.perform_request_at(
+            control_url,
+            None,
+            Action::new(messages::WAN_DSL_LINK_CONFIG, action),
+            &soap::build_envelope(messages::WAN_DSL_LINK_CONFIG, action, ""),
+        )
+        .await
+    }
+
+    /// Get the WAN connections the gateway reports as active, from its `WANCommonInterfaceConfig:1` service.
+    ///
+    /// On gateways with several connection services, this tells which one is actually live. The number of
+    /// active connections is only a state variable, read with `QueryStateVariable`, and the connections are
+    /// requested by index until that number, or until the gateway reports the end of the list or repeats a
+    /// connection, at most 16. `RequestError::UnsupportedAction` is returned when the gateway has no such service,
+    /// or was created with `Gateway::new`.
+    pub async fn active_connections(&self) -> Result<Vec<ActiveConnection>, RequestError> {
+        let unsupported = || RequestError::UnsupportedAction("GetActiveConnection".to_string());
+        let description = self.get_description().await?.ok_or_else(unsupported)?;
+        let control_url =
+            parsing::parse_service_control_url(&self.addr, &description, messages::WAN_COMMON_INTERFACE_CONFIG)?
+                .ok_or_else(unsupported)?;
+        let result = self
+            .perform_request_at(
+                &control_url,
+                None,
+                messages::QUERY_STATE_VARIABLE_ACTION,
+                &messages::format_query_state_variable_message("NumberOfActiveConnections"),
+            )
+            .await;
+        let count = parsing::parse_number_of_active_connections(result);
+
+        let mut connections = Vec::new();
+        for index in 0..count
+            .unwrap_or(common::MAX_ACTIVE_CONNECTIONS)
+            .min(common::MAX_ACTIVE_CONNECTIONS)
+        {
+            let result = self
+                .perform_request_at(
+                    &control_url,
+                    None,
+                    messages::GET_ACTIVE_CONNECTION_ACTION,
+                    &messages::format_get_active_connection_message(index),
+                )
+                .await;
+            match parsing::parse_active_connection(&self.addr, &description, result)? {
+                Some(connection) if !connections.contains(&connection) => connections.push(connection),
+                _ => break,
+            }
+        }
+        Ok(connections)
+    }
+
+    /// Get the number of packets the gateway sent on its WAN interface, from its `WANCommonInterfaceConfig:1`
+    /// service.
+    ///
+    /// The counter is 32 bits and wraps around, so compare successive readings modulo `2^32`.
+    /// `RequestError::UnsupportedAction` is returned when the gateway has no such service, or was created with
+    /// `Gateway::new`.
+    pub async fn get_total_packets_sent(&self) -> Result<u32, RequestError> {
+        self.get_counter(messages::GET_TOTAL_PACKETS_SENT_ACTION, "NewTotalPacketsSent")
+            .await
+    }
+
+    /// Get the number of packets the gateway received on its WAN interface, like `get_total_packets_sent`.
+    pub async fn get_total_packets_received(&self) -> Result<u32, RequestError> {
+        self.get_counter(messages::GET_TOTAL_PACKETS_RECEIVED_ACTION, "NewTotalPacketsReceived")
+            .await
+    }
+
+    async fn get_counter(&self, action: Action<'_>, field: &str) -> Result<u32, RequestError> {
+        let control_url = self
+            .find_service_control_url(messages::WAN_COMMON_INTERFACE_CONFIG)
+            .await?
+            .ok_or_else(|| RequestError::UnsupportedAction(action.name.to_string()))?;
+        let result = self
+            .perform_request_at(
+                &control_url,
+                None,
+                action,
+                &soap::build_envelope(messages::WAN_COMMON_INTERFACE_CONFIG, action.name, ""),
+            )
+            .await;
+        parsing::parse_counter(result, field)
+    }
+
+    // Look up the control url of another service of the gateway in its description.
+    async fn find_service_control_url(&self, service_type: &str) -> Result<Option<String>, RequestError> {
+        match self.get_description().await? {
+            Some(text) => parsing::parse_service_control_url(&self.addr, &text, service_type),
+            None => Ok(None),
+        }
+    }
+
+    // Fetch the device description, unless the gateway was created without its root url.
+    async fn get_description(&self) -> Result<Option<String>, RequestError> {
+        if self.root_url.is_empty() {
+            return Ok(None);
+        }
+
+        let url = format!("http://{}{}", self.addr, self.root_url);
+        let response = self.transport.send(HttpRequest::get(url, &self.options)).await?;
+        parsing::parse_http_status(response.status, response.body).map(Some)
+    }
+
+    /// List all the services advertised in the device description, including those of the nested devices.
+    ///
+    /// Returns an empty list when the gateway was created with `Gateway::new`, since the description is unknown.
+    pub async fn services(&self) -> Result<Vec<ServiceInfo>, RequestError> {
+        match self.get_description().await? {
+            Some(text) => parsing::parse_services(&text),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Get the longest lease duration in seconds the gateway accepts for port mappings.
+    ///
+    /// The limit is read from the `allowedValueRange` declared in the control schema, as IGDv2 gateways do.
+    /// Returns `None` when the lease duration is not constrained, or when the control schema url is unknown
+    /// because the gateway was created with `Gateway::new`.
+    pub async fn max_lease_duration(&self) -> Result<Option<u32>, RequestError> {
+        if self.control_schema_url.is_empty() {
+            return Ok(None);
+        }
+
+        let url = format!("http://{}{}", self.addr, self.control_schema_url);
+        let response = self.transport.send(HttpRequest::get(url, &self.options)).await?;
+        let text = parsing::parse_http_status(response.status, response.body)?;
+        parsing::parse_max_lease_duration(&text)
+    }
+
     /// Get an external socket address with our external ip and any port. This is a convenience
     /// function that calls `get_external_ip` followed by `add_any_port`
     ///
@@ -59,12 +611,103 @@ impl Gateway {
         lease_duration: u32,
         description: &str,
     ) -> Result<SocketAddrV4, AddAnyPortError> {
-        let description = description.to_owned();
         let ip = self.get_external_ip().await?;
-        let port = self
-            .add_any_port(protocol, local_addr, lease_duration, &description)
+        let any_port = self
+            .add_any_port_with_fallback(protocol, local_addr, lease_duration, description)
+            .await?;
+        Ok(SocketAddrV4::new(ip, any_port.port))
+    }
+
+    /// Add a port mapping with any external port, like `get_any_address`, and describe how it was added.
+    ///
+    /// Besides the external address, the result tells the lease to renew the mapping before, whether the
+    /// gateway lacked `AddAnyPortMapping` so that random ports were tried with `AddPortMapping`, and whether
+    /// the gateway forced the external port to be the internal port. The lease is read back from the gateway
+    /// with `GetSpecificPortMappingEntry`, which costs a request.
+    pub async fn get_any_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<MappingResult, AddAnyPortError> {
+        let ip = self.get_external_ip().await?;
+        let any_port = self
+            .add_any_port_with_fallback(protocol, local_addr, lease_duration, description)
             .await?;
-        Ok(SocketAddrV4::new(ip, port))
+        let lease_duration = common::lease_duration(&self.options, lease_duration);
+        let lease_duration = if self.options.dry_run {
+            lease_duration
+        } else {
+            self.get_specific_port_mapping_entry(protocol, any_port.port)
+                .await
+                .map_or(lease_duration, |entry| entry.lease_duration)
+        };
+        Ok(MappingResult {
+            external_addr: SocketAddrV4::new(ip, any_port.port),
+            lease_duration,
+            used_legacy_fallback: any_port.used_legacy_fallback,
+            same_port_forced: any_port.same_port_forced,
+        })
+    }
+
+    /// Map an external port of the gateway to a bound TCP listener, with any external port.
+    ///
+    /// This calls `get_any_address` with the local address of the listener, which must be bound to the IPv4
+    /// address of an interface rather than `0.0.0.0`, so the gateway knows where to send the traffic.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    ///
+    /// # Returns
+    ///
+    /// The external address that was mapped on success. Otherwise an error.
+    #[cfg(feature = "aio")]
+    pub async fn forward_listener(
+        &self,
+        listener: &TcpListener,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<SocketAddrV4, AddAnyPortError> {
+        let local_addr = common::forwarded_addr(listener.local_addr())?;
+        self.get_any_address(PortMappingProtocol::TCP, local_addr, lease_duration, description)
+            .await
+    }
+
+    /// Map an external port of the gateway to a bound UDP socket, like `forward_listener` does for TCP.
+    #[cfg(feature = "aio")]
+    pub async fn forward_udp_socket(
+        &self,
+        socket: &UdpSocket,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<SocketAddrV4, AddAnyPortError> {
+        let local_addr = common::forwarded_addr(socket.local_addr())?;
+        self.get_any_address(PortMappingProtocol::UDP, local_addr, lease_duration, description)
+            .await
+    }
+
+    /// Map `external_port` to `local_port` on this host, at the address given by `local_ip`.
+    ///
+    /// This is `add_port` without having to know which local address reaches the gateway. The lease_duration
+    /// parameter is in seconds. A value of 0 is infinite.
+    ///
+    /// # Returns
+    ///
+    /// The internal address that was mapped on success. Otherwise an error.
+    pub async fn forward_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_port: u16,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<SocketAddrV4, AddPortError> {
+        let local_ip = self
+            .local_ip()
+            .map_err(|e| AddPortError::RequestError(RequestError::IoError(e)))?;
+        let local_addr = SocketAddrV4::new(local_ip, local_port);
+        self.add_port(protocol, external_port, local_addr, lease_duration, description)
+            .await?;
+        Ok(local_addr)
     }
 
     /// Add a port mapping.with any external port.
@@ -75,6 +718,15 @@ impl Gateway {
     /// # Returns
     ///
     /// The external port that was mapped on success. Otherwise an error.
+    /// This is the internal port if the gateway requires both to be the same, which `get_any_mapping` reports.
+    ///
+    /// # Cancellation
+    ///
+    /// Dropping the future stops the retries: each attempt is a single request, and the next port is only tried
+    /// once the gateway rejected the previous one, so at most one attempt is in flight. If that attempt reached
+    /// the gateway before the future was dropped, the mapping may exist under a port the caller never learns.
+    /// Use a non-zero lease so such a mapping expires, or look it up by its description, for example with
+    /// `find_mappings`, to remove it.
     pub async fn add_any_port(
         &self,
         protocol: PortMappingProtocol,
@@ -82,90 +734,221 @@ impl Gateway {
         lease_duration: u32,
         description: &str,
     ) -> Result<u16, AddAnyPortError> {
+        self.add_any_port_with_fallback(protocol, local_addr, lease_duration, description)
+            .await
+            .map(|any_port| any_port.port)
+    }
+
+    async fn add_any_port_with_fallback(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<AnyPort, AddAnyPortError> {
         // This function first attempts to call AddAnyPortMapping on the IGD with a random port
         // number. If that fails due to the method being unknown it attempts to call AddPortMapping
-        // instead with a random port number. If that fails due to ConflictInMappingEntry it retrys
-        // with another port up to a maximum of 20 times. If it fails due to SamePortValuesRequired
+        // instead with a random port number. If that fails due to ConflictInMappingEntry or a retriable
+        // request error it retrys with another port up to a maximum of 20 times. If it fails due to SamePortValuesRequired
         // it retrys once with the same port values.
 
         if local_addr.port() == 0 {
             return Err(AddAnyPortError::InternalPortZeroInvalid);
         }
+        if self.options.only_local_clients && !common::is_local_address(*local_addr.ip()) {
+            return Err(AddAnyPortError::InternalClientNotLocal);
+        }
+        common::check_description(
+            &self.options,
+            description,
+            AddAnyPortError::DescriptionEmpty,
+            AddAnyPortError::DescriptionNotAscii,
+        )?;
+
+        if self.options.dry_run {
+            return Ok(AnyPort {
+                port: self.check_any_port(protocol, local_addr).await?,
+                used_legacy_fallback: !self.control_schema.contains_key("AddAnyPortMapping"),
+                same_port_forced: false,
+            });
+        }
+
+        let deadline = self
+            .options
+            .add_any_port_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let result = self
+            .add_any_port_before(deadline, protocol, local_addr, lease_duration, description)
+            .await;
+        match result {
+            Err(AddAnyPortError::RequestError(..)) if common::is_expired(deadline) => Err(AddAnyPortError::Timeout),
+            result => result,
+        }
+    }
 
+    async fn add_any_port_before(
+        &self,
+        deadline: Option<Instant>,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<AnyPort, AddAnyPortError> {
         let schema = self.control_schema.get("AddAnyPortMapping");
-        if let Some(schema) = schema {
-            let external_port = common::random_port();
+        if let (Some(schema), Some(true) | None) = (schema, self.supports_add_any_port()) {
+            let external_port = common::random_port(&self.options);
 
             let description = description.to_owned();
 
             let resp = self
-                .perform_request(
-                    messages::ADD_ANY_PORT_MAPPING_HEADER,
+                .perform_request_at(
+                    &self.control_url,
+                    deadline,
+                    messages::ADD_ANY_PORT_MAPPING_ACTION,
                     &messages::format_add_any_port_mapping_message(
                         schema,
                         protocol,
                         external_port,
                         local_addr,
-                        lease_duration,
+                        common::lease_duration(&self.options, lease_duration),
                         &description,
                     ),
-                    "AddAnyPortMappingResponse",
                 )
                 .await;
-            parsing::parse_add_any_port_mapping_response(resp)
+            if let Some(supported) = parsing::is_add_any_port_supported(&resp) {
+                *self.add_any_port_support.lock().unwrap_or_else(|e| e.into_inner()) = Some(supported);
+            }
+            match parsing::parse_add_any_port_mapping_response(resp) {
+                // The gateway lists the action in its schema but does not implement it.
+                Err(AddAnyPortError::RequestError(RequestError::ErrorCode { code: 401, .. })) => {
+                    self.retry_add_random_port_mapping(deadline, protocol, local_addr, lease_duration, &description)
+                        .await
+                }
+                result => result.map(|port| AnyPort {
+                    port,
+                    used_legacy_fallback: false,
+                    same_port_forced: false,
+                }),
+            }
         } else {
             // The router does not have the AddAnyPortMapping method.
             // Fall back to using AddPortMapping with a random port.
             let gateway = self.clone();
             gateway
-                .retry_add_random_port_mapping(protocol, local_addr, lease_duration, &description)
+                .retry_add_random_port_mapping(deadline, protocol, local_addr, lease_duration, description)
                 .await
         }
     }
 
+    async fn check_any_port(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+    ) -> Result<u16, AddAnyPortError> {
+        for _ in 0u8..20u8 {
+            let external_port = common::random_port(&self.options);
+            if self
+                .is_port_available(RemoteHost::Wildcard, protocol, external_port, local_addr)
+                .await?
+            {
+                return Ok(external_port);
+            }
+        }
+        Err(AddAnyPortError::NoPortsAvailable)
+    }
+
+    // The port is available if it is not mapped, or already mapped to local_addr.
+    async fn is_port_available(
+        &self,
+        remote_host: RemoteHost,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+    ) -> Result<bool, GetSpecificPortMappingEntryError> {
+        match self
+            .get_specific_port_mapping_entry_with_remote_host(remote_host, protocol, external_port)
+            .await
+        {
+            Ok(entry) => {
+                Ok(entry.internal_client == local_addr.ip().to_string() && entry.internal_port == local_addr.port())
+            }
+            Err(GetSpecificPortMappingEntryError::NoSuchEntryInArray) => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
     async fn retry_add_random_port_mapping(
         &self,
+        deadline: Option<Instant>,
         protocol: PortMappingProtocol,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
-    ) -> Result<u16, AddAnyPortError> {
-        for _ in 0u8..20u8 {
+    ) -> Result<AnyPort, AddAnyPortError> {
+        let mut last_error = AddAnyPortError::NoPortsAvailable;
+        for attempt in 0..20 {
+            if attempt > 0 {
+                backoff(common::backoff_delay(&self.options, attempt, deadline)).await;
+            }
+            if common::is_expired(deadline) {
+                return Err(AddAnyPortError::Timeout);
+            }
             match self
-                .add_random_port_mapping(protocol, local_addr, lease_duration, &description)
+                .add_random_port_mapping(deadline, protocol, local_addr, lease_duration, description)
                 .await
             {
-                Ok(port) => return Ok(port),
-                Err(AddAnyPortError::NoPortsAvailable) => continue,
+                Ok(any_port) => return Ok(any_port),
+                Err(e @ AddAnyPortError::NoPortsAvailable) => last_error = e,
+                // A transient failure may be over for the next port.
+                Err(AddAnyPortError::RequestError(e)) if e.is_retriable() => {
+                    last_error = AddAnyPortError::RequestError(e)
+                }
                 e => return e,
             }
         }
-        Err(AddAnyPortError::NoPortsAvailable)
+        Err(last_error)
     }
 
     async fn add_random_port_mapping(
         &self,
+        deadline: Option<Instant>,
         protocol: PortMappingProtocol,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
-    ) -> Result<u16, AddAnyPortError> {
+    ) -> Result<AnyPort, AddAnyPortError> {
         let description = description.to_owned();
         let gateway = self.clone();
 
-        let external_port = common::random_port();
+        let external_port = common::random_port(&self.options);
         let res = self
-            .add_port_mapping(protocol, external_port, local_addr, lease_duration, &description)
+            .add_wildcard_port_mapping(
+                deadline,
+                protocol,
+                external_port,
+                local_addr,
+                lease_duration,
+                &description,
+            )
             .await;
 
         match res {
-            Ok(_) => Ok(external_port),
+            Ok(_) => Ok(AnyPort {
+                port: external_port,
+                used_legacy_fallback: true,
+                same_port_forced: false,
+            }),
             Err(err) => match parsing::convert_add_random_port_mapping_error(err) {
                 Some(err) => Err(err),
                 None => {
-                    gateway
-                        .add_same_port_mapping(protocol, local_addr, lease_duration, &description)
-                        .await
+                    let port = gateway
+                        .add_same_port_mapping(deadline, protocol, local_addr, lease_duration, &description)
+                        .await?;
+                    Ok(AnyPort {
+                        port,
+                        used_legacy_fallback: true,
+                        same_port_forced: true,
+                    })
                 }
             },
         }
@@ -173,13 +956,21 @@ impl Gateway {
 
     async fn add_same_port_mapping(
         &self,
+        deadline: Option<Instant>,
         protocol: PortMappingProtocol,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
     ) -> Result<u16, AddAnyPortError> {
         let res = self
-            .add_port_mapping(protocol, local_addr.port(), local_addr, lease_duration, description)
+            .add_wildcard_port_mapping(
+                deadline,
+                protocol,
+                local_addr.port(),
+                local_addr,
+                lease_duration,
+                description,
+            )
             .await;
         match res {
             Ok(_) => Ok(local_addr.port()),
@@ -189,6 +980,7 @@ impl Gateway {
 
     async fn add_port_mapping(
         &self,
+        remote_host: RemoteHost,
         protocol: PortMappingProtocol,
         external_port: u16,
         local_addr: SocketAddrV4,
@@ -196,23 +988,69 @@ impl Gateway {
         description: &str,
     ) -> Result<(), RequestError> {
         self.perform_request(
-            messages::ADD_PORT_MAPPING_HEADER,
-            &messages::format_add_port_mapping_message(
-                self.control_schema
-                    .get("AddPortMapping")
-                    .ok_or_else(|| RequestError::UnsupportedAction("AddPortMapping".to_string()))?,
+            messages::ADD_PORT_MAPPING_ACTION,
+            &self.format_add_port_mapping(
+                remote_host,
                 protocol,
                 external_port,
                 local_addr,
                 lease_duration,
                 description,
-            ),
-            "AddPortMappingResponse",
+            )?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    // The mappings tried by `add_any_port`, which must complete before its deadline.
+    async fn add_wildcard_port_mapping(
+        &self,
+        deadline: Option<Instant>,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(), RequestError> {
+        self.perform_request_at(
+            &self.control_url,
+            deadline,
+            messages::ADD_PORT_MAPPING_ACTION,
+            &self.format_add_port_mapping(
+                RemoteHost::Wildcard,
+                protocol,
+                external_port,
+                local_addr,
+                lease_duration,
+                description,
+            )?,
         )
         .await?;
         Ok(())
     }
 
+    fn format_add_port_mapping(
+        &self,
+        remote_host: RemoteHost,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<String, RequestError> {
+        Ok(messages::format_add_port_mapping_message(
+            self.control_schema
+                .get("AddPortMapping")
+                .ok_or_else(|| RequestError::UnsupportedAction("AddPortMapping".to_string()))?,
+            remote_host,
+            protocol,
+            external_port,
+            local_addr,
+            common::lease_duration(&self.options, lease_duration),
+            description,
+        ))
+    }
+
     /// Add a port mapping.
     ///
     /// The local_addr is the address where the traffic is sent to.
@@ -224,6 +1062,164 @@ impl Gateway {
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
+    ) -> Result<(), AddPortError> {
+        self.add_port_with_remote_host(
+            RemoteHost::Wildcard,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        )
+        .await
+    }
+
+    /// Add a port mapping like `add_port`, then read it back to report how the gateway changed it.
+    ///
+    /// Gateways may silently shorten the lease, truncate or drop the description, or not list the mapping at all,
+    /// which `add_port` does not tell. This strict mode costs a `GetSpecificPortMappingEntry` request, and returns
+    /// the differences found, if any. Nothing is read back with `GatewayOptions::dry_run`.
+    pub async fn add_port_verified(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<Vec<MappingWarning>, AddPortError> {
+        self.add_port(protocol, external_port, local_addr, lease_duration, description)
+            .await?;
+        if self.options.dry_run {
+            return Ok(Vec::new());
+        }
+        match self.get_specific_port_mapping_entry(protocol, external_port).await {
+            Ok(entry) => {
+                let lease_duration = common::lease_duration(&self.options, lease_duration);
+                Ok(parsing::check_mapping_entry(&entry, lease_duration, description))
+            }
+            Err(GetSpecificPortMappingEntryError::NoSuchEntryInArray) => Ok(vec![MappingWarning::NotListed]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Add a permanent port mapping, which stays until it is removed or the gateway restarts.
+    ///
+    /// This is `add_port` with a `lease_duration` of 0. Some gateways only accept such leases, and return
+    /// `AddPortError::OnlyPermanentLeasesSupported` to `add_port_temporary`. A permanent mapping outlives the
+    /// application if it is not removed, so prefer a temporary one that is renewed when the gateway allows it.
+    pub async fn add_port_permanent(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        description: &str,
+    ) -> Result<(), AddPortError> {
+        self.add_port(protocol, external_port, local_addr, 0, description).await
+    }
+
+    /// Add a port mapping that expires after `lease_duration` seconds.
+    ///
+    /// Unlike `add_port`, a `lease_duration` of 0 is rejected with `AddPortError::LeaseDurationZeroInvalid`
+    /// rather than making the mapping permanent. Gateways that only support permanent leases return
+    /// `AddPortError::OnlyPermanentLeasesSupported`, after which the caller can fall back to `add_port_permanent`.
+    pub async fn add_port_temporary(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(), AddPortError> {
+        if lease_duration == 0 {
+            return Err(AddPortError::LeaseDurationZeroInvalid);
+        }
+        self.add_port(protocol, external_port, local_addr, lease_duration, description)
+            .await
+    }
+
+    /// Find the protocols the gateway accepts mappings of `external_port` for.
+    ///
+    /// Some gateways reject UDP, or TCP, mappings on some ports. For each protocol, a mapping of `external_port`
+    /// to the same port of this host is added with a lease of 30 seconds, or a permanent one if the gateway
+    /// requires it, and removed at once. The mapping is also removed when adding it failed without an answer
+    /// from the gateway. A protocol that already has a mapping of the port, be it listed or reported as a
+    /// conflict (718), is supported without probing further, and that mapping is left untouched.
+    ///
+    /// The gateway answering the probe with another error code means the protocol is not supported. A probe that
+    /// fails otherwise, such as on a lost connection, is skipped and its protocol is not reported.
+    pub async fn supported_protocols_for(&self, external_port: u16) -> Result<Vec<PortMappingProtocol>, RequestError> {
+        let local_addr = SocketAddrV4::new(self.local_ip().map_err(RequestError::IoError)?, external_port);
+        let mut supported = Vec::new();
+        for &protocol in &[PortMappingProtocol::TCP, PortMappingProtocol::UDP] {
+            match self.probe_protocol(protocol, local_addr).await {
+                Ok(true) => supported.push(protocol),
+                Ok(false) => {}
+                Err(e) => debug!("could not probe {} mappings of port {}: {}", protocol, external_port, e),
+            }
+        }
+        Ok(supported)
+    }
+
+    async fn probe_protocol(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+    ) -> Result<bool, RequestError> {
+        let external_port = local_addr.port();
+        match self.get_specific_port_mapping_entry(protocol, external_port).await {
+            Ok(_) => return Ok(true),
+            Err(GetSpecificPortMappingEntryError::NoSuchEntryInArray) => {}
+            // A conflict when adding the probe still tells about a mapping the lookup missed.
+            Err(e) => debug!(
+                "could not look up the {} mapping of port {}: {}",
+                protocol, external_port, e
+            ),
+        }
+
+        let description = common::PROBE_DESCRIPTION;
+        let result = match self
+            .add_port(
+                protocol,
+                external_port,
+                local_addr,
+                common::PROBE_LEASE_DURATION,
+                description,
+            )
+            .await
+        {
+            Err(AddPortError::OnlyPermanentLeasesSupported) => {
+                self.add_port(protocol, external_port, local_addr, 0, description).await
+            }
+            result => result,
+        };
+        match result {
+            Ok(()) => {}
+            Err(AddPortError::PortInUse) => return Ok(true),
+            Err(AddPortError::RequestError(RequestError::ErrorCode { .. })) => return Ok(false),
+            Err(AddPortError::RequestError(e)) => {
+                // The gateway may have added the mapping before the request failed.
+                let _ = self.remove_port(protocol, external_port).await;
+                return Err(e);
+            }
+            Err(_) => return Ok(false),
+        }
+        match self.remove_port(protocol, external_port).await {
+            Ok(()) | Err(RemovePortError::NoSuchPortMapping) => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Add a port mapping that only applies to traffic from `remote_host`.
+    ///
+    /// `add_port` is the same as this function with `RemoteHost::Wildcard`.
+    pub async fn add_port_with_remote_host(
+        &self,
+        remote_host: RemoteHost,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
     ) -> Result<(), AddPortError> {
         if external_port == 0 {
             return Err(AddPortError::ExternalPortZeroInvalid);
@@ -231,9 +1227,41 @@ impl Gateway {
         if local_addr.port() == 0 {
             return Err(AddPortError::InternalPortZeroInvalid);
         }
+        if self.options.only_local_clients && !common::is_local_address(*local_addr.ip()) {
+            return Err(AddPortError::InternalClientNotLocal);
+        }
+        common::check_description(
+            &self.options,
+            description,
+            AddPortError::DescriptionEmpty,
+            AddPortError::DescriptionNotAscii,
+        )?;
+
+        if self.options.dry_run {
+            if !self.control_schema.contains_key("AddPortMapping") {
+                return Err(AddPortError::RequestError(RequestError::UnsupportedAction(
+                    "AddPortMapping".to_string(),
+                )));
+            }
+            return if self
+                .is_port_available(remote_host, protocol, external_port, local_addr)
+                .await?
+            {
+                Ok(())
+            } else {
+                Err(AddPortError::PortInUse)
+            };
+        }
 
         let res = self
-            .add_port_mapping(protocol, external_port, local_addr, lease_duration, description)
+            .add_port_mapping(
+                remote_host,
+                protocol,
+                external_port,
+                local_addr,
+                lease_duration,
+                description,
+            )
             .await;
         if let Err(err) = res {
             return Err(parsing::convert_add_port_error(err));
@@ -241,21 +1269,186 @@ impl Gateway {
         Ok(())
     }
 
+    /// Renew the lease of an existing port mapping.
+    ///
+    /// The mapping is read with `get_specific_port_mapping_entry` and added again with the same internal
+    /// client, internal port, description, remote host and enabled state, for `lease_duration` seconds. A value
+    /// of 0 is infinite.
+    /// `RenewPortError::NoSuchPortMapping` is returned if the gateway dropped the mapping.
+    pub async fn renew_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        lease_duration: u32,
+    ) -> Result<(), RenewPortError> {
+        let entry = self.get_specific_port_mapping_entry(protocol, external_port).await?;
+        let internal_client = entry
+            .internal_client
+            .parse()
+            .map_err(|_| RequestError::InvalidResponse(format!("Invalid internal client {}", entry.internal_client)))?;
+        if self.options.dry_run {
+            return Ok(());
+        }
+
+        self.add_entry_again(
+            &entry,
+            SocketAddrV4::new(internal_client, entry.internal_port),
+            lease_duration,
+        )
+        .await
+        .map_err(parsing::convert_renew_port_error)
+    }
+
+    /// Point an existing port mapping to `new_local_addr`, keeping its description, remaining lease, remote host
+    /// and enabled state.
+    ///
+    /// IGD has no action to update a mapping, so the mapping is read with `get_specific_port_mapping_entry` and
+    /// added again with the new internal client. `AddPortMapping` overwrites the entry of the same external port
+    /// at once on most gateways, without the window of a removal followed by an addition during which inbound
+    /// traffic is dropped. Gateways that refuse to give the port to another client answer
+    /// `AddPortError::PortInUse`, and the mapping is left unchanged. A missing mapping is reported as
+    /// `RequestError::ErrorCode` with code 714 (NoSuchEntryInArray).
+    pub async fn repoint_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        new_local_addr: SocketAddrV4,
+    ) -> Result<(), AddPortError> {
+        if new_local_addr.port() == 0 {
+            return Err(AddPortError::InternalPortZeroInvalid);
+        }
+        if self.options.only_local_clients && !common::is_local_address(*new_local_addr.ip()) {
+            return Err(AddPortError::InternalClientNotLocal);
+        }
+        let entry = self.get_specific_port_mapping_entry(protocol, external_port).await?;
+        if self.options.dry_run {
+            return Ok(());
+        }
+
+        self.add_entry_again(&entry, new_local_addr, entry.lease_duration)
+            .await
+            .map_err(parsing::convert_add_port_error)
+    }
+
+    // Add `entry` again for `local_addr` and `lease_duration`, keeping its remote host and whether it is enabled.
+    async fn add_entry_again(
+        &self,
+        entry: &parsing::PortMappingEntry,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+    ) -> Result<(), RequestError> {
+        // A remote host name can not be sent back, the mapping then applies to any host.
+        let remote_host = parsing::parse_remote_host(&entry.remote_host).unwrap_or_default();
+        let message = self.format_add_port_mapping(
+            remote_host,
+            entry.protocol,
+            entry.external_port,
+            local_addr,
+            lease_duration,
+            &entry.port_mapping_description,
+        )?;
+        let message = if entry.enabled {
+            message
+        } else {
+            messages::disable_mapping(&message)
+        };
+        self.perform_request(messages::ADD_PORT_MAPPING_ACTION, &message)
+            .await?;
+        Ok(())
+    }
+
     /// Remove a port mapping.
+    ///
+    /// A gateway answering with an empty body is taken to have removed it, even though some gateways answer so
+    /// when there was no such mapping.
     pub async fn remove_port(&self, protocol: PortMappingProtocol, external_port: u16) -> Result<(), RemovePortError> {
+        self.remove_port_with_remote_host(RemoteHost::Wildcard, protocol, external_port)
+            .await
+    }
+
+    /// Remove a port mapping added for `remote_host`.
+    pub async fn remove_port_with_remote_host(
+        &self,
+        remote_host: RemoteHost,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<(), RemovePortError> {
+        if self.options.dry_run {
+            if !self.control_schema.contains_key("DeletePortMapping") {
+                return Err(RemovePortError::RequestError(RequestError::UnsupportedAction(
+                    "DeletePortMapping".to_string(),
+                )));
+            }
+            self.get_specific_port_mapping_entry_with_remote_host(remote_host, protocol, external_port)
+                .await?;
+            return Ok(());
+        }
+
         let res = self
             .perform_request(
-                messages::DELETE_PORT_MAPPING_HEADER,
+                messages::DELETE_PORT_MAPPING_ACTION,
                 &messages::format_delete_port_message(
-                    self.control_schema
-                        .get("DeletePortMapping")
-                        .ok_or_else(|| RemovePortError::RequestError(RequestError::UnsupportedAction(
-                            "DeletePortMapping".to_string(),
-                        )))?,
+                    self.control_schema.get("DeletePortMapping").ok_or_else(|| {
+                        RemovePortError::RequestError(RequestError::UnsupportedAction("DeletePortMapping".to_string()))
+                    })?,
+                    remote_host,
                     protocol,
                     external_port,
                 ),
-                "DeletePortMappingResponse",
+            )
+            .await;
+        parsing::parse_delete_port_mapping_response(res)
+    }
+
+    /// Remove the port mapping of an entry listed by `port_mappings`, with its remote host.
+    ///
+    /// A mapping that disappeared since it was listed is taken as removed. An entry whose remote host is a host
+    /// name can not be passed back to the gateway, and is reported as `RequestError::InvalidResponse`.
+    pub async fn remove_entry(&self, entry: &parsing::PortMappingEntry) -> Result<(), RemovePortError> {
+        let remote_host = parsing::parse_remote_host(&entry.remote_host).ok_or_else(|| {
+            RemovePortError::RequestError(RequestError::InvalidResponse(format!(
+                "remote host {} is not an IP address",
+                entry.remote_host
+            )))
+        })?;
+        match self
+            .remove_port_with_remote_host(remote_host, entry.protocol, entry.external_port)
+            .await
+        {
+            Err(RemovePortError::NoSuchPortMapping) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Remove the mappings of `protocol` whose external port is between `start_port` and `end_port`, in one
+    /// request
+    ///
+    /// `DeletePortMappingRange` is an action of `WANIPConnection:2`, like `get_list_of_port_mappings`: other
+    /// gateways answer with a `RequestError`. With `manage`, the mappings of all the clients are removed, which
+    /// the gateway may not authorize, otherwise only those of this host. A range without any mapping is
+    /// reported as `RemovePortError::NoSuchPortMapping`.
+    pub async fn remove_port_range(
+        &self,
+        protocol: PortMappingProtocol,
+        start_port: u16,
+        end_port: u16,
+        manage: bool,
+    ) -> Result<(), RemovePortError> {
+        if self.options.dry_run {
+            return match self
+                .get_list_of_port_mappings(protocol, start_port, end_port, manage, 1)
+                .await
+                .map_err(RemovePortError::RequestError)?
+            {
+                entries if entries.is_empty() => Err(RemovePortError::NoSuchPortMapping),
+                _ => Ok(()),
+            };
+        }
+
+        let res = self
+            .perform_request(
+                messages::DELETE_PORT_MAPPING_RANGE_ACTION,
+                &messages::format_delete_port_range_message(protocol, start_port, end_port, manage),
             )
             .await;
         parsing::parse_delete_port_mapping_response(res)
@@ -272,13 +1465,226 @@ impl Gateway {
     ) -> Result<parsing::PortMappingEntry, errors::GetGenericPortMappingEntryError> {
         let result = self
             .perform_request(
-                messages::GET_GENERIC_PORT_MAPPING_ENTRY,
+                messages::GET_GENERIC_PORT_MAPPING_ENTRY_ACTION,
                 &messages::formate_get_generic_port_mapping_entry_message(index),
-                "GetGenericPortMappingEntryResponse",
             )
             .await;
         parsing::parse_get_generic_port_mapping_entry(result)
     }
+
+    /// Stream the port mapping entries, the async counterpart of the blocking `Gateway::port_mappings`
+    ///
+    /// Each entry is yielded as soon as its `get_generic_port_mapping_entry` request completes, so a long table
+    /// can be shown while it loads. The stream ends when the gateway reports the end of the list, or after the
+    /// first error. It also ends when the gateway answers the previous entry again, as some ignore the index,
+    /// and after 131072 entries, two per external port.
+    pub fn port_mappings_stream(
+        &self,
+    ) -> impl Stream<Item = Result<parsing::PortMappingEntry, GetGenericPortMappingEntryError>> + '_ {
+        stream::unfold(Some((0, None)), move |state| async move {
+            let (index, last) = state?;
+            if index >= common::MAX_PORT_MAPPING_ENTRIES {
+                return None;
+            }
+            match self.get_generic_port_mapping_entry(index).await {
+                Ok(entry) if last.as_ref() == Some(&entry) => None,
+                Ok(entry) => Some((Ok(entry.clone()), Some((index + 1, Some(entry))))),
+                Err(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => None,
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+
+    /// Get all the port mapping entries accepted by `filter`
+    ///
+    /// Entries are fetched one by one with `get_generic_port_mapping_entry`, until the end of
+    /// `port_mappings_stream`.
+    pub async fn find_mappings<F>(
+        &self,
+        filter: F,
+    ) -> Result<Vec<parsing::PortMappingEntry>, GetGenericPortMappingEntryError>
+    where
+        F: Fn(&parsing::PortMappingEntry) -> bool,
+    {
+        let mut entries = Vec::new();
+        let mut mappings = Box::pin(self.port_mappings_stream());
+        while let Some(entry) = mappings.next().await {
+            let entry = entry?;
+            if filter(&entry) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Get all the port mapping entries whose internal client is `ip`
+    pub async fn find_mappings_for(
+        &self,
+        ip: Ipv4Addr,
+    ) -> Result<Vec<parsing::PortMappingEntry>, GetGenericPortMappingEntryError> {
+        let ip = ip.to_string();
+        self.find_mappings(|entry| entry.internal_client == ip).await
+    }
+
+    /// Remove all the port mappings whose internal client is `internal_client`, and return how many were removed.
+    ///
+    /// This cleans up the mappings left under the previous address of a host whose DHCP lease changed. The
+    /// mappings are removed like with `prune_mappings`, which tells the mappings that could not be removed: here
+    /// they are only logged and not counted. An error is returned if the mappings could not be listed.
+    pub async fn remove_mappings_for(&self, internal_client: Ipv4Addr) -> Result<usize, RequestError> {
+        let internal_client = internal_client.to_string();
+        let results = self
+            .prune_mappings(|entry| entry.internal_client == internal_client)
+            .await?;
+        for (entry, e) in &results.failures {
+            warn!(
+                "could not remove the {} mapping of port {}: {}",
+                entry.protocol, entry.external_port, e
+            );
+        }
+        Ok(results.removed)
+    }
+
+    /// Remove all the port mappings accepted by `predicate`, such as `PortMappingEntry::description_starts_with`.
+    ///
+    /// This reclaims the mappings leaked by crashed processes, before they fill the table of the gateway. The
+    /// mappings are listed first, then removed one by one. Those that disappeared in between are not counted,
+    /// and those with a host name as remote host are skipped, as they can not be passed back to the gateway.
+    /// The other removal errors do not stop the pruning, and are returned in `PruneResults::failures`.
+    pub async fn prune_mappings<F>(&self, predicate: F) -> Result<PruneResults, GetGenericPortMappingEntryError>
+    where
+        F: Fn(&parsing::PortMappingEntry) -> bool,
+    {
+        let mut results = PruneResults {
+            removed: 0,
+            failures: Vec::new(),
+        };
+        for entry in self.find_mappings(predicate).await? {
+            let remote_host = match parsing::parse_remote_host(&entry.remote_host) {
+                Some(remote_host) => remote_host,
+                None => {
+                    debug!("not removing mapping with remote host {}", entry.remote_host);
+                    continue;
+                }
+            };
+            match self
+                .remove_port_with_remote_host(remote_host, entry.protocol, entry.external_port)
+                .await
+            {
+                Ok(()) => results.removed += 1,
+                Err(RemovePortError::NoSuchPortMapping) => {}
+                Err(e) => results.failures.push((entry, e)),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Whether the port mapping of a protocol and external port is enabled, from its `NewEnabled` field.
+    ///
+    /// The mappings added by this crate are enabled, but some firmware disables them, or builds its own table
+    /// ignoring the flag. A missing mapping is reported as `RequestError::ErrorCode` with code 714
+    /// (NoSuchEntryInArray).
+    pub async fn is_mapping_enabled(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<bool, RequestError> {
+        Ok(self
+            .get_specific_port_mapping_entry(protocol, external_port)
+            .await?
+            .enabled)
+    }
+
+    /// Find the lowest external port of `range` that has no mapping for `protocol`
+    ///
+    /// The ports are asked one by one with `get_specific_port_mapping_entry`, so this costs a request per mapped
+    /// port, and port 0 is skipped. Returns `None` when all the ports of the range are mapped. This is racy:
+    /// another client may map the port before it is added, so the `add_port` that follows must handle
+    /// `AddPortError::PortInUse`.
+    pub async fn find_free_port(
+        &self,
+        protocol: PortMappingProtocol,
+        range: Range<u16>,
+    ) -> Result<Option<u16>, RequestError> {
+        for port in range.filter(|&port| port != 0) {
+            match self.get_specific_port_mapping_entry(protocol, port).await {
+                Ok(_) => {}
+                Err(GetSpecificPortMappingEntryError::NoSuchEntryInArray) => return Ok(Some(port)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get the port mapping entry of a protocol and external port
+    ///
+    /// If there is no such port mapping, GetSpecificPortMappingEntryError::NoSuchEntryInArray will be returned
+    pub async fn get_specific_port_mapping_entry(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<parsing::PortMappingEntry, GetSpecificPortMappingEntryError> {
+        self.get_specific_port_mapping_entry_with_remote_host(RemoteHost::Wildcard, protocol, external_port)
+            .await
+    }
+
+    /// Get the port mapping entry of a remote host, protocol and external port
+    pub async fn get_specific_port_mapping_entry_with_remote_host(
+        &self,
+        remote_host: RemoteHost,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<parsing::PortMappingEntry, GetSpecificPortMappingEntryError> {
+        let schema = self
+            .control_schema
+            .get("GetSpecificPortMappingEntry")
+            .ok_or_else(|| RequestError::UnsupportedAction("GetSpecificPortMappingEntry".to_string()))?;
+        let result = self
+            .perform_request(
+                messages::GET_SPECIFIC_PORT_MAPPING_ENTRY_ACTION,
+                &messages::format_get_specific_port_mapping_entry_message(schema, remote_host, protocol, external_port),
+            )
+            .await;
+        parsing::parse_get_specific_port_mapping_entry(result, remote_host, protocol, external_port)
+    }
+
+    /// Get the mappings of `protocol` whose external port is between `start_port` and `end_port`, in one request
+    ///
+    /// `GetListOfPortMappings` is an action of `WANIPConnection:2`, see `SearchOptions::prefer_igd_v2`: other
+    /// gateways answer with a `RequestError`, and the mappings are listed with `port_mappings` instead. With
+    /// `manage`, the mappings of all the clients are listed, which the gateway may not authorize, otherwise
+    /// only those of this host. At most `max_entries` are returned: the next page starts after the last external
+    /// port returned.
+    pub async fn get_list_of_port_mappings(
+        &self,
+        protocol: PortMappingProtocol,
+        start_port: u16,
+        end_port: u16,
+        manage: bool,
+        max_entries: u16,
+    ) -> Result<Vec<parsing::PortMappingEntry>, RequestError> {
+        let result = self
+            .perform_request(
+                messages::GET_LIST_OF_PORT_MAPPINGS_ACTION,
+                &messages::format_get_list_of_port_mappings_message(
+                    protocol,
+                    start_port,
+                    end_port,
+                    manage,
+                    max_entries,
+                ),
+            )
+            .await;
+        parsing::parse_get_list_of_port_mappings_response(result)
+    }
+}
+
+// Wait between the attempts of `add_any_port`, only with tokio as there is no timer otherwise.
+async fn backoff(delay: Duration) {
+    #[cfg(feature = "aio")]
+    tokio::time::sleep(delay).await;
+    #[cfg(not(feature = "aio"))]
+    let _ = delay;
 }
 
 impl fmt::Display for Gateway {
@@ -301,3 +1707,51 @@ impl Hash for Gateway {
         self.control_url.hash(state);
     }
 }
+
+#[cfg(test)]
+use crate::gateway::MockTransport;
+
+// The canned responses of the blocking tests, answered at once.
+#[cfg(test)]
+impl Transport for MockTransport {
+    fn send(
+        &self,
+        request: HttpRequest,
+    ) -> futures::future::BoxFuture<'static, Result<crate::transport::HttpResponse, RequestError>> {
+        let result = crate::transport::Transport::send(self, request);
+        Box::pin(async move { result })
+    }
+}
+
+#[test]
+fn test_port_mappings_stream() {
+    let entry = |port| MockTransport::generic_entry(PortMappingProtocol::TCP, port, "192.168.1.2", "test");
+    let transport = MockTransport::new(vec![
+        entry(8080),
+        entry(8081),
+        MockTransport::fault(713),
+        entry(8080),
+        MockTransport::fault(606),
+        // A gateway ignoring the index.
+        entry(8080),
+        entry(8080),
+    ]);
+    let gateway = Gateway::with_transport(
+        "192.168.1.1:5000".parse().unwrap(),
+        "/ctl/IPConn".into(),
+        transport.clone(),
+    )
+    .unwrap();
+    let list = || futures::executor::block_on(gateway.port_mappings_stream().collect::<Vec<_>>());
+
+    let ports: Vec<_> = list().into_iter().map(|entry| entry.unwrap().external_port).collect();
+    assert_eq!(ports, vec![8080, 8081]);
+    let entries = list();
+    assert_eq!(entries.len(), 2);
+    assert!(matches!(
+        entries[1],
+        Err(GetGenericPortMappingEntryError::ActionNotAuthorized)
+    ));
+    assert_eq!(list().len(), 1);
+    assert_eq!(transport.actions(), vec!["GetGenericPortMappingEntry"; 7]);
+}
@@ -1,8 +1,32 @@
 //! This module implements the same features as the main crate, but using async io.
+//!
+//! With the `aio` feature, requests are sent with hyper on the tokio runtime. The `aio_futures` feature
+//! provides the same `Gateway` without depending on a runtime: requests go through a `Transport` supplied by
+//! the caller, and the gateway can be found with the blocking `search_gateway` and converted with
+//! `Gateway::from_blocking`.
+//!
+//! # Cancellation
+//!
+//! Every operation can be cancelled by dropping its future, for example when it loses a `select!` or a
+//! `tokio::time::timeout`. No task is spawned on behalf of the caller: dropping the future drops the HTTP
+//! request in flight, which closes its connection, and no further request is sent.
+//!
+//! The gateway may however have already performed the action carried by that last request. For `add_port`,
+//! the caller knows which mapping to remove. For the multi-step `add_any_port`, see its documentation.
 
 mod gateway;
+#[cfg(feature = "aio")]
 mod search;
+#[cfg(feature = "aio")]
 mod soap;
+mod transport;
 
 pub use self::gateway::Gateway;
-pub use self::search::search_gateway;
+#[cfg(feature = "aio")]
+pub use self::search::{
+    listen_announcements, map_port, search_gateway, search_gateway_at, search_gateways, search_gateways_on_interfaces,
+    unmap_port,
+};
+#[cfg(feature = "aio")]
+pub use self::soap::HyperTransport;
+pub use self::transport::Transport;
@@ -1,108 +1,436 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
 
 use futures::prelude::*;
-use hyper::Client;
+use futures::{future, stream};
+use hyper::{header::AUTHORIZATION, Body, Client, Request};
 use tokio::net::UdpSocket;
-use tokio::time::timeout;
+use tokio::time::{timeout, timeout_at, Instant};
 
+use crate::aio::soap::read_body;
 use crate::aio::Gateway;
-use crate::common::{messages, parsing, SearchOptions};
-use crate::errors::SearchError;
-
-const MAX_RESPONSE_SIZE: usize = 1500;
+use crate::common::parsing::{self, Announcement, DeviceInfo, DiscoveryInfo};
+use crate::common::{self, messages, ConnectionService, GatewayOptions, SearchOptions, SearchResults};
+use crate::errors::{self, SearchError};
+use crate::PortMappingProtocol;
 
 /// Search for a gateway with the provided options
 pub async fn search_gateway(options: SearchOptions) -> Result<Gateway, SearchError> {
+    common::check_broadcast_address(options.broadcast_address)?;
+    if let Some(gateway) = CACHE.get(&options).filter(|gateway| is_selected(gateway, &options)) {
+        debug!("reusing the cached gateway {}", gateway.addr);
+        return Ok(Gateway {
+            options: options.gateway_options,
+            ..gateway
+        });
+    }
+    let gateway = search_with_retries(&options).await?;
+    let max_age = gateway.discovery_info.as_ref().and_then(|info| info.max_age);
+    CACHE.insert(&options, &gateway, max_age);
+    Ok(gateway)
+}
+
+// The gateways of `SearchOptions::use_cache`
+static CACHE: common::GatewayCache<Gateway> = common::GatewayCache::new();
+
+/// Search the gateway at `ip` with a unicast M-SEARCH, with the provided options
+///
+/// The request is sent to `ip` on the port of `options.broadcast_address`, 1900 by default, instead of the
+/// multicast group: this finds a gateway whose address is known, such as the default route, on networks that
+/// filter multicast. `options.bind_addr` must be an IPv4 address. The gateway is then used like with
+/// `search_gateway`.
+pub async fn search_gateway_at(ip: Ipv4Addr, options: SearchOptions) -> Result<Gateway, SearchError> {
+    search_with_retries(&common::unicast_search_options(ip, options)).await
+}
+
+async fn search_with_retries(options: &SearchOptions) -> Result<Gateway, SearchError> {
+    let start = std::time::Instant::now();
+    let mut result = find_gateway(options).await;
+    for retry in 1..=options.retries {
+        match result {
+            Err(ref e) if common::is_search_timeout(e) => {
+                debug!("no gateway found, searching again ({}/{})", retry, options.retries);
+                result = find_gateway(options).await;
+            }
+            _ => break,
+        }
+    }
+    common::record_search(options, start, result.is_ok() as usize);
+    result
+}
+
+async fn find_gateway(options: &SearchOptions) -> Result<Gateway, SearchError> {
     // Create socket for future calls
-    let mut socket = UdpSocket::bind(&options.bind_addr).await?;
+    let mut socket = bind_search_socket(options).await?;
+
+    send_search_requests(&mut socket, options).await?;
 
-    send_search_request(&mut socket, options.broadcast_address).await?;
+    // Receive search responses until a gateway can be used, optionally with a timeout after the last request
+    let mut retransmissions = common::Retransmissions::new(options);
+    let mut deadline = options.timeout.map(|t| Instant::now() + t);
+    let mut no_connection_service = false;
+    let mut tried = HashSet::new();
+    loop {
+        let retransmit_at = retransmissions.next().map(Instant::from_std);
+        let search_response = receive_search_response(&mut socket, options.response_buffer_size);
+        let (response_body, from) = match retransmit_at.or(deadline) {
+            Some(wait_until) => match timeout_at(wait_until, search_response).await {
+                Ok(response) => response,
+                Err(_) if retransmit_at.is_some() => {
+                    send_search_requests(&mut socket, options).await?;
+                    retransmissions.sent();
+                    deadline = options.timeout.map(|t| Instant::now() + t);
+                    continue;
+                }
+                Err(_) if no_connection_service => return Err(SearchError::NoConnectionService),
+                Err(e) => return Err(e.into()),
+            },
+            None => search_response.await,
+        }?;
 
-    let search_response = receive_search_response(&mut socket);
+        let (addr, root_url, discovery_info) = match handle_broadcast_resp(&from, &response_body) {
+            Ok(responder) => responder,
+            Err(e) => {
+                debug!("ignoring search response from {}: {}", from, e);
+                continue;
+            }
+        };
+        // The other responses of a device that could not be used have the same location.
+        if !tried.insert(common::device_key(addr, &root_url, &discovery_info)) {
+            continue;
+        }
 
-    // Receive search response, optionally with a timeout
-    let (response_body, from) = match options.timeout {
-        Some(t) => timeout(t, search_response).await?,
-        None => search_response.await,
-    }?;
+        match get_gateway(addr, root_url, discovery_info, options).await {
+            Ok(gateway) if is_selected(&gateway, options) => return Ok(gateway),
+            Ok(_) => debug!("ignoring {}, which the selector rejects", addr),
+            Err(SearchError::NoConnectionService) => {
+                debug!("ignoring {}, which has no connection service", addr);
+                no_connection_service = true;
+            }
+            Err(e) => debug!("could not fetch the description of {}: {}", addr, e),
+        }
+    }
+}
+
+/// Search all the gateways answering within `options.search_window`, with the provided options
+///
+/// Responses are collected until the window expires, which is `options.timeout` when it is `None`, or 3 seconds.
+/// A device sends a response per device and service it has: one response is kept per device, identified by the
+/// UUID of its USN or by its address, preferring the one whose `ST` is the gateway device. The descriptions of
+/// the gateways are then fetched concurrently, each within the timeout. A gateway whose description can not be
+/// fetched or parsed does not fail the search: its address and error are returned in `SearchResults::failures`
+/// instead.
+pub async fn search_gateways(options: SearchOptions) -> Result<SearchResults<Gateway>, SearchError> {
+    let start = std::time::Instant::now();
+    let result = find_gateways(&options).await;
+    let gateways = result.as_ref().map_or(0, |results| results.gateways.len());
+    common::record_search(&options, start, gateways);
+    result
+}
 
-    let (addr, root_url) = handle_broadcast_resp(&from, &response_body)?;
+async fn find_gateways(options: &SearchOptions) -> Result<SearchResults<Gateway>, SearchError> {
+    common::check_broadcast_address(options.broadcast_address)?;
+    let mut socket = bind_search_socket(options).await?;
 
-    let (control_schema_url, control_url) = get_control_urls(&addr, &root_url).await?;
-    let control_schema = get_control_schemas(&addr, &control_schema_url).await?;
+    send_search_requests(&mut socket, options).await?;
 
-    let addr = match addr {
-        SocketAddr::V4(a) => Ok(a),
-        _ => {
-            warn!("unsupported IPv6 gateway response from addr: {}", addr);
-            Err(SearchError::InvalidResponse)
+    let deadline = Instant::now() + common::search_window(options);
+    let mut retransmissions = common::Retransmissions::new(options);
+    let mut responders = Vec::new();
+    while Instant::now() < deadline {
+        let retransmit_at = retransmissions
+            .next()
+            .map(Instant::from_std)
+            .filter(|at| *at < deadline);
+        let search_response = receive_search_response(&mut socket, options.response_buffer_size);
+        let (response_body, from) = match timeout_at(retransmit_at.unwrap_or(deadline), search_response).await {
+            Ok(response) => response?,
+            Err(_) => {
+                if retransmit_at.is_some() {
+                    send_search_requests(&mut socket, options).await?;
+                    retransmissions.sent();
+                }
+                continue;
+            }
+        };
+        // A device sends a response per device and service, some without a location.
+        match handle_broadcast_resp(&from, &response_body) {
+            Ok(responder) => common::add_responder(&mut responders, responder),
+            Err(e) => debug!("ignoring search response from {}: {}", from, e),
         }
-    }?;
+    }
+
+    let search_options = &options;
+    let fetch_timeout = options.timeout;
+    let results = stream::iter(responders)
+        .map(|(addr, root_url, discovery_info)| async move {
+            let fetch = get_gateway(addr, root_url, discovery_info, search_options);
+            let result = match fetch_timeout {
+                Some(t) => timeout(t, fetch).await.map_err(SearchError::from).and_then(|r| r),
+                None => fetch.await,
+            };
+            (addr, result)
+        })
+        .buffer_unordered(MAX_PARALLEL_FETCHES)
+        .fold(
+            SearchResults {
+                gateways: Vec::new(),
+                failures: Vec::new(),
+            },
+            |mut results, (addr, result)| {
+                match result {
+                    Ok(gateway) if is_selected(&gateway, search_options) => results.gateways.push(gateway),
+                    Ok(_) => debug!("ignoring {}, which the selector rejects", addr),
+                    Err(e) => {
+                        debug!("could not fetch the description of {}: {}", addr, e);
+                        results.failures.push((addr, e));
+                    }
+                }
+                future::ready(results)
+            },
+        )
+        .await;
+
+    Ok(results)
+}
+
+/// Search all the gateways like `search_gateways`, out of each IPv4 interface of this host at the same time
+///
+/// On a host with several networks, such as Ethernet, Wi-Fi and a VPN, the multicast M-SEARCH otherwise
+/// leaves through a single interface, which may not be the one leading to the gateway. The interfaces that
+/// are up, other than loopback, are each searched from their address with the port of `options.bind_addr`,
+/// and the results are merged: the interface a gateway was found from is its `DiscoveryInfo::local_ip`, so a
+/// gateway reachable from two interfaces is returned for each. An interface whose search fails is in
+/// `SearchResults::failures` with its address.
+///
+/// Only Unix systems list their interfaces: elsewhere, the interface routing to the SSDP group is searched.
+pub async fn search_gateways_on_interfaces(options: SearchOptions) -> Result<SearchResults<Gateway>, SearchError> {
+    common::check_broadcast_address(options.broadcast_address)?;
+    if !options.broadcast_address.is_ipv4() {
+        return Err(SearchError::InvalidBroadcastAddress(options.broadcast_address));
+    }
+    let start = std::time::Instant::now();
+    let searches = common::ipv4_interfaces()?.into_iter().map(|ip| {
+        let options = common::interface_search_options(ip, &options);
+        async move { (options.bind_addr, find_gateways(&options).await) }
+    });
+    let results = common::merge_search_results(future::join_all(searches).await);
+    common::record_search(&options, start, results.gateways.len());
+    Ok(results)
+}
+
+/// Listen for the `ssdp:alive` and `ssdp:byebye` announcements of gateways, with the provided options
+///
+/// The socket joins the multicast group of `options.broadcast_address` on the interface of
+/// `options.bind_addr`, and receives on the port of the group along with the other SSDP listeners of the host.
+/// Gateways announce themselves when they join the network and before their `max_age` expires, so
+/// applications learn about the gateways appearing and disappearing without searching again.
+///
+/// The stream goes on until it is dropped, unless a receive fails: it then ends after yielding the error.
+pub async fn listen_announcements(
+    options: SearchOptions,
+) -> Result<impl Stream<Item = Result<Announcement, SearchError>>, SearchError> {
+    let socket = common::bind_announcement_socket(&options)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    let buffer_size = options.response_buffer_size;
+    Ok(stream::unfold(Some(socket), move |socket| async move {
+        let mut socket = socket?;
+        loop {
+            let (data, from) = match receive_search_response(&mut socket, buffer_size).await {
+                Ok(response) => response,
+                Err(e) => return Some((Err(e), None)),
+            };
+            match std::str::from_utf8(&data).ok().and_then(parsing::parse_notify) {
+                Some(announcement) => return Some((Ok(announcement), Some(socket))),
+                None => trace!("ignoring SSDP message from {}", from),
+            }
+        }
+    }))
+}
+
+/// Search a gateway with `options` and map any external port to `local_addr`, in one call.
+///
+/// This is `search_gateway` followed by `Gateway::get_any_address`, for programs that only need a port
+/// forwarded. The lease_duration parameter is in seconds. A value of 0 is infinite.
+///
+/// # Returns
+///
+/// The external address that was mapped on success. Otherwise an error.
+pub async fn map_port(
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    options: SearchOptions,
+) -> errors::Result<SocketAddrV4> {
+    let gateway = search_gateway(options).await?;
+    Ok(gateway
+        .get_any_address(protocol, local_addr, lease_duration, description)
+        .await?)
+}
+
+/// Search a gateway with `options` and remove the mapping of `external_port`, like `map_port` added it.
+pub async fn unmap_port(protocol: PortMappingProtocol, external_port: u16, options: SearchOptions) -> errors::Result {
+    let gateway = search_gateway(options).await?;
+    Ok(gateway.remove_port(protocol, external_port).await?)
+}
+
+// Number of gateway descriptions fetched at the same time by `search_gateways`.
+const MAX_PARALLEL_FETCHES: usize = 8;
+
+async fn bind_search_socket(options: &SearchOptions) -> Result<UdpSocket, SearchError> {
+    let socket = common::bind_search_socket(options.bind_addr, options.reuse_address)
+        .and_then(|socket| {
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket)
+        })
+        .map_err(|e| SearchError::BindFailed(options.bind_addr, e))?;
+    if options.bind_addr.is_ipv4() {
+        socket.set_multicast_loop_v4(options.multicast_loop)?;
+        if let Some(ttl) = options.multicast_ttl {
+            socket.set_multicast_ttl_v4(ttl)?;
+        }
+    } else {
+        socket.set_multicast_loop_v6(options.multicast_loop)?;
+    }
+    Ok(socket)
+}
+
+fn is_selected(gateway: &Gateway, options: &SearchOptions) -> bool {
+    common::is_selected(
+        options,
+        gateway.addr,
+        gateway.discovery_info.as_ref(),
+        gateway.device_info.as_ref(),
+    )
+}
+
+// The gateway of `Gateway::from_location`, fetched like a gateway found by a search
+pub(crate) async fn gateway_from_location(location: &str, options: &SearchOptions) -> Result<Gateway, SearchError> {
+    let (addr, root_url) = parsing::parse_location(location)?;
+    let fetch = get_gateway(addr, root_url, DiscoveryInfo::default(), options);
+    let gateway = match options.timeout {
+        Some(t) => timeout(t, fetch).await??,
+        None => fetch.await?,
+    };
+    Ok(Gateway {
+        discovery_info: None,
+        ..gateway
+    })
+}
+
+// Fetch the description and control schema of a gateway found by a search, unless its control url is known
+async fn get_gateway(
+    addr: SocketAddr,
+    root_url: String,
+    discovery_info: DiscoveryInfo,
+    search_options: &SearchOptions,
+) -> Result<Gateway, SearchError> {
+    let options = &search_options.gateway_options;
+    let discovery_info = DiscoveryInfo {
+        local_ip: common::search_local_ip(search_options, addr),
+        ..discovery_info
+    };
+    if let Some(ref control_url) = search_options.control_url {
+        return Ok(Gateway {
+            root_url,
+            options: options.clone(),
+            discovery_info: Some(discovery_info),
+            ..Gateway::new(addr, control_url.clone())?
+        });
+    }
+
+    let (control_schema_url, control_url, service_type, device_info) =
+        get_control_urls(&addr, &root_url, search_options).await?;
+    let control_schema = get_control_schemas(&addr, &control_schema_url, options).await?;
 
     Ok(Gateway {
-        addr,
         root_url,
-        control_url,
         control_schema_url,
         control_schema,
+        options: options.clone(),
+        discovery_info: Some(discovery_info),
+        device_info: Some(device_info),
+        connection_service: Arc::new(Mutex::new(ConnectionService::confirmed(service_type))),
+        ..Gateway::new(addr, control_url)?
     })
 }
 
 // Create a new search
-async fn send_search_request(socket: &mut UdpSocket, addr: SocketAddr) -> Result<(), SearchError> {
+async fn send_search_requests(socket: &mut UdpSocket, options: &SearchOptions) -> Result<(), SearchError> {
+    let addr = options.broadcast_address;
     debug!(
         "sending broadcast request to: {} on interface: {:?}",
         addr,
         socket.local_addr()
     );
-    socket
-        .send_to(messages::SEARCH_REQUEST.as_bytes(), &addr)
-        .map_ok(|_| ())
-        .map_err(SearchError::from)
-        .await
+    for search_target in common::search_targets(options) {
+        let request = messages::format_search_request(addr, search_target, options.mx);
+        socket.send_to(request.as_bytes(), &addr).await?;
+    }
+    Ok(())
 }
 
-async fn receive_search_response(socket: &mut UdpSocket) -> Result<(Vec<u8>, SocketAddr), SearchError> {
-    let mut buff = [0u8; MAX_RESPONSE_SIZE];
+async fn receive_search_response(
+    socket: &mut UdpSocket,
+    buffer_size: usize,
+) -> Result<(Vec<u8>, SocketAddr), SearchError> {
+    let mut buff = vec![0u8; buffer_size];
     let (n, from) = socket.recv_from(&mut buff).map_err(SearchError::from).await?;
     debug!("received broadcast response from: {}", from);
-    Ok((buff[..n].to_vec(), from))
+    common::warn_if_truncated(n, buffer_size, from);
+    buff.truncate(n);
+    Ok((buff, from))
 }
 
 // Handle a UDP response message
-fn handle_broadcast_resp(from: &SocketAddr, data: &[u8]) -> Result<(SocketAddr, String), SearchError> {
+fn handle_broadcast_resp(from: &SocketAddr, data: &[u8]) -> Result<(SocketAddr, String, DiscoveryInfo), SearchError> {
     debug!("handling broadcast response from: {}", from);
 
     // Convert response to text
-    let text = std::str::from_utf8(&data).map_err(SearchError::from)?;
+    let text = std::str::from_utf8(data).map_err(SearchError::from)?;
 
     // Parse socket address and path
     let (addr, root_url) = parsing::parse_search_result(text)?;
 
-    Ok((SocketAddr::V4(addr), root_url))
+    Ok((addr, root_url, parsing::parse_discovery_info(text)))
 }
 
-async fn get_control_urls(addr: &SocketAddr, path: &str) -> Result<(String, String), SearchError> {
+async fn get_control_urls(
+    addr: &SocketAddr,
+    path: &str,
+    search_options: &SearchOptions,
+) -> Result<(String, String, &'static str, DeviceInfo), SearchError> {
     let uri = match format!("http://{}{}", addr, path).parse() {
         Ok(uri) => uri,
         Err(err) => return Err(SearchError::from(err)),
     };
 
     debug!("requesting control url from: {}", uri);
-    let client = Client::new();
-    let resp = hyper::body::to_bytes(client.get(uri).await?.into_body())
-        .map_err(SearchError::from)
-        .await?;
+    let resp = get(uri, &search_options.gateway_options).await?;
 
     debug!("handling control response from: {}", addr);
-    let c = std::io::Cursor::new(&resp);
-    parsing::parse_control_urls(c)
+    let device_info = parsing::parse_device_info(&resp[..])?;
+    if search_options.prefer_igd_v2 {
+        if let Some((control_schema_url, control_url)) = parsing::parse_igd_v2_control_urls(&resp[..])? {
+            return Ok((
+                control_schema_url,
+                control_url,
+                messages::WAN_IP_CONNECTION_2,
+                device_info,
+            ));
+        }
+    }
+    let (control_schema_url, control_url, service_type) = parsing::parse_control_urls(&resp[..])?;
+    Ok((control_schema_url, control_url, service_type, device_info))
 }
 
 async fn get_control_schemas(
     addr: &SocketAddr,
     control_schema_url: &str,
+    options: &GatewayOptions,
 ) -> Result<HashMap<String, Vec<String>>, SearchError> {
     let uri = match format!("http://{}{}", addr, control_schema_url).parse() {
         Ok(uri) => uri,
@@ -110,12 +438,46 @@ async fn get_control_schemas(
     };
 
     debug!("requesting control schema from: {}", uri);
-    let client = Client::new();
-    let resp = hyper::body::to_bytes(client.get(uri).await?.into_body())
-        .map_err(SearchError::from)
-        .await?;
+    let resp = get(uri, options).await?;
 
     debug!("handling schema response from: {}", addr);
     let c = std::io::Cursor::new(&resp);
     parsing::parse_schemas(c)
 }
+
+async fn get(uri: hyper::Uri, options: &GatewayOptions) -> Result<Vec<u8>, SearchError> {
+    let mut req = Request::get(uri);
+    if let Some(ref credentials) = options.credentials {
+        req = req.header(AUTHORIZATION, credentials.authorization());
+    }
+    let req = req.body(Body::empty()).map_err(|_| SearchError::InvalidResponse)?;
+
+    let client = Client::new();
+    let resp = client.request(req).await?;
+    if let Some(e) = common::fetch_status_error(resp.status().as_u16()) {
+        return Err(e);
+    }
+    read_body(resp.into_body(), options.max_response_size)
+        .await?
+        .ok_or(SearchError::ResponseTooLarge(options.max_response_size))
+}
+
+#[cfg(feature = "test-server")]
+#[test]
+fn test_search_gateway_skips_unusable_responders() {
+    use crate::test_server::{search_responder, unusable_and_server_locations, TestServer};
+
+    let server = TestServer::start().unwrap();
+    let (broadcast_address, thread) = search_responder(unusable_and_server_locations(&server));
+
+    let options = SearchOptions {
+        broadcast_address,
+        ..server.search_options()
+    };
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let gateway = runtime.block_on(search_gateway(options)).unwrap();
+    thread.join().unwrap();
+
+    assert_eq!(gateway.addr, server.addr());
+    assert_eq!(gateway.control_url, server.control_url());
+}
@@ -1,34 +1,100 @@
-use hyper::{
-    header::{CONTENT_LENGTH, CONTENT_TYPE},
-    Body, Client, Request,
-};
+use std::time::Duration;
 
+use futures::future::BoxFuture;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::{header::CONTENT_LENGTH, Body, Client, Request};
+use tokio::time::timeout;
+
+use super::Transport;
 use crate::errors::RequestError;
+use crate::transport::{HttpRequest, HttpResponse};
+
+// Idle keep-alive connections kept per gateway by `HyperTransport::default()`
+const DEFAULT_POOL_MAX_IDLE: usize = 2;
+// How long `HyperTransport::default()` keeps an idle connection open, below the timeout of most routers
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// The default `Transport`, sending requests with hyper on the tokio runtime
+///
+/// Connections to the gateway are kept alive and reused across requests, so that walking the mapping table
+/// does not open a connection per action. The default keeps up to 2 idle connections per gateway for 5
+/// seconds; when the gateway closes a connection anyway, a read-only request is retried once on a new one.
+/// Clones of a transport, and thus of a `Gateway`, share its pool.
 #[derive(Clone, Debug)]
-pub struct Action(String);
+pub struct HyperTransport {
+    client: Client<HttpConnector>,
+}
 
-impl Action {
-    pub fn new(action: &str) -> Action {
-        Action(action.into())
+impl HyperTransport {
+    /// Create a transport keeping at most `max_idle` idle connections per host, each for `idle_timeout`.
+    ///
+    /// A `max_idle` of 0 disables keep-alive, opening a new connection for every request.
+    pub fn with_pool(max_idle: usize, idle_timeout: Duration) -> HyperTransport {
+        let client = Client::builder()
+            .pool_max_idle_per_host(max_idle)
+            .pool_idle_timeout(idle_timeout)
+            .build_http();
+        HyperTransport { client }
     }
 }
 
-const HEADER_NAME: &str = "SOAPAction";
+impl Default for HyperTransport {
+    fn default() -> HyperTransport {
+        HyperTransport::with_pool(DEFAULT_POOL_MAX_IDLE, DEFAULT_POOL_IDLE_TIMEOUT)
+    }
+}
 
-pub async fn send_async(url: &str, action: Action, body: &str) -> Result<String, RequestError> {
-    let client = Client::new();
+impl Transport for HyperTransport {
+    fn send(&self, request: HttpRequest) -> BoxFuture<'static, Result<HttpResponse, RequestError>> {
+        let client = self.client.clone();
+        Box::pin(async move {
+            match request.timeout {
+                Some(t) => timeout(t, send_async(&client, request)).await?,
+                None => send_async(&client, request).await,
+            }
+        })
+    }
+}
 
-    let req = Request::builder()
-        .uri(url)
-        .method("POST")
-        .header(HEADER_NAME, action.0)
-        .header(CONTENT_TYPE, "text/xml")
-        .header(CONTENT_LENGTH, body.len() as u64)
-        .body(Body::from(body.to_string()))?;
+async fn send_async(client: &Client<HttpConnector>, request: HttpRequest) -> Result<HttpResponse, RequestError> {
+    let resp = match client.request(build_request(&request)?).await {
+        // The gateway closed a pooled connection before answering, try once more on a new one. hyper already
+        // retries the requests it could not write, the others may have been performed.
+        Err(e) if (e.is_incomplete_message() || e.is_canceled()) && request.is_read_only() => {
+            client.request(build_request(&request)?).await?
+        }
+        resp => resp?,
+    };
+    let status = resp.status().as_u16();
+    let body = read_body(resp.into_body(), request.max_response_size)
+        .await?
+        .ok_or(RequestError::ResponseTooLarge(request.max_response_size))?;
+    // Invalid UTF-8 is replaced, like `AttoHttpTransport` does.
+    let body = String::from_utf8_lossy(&body).into_owned();
+    Ok(HttpResponse { status, body })
+}
+
+fn build_request(request: &HttpRequest) -> Result<Request<Body>, RequestError> {
+    let mut req = Request::builder().uri(&request.url).method(request.method);
+    for (name, value) in &request.headers {
+        req = req.header(*name, value);
+    }
+    if request.method == "POST" {
+        req = req.header(CONTENT_LENGTH, request.body.len() as u64);
+    }
+    Ok(req.body(Body::from(request.body.clone()))?)
+}
 
-    let resp = client.request(req).await?;
-    let body = hyper::body::to_bytes(resp.into_body()).await?;
-    let string = String::from_utf8(body.to_vec())?;
-    Ok(string)
+// Read a whole response body, or `None` if it is larger than `limit`.
+pub(crate) async fn read_body(mut body: Body, limit: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if data.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some(data))
 }
@@ -0,0 +1,18 @@
+use std::fmt;
+
+use futures::future::BoxFuture;
+
+use crate::errors::RequestError;
+use crate::transport::{HttpRequest, HttpResponse};
+
+/// HTTP client used by the async `Gateway` to send its requests.
+///
+/// With the `aio` feature, gateways use `HyperTransport`, which runs on tokio. Implement this trait to use
+/// the async API with another HTTP client or runtime, such as async-std or smol.
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Send the request and return the response, whatever its status code.
+    ///
+    /// Errors that are not specific to the client can be reported as `RequestError::IoError`. Dropping the
+    /// returned future must abort the request, as the `Gateway` operations rely on it to be cancellable.
+    fn send(&self, request: HttpRequest) -> BoxFuture<'static, Result<HttpResponse, RequestError>>;
+}
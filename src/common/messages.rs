@@ -1,44 +1,161 @@
-use crate::PortMappingProtocol;
-use std::net::SocketAddrV4;
+use crate::soap::{self, Action};
+use crate::{PortMappingProtocol, RemoteHost};
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4};
 
-// Content of the request.
-pub const SEARCH_REQUEST: &str = "M-SEARCH * HTTP/1.1\r
-Host:239.255.255.250:1900\r
-ST:urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+pub const IGD_SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+// IGDv2 gateways also answer searches for IGD_SEARCH_TARGET, but some only answer this one.
+pub const IGD_2_SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:2";
+
+// Content of the request for `search_target`, sent to `addr`. The scope of an IPv6 address is not part of the Host
+// header. UPnP 1.1 devices ignore a request with an MX outside of 1 to 5, so `mx` is clamped to that range.
+pub fn format_search_request(addr: SocketAddr, search_target: &str, mx: u8) -> String {
+    let host = match addr {
+        SocketAddr::V4(addr) => addr.to_string(),
+        SocketAddr::V6(addr) => format!("[{}]:{}", addr.ip(), addr.port()),
+    };
+    format!(
+        "M-SEARCH * HTTP/1.1\r
+Host:{}\r
+ST:{}\r
 Man:\"ssdp:discover\"\r
-MX:3\r\n\r\n";
+MX:{}\r\n\r\n",
+        host,
+        search_target,
+        mx.clamp(1, 5)
+    )
+}
+
+pub const GET_EXTERNAL_IP_ACTION: Action<'static> = Action::new(SERVICE_TYPE, "GetExternalIPAddress");
+
+// Vendor extension of AVM FRITZ!Box gateways, as IGD defines no action reporting an IPv6 address.
+pub const GET_EXTERNAL_IPV6_ACTION: Action<'static> = Action::new(SERVICE_TYPE, "X_AVM_DE_GetExternalIPv6Address");
+
+pub const GET_STATUS_INFO_ACTION: Action<'static> = Action::new(SERVICE_TYPE, "GetStatusInfo");
+
+pub const GET_DNS_SERVERS_ACTION: Action<'static> = Action::new(SERVICE_TYPE, "GetDNSServers");
+
+pub const ADD_ANY_PORT_MAPPING_ACTION: Action<'static> = Action::new(SERVICE_TYPE, "AddAnyPortMapping");
+
+pub const ADD_PORT_MAPPING_ACTION: Action<'static> = Action::new(SERVICE_TYPE, "AddPortMapping");
+
+pub const DELETE_PORT_MAPPING_ACTION: Action<'static> = Action::new(SERVICE_TYPE, "DeletePortMapping");
+
+pub const GET_ACTIVE_CONNECTION_ACTION: Action<'static> =
+    Action::new(WAN_COMMON_INTERFACE_CONFIG, "GetActiveConnection");
+
+pub const GET_TOTAL_PACKETS_SENT_ACTION: Action<'static> =
+    Action::new(WAN_COMMON_INTERFACE_CONFIG, "GetTotalPacketsSent");
+
+pub const GET_TOTAL_PACKETS_RECEIVED_ACTION: Action<'static> =
+    Action::new(WAN_COMMON_INTERFACE_CONFIG, "GetTotalPacketsReceived");
+
+pub const QUERY_STATE_VARIABLE_ACTION: Action<'static> = Action::new(CONTROL_NAMESPACE, "QueryStateVariable");
+
+pub const GET_GENERIC_PORT_MAPPING_ENTRY_ACTION: Action<'static> =
+    Action::new(SERVICE_TYPE, "GetGenericPortMappingEntry");
+
+pub const GET_SPECIFIC_PORT_MAPPING_ENTRY_ACTION: Action<'static> =
+    Action::new(SERVICE_TYPE, "GetSpecificPortMappingEntry");
+
+// Only WANIPConnection:2 has it, but it is sent to the connection service like the other actions.
+pub const GET_LIST_OF_PORT_MAPPINGS_ACTION: Action<'static> = Action::new(SERVICE_TYPE, "GetListOfPortMappings");
+
+// Only WANIPConnection:2 has it, like GetListOfPortMappings.
+pub const DELETE_PORT_MAPPING_RANGE_ACTION: Action<'static> = Action::new(SERVICE_TYPE, "DeletePortMappingRange");
+
+// Input arguments of the WANIPConnection:1 actions, used when the control schema was not fetched.
+pub fn default_control_schema() -> HashMap<String, Vec<String>> {
+    let schema: &[(&str, &[&str])] = &[
+        (
+            "AddPortMapping",
+            &[
+                "NewRemoteHost",
+                "NewExternalPort",
+                "NewProtocol",
+                "NewInternalPort",
+                "NewInternalClient",
+                "NewEnabled",
+                "NewPortMappingDescription",
+                "NewLeaseDuration",
+            ],
+        ),
+        (
+            "DeletePortMapping",
+            &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+        ),
+        ("GetGenericPortMappingEntry", &["NewPortMappingIndex"]),
+        (
+            "GetSpecificPortMappingEntry",
+            &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+        ),
+    ];
+    schema
+        .iter()
+        .map(|(action, args)| (action.to_string(), args.iter().map(|a| a.to_string()).collect()))
+        .collect()
+}
 
-pub const GET_EXTERNAL_IP_HEADER: &str = r#""urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress""#;
+pub const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
 
-pub const ADD_ANY_PORT_MAPPING_HEADER: &str = r#""urn:schemas-upnp-org:service:WANIPConnection:1#AddAnyPortMapping""#;
+// Connection service of IGDv2 gateways, which also has the actions of WANIPConnection:1.
+pub const WAN_IP_CONNECTION_2: &str = "urn:schemas-upnp-org:service:WANIPConnection:2";
 
-pub const ADD_PORT_MAPPING_HEADER: &str = r#""urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping""#;
+// Connection service of DSL gateways, which has the same actions as WANIPConnection:1.
+pub const WAN_PPP_CONNECTION: &str = "urn:schemas-upnp-org:service:WANPPPConnection:1";
 
-pub const DELETE_PORT_MAPPING_HEADER: &str = r#""urn:schemas-upnp-org:service:WANIPConnection:1#DeletePortMapping""#;
+// Actions every connection service has, so a gateway rejecting one of them has another service type.
+pub const REQUIRED_ACTIONS: [&str; 5] = [
+    "GetExternalIPAddress",
+    "AddPortMapping",
+    "DeletePortMapping",
+    "GetSpecificPortMappingEntry",
+    "GetGenericPortMappingEntry",
+];
 
-pub const GET_GENERIC_PORT_MAPPING_ENTRY: &str =
-    r#""urn:schemas-upnp-org:service:WANIPConnection:1#GetGenericPortMappingEntry""#;
+// Whether `action` only reads the state of the gateway, so sending it twice is harmless.
+pub fn is_read_only(action: &str) -> bool {
+    action.starts_with("Get") || action.contains("_Get") || action == "QueryStateVariable"
+}
+
+// Namespace of the actions that apply to any service.
+const CONTROL_NAMESPACE: &str = "urn:schemas-upnp-org:control-1-0";
+
+pub const WAN_DSL_LINK_CONFIG: &str = "urn:schemas-upnp-org:service:WANDSLLinkConfig:1";
 
-const MESSAGE_HEAD: &str = r#"<?xml version="1.0"?>
-<s:Envelope s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/" xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
-<s:Body>"#;
+pub const WAN_IPV6_FIREWALL_CONTROL: &str = "urn:schemas-upnp-org:service:WANIPv6FirewallControl:1";
 
-const MESSAGE_TAIL: &str = r#"</s:Body>
-</s:Envelope>"#;
+pub const WAN_COMMON_INTERFACE_CONFIG: &str = "urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1";
 
-fn format_message(body: String) -> String {
-    format!("{}{}{}", MESSAGE_HEAD, body, MESSAGE_TAIL)
+// Send an envelope built for SERVICE_TYPE to another connection service.
+pub fn rebind_service_type(envelope: &str, service_type: &str) -> String {
+    envelope.replacen(
+        &format!(r#"xmlns:u="{}""#, SERVICE_TYPE),
+        &format!(r#"xmlns:u="{}""#, service_type),
+        1,
+    )
+}
+
+// Add a mapping disabled, as `format_add_port_mapping_message` enables it.
+pub fn disable_mapping(envelope: &str) -> String {
+    envelope.replacen("<NewEnabled>1</NewEnabled>", "<NewEnabled>0</NewEnabled>", 1)
 }
 
 pub fn format_get_external_ip_message() -> String {
-    r#"<?xml version="1.0"?>
-<s:Envelope s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/" xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
-    <s:Body>
-        <m:GetExternalIPAddress xmlns:m="urn:schemas-upnp-org:service:WANIPConnection:1">
-        </m:GetExternalIPAddress>
-    </s:Body>
-</s:Envelope>"#
-    .into()
+    soap::build_envelope(SERVICE_TYPE, "GetExternalIPAddress", "")
+}
+
+pub fn format_get_external_ipv6_message() -> String {
+    soap::build_envelope(SERVICE_TYPE, "X_AVM_DE_GetExternalIPv6Address", "")
+}
+
+pub fn format_get_status_info_message() -> String {
+    soap::build_envelope(SERVICE_TYPE, "GetStatusInfo", "")
+}
+
+pub fn format_get_dns_servers_message() -> String {
+    soap::build_envelope(SERVICE_TYPE, "GetDNSServers", "")
 }
 
 pub fn format_add_any_port_mapping_message(
@@ -58,7 +175,7 @@ pub fn format_add_any_port_mapping_message(
                 "NewInternalClient" => local_addr.ip().to_string(),
                 "NewInternalPort" => local_addr.port().to_string(),
                 "NewLeaseDuration" => lease_duration.to_string(),
-                "NewPortMappingDescription" => description.to_string(),
+                "NewPortMappingDescription" => soap::escape(description),
                 "NewProtocol" => protocol.to_string(),
                 "NewRemoteHost" => "".to_string(),
                 unknown => {
@@ -75,16 +192,12 @@ pub fn format_add_any_port_mapping_message(
         .collect::<Vec<_>>()
         .join("\n");
 
-    format_message(format!(
-        r#"<u:AddAnyPortMapping xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
-        {}
-        </u:AddAnyPortMapping>"#,
-        args,
-    ))
+    soap::build_envelope(SERVICE_TYPE, "AddAnyPortMapping", &args)
 }
 
 pub fn format_add_port_mapping_message(
     schema: &[String],
+    remote_host: RemoteHost,
     protocol: PortMappingProtocol,
     external_port: u16,
     local_addr: SocketAddrV4,
@@ -100,9 +213,9 @@ pub fn format_add_port_mapping_message(
                 "NewInternalClient" => local_addr.ip().to_string(),
                 "NewInternalPort" => local_addr.port().to_string(),
                 "NewLeaseDuration" => lease_duration.to_string(),
-                "NewPortMappingDescription" => description.to_string(),
+                "NewPortMappingDescription" => soap::escape(description),
                 "NewProtocol" => protocol.to_string(),
-                "NewRemoteHost" => "".to_string(),
+                "NewRemoteHost" => remote_host.to_string(),
                 unknown => {
                     warn!("Unknown argument: {}", unknown);
                     return None;
@@ -117,22 +230,22 @@ pub fn format_add_port_mapping_message(
         .collect::<Vec<_>>()
         .join("\n");
 
-    format_message(format!(
-        r#"<u:AddPortMapping xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
-        {}
-        </u:AddPortMapping>"#,
-        args,
-    ))
+    soap::build_envelope(SERVICE_TYPE, "AddPortMapping", &args)
 }
 
-pub fn format_delete_port_message(schema: &[String], protocol: PortMappingProtocol, external_port: u16) -> String {
+pub fn format_delete_port_message(
+    schema: &[String],
+    remote_host: RemoteHost,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+) -> String {
     let args = schema
         .iter()
         .filter_map(|argument| {
             let value = match argument.as_str() {
                 "NewExternalPort" => external_port.to_string(),
                 "NewProtocol" => protocol.to_string(),
-                "NewRemoteHost" => "".to_string(),
+                "NewRemoteHost" => remote_host.to_string(),
                 unknown => {
                     warn!("Unknown argument: {}", unknown);
                     return None;
@@ -147,19 +260,139 @@ pub fn format_delete_port_message(schema: &[String], protocol: PortMappingProtoc
         .collect::<Vec<_>>()
         .join("\n");
 
-    format_message(format!(
-        r#"<u:DeletePortMapping xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
-        {}
-        </u:DeletePortMapping>"#,
-        args,
-    ))
+    soap::build_envelope(SERVICE_TYPE, "DeletePortMapping", &args)
 }
 
 pub fn formate_get_generic_port_mapping_entry_message(port_mapping_index: u32) -> String {
-    format_message(format!(
-        r#"<u:GetGenericPortMappingEntry xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
-        <NewPortMappingIndex>{}</NewPortMappingIndex>
-        </u:GetGenericPortMappingEntry>"#,
-        port_mapping_index
-    ))
+    soap::build_envelope(
+        SERVICE_TYPE,
+        "GetGenericPortMappingEntry",
+        &format!("<NewPortMappingIndex>{}</NewPortMappingIndex>", port_mapping_index),
+    )
+}
+
+pub fn format_query_state_variable_message(name: &str) -> String {
+    soap::build_envelope(
+        CONTROL_NAMESPACE,
+        "QueryStateVariable",
+        &format!("<u:varName>{}</u:varName>", soap::escape(name)),
+    )
+}
+
+pub fn format_get_active_connection_message(active_connection_index: u32) -> String {
+    soap::build_envelope(
+        WAN_COMMON_INTERFACE_CONFIG,
+        "GetActiveConnection",
+        &format!(
+            "<NewActiveConnectionIndex>{}</NewActiveConnectionIndex>",
+            active_connection_index
+        ),
+    )
+}
+
+pub fn format_get_specific_port_mapping_entry_message(
+    schema: &[String],
+    remote_host: RemoteHost,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+) -> String {
+    let args = schema
+        .iter()
+        .filter_map(|argument| {
+            let value = match argument.as_str() {
+                "NewExternalPort" => external_port.to_string(),
+                "NewProtocol" => protocol.to_string(),
+                "NewRemoteHost" => remote_host.to_string(),
+                unknown => {
+                    warn!("Unknown argument: {}", unknown);
+                    return None;
+                }
+            };
+            Some(format!(
+                "<{argument}>{value}</{argument}>",
+                argument = argument,
+                value = value
+            ))
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    soap::build_envelope(SERVICE_TYPE, "GetSpecificPortMappingEntry", &args)
+}
+
+pub fn format_get_list_of_port_mappings_message(
+    protocol: PortMappingProtocol,
+    start_port: u16,
+    end_port: u16,
+    manage: bool,
+    max_entries: u16,
+) -> String {
+    let args = format!(
+        "<NewStartPort>{}</NewStartPort><NewEndPort>{}</NewEndPort><NewProtocol>{}</NewProtocol>\
+         <NewManage>{}</NewManage><NewNumberOfPorts>{}</NewNumberOfPorts>",
+        start_port, end_port, protocol, manage as u8, max_entries
+    );
+    soap::build_envelope(SERVICE_TYPE, "GetListOfPortMappings", &args)
+}
+
+pub fn format_delete_port_range_message(
+    protocol: PortMappingProtocol,
+    start_port: u16,
+    end_port: u16,
+    manage: bool,
+) -> String {
+    let args = format!(
+        "<NewStartPort>{}</NewStartPort><NewEndPort>{}</NewEndPort><NewProtocol>{}</NewProtocol>\
+         <NewManage>{}</NewManage>",
+        start_port, end_port, protocol, manage as u8
+    );
+    soap::build_envelope(SERVICE_TYPE, "DeletePortMappingRange", &args)
+}
+
+#[test]
+fn test_format_add_port_mapping_message_escapes_description() {
+    let schema = &default_control_schema()["AddPortMapping"];
+    let local_addr = "192.168.1.2:8080".parse().unwrap();
+    let message = format_add_port_mapping_message(
+        schema,
+        RemoteHost::Wildcard,
+        PortMappingProtocol::TCP,
+        8080,
+        local_addr,
+        0,
+        "Tom & Jerry <game>",
+    );
+    let xml = xmltree::Element::parse(message.as_bytes()).unwrap();
+    let description = xml
+        .get_child("Body")
+        .and_then(|b| b.get_child("AddPortMapping"))
+        .and_then(|a| a.get_child("NewPortMappingDescription"))
+        .and_then(|d| d.get_text())
+        .unwrap();
+    assert_eq!(description, "Tom & Jerry <game>");
+}
+
+#[test]
+fn test_format_search_request() {
+    let request = format_search_request("239.255.255.250:1900".parse().unwrap(), IGD_SEARCH_TARGET, 3);
+    assert!(request.contains("\r\nHost:239.255.255.250:1900\r\n"));
+    assert!(request.contains("\r\nST:urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n"));
+    let request = format_search_request("[ff02::c%2]:1900".parse().unwrap(), IGD_2_SEARCH_TARGET, 1);
+    assert!(request.contains("\r\nHost:[ff02::c]:1900\r\n"));
+    assert!(request.ends_with("\r\nMX:1\r\n\r\n"));
+}
+
+#[test]
+fn test_format_search_request_clamps_mx() {
+    let addr = "239.255.255.250:1900".parse().unwrap();
+    assert!(format_search_request(addr, IGD_SEARCH_TARGET, 0).ends_with("\r\nMX:1\r\n\r\n"));
+    assert!(format_search_request(addr, IGD_SEARCH_TARGET, 5).ends_with("\r\nMX:5\r\n\r\n"));
+    assert!(format_search_request(addr, IGD_SEARCH_TARGET, 120).ends_with("\r\nMX:5\r\n\r\n"));
+}
+
+#[test]
+fn test_rebind_service_type() {
+    let message = rebind_service_type(&format_get_external_ip_message(), WAN_PPP_CONNECTION);
+    assert!(message.contains(r#"<u:GetExternalIPAddress xmlns:u="urn:schemas-upnp-org:service:WANPPPConnection:1">"#));
+    assert!(!message.contains(SERVICE_TYPE));
 }
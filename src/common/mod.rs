@@ -2,10 +2,776 @@ pub mod messages;
 pub mod options;
 pub mod parsing;
 
-pub use self::options::SearchOptions;
+pub use self::options::{
+    Credentials, GatewayOptions, GatewaySelector, Metrics, PortRng, ResponseFilter, SearchOptions,
+};
+
+use self::parsing::DiscoveryInfo;
+use crate::errors::{AddAnyPortError, RemovePortError, RequestError, SearchError};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use rand::{self, Rng};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Gateways found by `search_gateways`, along with the responders whose description could not be used
+#[derive(Debug)]
+pub struct SearchResults<G = crate::Gateway> {
+    /// The gateways whose description was fetched and parsed
+    pub gateways: Vec<G>,
+    /// The address of each responder whose description failed, with the error
+    pub failures: Vec<(SocketAddr, SearchError)>,
+}
+
+/// Mappings removed by `Gateway::prune_mappings`, along with those that could not be removed
+#[derive(Debug)]
+pub struct PruneResults {
+    /// How many mappings were removed
+    pub removed: usize,
+    /// Each mapping accepted by the predicate that the gateway failed to remove, with the error
+    pub failures: Vec<(parsing::PortMappingEntry, RemovePortError)>,
+}
+
+/// What a gateway can do, as returned by `Gateway::capabilities`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Version of the connection service the actions are sent to, such as 2 for `WANIPConnection:2`
+    pub service_version: u8,
+    /// Whether the control schema lists `AddAnyPortMapping` and the gateway did not refuse it, see
+    /// `Gateway::supports_add_any_port`
+    pub supports_add_any_port: bool,
+    /// Whether the control schema lists the IGDv2 `GetListOfPortMappings` action
+    pub supports_list_mappings: bool,
+    /// Whether the device has a `WANIPv6FirewallControl:1` service, to open IPv6 pinholes
+    pub has_ipv6_firewall: bool,
+    /// The longest lease duration the gateway accepts, see `Gateway::max_lease_duration`
+    pub max_lease_duration: Option<u32>,
+}
+
+impl Capabilities {
+    // What the description and control schema tell, which does not change for a gateway.
+    pub(crate) fn from_description(
+        control_schema: &HashMap<String, Vec<String>>,
+        services: &[parsing::ServiceInfo],
+        max_lease_duration: Option<u32>,
+    ) -> Capabilities {
+        Capabilities {
+            service_version: 1,
+            supports_add_any_port: control_schema.contains_key("AddAnyPortMapping"),
+            supports_list_mappings: control_schema.contains_key("GetListOfPortMappings"),
+            has_ipv6_firewall: services
+                .iter()
+                .any(|s| s.service_type == messages::WAN_IPV6_FIREWALL_CONTROL),
+            max_lease_duration,
+        }
+    }
+
+    // Update what the gateway answered since, with the service type the actions are sent to.
+    pub(crate) fn update(&mut self, service_type: &str, supports_add_any_port: Option<bool>) {
+        self.service_version = service_type
+            .rsplit(':')
+            .next()
+            .and_then(|version| version.parse().ok())
+            .unwrap_or(1);
+        self.supports_add_any_port = supports_add_any_port != Some(false);
+    }
+}
+
+/// The external port chosen for a mapping with any external port, and how it was found
+#[derive(Debug, Clone, Copy)]
+pub struct AnyPort {
+    pub port: u16,
+    /// `AddPortMapping` was tried with random ports, instead of `AddAnyPortMapping`
+    pub used_legacy_fallback: bool,
+    /// The gateway answered `SamePortValuesRequired`, so the external port is the internal one
+    pub same_port_forced: bool,
+}
+
+/// The connection service type the actions of a gateway are sent with
+///
+/// A gateway has either a `WANIPConnection` or a `WANPPPConnection` service, and rejects the actions of the other
+/// one. The service found in the description is used as is. A gateway created without it starts with
+/// `WANIPConnection:1`, and a rejected `WANIPConnection:1` is tried again as `WANPPPConnection:1` and the other way
+/// round, but only for the actions every connection service has: the optional ones may just be missing. The type
+/// is kept once the gateway understood an action, so later actions are not probed.
+#[derive(Debug)]
+pub struct ConnectionService {
+    pub service_type: &'static str,
+    pub confirmed: bool,
+}
+
+impl Default for ConnectionService {
+    fn default() -> ConnectionService {
+        ConnectionService::new(messages::SERVICE_TYPE)
+    }
+}
+
+impl ConnectionService {
+    pub fn new(service_type: &'static str) -> ConnectionService {
+        ConnectionService {
+            service_type,
+            confirmed: false,
+        }
+    }
+
+    /// The service type listed in the description of the gateway, which is not probed
+    pub fn confirmed(service_type: &'static str) -> ConnectionService {
+        ConnectionService {
+            service_type,
+            confirmed: true,
+        }
+    }
+
+    /// The service type to try when the gateway rejects `action` with the current one, unless it was confirmed
+    pub fn other(&self, action: &str) -> Option<&'static str> {
+        if self.confirmed || !messages::REQUIRED_ACTIONS.contains(&action) {
+            None
+        } else if self.service_type == messages::SERVICE_TYPE {
+            Some(messages::WAN_PPP_CONNECTION)
+        } else if self.service_type == messages::WAN_PPP_CONNECTION {
+            Some(messages::SERVICE_TYPE)
+        } else {
+            // `WANIPConnection:2` has its own control url, which the other services are not sent to.
+            None
+        }
+    }
+
+    pub fn confirm(&mut self, service_type: &'static str) {
+        self.service_type = service_type;
+        self.confirmed = true;
+    }
+}
+
+// Most connections `active_connections` requests, for gateways that do not report their number and never answer
+// with the end of the list.
+pub const MAX_ACTIVE_CONNECTIONS: u32 = 16;
+
+// Bound of the listings of the mapping table, for gateways that never report its end: a table has at most a
+// mapping per protocol and external port.
+pub const MAX_PORT_MAPPING_ENTRIES: u32 = 2 * 65536;
+
+pub fn random_port(options: &GatewayOptions) -> u16 {
+    match options.port_rng {
+        Some(ref rng) => rng.random_port(),
+        None => rand::thread_rng().gen_range(32_768_u16..65_535_u16),
+    }
+}
+
+pub fn filter_response(options: &GatewayOptions, text: String) -> String {
+    match options.response_filter {
+        Some(ref filter) => filter.apply(text),
+        None => text,
+    }
+}
+
+// Searches go to a multicast group, or to a responder on this host.
+pub fn check_broadcast_address(addr: SocketAddr) -> Result<(), SearchError> {
+    if addr.port() == 0 || !(addr.ip().is_multicast() || addr.ip().is_loopback()) {
+        return Err(SearchError::InvalidBroadcastAddress(addr));
+    }
+    Ok(())
+}
+
+// The options of `search_gateway_at`, whose M-SEARCH is sent to `ip` on the port of the broadcast address.
+pub fn unicast_search_options(ip: Ipv4Addr, options: SearchOptions) -> SearchOptions {
+    SearchOptions {
+        broadcast_address: SocketAddr::V4(SocketAddrV4::new(ip, options.broadcast_address.port())),
+        ..options
+    }
+}
+
+// How long `search_gateways` listens for responses when the search has neither a window nor a timeout.
+const DEFAULT_SEARCH_WINDOW: Duration = Duration::from_secs(3);
+
+pub fn search_window(options: &SearchOptions) -> Duration {
+    options
+        .search_window
+        .or(options.timeout)
+        .unwrap_or(DEFAULT_SEARCH_WINDOW)
+}
+
+// A response filling the whole buffer was probably cut.
+pub fn warn_if_truncated(read: usize, buffer_size: usize, from: SocketAddr) {
+    if read == buffer_size {
+        warn!(
+            "search response from {} may be truncated to {} bytes, see SearchOptions::response_buffer_size",
+            from, read
+        );
+    }
+}
+
+// Check a description against `GatewayOptions::strict_descriptions`, returning `empty` or `not_ascii` if it fails.
+pub fn check_description<E>(options: &GatewayOptions, description: &str, empty: E, not_ascii: E) -> Result<(), E> {
+    if !options.strict_descriptions {
+        Ok(())
+    } else if description.is_empty() {
+        Err(empty)
+    } else if !description.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        Err(not_ascii)
+    } else {
+        Ok(())
+    }
+}
+
+// Keep one response per device, identified by the UUID of its USN or else by its address, preferring the
+// response whose search target is the gateway device.
+pub fn add_responder<A: PartialEq>(
+    responders: &mut Vec<(A, String, DiscoveryInfo)>,
+    responder: (A, String, DiscoveryInfo),
+) {
+    let same_device = |other: &(A, String, DiscoveryInfo)| match (other.2.uuid(), responder.2.uuid()) {
+        (Some(uuid), Some(other_uuid)) => uuid == other_uuid,
+        _ => other.0 == responder.0,
+    };
+    match responders.iter().position(same_device) {
+        Some(i) => {
+            if parsing::search_target_rank(&responder.2.st) > parsing::search_target_rank(&responders[i].2.st) {
+                responders[i] = responder;
+            }
+        }
+        None => responders.push(responder),
+    }
+}
+
+// The device of a response, to try each device and location once when searching a single gateway.
+pub fn device_key<A: fmt::Display>(addr: A, root_url: &str, discovery_info: &DiscoveryInfo) -> (String, String) {
+    let device = match discovery_info.uuid() {
+        Some(uuid) => uuid.to_string(),
+        None => addr.to_string(),
+    };
+    (device, root_url.to_string())
+}
+
+// Bind the UDP socket of a search, with `SO_REUSEADDR` when `reuse_address` is set, and send its multicast packets
+// from the interface of `addr`. The responses are unicast to it, so it never sets `SO_REUSEPORT`, which would
+// spread them across the processes sharing the port.
+pub fn bind_search_socket(addr: SocketAddr, reuse_address: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if reuse_address {
+        socket.set_reuse_address(true)?;
+    }
+    bind_multicast_socket(socket, addr)
+}
+
+// Bind a socket receiving multicast packets on the SSDP port, which every SSDP listener of the host shares.
+fn bind_shared_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    bind_multicast_socket(socket, addr)
+}
+
+// Bind `socket` to `addr`, sending its multicast packets from the interface of `addr`.
+fn bind_multicast_socket(socket: Socket, addr: SocketAddr) -> io::Result<UdpSocket> {
+    socket.bind(&addr.into())?;
+    // The multicast M-SEARCH leaves through the interface of the routing table otherwise.
+    if let SocketAddr::V4(addr) = addr {
+        if !addr.ip().is_unspecified() {
+            socket.set_multicast_if_v4(addr.ip())?;
+        }
+    }
+    Ok(socket.into())
+}
+
+// Bind the socket of `listen_announcements` on the port of the multicast group, shared with the other SSDP
+// listeners, and join the group on the interface of `bind_addr`.
+pub fn bind_announcement_socket(options: &SearchOptions) -> Result<UdpSocket, SearchError> {
+    let (addr, socket) = match options.broadcast_address {
+        SocketAddr::V4(group) if group.ip().is_multicast() => {
+            let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port()));
+            let socket = bind_shared_socket(addr).map_err(|e| SearchError::BindFailed(addr, e))?;
+            let interface = match options.bind_addr {
+                SocketAddr::V4(bind_addr) => *bind_addr.ip(),
+                SocketAddr::V6(..) => Ipv4Addr::UNSPECIFIED,
+            };
+            socket.join_multicast_v4(group.ip(), &interface)?;
+            (addr, socket)
+        }
+        SocketAddr::V6(group) if group.ip().is_multicast() => {
+            let addr = SocketAddr::from((Ipv6Addr::UNSPECIFIED, group.port()));
+            let socket = bind_shared_socket(addr).map_err(|e| SearchError::BindFailed(addr, e))?;
+            socket.join_multicast_v6(group.ip(), group.scope_id())?;
+            (addr, socket)
+        }
+        addr => return Err(SearchError::InvalidBroadcastAddress(addr)),
+    };
+    debug!("listening for announcements on {}", addr);
+    Ok(socket)
+}
+
+// Binding only succeeds for addresses assigned to one of our interfaces, or `0.0.0.0`. The gateway can not
+// forward to `0.0.0.0`, loopback or multicast addresses, even though they bind.
+pub fn is_local_address(ip: Ipv4Addr) -> bool {
+    !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast()) && UdpSocket::bind((ip, 0)).is_ok()
+}
+
+// Gateways report `0.0.0.0` or a LAN address as their external address while the WAN link comes up. This
+// only tells for gateways that do not report their connection status: behind another NAT, a connected gateway
+// has a private or shared (`100.64.0.0/10`) external address.
+pub fn is_connected_address(ip: Ipv4Addr) -> bool {
+    !(ip.is_unspecified() || ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_broadcast())
+}
+
+// The addresses of the IPv4 interfaces that are up, other than loopback, searched by
+// `search_gateways_on_interfaces`.
+#[cfg(unix)]
+pub fn ipv4_interfaces() -> io::Result<Vec<Ipv4Addr>> {
+    let mut ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut ips = Vec::new();
+    let mut next = ifaddrs;
+    // The list is only read until it is freed.
+    while let Some(ifaddr) = unsafe { next.as_ref() } {
+        next = ifaddr.ifa_next;
+        if ifaddr.ifa_flags & libc::IFF_UP as libc::c_uint == 0 {
+            continue;
+        }
+        let ip = match unsafe { ifaddr.ifa_addr.as_ref() } {
+            Some(addr) if i32::from(addr.sa_family) == libc::AF_INET => {
+                let addr = unsafe { &*(ifaddr.ifa_addr as *const libc::sockaddr_in) };
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))
+            }
+            _ => continue,
+        };
+        if !ip.is_loopback() && !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(ips)
+}
+
+// Without getifaddrs, only the interface of the route to the SSDP group is known.
+#[cfg(not(unix))]
+pub fn ipv4_interfaces() -> io::Result<Vec<Ipv4Addr>> {
+    local_ip_for(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900)).map(|ip| vec![ip])
+}
+
+// The options searching out of the interface `ip` only, from the port of `bind_addr`.
+pub fn interface_search_options(ip: Ipv4Addr, options: &SearchOptions) -> SearchOptions {
+    SearchOptions {
+        bind_addr: SocketAddr::V4(SocketAddrV4::new(ip, options.bind_addr.port())),
+        ..options.clone()
+    }
+}
+
+// Merge the searches of `search_gateways_on_interfaces`. An interface that fails is a failure of its address.
+pub fn merge_search_results<G>(results: Vec<(SocketAddr, Result<SearchResults<G>, SearchError>)>) -> SearchResults<G> {
+    let mut merged = SearchResults {
+        gateways: Vec::new(),
+        failures: Vec::new(),
+    };
+    for (bind_addr, result) in results {
+        match result {
+            Ok(results) => {
+                merged.gateways.extend(results.gateways);
+                merged.failures.extend(results.failures);
+            }
+            Err(e) => {
+                debug!("could not search from {}: {}", bind_addr, e);
+                merged.failures.push((bind_addr, e));
+            }
+        }
+    }
+    merged
+}
+
+// The error of a description or control schema fetched with an HTTP error status, which is not XML to parse.
+pub fn fetch_status_error(status: u16) -> Option<SearchError> {
+    match status {
+        401 => Some(SearchError::HttpUnauthorized),
+        400..=599 => Some(SearchError::HttpStatus(status)),
+        _ => None,
+    }
+}
+
+// The address of this host to which the gateway at `addr` sent its response. Unless `bind_addr` names it, this is
+// the address the system routes unicast packets to the gateway from, which is the interface facing it on a host
+// with several.
+pub fn search_local_ip(options: &SearchOptions, addr: SocketAddr) -> Option<Ipv4Addr> {
+    match options.bind_addr {
+        SocketAddr::V4(bind_addr) if !bind_addr.ip().is_unspecified() => Some(*bind_addr.ip()),
+        // The internal clients are IPv4 hosts, even after an IPv6 search.
+        _ => local_ipv4_for(addr).ok(),
+    }
+}
+
+// The IPv4 address of this host on the network of the gateway at `addr`. Mappings are only made for IPv4 clients,
+// so for a gateway reached over IPv6 it is the address of the interface routing to the SSDP multicast group.
+pub fn local_ipv4_for(addr: SocketAddr) -> io::Result<Ipv4Addr> {
+    match addr {
+        SocketAddr::V4(addr) => local_ip_for(addr),
+        SocketAddr::V6(..) => local_ip_for(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900)),
+    }
+}
+
+// The address of the interface routing to `addr`. Connecting a UDP socket sends nothing.
+pub fn local_ip_for(addr: SocketAddrV4) -> io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(addr)?;
+    match socket.local_addr()? {
+        SocketAddr::V4(local_addr) => Ok(*local_addr.ip()),
+        SocketAddr::V6(_) => Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no IPv4 route")),
+    }
+}
+
+// The lease sent to the gateway, see `GatewayOptions::permanent_lease_duration`.
+pub fn lease_duration(options: &GatewayOptions, lease_duration: u32) -> u32 {
+    match lease_duration {
+        0 => options.permanent_lease_duration,
+        lease_duration => lease_duration,
+    }
+}
+
+// Count a sent action with its outcome, see `GatewayOptions::metrics`.
+pub fn record_request<T>(options: &GatewayOptions, action: &str, result: &Result<T, RequestError>) {
+    if let Some(metrics) = &options.metrics {
+        metrics.request(action);
+        match result {
+            Err(RequestError::ErrorCode { code, .. }) => metrics.error_code(action, *code),
+            Err(RequestError::IoError(e)) if is_timeout(e) => metrics.timeout(action),
+            _ => {}
+        }
+    }
+}
+
+pub fn record_retry(options: &GatewayOptions, action: &str) {
+    if let Some(metrics) = &options.metrics {
+        metrics.retry(action);
+    }
+}
+
+// The search targets of the M-SEARCH requests, IGDv2 first with `SearchOptions::prefer_igd_v2`.
+pub fn search_targets(options: &SearchOptions) -> &'static [&'static str] {
+    if options.prefer_igd_v2 {
+        &[messages::IGD_2_SEARCH_TARGET, messages::IGD_SEARCH_TARGET]
+    } else {
+        &[messages::IGD_SEARCH_TARGET, messages::IGD_2_SEARCH_TARGET]
+    }
+}
+
+// Gateways found by `search_gateway` with `SearchOptions::use_cache`, until their `max_age` expires.
+pub struct GatewayCache<G> {
+    entries: Mutex<Vec<CacheEntry<G>>>,
+}
+
+struct CacheEntry<G> {
+    key: CacheKey,
+    expires: Instant,
+    gateway: G,
+}
+
+// The options that change which gateway a search finds and how it is fetched. The `gateway_options` are those of
+// each search, and the `selector` is applied to the cached gateway.
+#[derive(PartialEq)]
+struct CacheKey {
+    bind_addr: SocketAddr,
+    broadcast_address: SocketAddr,
+    timeout: Option<Duration>,
+    retries: u32,
+    retransmissions: u32,
+    retransmit_interval: Duration,
+    mx: u8,
+    response_buffer_size: usize,
+    reuse_address: bool,
+    multicast_loop: bool,
+    multicast_ttl: Option<u32>,
+    prefer_igd_v2: bool,
+    control_url: Option<String>,
+}
+
+impl CacheKey {
+    fn new(options: &SearchOptions) -> CacheKey {
+        CacheKey {
+            bind_addr: options.bind_addr,
+            broadcast_address: options.broadcast_address,
+            timeout: options.timeout,
+            retries: options.retries,
+            retransmissions: options.retransmissions,
+            retransmit_interval: options.retransmit_interval,
+            mx: options.mx,
+            response_buffer_size: options.response_buffer_size,
+            reuse_address: options.reuse_address,
+            multicast_loop: options.multicast_loop,
+            multicast_ttl: options.multicast_ttl,
+            prefer_igd_v2: options.prefer_igd_v2,
+            control_url: options.control_url.clone(),
+        }
+    }
+}
+
+impl<G: Clone> GatewayCache<G> {
+    pub const fn new() -> GatewayCache<G> {
+        GatewayCache {
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    // The gateway of the searches like `options` that has not expired, unless it is not used or refreshed.
+    pub fn get(&self, options: &SearchOptions) -> Option<G> {
+        if !options.use_cache || options.force_refresh {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|entry| entry.expires > now);
+        let key = CacheKey::new(options);
+        entries
+            .iter()
+            .find(|entry| entry.key == key)
+            .map(|entry| entry.gateway.clone())
+    }
+
+    // Replace the gateway of the searches like `options`, for the `max_age` of its response.
+    pub fn insert(&self, options: &SearchOptions, gateway: &G, max_age: Option<Duration>) {
+        if !options.use_cache {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let key = CacheKey::new(options);
+        entries.retain(|entry| entry.key != key);
+        if let Some(max_age) = max_age {
+            entries.push(CacheEntry {
+                key,
+                expires: Instant::now() + max_age,
+                gateway: gateway.clone(),
+            });
+        }
+    }
+}
+
+// Whether `SearchOptions::selector` returns the gateway at `addr`, which a search found.
+pub fn is_selected(
+    options: &SearchOptions,
+    addr: SocketAddr,
+    discovery_info: Option<&DiscoveryInfo>,
+    device_info: Option<&parsing::DeviceInfo>,
+) -> bool {
+    match (&options.selector, discovery_info) {
+        (Some(selector), Some(discovery_info)) => selector.selects(addr, discovery_info, device_info),
+        _ => true,
+    }
+}
+
+// The M-SEARCH retransmissions left in a search iteration, see `SearchOptions::retransmissions`.
+pub struct Retransmissions {
+    remaining: u32,
+    interval: Duration,
+    next: Instant,
+}
+
+impl Retransmissions {
+    // Right after the first requests were sent.
+    pub fn new(options: &SearchOptions) -> Retransmissions {
+        Retransmissions {
+            remaining: options.retransmissions,
+            interval: options.retransmit_interval,
+            next: Instant::now() + options.retransmit_interval,
+        }
+    }
+
+    // When the requests are sent again, unless they were sent for the last time.
+    pub fn next(&self) -> Option<Instant> {
+        if self.remaining > 0 {
+            Some(self.next)
+        } else {
+            None
+        }
+    }
+
+    pub fn sent(&mut self) {
+        self.remaining = self.remaining.saturating_sub(1);
+        self.next = Instant::now() + self.interval;
+    }
+}
+
+// The read timeout of a search socket until `at`, which must not be zero.
+pub fn read_timeout_until(at: Instant) -> Duration {
+    at.saturating_duration_since(Instant::now())
+        .max(Duration::from_millis(1))
+}
+
+// A search iteration that ended without a gateway, which `SearchOptions::retries` repeats.
+pub fn is_search_timeout(err: &SearchError) -> bool {
+    match err {
+        SearchError::IoError(e) => is_timeout(e),
+        _ => false,
+    }
+}
+
+// The error of a read that timed out, which depends on the platform.
+pub fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+pub fn record_search(options: &SearchOptions, start: Instant, gateways: usize) {
+    if let Some(metrics) = &options.gateway_options.metrics {
+        metrics.search(start.elapsed(), gateways);
+    }
+}
+
+// Mappings added by `supported_protocols_for` must not outlive a failed removal for long, but some gateways
+// reject leases of a few seconds.
+pub const PROBE_LEASE_DURATION: u32 = 30;
+
+pub const PROBE_DESCRIPTION: &str = "igd protocol probe";
+
+// Time left before the deadline of an operation, to bound its next request.
+pub fn time_left(deadline: Option<Instant>) -> Result<Option<Duration>, RequestError> {
+    match deadline {
+        Some(deadline) if is_expired(Some(deadline)) => Err(RequestError::IoError(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "deadline of the operation exceeded",
+        ))),
+        Some(deadline) => Ok(Some(deadline - Instant::now())),
+        None => Ok(None),
+    }
+}
+
+// Random delay before the next attempt of `add_any_port` after `conflicts` conflicts, within the deadline.
+pub fn backoff_delay(options: &GatewayOptions, conflicts: u32, deadline: Option<Instant>) -> Duration {
+    let limit = options
+        .add_any_port_backoff
+        .saturating_mul(1 << conflicts.saturating_sub(1).min(16))
+        .min(options.add_any_port_max_backoff);
+    let limit = match deadline {
+        Some(deadline) => limit.min(deadline.saturating_duration_since(Instant::now())),
+        None => limit,
+    };
+    Duration::from_micros(rand::thread_rng().gen_range(0..=limit.as_micros() as u64))
+}
+
+pub fn is_expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+// Read a whole response body, or `None` if it is larger than `limit`.
+pub fn read_limited<R: Read>(reader: R, limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut data)?;
+    Ok(if data.len() > limit { None } else { Some(data) })
+}
+
+// The local address of a socket to forward, which must name a single IPv4 internal client.
+pub fn forwarded_addr(local_addr: io::Result<SocketAddr>) -> Result<SocketAddrV4, AddAnyPortError> {
+    match local_addr.map_err(RequestError::IoError)? {
+        SocketAddr::V4(addr) if !addr.ip().is_unspecified() => Ok(addr),
+        _ => Err(AddAnyPortError::InternalClientUnspecified),
+    }
+}
+
+#[test]
+fn test_is_local_address() {
+    assert!(!is_local_address(Ipv4Addr::LOCALHOST));
+    assert!(!is_local_address(Ipv4Addr::UNSPECIFIED));
+    assert!(!is_local_address(Ipv4Addr::new(239, 255, 255, 250)));
+    assert!(!is_local_address(Ipv4Addr::new(192, 0, 2, 1)));
+}
+
+#[test]
+fn test_backoff_delay() {
+    let options = GatewayOptions::default();
+    for _ in 0..100 {
+        assert!(backoff_delay(&options, 1, None) <= Duration::from_millis(10));
+        assert!(backoff_delay(&options, 3, None) <= Duration::from_millis(40));
+        assert!(backoff_delay(&options, 30, None) <= Duration::from_millis(200));
+    }
+    assert_eq!(backoff_delay(&options, 3, Some(Instant::now())), Duration::from_secs(0));
+
+    let options = GatewayOptions {
+        add_any_port_backoff: Duration::from_secs(0),
+        ..Default::default()
+    };
+    assert_eq!(backoff_delay(&options, 10, None), Duration::from_secs(0));
+}
+
+#[test]
+fn test_search_local_ip() {
+    // The interface facing the gateway, not the one routing to the multicast group.
+    let gateway = "127.0.0.1:5000".parse().unwrap();
+    let options = SearchOptions::default();
+    assert_eq!(search_local_ip(&options, gateway), Some(Ipv4Addr::LOCALHOST));
+    let options = SearchOptions {
+        bind_addr: "192.168.1.2:0".parse().unwrap(),
+        ..options
+    };
+    assert_eq!(search_local_ip(&options, gateway), Some(Ipv4Addr::new(192, 168, 1, 2)));
+    assert_eq!(
+        search_local_ip(&SearchOptions::ipv6(0), gateway),
+        Some(Ipv4Addr::LOCALHOST)
+    );
+}
+
+#[test]
+fn test_ipv4_interfaces() {
+    let ips = ipv4_interfaces().unwrap();
+    assert!(ips.iter().all(|ip| !ip.is_loopback()));
+    let options = interface_search_options(Ipv4Addr::new(192, 168, 1, 2), &SearchOptions::default());
+    assert_eq!(options.bind_addr, "192.168.1.2:0".parse::<SocketAddr>().unwrap());
+}
+
+#[test]
+fn test_local_ip_for() {
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 5000);
+    assert_eq!(local_ip_for(addr).unwrap(), Ipv4Addr::LOCALHOST);
+}
+
+#[test]
+fn test_read_limited() {
+    assert_eq!(read_limited(&b"abcd"[..], 4).unwrap(), Some(b"abcd".to_vec()));
+    assert_eq!(read_limited(&b"abcde"[..], 4).unwrap(), None);
+}
+
+#[test]
+fn test_check_broadcast_address() {
+    assert!(check_broadcast_address("239.255.255.250:1900".parse().unwrap()).is_ok());
+    assert!(check_broadcast_address("[ff02::c]:1900".parse().unwrap()).is_ok());
+    assert!(check_broadcast_address("127.0.0.1:40000".parse().unwrap()).is_ok());
+    assert!(check_broadcast_address("192.168.1.1:1900".parse().unwrap()).is_err());
+    assert!(check_broadcast_address("239.255.255.250:0".parse().unwrap()).is_err());
+}
+
+#[test]
+fn test_check_description() {
+    let strict = GatewayOptions {
+        strict_descriptions: true,
+        ..Default::default()
+    };
+    assert_eq!(check_description(&strict, "web server", 1, 2), Ok(()));
+    assert_eq!(check_description(&strict, "", 1, 2), Err(1));
+    assert_eq!(check_description(&strict, "café", 1, 2), Err(2));
+    assert_eq!(check_description(&strict, "line\nbreak", 1, 2), Err(2));
+    assert_eq!(check_description(&GatewayOptions::default(), "", 1, 2), Ok(()));
+}
+
+#[test]
+fn test_forwarded_addr() {
+    let addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 8080);
+    assert_eq!(forwarded_addr(Ok(SocketAddr::V4(addr))).unwrap(), addr);
+    assert!(matches!(
+        forwarded_addr(Ok("0.0.0.0:8080".parse().unwrap())),
+        Err(AddAnyPortError::InternalClientUnspecified)
+    ));
+    assert!(matches!(
+        forwarded_addr(Ok("[::1]:8080".parse().unwrap())),
+        Err(AddAnyPortError::InternalClientUnspecified)
+    ));
+}
 
-pub fn random_port() -> u16 {
-    rand::thread_rng().gen_range(32_768_u16..65_535_u16)
+#[test]
+fn test_time_left() {
+    assert_eq!(time_left(None).unwrap(), None);
+    assert!(time_left(Some(Instant::now() + Duration::from_secs(60)))
+        .unwrap()
+        .is_some());
+    assert!(is_expired(Some(Instant::now())));
+    assert!(matches!(time_left(Some(Instant::now())), Err(RequestError::IoError(_))));
 }
@@ -1,6 +1,13 @@
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use super::parsing::{DeviceInfo, DiscoveryInfo};
+
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+
 /// Gateway search configuration
 ///
 /// SearchOptions::default() should suffice for most situations.
@@ -16,13 +23,104 @@ use std::time::Duration;
 ///     ..Default::default()
 /// };
 /// ```
+#[derive(Clone)]
 pub struct SearchOptions {
     /// Bind address for UDP socket (defaults to all `0.0.0.0`)
+    ///
+    /// An IPv4 address of an interface also sends the multicast M-SEARCH out of that interface, for machines with
+    /// several network interfaces or a VPN, where the default route may lead to the wrong network.
+    ///
+    /// Its port is the source port of the M-SEARCH, picked by the system when it is 0. Set it for a firewall
+    /// that only lets SSDP through from a fixed port; a port that cannot be bound fails the search with
+    /// `SearchError::BindFailed`.
     pub bind_addr: SocketAddr,
     /// Broadcast address for discovery packets (defaults to `239.255.255.250:1900`)
+    ///
+    /// It must be a multicast group, or a loopback address to reach a local SSDP responder, for example in
+    /// tests. Other addresses fail the search with `SearchError::InvalidBroadcastAddress`: `search_gateway_at`
+    /// searches a known gateway address. See `SearchOptions::ipv6` to send the search over IPv6.
     pub broadcast_address: SocketAddr,
     /// Timeout for a search iteration (defaults to 10s)
     pub timeout: Option<Duration>,
+    /// How many times `search_gateway` searches again after an iteration timed out without a gateway (defaults
+    /// to 0)
+    ///
+    /// Each iteration sends a new M-SEARCH and waits for `timeout`, for networks that drop multicast packets, such
+    /// as a slow Wi-Fi link. `search_gateways` collects responses for its window once.
+    pub retries: u32,
+    /// How many times the M-SEARCH is sent again while waiting for responses (defaults to 0)
+    ///
+    /// UDP is lossy, so the UPnP specification recommends sending the search more than once. Unlike `retries`,
+    /// the requests are sent every `retransmit_interval` within a search iteration, whose `timeout` then counts
+    /// from the last request; `search_gateways` sends them within its window.
+    pub retransmissions: u32,
+    /// How long the search waits for responses before each M-SEARCH retransmission (defaults to 500ms)
+    pub retransmit_interval: Duration,
+    /// The MX header of the M-SEARCH, the number of seconds over which devices spread their responses (defaults
+    /// to 3)
+    ///
+    /// UPnP 1.1 devices expect it between 1 and 5, so other values are sent as the nearest of these. A shorter
+    /// delay gets the responses sooner, at the risk of responses lost in a burst on busy networks.
+    pub mx: u8,
+    /// How long `search_gateways` collects responses before fetching the descriptions (defaults to `None`, the
+    /// `timeout`, or 3s when there is none)
+    ///
+    /// Responses keep being collected for the whole window, since each gateway answers on its own time.
+    pub search_window: Option<Duration>,
+    /// Size in bytes of the buffer receiving each search response (defaults to 8192)
+    ///
+    /// Longer responses are truncated, losing the headers past the limit, so verbose devices need more than the
+    /// 1500 bytes of an Ethernet frame.
+    pub response_buffer_size: usize,
+    /// Whether the search socket sets `SO_REUSEADDR` (defaults to `false`)
+    ///
+    /// This lets a search bind a fixed `bind_addr` port again while a previous socket lingers. Processes sharing
+    /// the port may receive the responses to each other's searches, and on Windows any of them can take it
+    /// over, so searches otherwise fail with `SearchError::BindFailed` when the port is taken.
+    pub reuse_address: bool,
+    /// Whether our own multicast M-SEARCH is looped back to this host (defaults to `false`)
+    pub multicast_loop: bool,
+    /// Time to live of the multicast M-SEARCH, 1 keeps it on the local network (defaults to `None`, the system default)
+    ///
+    /// It only applies to IPv4 searches.
+    pub multicast_ttl: Option<u32>,
+    /// Options for requests to the gateways found, also used to fetch their description
+    pub gateway_options: GatewayOptions,
+    /// Use the `WANIPConnection:2` service of the gateways that advertise it along with `WANIPConnection:1`
+    /// (defaults to `false`)
+    ///
+    /// The IGDv2 service reliably implements `AddAnyPortMapping`. All the actions are then sent to its control
+    /// url with its service type, without falling back to `WANIPConnection:1`. The search always asks for both
+    /// `InternetGatewayDevice:1` and `InternetGatewayDevice:2`, which some IGDv2 gateways answer exclusively,
+    /// and this asks for `InternetGatewayDevice:2` first.
+    ///
+    /// IGDv2 no longer accepts the wildcard external and internal ports (0) of IGDv1: `add_port` already
+    /// rejects them, and the IGDv2 errors `WildCardNotPermittedInExtPort` (716) and
+    /// `WildCardNotPermittedInIntPort` (732) are reported as `ExternalPortZeroInvalid` and
+    /// `InternalPortZeroInvalid`. A lease of 0 is sent unchanged unless `GatewayOptions::permanent_lease_duration`
+    /// is set, and IGDv2 gateways take it as their longest lease (a week) instead of a permanent mapping.
+    pub prefer_igd_v2: bool,
+    /// Control url of the gateways found, for gateways that refuse to serve their description (defaults to `None`)
+    ///
+    /// When set, the description and control schema are not fetched: the gateways are created like with
+    /// `Gateway::new`, which also suits a gateway whose address is known without a search.
+    pub control_url: Option<String>,
+    /// Which of the gateways answering the search are returned (defaults to `None`, all of them)
+    ///
+    /// `search_gateway` returns the first gateway selected instead of the first that answers, and
+    /// `search_gateways` leaves the others out, for networks where another router advertises IGD without an
+    /// uplink to the internet.
+    pub selector: Option<GatewaySelector>,
+    /// Whether `search_gateway` reuses the gateway it found before, until the `max_age` of its response expires
+    /// (defaults to `false`)
+    ///
+    /// The gateways are cached in the process, for the searches with the same options but `gateway_options`,
+    /// `selector` and `force_refresh`. A gateway without a `CACHE-CONTROL: max-age` is not cached. The cached
+    /// gateway is returned with the `gateway_options` of the new search, if the `selector` selects it.
+    pub use_cache: bool,
+    /// Whether `search_gateway` searches again even when `use_cache` has a valid gateway, and caches the gateway
+    /// found instead (defaults to `false`)
+    pub force_refresh: bool,
 }
 
 impl Default for SearchOptions {
@@ -31,6 +129,417 @@ impl Default for SearchOptions {
             bind_addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
             broadcast_address: "239.255.255.250:1900".parse().unwrap(),
             timeout: Some(Duration::from_secs(10)),
+            retries: 0,
+            retransmissions: 0,
+            retransmit_interval: Duration::from_millis(500),
+            mx: 3,
+            search_window: None,
+            response_buffer_size: 8192,
+            reuse_address: false,
+            multicast_loop: false,
+            multicast_ttl: None,
+            gateway_options: GatewayOptions::default(),
+            prefer_igd_v2: false,
+            control_url: None,
+            selector: None,
+            use_cache: false,
+            force_refresh: false,
+        }
+    }
+}
+
+impl SearchOptions {
+    /// Options to search over IPv6, on the link of the interface with index `scope_id`
+    ///
+    /// The M-SEARCH is sent to the link-local SSDP multicast group `[FF02::C]:1900`, for dual-stack networks where
+    /// the gateway only answers SSDP over IPv6. The interface index is listed by `ip link` on Linux, or
+    /// `netsh interface ipv6 show interfaces` on Windows.
+    ///
+    /// A gateway is used at the address of the location of its description, which is an IPv6 one for gateways
+    /// only reachable over IPv6, such as `http://[2001:db8::1]:5000/rootDesc.xml`. Its port mappings are still
+    /// for IPv4 clients, see `Gateway::local_ip`. A link-local location (`fe80::/10`) can not be used, since an
+    /// HTTP url does not name the interface.
+    pub fn ipv6(scope_id: u32) -> SearchOptions {
+        SearchOptions {
+            bind_addr: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+            broadcast_address: SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc),
+                1900,
+                0,
+                scope_id,
+            )),
+            ..Default::default()
+        }
+    }
+}
+
+/// Options for the HTTP requests made to a gateway
+///
+/// They are stored on the `Gateway` and can be changed through its `options` field.
+/// GatewayOptions::default() should suffice for most gateways.
+#[derive(Clone, Debug)]
+pub struct GatewayOptions {
+    /// Credentials for gateways protecting their UPnP endpoints with HTTP basic authentication (defaults to `None`)
+    pub credentials: Option<Credentials>,
+    /// Only check `add_port`, `add_any_port` and `remove_port` against the existing mappings, without changing
+    /// them (defaults to `false`)
+    ///
+    /// A successful dry run can not guarantee that the real call succeeds: the mappings may change in between, and
+    /// the gateway can reject a request for reasons it does not report up front.
+    pub dry_run: bool,
+    /// Refuse to map ports to an internal client that is not an address of this host (defaults to `false`)
+    ///
+    /// The unspecified, loopback and multicast addresses are refused too, as the gateway can not forward to them.
+    /// This prevents exposing another machine of the network by mistake, but also forbids adding mappings
+    /// on behalf of other hosts.
+    pub only_local_clients: bool,
+    /// Reject empty descriptions, and descriptions with characters other than printable ASCII, before adding a
+    /// mapping (defaults to `false`)
+    ///
+    /// Some gateways reject such descriptions with an unrelated error, or garble them in their interface.
+    pub strict_descriptions: bool,
+    /// Maximum time an `add_any_port` call may take over all its attempts, after which it fails with
+    /// `AddAnyPortError::Timeout` (defaults to `None`, no limit)
+    pub add_any_port_timeout: Option<Duration>,
+    /// Longest random delay before `add_any_port` tries another port after its first conflict (defaults to 10ms)
+    ///
+    /// The limit doubles after each conflict, up to `add_any_port_max_backoff`, so that applications mapping
+    /// ports at the same time stop colliding on the same ports. Zero retries at once. The async `Gateway` only
+    /// waits with the `aio` feature, since it has no timer otherwise.
+    pub add_any_port_backoff: Duration,
+    /// Cap of the delay between the attempts of `add_any_port`, see `add_any_port_backoff` (defaults to 200ms)
+    pub add_any_port_max_backoff: Duration,
+    /// Lease duration in seconds sent instead of 0 (infinite) when adding a mapping (defaults to 0, unchanged)
+    ///
+    /// Some gateways take a lease of 0 as one second, or reject it, instead of a permanent mapping. For those,
+    /// set a long finite lease here, such as 604800 (a week), and renew the mappings before it expires.
+    pub permanent_lease_duration: u32,
+    /// Random source of the external ports tried by `add_any_port` (defaults to `None`, `rand::thread_rng`)
+    pub port_rng: Option<PortRng>,
+    /// Largest response body accepted from the gateway, including its description and control schema, after
+    /// which the request fails with `ResponseTooLarge` (defaults to 4 MiB)
+    ///
+    /// This keeps a hostile or broken device from exhausting memory with an endless response.
+    pub max_response_size: usize,
+    /// Fix applied to the text of the SOAP responses before they are parsed (defaults to `None`, unchanged)
+    ///
+    /// This works around a firmware sending invalid XML, such as unescaped ampersands or a wrong encoding
+    /// declaration.
+    pub response_filter: Option<ResponseFilter>,
+    /// Receiver of the requests, retries, timeouts and error codes of this gateway, and of the searches made with
+    /// these options (defaults to `None`, nothing is counted)
+    pub metrics: Option<Arc<dyn Metrics>>,
+}
+
+impl Default for GatewayOptions {
+    fn default() -> Self {
+        Self {
+            credentials: None,
+            dry_run: false,
+            only_local_clients: false,
+            strict_descriptions: false,
+            add_any_port_timeout: None,
+            add_any_port_backoff: Duration::from_millis(10),
+            add_any_port_max_backoff: Duration::from_millis(200),
+            permanent_lease_duration: 0,
+            port_rng: None,
+            max_response_size: 4 * 1024 * 1024,
+            response_filter: None,
+            metrics: None,
+        }
+    }
+}
+
+/// Username and password for HTTP basic authentication
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// User name
+    pub username: String,
+    /// Password
+    pub password: String,
+}
+
+impl Credentials {
+    /// Create credentials from a username and password.
+    pub fn new(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    /// Value of the `Authorization` header for these credentials.
+    pub(crate) fn authorization(&self) -> String {
+        format!(
+            "Basic {}",
+            base64(format!("{}:{}", self.username, self.password).as_bytes())
+        )
+    }
+}
+
+// Keep the password out of logs.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+/// Random source of the external ports tried by `add_any_port`, see `GatewayOptions::port_rng`
+///
+/// A seeded source makes the sequence of ports reproducible, for example in tests. Clones share the same
+/// source, so gateways created from the same options continue the same sequence.
+#[derive(Clone)]
+pub struct PortRng(Arc<Mutex<dyn RngCore + Send>>);
+
+impl PortRng {
+    /// Pick the ports with `rng`.
+    pub fn new<R: RngCore + Send + 'static>(rng: R) -> PortRng {
+        PortRng(Arc::new(Mutex::new(rng)))
+    }
+
+    /// Pick the ports with a `StdRng` seeded from `seed`.
+    pub fn seed_from_u64(seed: u64) -> PortRng {
+        PortRng::new(StdRng::seed_from_u64(seed))
+    }
+
+    pub(crate) fn random_port(&self) -> u16 {
+        let mut rng = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        rng.gen_range(32_768_u16..65_535_u16)
+    }
+}
+
+impl fmt::Debug for PortRng {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PortRng")
+    }
+}
+
+/// Rewrites the text of the SOAP responses of a gateway, see `GatewayOptions::response_filter`
+///
+/// ```
+/// use igd::{GatewayOptions, ResponseFilter};
+///
+/// let options = GatewayOptions {
+///     response_filter: Some(ResponseFilter::new(|text| text.replace(" & ", " &amp; "))),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct ResponseFilter(Arc<dyn Fn(String) -> String + Send + Sync>);
+
+impl ResponseFilter {
+    /// Rewrite the responses with `filter`.
+    pub fn new<F: Fn(String) -> String + Send + Sync + 'static>(filter: F) -> ResponseFilter {
+        ResponseFilter(Arc::new(filter))
+    }
+
+    pub(crate) fn apply(&self, text: String) -> String {
+        (self.0)(text)
+    }
+}
+
+impl fmt::Debug for ResponseFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ResponseFilter")
+    }
+}
+
+/// Chooses the gateways a search returns, see `SearchOptions::selector`
+///
+/// The selector is given the address of a gateway, the headers of its search response, and the names of its
+/// description, which is `None` with `SearchOptions::control_url`.
+///
+/// ```
+/// use igd::{GatewaySelector, SearchOptions};
+///
+/// let options = SearchOptions {
+///     selector: Some(GatewaySelector::subnet("192.168.1.0".parse().unwrap(), 24)),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct GatewaySelector(Arc<SelectorFn>);
+
+type SelectorFn = dyn Fn(SocketAddr, &DiscoveryInfo, Option<&DeviceInfo>) -> bool + Send + Sync;
+
+impl GatewaySelector {
+    /// Select the gateways for which `selector` returns `true`.
+    pub fn new<F>(selector: F) -> GatewaySelector
+    where
+        F: Fn(SocketAddr, &DiscoveryInfo, Option<&DeviceInfo>) -> bool + Send + Sync + 'static,
+    {
+        GatewaySelector(Arc::new(selector))
+    }
+
+    /// Select the gateways whose IPv4 address is in the network `network`/`prefix_len`, such as 192.168.1.0/24.
+    pub fn subnet(network: Ipv4Addr, prefix_len: u8) -> GatewaySelector {
+        let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len.min(32))).unwrap_or(0);
+        let network = u32::from(network) & mask;
+        GatewaySelector::new(move |addr, _, _| match addr {
+            SocketAddr::V4(addr) => u32::from(*addr.ip()) & mask == network,
+            SocketAddr::V6(..) => false,
+        })
+    }
+
+    /// Select the gateway whose description has the unique device name `udn`, like `uuid:<UUID>`.
+    pub fn udn(udn: &str) -> GatewaySelector {
+        let udn = udn.trim().to_string();
+        GatewaySelector::new(move |_, _, device_info| {
+            device_info.is_some_and(|device_info| device_info.udn.eq_ignore_ascii_case(&udn))
+        })
+    }
+
+    pub(crate) fn selects(
+        &self,
+        addr: SocketAddr,
+        discovery_info: &DiscoveryInfo,
+        device_info: Option<&DeviceInfo>,
+    ) -> bool {
+        (self.0)(addr, discovery_info, device_info)
+    }
+}
+
+impl fmt::Debug for GatewaySelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("GatewaySelector")
+    }
+}
+
+/// Events counted by the crate, for operators to watch retry rates and discovery failures, see
+/// `GatewayOptions::metrics`
+///
+/// Every method does nothing by default, so an implementation only overrides the events it counts. They are
+/// called from the thread or task making the request, and must be cheap, such as incrementing atomics.
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+/// use igd::{GatewayOptions, Metrics};
+///
+/// #[derive(Debug, Default)]
+/// struct Retries(AtomicUsize);
+///
+/// impl Metrics for Retries {
+///     fn retry(&self, _action: &str) {
+///         self.0.fetch_add(1, Ordering::Relaxed);
+///     }
+/// }
+///
+/// let options = GatewayOptions {
+///     metrics: Some(Arc::new(Retries::default())),
+///     ..Default::default()
+/// };
+/// ```
+pub trait Metrics: fmt::Debug + Send + Sync {
+    /// A SOAP action such as `AddPortMapping` was sent to the gateway, counting each retry.
+    fn request(&self, _action: &str) {}
+    /// An action is sent again, with the unquoted `SOAPAction` header or with the other connection service type.
+    fn retry(&self, _action: &str) {}
+    /// An action failed with an IO error of the `TimedOut` kind, or `WouldBlock`, which some platforms report for a
+    /// read that timed out.
+    fn timeout(&self, _action: &str) {}
+    /// The gateway answered an action with a UPnP error code, such as 718 (ConflictInMappingEntry).
+    fn error_code(&self, _action: &str, _code: u16) {}
+    /// A search ended after `duration` with `gateways` gateways found, 0 when it failed.
+    fn search(&self, _duration: Duration, _gateways: usize) {}
+}
+
+// Standard base64 with padding (RFC 4648), only used for the `Authorization` header.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_credentials_authorization() {
+    assert_eq!(
+        Credentials::new("Aladdin", "open sesame").authorization(),
+        "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+    );
+    assert_eq!(base64(b"ab"), "YWI=");
+    assert_eq!(base64(b"abc"), "YWJj");
+}
+
+#[test]
+fn test_base64() {
+    // Test vectors of RFC 4648
+    for (data, encoded) in [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ] {
+        assert_eq!(base64(data.as_bytes()), encoded);
+    }
+
+    fn decode(text: &str) -> Vec<u8> {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = Vec::new();
+        for chunk in text.as_bytes().chunks(4) {
+            let digits: Vec<u32> = chunk
+                .iter()
+                .take_while(|&&c| c != b'=')
+                .map(|c| ALPHABET.iter().position(|a| a == c).unwrap() as u32)
+                .collect();
+            let n = digits.iter().enumerate().fold(0, |n, (i, d)| n | d << (18 - 6 * i));
+            out.extend((0..digits.len() - 1).map(|i| (n >> (16 - 8 * i)) as u8));
         }
+        out
     }
+
+    // Round trip of random inputs of every length up to 64 bytes, with all byte values
+    let mut rng = StdRng::seed_from_u64(323);
+    for len in 0..=64usize {
+        for _ in 0..32 {
+            let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let encoded = base64(&data);
+            assert_eq!(encoded.len(), len.div_ceil(3) * 4);
+            assert_eq!(decode(&encoded), data);
+        }
+    }
+    let all: Vec<u8> = (0..=255).collect();
+    assert_eq!(decode(&base64(&all)), all);
+}
+
+#[test]
+fn test_port_rng_seed() {
+    let ports = |rng: PortRng| (0..5).map(|_| rng.random_port()).collect::<Vec<_>>();
+    assert_eq!(ports(PortRng::seed_from_u64(7)), ports(PortRng::seed_from_u64(7)));
+    assert!(ports(PortRng::seed_from_u64(7)).iter().all(|port| *port >= 32_768));
+}
+
+#[test]
+fn test_gateway_selector() {
+    let info = DiscoveryInfo::default();
+    let addr = "192.168.1.1:5000".parse().unwrap();
+    assert!(GatewaySelector::subnet("192.168.1.0".parse().unwrap(), 24).selects(addr, &info, None));
+    assert!(!GatewaySelector::subnet("192.168.2.0".parse().unwrap(), 24).selects(addr, &info, None));
+    assert!(GatewaySelector::subnet("10.0.0.0".parse().unwrap(), 0).selects(addr, &info, None));
+
+    let device_info = DeviceInfo {
+        udn: "uuid:bc6e5f3c".to_string(),
+        ..Default::default()
+    };
+    let selector = GatewaySelector::udn("uuid:BC6E5F3C");
+    assert!(selector.selects(addr, &info, Some(&device_info)));
+    assert!(!selector.selects(addr, &info, None));
 }
@@ -1,80 +1,309 @@
 use std::collections::HashMap;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
 
 use url::Url;
 use xmltree::{self, Element};
 
+use crate::common::messages;
 use crate::errors::{
-    AddAnyPortError, AddPortError, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError, RequestError,
+    AddAnyPortError, AddPortError, GetDnsServersError, GetExternalIpError, GetExternalIpv6Error,
+    GetGenericPortMappingEntryError, GetSpecificPortMappingEntryError, RemovePortError, RenewPortError, RequestError,
     SearchError,
 };
-use crate::PortMappingProtocol;
+use crate::{PortMappingProtocol, RemoteHost};
 
 // Parse the result.
-pub fn parse_search_result(text: &str) -> Result<(SocketAddrV4, String), SearchError> {
+pub fn parse_search_result(text: &str) -> Result<(SocketAddr, String), SearchError> {
     use SearchError::InvalidResponse;
 
     for line in text.lines() {
         let line = line.trim();
         if line.to_ascii_lowercase().starts_with("location:") {
             if let Some(colon) = line.find(':') {
-                let url_text = &line[colon + 1..].trim();
-                let url = Url::parse(url_text).map_err(|_| InvalidResponse)?;
-                let addr: Ipv4Addr = url
-                    .host_str()
-                    .ok_or(InvalidResponse)
-                    .and_then(|s| s.parse().map_err(|_| InvalidResponse))?;
-                let port: u16 = url.port_or_known_default().ok_or(InvalidResponse)?;
-
-                return Ok((SocketAddrV4::new(addr, port), url.path().to_string()));
+                return parse_location(&line[colon + 1..]);
             }
         }
     }
     Err(InvalidResponse)
 }
 
-pub fn parse_control_urls<R>(resp: R) -> Result<(String, String), SearchError>
-where
-    R: io::Read,
-{
-    let root = Element::parse(resp)?;
+// The address and root url of a description url, such as the LOCATION of a search response. The host must be an
+// IPv4 address, or an IPv6 address in brackets.
+pub fn parse_location(location: &str) -> Result<(SocketAddr, String), SearchError> {
+    use SearchError::InvalidResponse;
 
-    let mut urls = root.children.iter().filter_map(|child| {
-        let child = child.as_element()?;
-        if child.name == "device" {
-            Some(parse_device(child)?)
+    let url = Url::parse(location.trim()).map_err(|_| InvalidResponse)?;
+    let addr: IpAddr = match url.host() {
+        Some(url::Host::Ipv4(addr)) => addr.into(),
+        Some(url::Host::Ipv6(addr)) => addr.into(),
+        _ => return Err(InvalidResponse),
+    };
+    let port: u16 = url.port_or_known_default().ok_or(InvalidResponse)?;
+
+    Ok((SocketAddr::new(addr, port), url.path().to_string()))
+}
+
+/// Headers of the SSDP response through which a gateway was found
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiscoveryInfo {
+    /// The `SERVER` header, naming the operating system and UPnP stack of the device
+    pub server: String,
+    /// The `USN` header, the unique service name of the device
+    pub usn: String,
+    /// The `ST` header, the search target the device answered to
+    pub st: String,
+    /// The `max-age` of the `CACHE-CONTROL` header, how long the advertisement is valid
+    ///
+    /// Searching again after it expired tells whether the gateway is still there, or was replaced.
+    pub max_age: Option<Duration>,
+    /// The address of this host the response was sent to, the source address of the M-SEARCH
+    ///
+    /// It is the IPv4 `SearchOptions::bind_addr` when set. With the unspecified `bind_addr` (the default), it
+    /// is the address of the interface the system routes packets to the gateway through, found from the
+    /// routing table rather than from the response. `None` when it could not be found, or for an announcement.
+    pub local_ip: Option<Ipv4Addr>,
+}
+
+impl DiscoveryInfo {
+    /// The UUID of the device, from a USN like `uuid:<UUID>::urn:schemas-upnp-org:device:InternetGatewayDevice:1`
+    ///
+    /// A device sends a response per device and service it has, all with the same UUID.
+    pub fn uuid(&self) -> Option<&str> {
+        let usn = self.usn.trim();
+        let uuid = usn.get(..5).filter(|prefix| prefix.eq_ignore_ascii_case("uuid:"))?;
+        let uuid = &usn[uuid.len()..];
+        let uuid = uuid.split("::").next().unwrap_or_default().trim();
+        if uuid.is_empty() {
+            None
+        } else {
+            Some(uuid)
+        }
+    }
+}
+
+// How specific a search target is to a gateway, to pick the best of the responses of a device.
+pub fn search_target_rank(st: &str) -> u8 {
+    if st.contains(":device:InternetGatewayDevice:") {
+        3
+    } else if st.contains(":device:WANConnectionDevice:")
+        || st.contains(":service:WANIPConnection:")
+        || st.contains(":service:WANPPPConnection:")
+    {
+        2
+    } else if st.eq_ignore_ascii_case("upnp:rootdevice") {
+        1
+    } else {
+        0
+    }
+}
+
+// Missing headers are left empty, since only the location is required to use the gateway.
+pub fn parse_discovery_info(text: &str) -> DiscoveryInfo {
+    let mut info = DiscoveryInfo::default();
+    for line in text.lines() {
+        if let Some(colon) = line.find(':') {
+            let value = line[colon + 1..].trim().to_string();
+            match line[..colon].trim().to_ascii_lowercase().as_str() {
+                "server" => info.server = value,
+                "usn" => info.usn = value,
+                "st" => info.st = value,
+                "cache-control" => info.max_age = parse_max_age(&value),
+                _ => {}
+            }
+        }
+    }
+    info
+}
+
+/// An SSDP announcement of a gateway, received with `listen_announcements`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Announcement {
+    /// The gateway joined the network or is still there (`ssdp:alive`)
+    Alive {
+        /// The url of its description, for `Gateway::from_location`
+        location: String,
+        /// The headers of the announcement, with its `NT` header as `st`
+        discovery_info: DiscoveryInfo,
+    },
+    /// The gateway leaves the network (`ssdp:byebye`), identified by the USN of its `discovery_info`
+    ByeBye {
+        /// The headers of the announcement, with its `NT` header as `st`
+        discovery_info: DiscoveryInfo,
+    },
+}
+
+// A NOTIFY of a gateway device. Devices also announce their embedded devices and services, which are ignored.
+pub fn parse_notify(text: &str) -> Option<Announcement> {
+    let mut lines = text.lines();
+    if !lines.next()?.trim().to_ascii_uppercase().starts_with("NOTIFY ") {
+        return None;
+    }
+    let (mut nt, mut nts, mut location) = (None, None, None);
+    for line in lines {
+        if let Some(colon) = line.find(':') {
+            let value = line[colon + 1..].trim();
+            match line[..colon].trim().to_ascii_lowercase().as_str() {
+                "nt" => nt = Some(value),
+                "nts" => nts = Some(value),
+                "location" => location = Some(value),
+                _ => {}
+            }
+        }
+    }
+    let nt = nt.filter(|nt| nt.starts_with("urn:schemas-upnp-org:device:InternetGatewayDevice:"))?;
+    let discovery_info = DiscoveryInfo {
+        st: nt.to_string(),
+        ..parse_discovery_info(text)
+    };
+    match nts? {
+        "ssdp:alive" => {
+            let location = location.filter(|location| parse_location(location).is_ok())?;
+            Some(Announcement::Alive {
+                location: location.to_string(),
+                discovery_info,
+            })
+        }
+        "ssdp:byebye" => Some(Announcement::ByeBye { discovery_info }),
+        _ => None,
+    }
+}
+
+// The directives of the header are separated by commas, like `max-age=1800, no-cache="Ext"`.
+fn parse_max_age(cache_control: &str) -> Option<Duration> {
+    cache_control.split(',').find_map(|directive| {
+        let (name, value) = directive.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("max-age") {
+            value.trim().trim_matches('"').parse().ok().map(Duration::from_secs)
         } else {
             None
         }
+    })
+}
+
+// Some devices send whitespace before the XML declaration, which the XML parser rejects.
+fn parse_document<R>(mut resp: R) -> Result<Element, SearchError>
+where
+    R: io::Read,
+{
+    let mut data = Vec::new();
+    resp.read_to_end(&mut data)?;
+    let start = data.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(data.len());
+    Ok(Element::parse(&data[start..])?)
+}
+
+// Check that a control url points at the gateway and normalize it to an absolute path.
+pub fn parse_control_url(addr: &SocketAddr, control_url: &str) -> Result<String, SearchError> {
+    let invalid = || SearchError::InvalidControlUrl(control_url.to_string());
+
+    if control_url.trim().is_empty() {
+        return Err(invalid());
+    }
+    let base = Url::parse(&format!("http://{}/", addr)).map_err(|_| invalid())?;
+    let url = base.join(control_url.trim()).map_err(|_| invalid())?;
+    if url.scheme() != "http" || url.host_str() != base.host_str() || url.port_or_known_default() != Some(addr.port()) {
+        return Err(invalid());
+    }
+
+    Ok(match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    })
+}
+
+pub fn parse_control_urls<R>(resp: R) -> Result<(String, String, &'static str), SearchError>
+where
+    R: io::Read,
+{
+    let root = parse_document(resp)?;
+
+    let mut devices = root
+        .children
+        .iter()
+        .filter_map(|child| child.as_element())
+        .filter(|child| child.name == "device")
+        .peekable();
+    if devices.peek().is_none() {
+        return Err(SearchError::InvalidResponse);
+    }
+
+    devices.find_map(parse_device).ok_or(SearchError::NoConnectionService)
+}
+
+/// Attributes of the root device of a gateway, from its description
+///
+/// Missing attributes are left empty. They let an application name the gateway, as in "Port mapped on FRITZ!Box
+/// 7590".
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// The `friendlyName` of the device, a short name for the user
+    pub friendly_name: String,
+    /// The `manufacturer` of the device, such as `AVM Berlin`
+    pub manufacturer: String,
+    /// The `modelName` of the device, such as `FRITZ!Box 7590`
+    pub model_name: String,
+    /// The `modelNumber` of the device
+    pub model_number: String,
+    /// The `UDN` of the device, its unique device name like `uuid:<UUID>`
+    pub udn: String,
+}
+
+pub fn parse_device_info<R>(resp: R) -> Result<DeviceInfo, SearchError>
+where
+    R: io::Read,
+{
+    let root = parse_document(resp)?;
+    let device = root.get_child("device").ok_or(SearchError::InvalidResponse)?;
+    let text = |name| child_text(device, name).unwrap_or_default();
+    Ok(DeviceInfo {
+        friendly_name: text("friendlyName"),
+        manufacturer: text("manufacturer"),
+        model_name: text("modelName"),
+        model_number: text("modelNumber"),
+        udn: text("UDN"),
+    })
+}
+
+// The control schema and control urls of the `WANIPConnection:2` service, if the description has one.
+pub fn parse_igd_v2_control_urls<R>(resp: R) -> Result<Option<(String, String)>, SearchError>
+where
+    R: io::Read,
+{
+    let root = parse_document(resp)?;
+    let service = find_element(&root, "service", &|service| {
+        child_text(service, "serviceType").is_some_and(|t| t == messages::WAN_IP_CONNECTION_2)
+            && service.get_child("SCPDURL").is_some()
+            && service.get_child("controlURL").is_some()
     });
+    Ok(service.map(|service| {
+        (
+            child_text(service, "SCPDURL").unwrap_or_default(),
+            child_text(service, "controlURL").unwrap_or_default(),
+        )
+    }))
+}
 
-    urls.next().ok_or(SearchError::InvalidResponse)
-}
-
-fn parse_device(device: &Element) -> Option<(String, String)> {
-    let services = device
-        .get_child("serviceList")
-        .map(|service_list| {
-            service_list
-                .children
-                .iter()
-                .filter_map(|child| {
-                    let child = child.as_element()?;
-                    if child.name == "service" {
-                        parse_service(child)
-                    } else {
-                        None
-                    }
-                })
-                .next()
-        })
-        .flatten();
-    let devices = device.get_child("deviceList").map(parse_device_list).flatten();
+fn parse_device(device: &Element) -> Option<(String, String, &'static str)> {
+    let services = device.get_child("serviceList").and_then(|service_list| {
+        service_list
+            .children
+            .iter()
+            .filter_map(|child| {
+                let child = child.as_element()?;
+                if child.name == "service" {
+                    parse_service(child)
+                } else {
+                    None
+                }
+            })
+            .next()
+    });
+    let devices = device.get_child("deviceList").and_then(parse_device_list);
     services.or(devices)
 }
 
-fn parse_device_list(device_list: &Element) -> Option<(String, String)> {
+fn parse_device_list(device_list: &Element) -> Option<(String, String, &'static str)> {
     device_list
         .children
         .iter()
@@ -89,42 +318,134 @@ fn parse_device_list(device_list: &Element) -> Option<(String, String)> {
         .next()
 }
 
-fn parse_service(service: &Element) -> Option<(String, String)> {
+// The connection service found is also the type its actions are sent with.
+fn parse_service(service: &Element) -> Option<(String, String, &'static str)> {
     let service_type = service.get_child("serviceType")?;
     let service_type = service_type
         .get_text()
         .map(|s| s.into_owned())
         .unwrap_or_else(|| "".into());
-    if [
-        "urn:schemas-upnp-org:service:WANPPPConnection:1",
-        "urn:schemas-upnp-org:service:WANIPConnection:1",
-        "urn:schemas-upnp-org:service:WANIPConnection:2",
+    let service_type = [
+        messages::WAN_PPP_CONNECTION,
+        messages::SERVICE_TYPE,
+        messages::WAN_IP_CONNECTION_2,
     ]
-    .contains(&service_type.as_str())
-    {
-        let scpd_url = service.get_child("SCPDURL");
-        let control_url = service.get_child("controlURL");
-        if let (Some(scpd_url), Some(control_url)) = (scpd_url, control_url) {
-            Some((
-                scpd_url.get_text().map(|s| s.into_owned()).unwrap_or_else(|| "".into()),
-                control_url
-                    .get_text()
-                    .map(|s| s.into_owned())
-                    .unwrap_or_else(|| "".into()),
-            ))
-        } else {
-            None
-        }
+    .iter()
+    .copied()
+    .find(|known| *known == service_type.trim())?;
+
+    let scpd_url = service.get_child("SCPDURL");
+    let control_url = service.get_child("controlURL");
+    if let (Some(scpd_url), Some(control_url)) = (scpd_url, control_url) {
+        Some((
+            scpd_url.get_text().map(|s| s.into_owned()).unwrap_or_else(|| "".into()),
+            control_url
+                .get_text()
+                .map(|s| s.into_owned())
+                .unwrap_or_else(|| "".into()),
+            service_type,
+        ))
     } else {
         None
     }
 }
 
+// Find the control url of a service in a device description, normalized like the main control url.
+pub fn parse_service_control_url(
+    addr: &SocketAddr,
+    text: &str,
+    service_type: &str,
+) -> Result<Option<String>, RequestError> {
+    let root = parse_document(text.as_bytes()).map_err(|_| RequestError::InvalidResponse(text.to_string()))?;
+    let control_url = find_service(&root, service_type)
+        .and_then(|service| service.get_child("controlURL"))
+        .and_then(|control_url| control_url.get_text());
+    match control_url {
+        Some(control_url) => parse_control_url(addr, &control_url)
+            .map(Some)
+            .map_err(|_| RequestError::InvalidResponse(text.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// A service advertised in the device description of a gateway, as returned by `Gateway::services`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceInfo {
+    /// The type of the device providing the service, such as `urn:schemas-upnp-org:device:WANDevice:1`
+    pub device_type: String,
+    /// The type of the service, such as `urn:schemas-upnp-org:service:WANIPConnection:1`
+    pub service_type: String,
+    /// The id of the service, such as `urn:upnp-org:serviceId:WANIPConn1`
+    pub service_id: String,
+    /// The url to send the actions of the service to, as written in the description
+    pub control_url: String,
+    /// The url to subscribe to the events of the service, as written in the description
+    pub event_sub_url: String,
+    /// The url of the control schema of the service, as written in the description
+    pub scpd_url: String,
+}
+
+// All the services of the description, including those of the nested devices, in document order.
+pub fn parse_services(text: &str) -> Result<Vec<ServiceInfo>, RequestError> {
+    fn collect(element: &Element, services: &mut Vec<ServiceInfo>) {
+        if element.name == "device" {
+            let device_type = child_text(element, "deviceType").unwrap_or_default();
+            let list = element.get_child("serviceList").into_iter();
+            for service in list.flat_map(|list| list.children.iter().filter_map(|child| child.as_element())) {
+                if service.name == "service" {
+                    let text = |name| child_text(service, name).unwrap_or_default();
+                    services.push(ServiceInfo {
+                        device_type: device_type.clone(),
+                        service_type: text("serviceType"),
+                        service_id: text("serviceId"),
+                        control_url: text("controlURL"),
+                        event_sub_url: text("eventSubURL"),
+                        scpd_url: text("SCPDURL"),
+                    });
+                }
+            }
+        }
+        for child in element.children.iter().filter_map(|child| child.as_element()) {
+            collect(child, services);
+        }
+    }
+
+    let root = parse_document(text.as_bytes()).map_err(|_| RequestError::InvalidResponse(text.to_string()))?;
+    let mut services = Vec::new();
+    collect(&root, &mut services);
+    Ok(services)
+}
+
+fn find_service<'a>(element: &'a Element, service_type: &str) -> Option<&'a Element> {
+    find_element(element, "service", &|service| {
+        child_text(service, "serviceType").is_some_and(|t| t == service_type)
+    })
+}
+
+// Find the first element named `name` accepted by `matches`, depth first.
+fn find_element<'a>(element: &'a Element, name: &str, matches: &dyn Fn(&Element) -> bool) -> Option<&'a Element> {
+    if element.name == name && matches(element) {
+        return Some(element);
+    }
+    element
+        .children
+        .iter()
+        .filter_map(|child| child.as_element())
+        .find_map(|child| find_element(child, name, matches))
+}
+
+fn child_text(element: &Element, name: &str) -> Option<String> {
+    element
+        .get_child(name)
+        .and_then(|child| child.get_text())
+        .map(|text| text.trim().to_string())
+}
+
 pub fn parse_schemas<R>(resp: R) -> Result<HashMap<String, Vec<String>>, SearchError>
 where
     R: io::Read,
 {
-    let root = Element::parse(resp)?;
+    let root = parse_document(resp)?;
 
     let mut schema = root.children.iter().filter_map(|child| {
         let child = child.as_element()?;
@@ -187,18 +508,151 @@ fn parse_argument(action: &Element) -> Option<String> {
     }
 }
 
+pub fn parse_max_lease_duration(text: &str) -> Result<Option<u32>, RequestError> {
+    let root = match Element::parse(text.trim_start().as_bytes()) {
+        Ok(root) => root,
+        Err(..) => return Err(RequestError::InvalidResponse(text.to_string())),
+    };
+    fn elements<'a>(parent: Option<&'a Element>, name: &str) -> Vec<&'a Element> {
+        parent
+            .into_iter()
+            .flat_map(|e| e.children.iter())
+            .filter_map(|c| c.as_element())
+            .filter(|c| c.name == name)
+            .collect()
+    }
+    fn text_of(e: &Element, name: &str) -> Option<String> {
+        e.get_child(name)
+            .and_then(|c| c.get_text())
+            .map(|t| t.trim().to_string())
+    }
+
+    // The lease duration argument refers to the state variable holding its allowed range.
+    let variable = elements(root.get_child("actionList"), "action")
+        .into_iter()
+        .filter(|a| text_of(a, "name").as_deref() == Some("AddPortMapping"))
+        .flat_map(|a| elements(a.get_child("argumentList"), "argument"))
+        .find(|a| text_of(a, "name").as_deref() == Some("NewLeaseDuration"))
+        .and_then(|a| text_of(a, "relatedStateVariable"))
+        .unwrap_or_else(|| "PortMappingLeaseDuration".into());
+    let maximum = elements(root.get_child("serviceStateTable"), "stateVariable")
+        .into_iter()
+        .find(|v| text_of(v, "name").as_deref() == Some(variable.as_str()))
+        .and_then(|v| v.get_child("allowedValueRange"))
+        .map(|r| text_of(r, "maximum"));
+
+    match maximum {
+        None => Ok(None),
+        Some(maximum) => match maximum.and_then(|m| m.parse::<u32>().ok()) {
+            Some(maximum) => Ok(Some(maximum)),
+            None => Err(RequestError::InvalidResponse(text.to_string())),
+        },
+    }
+}
+
 pub struct RequestReponse {
     text: String,
     xml: xmltree::Element,
 }
 
+impl RequestReponse {
+    pub fn into_xml(self) -> xmltree::Element {
+        self.xml
+    }
+}
+
 pub type RequestResult = Result<RequestReponse, RequestError>;
 
+// Gateways send UPnP errors with an HTTP error status, so the status only matters when the body is not SOAP.
+pub fn parse_http_response(status: u16, text: String, ok: &str) -> RequestResult {
+    if status == 401 {
+        return Err(RequestError::HttpUnauthorized);
+    }
+    match parse_response(text, ok) {
+        Err(RequestError::InvalidResponse(..)) | Err(RequestError::NotSoap(..)) if status == 404 => {
+            Err(RequestError::ControlUrlUnreachable(status))
+        }
+        Err(RequestError::InvalidResponse(text)) | Err(RequestError::NotSoap(text)) if status >= 400 => {
+            Err(http_status_error(status, text))
+        }
+        result => result,
+    }
+}
+
+// A non-SOAP 400 or 500 answer may come from a gateway that could not parse the SOAPAction header. It may also
+// have failed after performing the action, so only the actions that change nothing are sent again.
+pub fn is_soap_action_rejected(err: &RequestError, action: &str) -> bool {
+    messages::is_read_only(action)
+        && matches!(
+            err,
+            RequestError::Http(attohttpc::StatusCode::INTERNAL_SERVER_ERROR)
+                | RequestError::Http(attohttpc::StatusCode::BAD_REQUEST)
+        )
+}
+
+// Error 401 (Invalid Action), or an HTTP 404 for the service, may come from a gateway that has the other connection
+// service.
+pub fn is_service_type_rejected(err: &RequestError) -> bool {
+    matches!(
+        err,
+        RequestError::ErrorCode { code: 401, .. } | RequestError::ControlUrlUnreachable(404)
+    )
+}
+
+// Whether the gateway understood an action, even if it failed, so its service type is the right one.
+pub fn is_service_type_accepted(result: &RequestResult) -> bool {
+    match result {
+        Ok(_) => true,
+        Err(err @ RequestError::ErrorCode { .. }) => !is_service_type_rejected(err),
+        Err(_) => false,
+    }
+}
+
+// Whether an `AddAnyPortMapping` result shows the gateway implements the action, `None` if it did not answer.
+pub fn is_add_any_port_supported(result: &RequestResult) -> Option<bool> {
+    match result {
+        Ok(_) => Some(true),
+        Err(RequestError::ErrorCode { code: 401, .. }) => Some(false),
+        Err(RequestError::ErrorCode { .. }) => Some(true),
+        Err(_) => None,
+    }
+}
+
+// Check the status of a response that is not SOAP, like a control schema.
+pub fn parse_http_status(status: u16, text: String) -> Result<String, RequestError> {
+    match status {
+        401 => Err(RequestError::HttpUnauthorized),
+        400..=999 => Err(http_status_error(status, text)),
+        _ => Ok(text),
+    }
+}
+
+fn http_status_error(status: u16, text: String) -> RequestError {
+    match attohttpc::StatusCode::from_u16(status) {
+        Ok(status) => RequestError::Http(status),
+        Err(..) => RequestError::InvalidResponse(text),
+    }
+}
+
+// Routers answer with an HTML page when they redirect to their login page, or fail outside of UPnP.
+fn is_html(text: &str) -> bool {
+    let start = text.trim_start().as_bytes();
+    let starts_with = |prefix: &[u8]| start.len() >= prefix.len() && start[..prefix.len()].eq_ignore_ascii_case(prefix);
+    starts_with(b"<!doctype html") || starts_with(b"<html")
+}
+
 pub fn parse_response(text: String, ok: &str) -> RequestResult {
+    if is_html(&text) {
+        return Err(RequestError::NotSoap(text));
+    }
     let mut xml = match xmltree::Element::parse(text.as_bytes()) {
         Ok(xml) => xml,
         Err(..) => return Err(RequestError::InvalidResponse(text)),
     };
+    if xml.name != "Envelope" {
+        return Err(RequestError::NotSoap(text));
+    }
+    // The names are matched without their namespace prefix, which varies between gateways.
     let body = match xml.get_mut_child("Body") {
         Some(body) => body,
         None => return Err(RequestError::InvalidResponse(text)),
@@ -206,48 +660,160 @@ pub fn parse_response(text: String, ok: &str) -> RequestResult {
     if let Some(ok) = body.take_child(ok) {
         return Ok(RequestReponse { text, xml: ok });
     }
-    let upnp_error = match body
-        .get_child("Fault")
-        .and_then(|e| e.get_child("detail"))
-        .and_then(|e| e.get_child("UPnPError"))
-    {
-        Some(upnp_error) => upnp_error,
+    let fault = match body.get_child("Fault") {
+        Some(fault) => fault,
         None => return Err(RequestError::InvalidResponse(text)),
     };
 
-    match (
-        upnp_error.get_child("errorCode"),
-        upnp_error.get_child("errorDescription"),
-    ) {
-        (Some(e), Some(d)) => match (e.get_text().as_ref(), d.get_text().as_ref()) {
-            (Some(et), Some(dt)) => match et.parse::<u16>() {
-                Ok(en) => Err(RequestError::ErrorCode(en, From::from(&dt[..]))),
-                Err(..) => Err(RequestError::InvalidResponse(text)),
-            },
-            _ => Err(RequestError::InvalidResponse(text)),
-        },
-        _ => Err(RequestError::InvalidResponse(text)),
+    // The error is usually in `detail/UPnPError`, but some firmware nests it in a vendor element, or puts it
+    // right under `Fault`.
+    let code = find_element(fault, "errorCode", &|_| true)
+        .and_then(|e| e.get_text())
+        .and_then(|code| code.trim().parse::<u16>().ok());
+    match code {
+        Some(code) => {
+            let description = find_element(fault, "errorDescription", &|_| true)
+                .and_then(|e| e.get_text())
+                .unwrap_or_default();
+            Err(RequestError::error_code(
+                ok.trim_end_matches("Response"),
+                code,
+                description.trim(),
+            ))
+        }
+        None => Err(RequestError::InvalidResponse(text)),
     }
 }
 
 pub fn parse_get_external_ip_response(result: RequestResult) -> Result<Ipv4Addr, GetExternalIpError> {
     match result {
-        Ok(resp) => match resp
-            .xml
-            .get_child("NewExternalIPAddress")
-            .and_then(|e| e.get_text())
-            .and_then(|t| t.parse::<Ipv4Addr>().ok())
-        {
-            Some(ipv4_addr) => Ok(ipv4_addr),
-            None => Err(GetExternalIpError::RequestError(RequestError::InvalidResponse(
-                resp.text,
-            ))),
-        },
-        Err(RequestError::ErrorCode(606, _)) => Err(GetExternalIpError::ActionNotAuthorized),
+        Ok(resp) => {
+            let text = match resp.xml.get_child("NewExternalIPAddress") {
+                Some(e) => e.get_text().unwrap_or_default().trim().to_string(),
+                None => {
+                    return Err(GetExternalIpError::RequestError(RequestError::InvalidResponse(
+                        resp.text,
+                    )))
+                }
+            };
+            // A PPP link that is still coming up has no address yet.
+            match text.parse::<Ipv4Addr>() {
+                _ if text.is_empty() => Err(GetExternalIpError::NotConnected),
+                Ok(ipv4_addr) if ipv4_addr.is_unspecified() => Err(GetExternalIpError::NotConnected),
+                Ok(ipv4_addr) => Ok(ipv4_addr),
+                Err(..) => Err(GetExternalIpError::RequestError(RequestError::InvalidResponse(
+                    resp.text,
+                ))),
+            }
+        }
+        Err(RequestError::ErrorCode { code: 606, .. }) => Err(GetExternalIpError::ActionNotAuthorized),
         Err(e) => Err(GetExternalIpError::RequestError(e)),
     }
 }
 
+pub fn parse_get_external_ipv6_response(result: RequestResult) -> Result<Ipv6Addr, GetExternalIpv6Error> {
+    let resp = result?;
+    let text = match resp.xml.get_child("NewExternalIPv6Address") {
+        Some(e) => e.get_text().unwrap_or_default().into_owned(),
+        None => {
+            return Err(GetExternalIpv6Error::RequestError(RequestError::InvalidResponse(
+                resp.text,
+            )))
+        }
+    };
+    // The address is empty while the gateway has no IPv6 connection.
+    if text.trim().is_empty() {
+        return Err(GetExternalIpv6Error::NotSupported);
+    }
+    text.trim()
+        .parse::<Ipv6Addr>()
+        .map_err(|_| GetExternalIpv6Error::RequestError(RequestError::InvalidResponse(resp.text.clone())))
+}
+
+// Any answer but 606 means the client may perform actions, even if the external IP address is not usable.
+pub fn parse_check_authorized_response(result: RequestResult) -> Result<bool, RequestError> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(RequestError::ErrorCode { code: 606, .. }) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The state of the WAN connection, as returned by `Gateway::get_status_info`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatusInfo {
+    /// The status of the connection, such as `Connected`, `Connecting` or `Disconnected`
+    pub connection_status: String,
+    /// The cause of the last connection failure, such as `ERROR_NONE` or `ERROR_NO_CARRIER`, if reported
+    pub last_connection_error: Option<String>,
+    /// The time in seconds the connection has been up, if reported
+    pub uptime: Option<u32>,
+}
+
+impl StatusInfo {
+    /// Whether the WAN connection is up
+    pub fn is_connected(&self) -> bool {
+        self.connection_status == "Connected"
+    }
+}
+
+// Only `NewConnectionStatus` is required, some gateways leave out the other arguments.
+pub fn parse_get_status_info_response(result: RequestResult) -> Result<StatusInfo, RequestError> {
+    let resp = result?;
+    let connection_status =
+        child_text(&resp.xml, "NewConnectionStatus").ok_or_else(|| RequestError::InvalidResponse(resp.text.clone()))?;
+    let uptime = match child_text(&resp.xml, "NewUptime") {
+        Some(uptime) => Some(
+            uptime
+                .trim()
+                .parse()
+                .map_err(|_| RequestError::InvalidResponse(resp.text.clone()))?,
+        ),
+        None => None,
+    };
+    Ok(StatusInfo {
+        connection_status,
+        last_connection_error: child_text(&resp.xml, "NewLastConnectionError"),
+        uptime,
+    })
+}
+
+pub fn parse_query_state_variable_response(result: RequestResult) -> Result<String, RequestError> {
+    let response = result?;
+    match response.xml.get_child("return") {
+        Some(value) => Ok(value.get_text().unwrap_or_default().into_owned()),
+        None => Err(RequestError::InvalidResponse(response.text)),
+    }
+}
+
+// The value of the `NumberOfActiveConnections` state variable, or `None` if the gateway does not tell it.
+pub fn parse_number_of_active_connections(result: RequestResult) -> Option<u32> {
+    parse_query_state_variable_response(result)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+}
+
+pub fn parse_get_dns_servers_response(result: RequestResult) -> Result<Vec<Ipv4Addr>, GetDnsServersError> {
+    let resp = result?;
+    let servers = match resp.xml.get_child("NewDNSServers") {
+        Some(e) => e.get_text().unwrap_or_default().into_owned(),
+        None => {
+            return Err(GetDnsServersError::RequestError(RequestError::InvalidResponse(
+                resp.text,
+            )))
+        }
+    };
+    servers
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<Ipv4Addr>()
+                .map_err(|_| GetDnsServersError::RequestError(RequestError::InvalidResponse(resp.text.clone())))
+        })
+        .collect()
+}
+
 pub fn parse_add_any_port_mapping_response(result: RequestResult) -> Result<u16, AddAnyPortError> {
     match result {
         Ok(resp) => {
@@ -262,9 +828,10 @@ pub fn parse_add_any_port_mapping_response(result: RequestResult) -> Result<u16,
             }
         }
         Err(err) => Err(match err {
-            RequestError::ErrorCode(605, _) => AddAnyPortError::DescriptionTooLong,
-            RequestError::ErrorCode(606, _) => AddAnyPortError::ActionNotAuthorized,
-            RequestError::ErrorCode(728, _) => AddAnyPortError::NoPortsAvailable,
+            RequestError::ErrorCode { code: 605, .. } => AddAnyPortError::DescriptionTooLong,
+            RequestError::ErrorCode { code: 606, .. } => AddAnyPortError::ActionNotAuthorized,
+            RequestError::ErrorCode { code: 728, .. } => AddAnyPortError::NoPortsAvailable,
+            RequestError::ErrorCode { code: 733, .. } => AddAnyPortError::InconsistentParameters,
             e => AddAnyPortError::RequestError(e),
         }),
     }
@@ -272,47 +839,202 @@ pub fn parse_add_any_port_mapping_response(result: RequestResult) -> Result<u16,
 
 pub fn convert_add_random_port_mapping_error(error: RequestError) -> Option<AddAnyPortError> {
     match error {
-        RequestError::ErrorCode(724, _) => None,
-        RequestError::ErrorCode(605, _) => Some(AddAnyPortError::DescriptionTooLong),
-        RequestError::ErrorCode(606, _) => Some(AddAnyPortError::ActionNotAuthorized),
-        RequestError::ErrorCode(718, _) => Some(AddAnyPortError::NoPortsAvailable),
-        RequestError::ErrorCode(725, _) => Some(AddAnyPortError::OnlyPermanentLeasesSupported),
+        RequestError::ErrorCode { code: 724, .. } => None,
+        RequestError::ErrorCode { code: 605, .. } => Some(AddAnyPortError::DescriptionTooLong),
+        RequestError::ErrorCode { code: 606, .. } => Some(AddAnyPortError::ActionNotAuthorized),
+        // A port reserved by the gateway itself (729) is as taken as one mapped by another client.
+        RequestError::ErrorCode { code: 718, .. } | RequestError::ErrorCode { code: 729, .. } => {
+            Some(AddAnyPortError::NoPortsAvailable)
+        }
+        RequestError::ErrorCode { code: 725, .. } => Some(AddAnyPortError::OnlyPermanentLeasesSupported),
+        RequestError::ErrorCode { code: 733, .. } => Some(AddAnyPortError::InconsistentParameters),
         e => Some(AddAnyPortError::RequestError(e)),
     }
 }
 
 pub fn convert_add_same_port_mapping_error(error: RequestError) -> AddAnyPortError {
     match error {
-        RequestError::ErrorCode(606, _) => AddAnyPortError::ActionNotAuthorized,
-        RequestError::ErrorCode(718, _) => AddAnyPortError::ExternalPortInUse,
-        RequestError::ErrorCode(725, _) => AddAnyPortError::OnlyPermanentLeasesSupported,
+        RequestError::ErrorCode { code: 606, .. } => AddAnyPortError::ActionNotAuthorized,
+        RequestError::ErrorCode { code: 718, .. } | RequestError::ErrorCode { code: 729, .. } => {
+            AddAnyPortError::ExternalPortInUse
+        }
+        RequestError::ErrorCode { code: 725, .. } => AddAnyPortError::OnlyPermanentLeasesSupported,
+        RequestError::ErrorCode { code: 733, .. } => AddAnyPortError::InconsistentParameters,
         e => AddAnyPortError::RequestError(e),
     }
 }
 
 pub fn convert_add_port_error(err: RequestError) -> AddPortError {
     match err {
-        RequestError::ErrorCode(605, _) => AddPortError::DescriptionTooLong,
-        RequestError::ErrorCode(606, _) => AddPortError::ActionNotAuthorized,
-        RequestError::ErrorCode(718, _) => AddPortError::PortInUse,
-        RequestError::ErrorCode(724, _) => AddPortError::SamePortValuesRequired,
-        RequestError::ErrorCode(725, _) => AddPortError::OnlyPermanentLeasesSupported,
+        RequestError::ErrorCode { code: 605, .. } => AddPortError::DescriptionTooLong,
+        RequestError::ErrorCode { code: 606, .. } => AddPortError::ActionNotAuthorized,
+        // WildCardNotPermittedInExtPort and WildCardNotPermittedInIntPort of IGDv2.
+        RequestError::ErrorCode { code: 716, .. } => AddPortError::ExternalPortZeroInvalid,
+        RequestError::ErrorCode { code: 732, .. } => AddPortError::InternalPortZeroInvalid,
+        RequestError::ErrorCode { code: 718, .. } => AddPortError::PortInUse,
+        RequestError::ErrorCode { code: 724, .. } => AddPortError::SamePortValuesRequired,
+        RequestError::ErrorCode { code: 725, .. } => AddPortError::OnlyPermanentLeasesSupported,
+        RequestError::ErrorCode { code: 726, .. } => AddPortError::RemoteHostOnlySupportsWildcard,
+        RequestError::ErrorCode { code: 729, .. } => AddPortError::ConflictWithOtherMechanisms,
+        RequestError::ErrorCode { code: 733, .. } => AddPortError::InconsistentParameters,
         e => AddPortError::RequestError(e),
     }
 }
 
+pub fn convert_renew_port_error(err: RequestError) -> RenewPortError {
+    match err {
+        RequestError::ErrorCode { code: 606, .. } => RenewPortError::ActionNotAuthorized,
+        RequestError::ErrorCode { code: 714, .. } => RenewPortError::NoSuchPortMapping,
+        RequestError::ErrorCode { code: 725, .. } => RenewPortError::OnlyPermanentLeasesSupported,
+        e => RenewPortError::RequestError(e),
+    }
+}
+
 pub fn parse_delete_port_mapping_response(result: RequestResult) -> Result<(), RemovePortError> {
     match result {
         Ok(_) => Ok(()),
+        // Some gateways answer a successful status without a body, whether or not the mapping existed.
+        Err(RequestError::InvalidResponse(text)) if text.trim().is_empty() => Ok(()),
         Err(err) => Err(match err {
-            RequestError::ErrorCode(606, _) => RemovePortError::ActionNotAuthorized,
-            RequestError::ErrorCode(714, _) => RemovePortError::NoSuchPortMapping,
+            RequestError::ErrorCode { code: 606, .. } => RemovePortError::ActionNotAuthorized,
+            RequestError::ErrorCode { code: 714, .. } => RemovePortError::NoSuchPortMapping,
+            // PortMappingNotFound, the 714 of DeletePortMappingRange.
+            RequestError::ErrorCode { code: 730, .. } => RemovePortError::NoSuchPortMapping,
             e => RemovePortError::RequestError(e),
         }),
     }
 }
 
+/// Line attributes of a DSL modem, as returned by `Gateway::get_dsl_link_info`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DslLinkInfo {
+    /// The type of the DSL link, such as `EoA` or `PPPoA`
+    pub link_type: String,
+    /// The status of the DSL link, such as `Up` or `Down`
+    pub link_status: String,
+    /// Whether the link type is configured automatically by the modem
+    pub auto_config: bool,
+    /// The modulation of the line, such as `ADSL_G.dmt`, when the modem reports it
+    pub modulation_type: Option<String>,
+}
+
+pub fn parse_dsl_link_info(
+    link_info: RequestResult,
+    auto_config: RequestResult,
+    modulation_type: RequestResult,
+) -> Result<DslLinkInfo, RequestError> {
+    fn get_field(resp: &RequestReponse, field: &str) -> Result<String, RequestError> {
+        resp.xml
+            .get_child(field)
+            .map(|e| e.get_text().unwrap_or_default().into_owned())
+            .ok_or_else(|| RequestError::InvalidResponse(resp.text.clone()))
+    }
+
+    let link_info = link_info?;
+    let auto_config = auto_config?;
+    let modulation_type = match modulation_type {
+        Ok(resp) => Some(get_field(&resp, "NewModulationType")?),
+        // The modulation getter is optional.
+        Err(RequestError::ErrorCode { code: 401, .. }) | Err(RequestError::ErrorCode { code: 602, .. }) => None,
+        Err(e) => return Err(e),
+    };
+
+    Ok(DslLinkInfo {
+        link_type: get_field(&link_info, "NewLinkType")?,
+        link_status: get_field(&link_info, "NewLinkStatus")?,
+        auto_config: match get_field(&auto_config, "NewAutoConfig")?.as_str() {
+            "1" | "true" => true,
+            "0" | "false" => false,
+            _ => return Err(RequestError::InvalidResponse(auto_config.text)),
+        },
+        modulation_type,
+    })
+}
+
+/// A port mapping added by `Gateway::get_any_mapping`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MappingResult {
+    /// The external address of the mapping, with the external IP address of the gateway
+    pub external_addr: SocketAddrV4,
+    /// The lease duration of the mapping in seconds, 0 for a permanent mapping
+    ///
+    /// This is the lease the gateway reports for the mapping once added, as IGDv2 gateways may shorten leases
+    /// longer than a week. It is the lease sent, after `GatewayOptions::permanent_lease_duration`, for gateways
+    /// that do not list the mapping.
+    pub lease_duration: u32,
+    /// Whether `AddPortMapping` was tried with random ports, because the gateway lacks `AddAnyPortMapping`
+    pub used_legacy_fallback: bool,
+    /// Whether the gateway required the external port to equal the internal port of `local_addr`
+    ///
+    /// The random external port is then replaced with the internal port, which peers must be told instead.
+    pub same_port_forced: bool,
+}
+
+/// A WAN connection reported as active by `Gateway::active_connections`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ActiveConnection {
+    /// The UDN of the `WANConnectionDevice` holding the connection
+    pub device_container: String,
+    /// The id of the connection service, such as `urn:upnp-org:serviceId:WANIPConn1`
+    pub service_id: String,
+    /// The type of the connection service, such as `urn:schemas-upnp-org:service:WANPPPConnection:1`
+    pub service_type: String,
+    /// The control url of the connection service
+    pub control_url: String,
+}
+
+// Read a `ui4` counter of the WANCommonInterfaceConfig service, such as `NewTotalPacketsSent`.
+pub fn parse_counter(result: RequestResult, field: &str) -> Result<u32, RequestError> {
+    let response = result?;
+    child_text(&response.xml, field)
+        .and_then(|text| text.parse().ok())
+        .ok_or(RequestError::InvalidResponse(response.text))
+}
+
+// Parse one GetActiveConnection response, resolving the service in the device description.
+// Returns `None` past the last connection.
+pub fn parse_active_connection(
+    addr: &SocketAddr,
+    description: &str,
+    result: RequestResult,
+) -> Result<Option<ActiveConnection>, RequestError> {
+    let response = match result {
+        Ok(response) => response,
+        Err(RequestError::ErrorCode { code: 713, .. }) | Err(RequestError::ErrorCode { code: 402, .. }) => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    };
+    let invalid = || RequestError::InvalidResponse(response.text.clone());
+    let device_container = child_text(&response.xml, "NewActiveConnDeviceContainer").ok_or_else(invalid)?;
+    let service_id = child_text(&response.xml, "NewActiveConnectionServiceID").ok_or_else(invalid)?;
+
+    // Some gateways prefix the service id with the device container.
+    let id = service_id.rsplit(',').next().unwrap_or_default().trim();
+    let root = parse_document(description.as_bytes()).map_err(|_| RequestError::InvalidResponse(description.into()))?;
+    let has_id = |service: &Element| child_text(service, "serviceId").is_some_and(|s| s == id);
+    let service = find_element(&root, "device", &|device| {
+        child_text(device, "UDN").is_some_and(|udn| !udn.is_empty() && device_container.starts_with(&udn))
+    })
+    .and_then(|device| device.get_child("serviceList"))
+    .and_then(|services| find_element(services, "service", &has_id))
+    .or_else(|| find_element(&root, "service", &has_id))
+    .ok_or_else(invalid)?;
+
+    let service_type = child_text(service, "serviceType").ok_or_else(invalid)?;
+    let control_url = child_text(service, "controlURL")
+        .and_then(|url| parse_control_url(addr, &url).ok())
+        .ok_or_else(invalid)?;
+    Ok(Some(ActiveConnection {
+        device_container,
+        service_id,
+        service_type,
+        control_url,
+    }))
+}
+
 /// One port mapping entry as returned by GetGenericPortMappingEntry
+#[derive(Clone, Debug, PartialEq)]
 pub struct PortMappingEntry {
     /// The remote host for which the mapping is valid
     /// Can be an IP address or a host name
@@ -334,6 +1056,124 @@ pub struct PortMappingEntry {
     pub lease_duration: u32,
 }
 
+impl PortMappingEntry {
+    /// A predicate for `Gateway::prune_mappings`, accepting the entries whose description starts with `prefix`.
+    ///
+    /// Giving the mappings of an application a common description prefix lets it reclaim those leaked by a
+    /// previous run.
+    pub fn description_starts_with(prefix: &str) -> impl Fn(&PortMappingEntry) -> bool + '_ {
+        move |entry| entry.port_mapping_description.starts_with(prefix)
+    }
+}
+
+/// A difference between a mapping added by `Gateway::add_port_verified` and the entry stored by the gateway
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MappingWarning {
+    /// The gateway granted another lease than requested, in seconds, 0 being permanent
+    LeaseClamped {
+        /// The requested lease
+        requested: u32,
+        /// The lease left on the stored entry
+        granted: u32,
+    },
+    /// The gateway stored the beginning of the requested description
+    DescriptionTruncated {
+        /// The stored description
+        stored: String,
+    },
+    /// The gateway stored another description, or ignored it
+    DescriptionChanged {
+        /// The stored description
+        stored: String,
+    },
+    /// The gateway stored the mapping, but disabled
+    Disabled,
+    /// The gateway accepted the mapping, but does not list it
+    NotListed,
+}
+
+// How much the lease read back may have run down since the mapping was added, in seconds.
+const LEASE_READ_BACK_SLACK: u32 = 10;
+
+// Compare an entry read back after adding it with the requested values.
+pub fn check_mapping_entry(entry: &PortMappingEntry, lease_duration: u32, description: &str) -> Vec<MappingWarning> {
+    let mut warnings = Vec::new();
+    let granted = entry.lease_duration;
+    let ran_down = granted <= lease_duration && lease_duration - granted <= LEASE_READ_BACK_SLACK;
+    if granted != lease_duration && (lease_duration == 0 || granted == 0 || !ran_down) {
+        warnings.push(MappingWarning::LeaseClamped {
+            requested: lease_duration,
+            granted,
+        });
+    }
+    let stored = &entry.port_mapping_description;
+    if stored != description {
+        let stored = stored.clone();
+        if !stored.is_empty() && description.starts_with(stored.as_str()) {
+            warnings.push(MappingWarning::DescriptionTruncated { stored });
+        } else {
+            warnings.push(MappingWarning::DescriptionChanged { stored });
+        }
+    }
+    if !entry.enabled {
+        warnings.push(MappingWarning::Disabled);
+    }
+    warnings
+}
+
+// The remote host of a listed mapping, or `None` for a host name, which can not be passed back to the gateway.
+pub fn parse_remote_host(text: &str) -> Option<RemoteHost> {
+    match text.trim() {
+        "" => Some(RemoteHost::Wildcard),
+        text => text.parse().ok().map(RemoteHost::Specific),
+    }
+}
+
+// The `NewPortListing` is an escaped `PortMappingList` document, whose entries name their fields unlike the
+// other actions. 730 (PortMappingNotFound) means that no mapping is in the range.
+pub fn parse_get_list_of_port_mappings_response(result: RequestResult) -> Result<Vec<PortMappingEntry>, RequestError> {
+    let response = match result {
+        Ok(response) => response,
+        Err(RequestError::ErrorCode { code: 730, .. }) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let invalid = || RequestError::InvalidResponse(response.text.clone());
+    let listing = response
+        .xml
+        .get_child("NewPortListing")
+        .ok_or_else(invalid)?
+        .get_text()
+        .unwrap_or_default();
+    if listing.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let list = parse_document(listing.as_bytes()).map_err(|_| invalid())?;
+    list.children
+        .iter()
+        .filter_map(|child| child.as_element())
+        .filter(|entry| entry.name == "PortMappingEntry")
+        .map(|entry| {
+            let text = |name| child_text(entry, name).unwrap_or_default();
+            let protocol = match text("NewProtocol").to_ascii_uppercase().as_str() {
+                "TCP" => PortMappingProtocol::TCP,
+                "UDP" => PortMappingProtocol::UDP,
+                _ => return Err(invalid()),
+            };
+            Ok(PortMappingEntry {
+                remote_host: text("NewRemoteHost"),
+                external_port: text("NewExternalPort").parse().map_err(|_| invalid())?,
+                protocol,
+                internal_port: text("NewInternalPort").parse().map_err(|_| invalid())?,
+                internal_client: text("NewInternalClient"),
+                enabled: matches!(text("NewEnabled").as_str(), "1" | "true"),
+                port_mapping_description: text("NewDescription"),
+                lease_duration: text("NewLeaseTime").parse().unwrap_or(0),
+            })
+        })
+        .collect()
+}
+
 pub fn parse_get_generic_port_mapping_entry(
     result: RequestResult,
 ) -> Result<PortMappingEntry, GetGenericPortMappingEntryError> {
@@ -402,6 +1242,60 @@ pub fn parse_get_generic_port_mapping_entry(
     })
 }
 
+pub fn parse_get_specific_port_mapping_entry(
+    result: RequestResult,
+    remote_host: RemoteHost,
+    protocol: PortMappingProtocol,
+    external_port: u16,
+) -> Result<PortMappingEntry, GetSpecificPortMappingEntryError> {
+    let response = result?;
+    let xml = response.xml;
+    let make_err = |msg: String| || GetSpecificPortMappingEntryError::RequestError(RequestError::InvalidResponse(msg));
+    let extract_field = |field: &str| {
+        xml.get_child(field)
+            .ok_or_else(make_err(format!("{} is missing", field)))
+    };
+    let internal_port = extract_field("NewInternalPort")?
+        .get_text()
+        .and_then(|t| t.parse::<u16>().ok())
+        .ok_or_else(make_err("Field NewInternalPort is invalid".into()))?;
+    let internal_client = extract_field("NewInternalClient")?
+        .get_text()
+        .map(|c| c.into_owned())
+        .ok_or_else(make_err("Field NewInternalClient is empty".into()))?;
+    let enabled = match extract_field("NewEnabled")?
+        .get_text()
+        .and_then(|t| t.parse::<u16>().ok())
+        .ok_or_else(make_err("Field Enabled is invalid".into()))?
+    {
+        0 => false,
+        1 => true,
+        _ => {
+            return Err(GetSpecificPortMappingEntryError::RequestError(
+                RequestError::InvalidResponse("Field NewEnabled is invalid".into()),
+            ))
+        }
+    };
+    let port_mapping_description = extract_field("NewPortMappingDescription")?
+        .get_text()
+        .map(|c| c.into_owned())
+        .unwrap_or_else(|| "".into());
+    let lease_duration = extract_field("NewLeaseDuration")?
+        .get_text()
+        .and_then(|t| t.parse::<u32>().ok())
+        .ok_or_else(make_err("Field NewLeaseDuration is invalid".into()))?;
+    Ok(PortMappingEntry {
+        remote_host: remote_host.to_string(),
+        external_port,
+        protocol,
+        internal_port,
+        internal_client,
+        enabled,
+        port_mapping_description,
+        lease_duration,
+    })
+}
+
 #[test]
 fn test_parse_search_result_case_insensitivity() {
     assert!(parse_search_result("location:http://0.0.0.0:0/control_url").is_ok());
@@ -411,14 +1305,80 @@ fn test_parse_search_result_case_insensitivity() {
 #[test]
 fn test_parse_search_result_ok() {
     let result = parse_search_result("location:http://0.0.0.0:0/control_url").unwrap();
-    assert_eq!(result.0.ip(), &Ipv4Addr::new(0, 0, 0, 0));
+    assert_eq!(result.0.ip(), Ipv4Addr::new(0, 0, 0, 0));
     assert_eq!(result.0.port(), 0);
     assert_eq!(&result.1[..], "/control_url");
+
+    let result = parse_search_result("location:http://[2001:db8::1]:5000/rootDesc.xml").unwrap();
+    assert_eq!(result.0, "[2001:db8::1]:5000".parse().unwrap());
+    assert_eq!(result.1, "/rootDesc.xml");
+}
+
+#[test]
+fn test_parse_discovery_info() {
+    let text = "HTTP/1.1 200 OK\r
+Cache-Control: max-age=120\r
+Location: http://192.168.1.1:5000/rootDesc.xml\r
+Server: OpenWRT/18.06 UPnP/1.1 MiniUPnPd/2.1\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+usn: uuid:fc4ec57e-b051-11db-88f8-0060085db3f6::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+\r
+";
+    assert_eq!(
+        parse_discovery_info(text),
+        DiscoveryInfo {
+            server: "OpenWRT/18.06 UPnP/1.1 MiniUPnPd/2.1".into(),
+            usn: "uuid:fc4ec57e-b051-11db-88f8-0060085db3f6::urn:schemas-upnp-org:device:InternetGatewayDevice:1"
+                .into(),
+            st: "urn:schemas-upnp-org:device:InternetGatewayDevice:1".into(),
+            max_age: Some(Duration::from_secs(120)),
+            local_ip: None,
+        }
+    );
+    assert_eq!(
+        parse_discovery_info("HTTP/1.1 200 OK\r\n\r\n"),
+        DiscoveryInfo::default()
+    );
+    assert_eq!(
+        parse_discovery_info("HTTP/1.1 200 OK\r\nCACHE-CONTROL: no-cache=\"Ext\", Max-Age = 1800\r\n\r\n").max_age,
+        Some(Duration::from_secs(1800))
+    );
+    assert_eq!(
+        parse_discovery_info("HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=forever\r\n\r\n").max_age,
+        None
+    );
 }
 
 #[test]
 fn test_parse_search_result_fail() {
     assert!(parse_search_result("content-type:http://0.0.0.0:0/control_url").is_err());
+    assert!(parse_search_result("location:http://gateway.local:5000/rootDesc.xml").is_err());
+}
+
+#[test]
+fn test_parse_control_url_ok() {
+    let addr = SocketAddr::from((Ipv4Addr::new(192, 168, 1, 1), 5000));
+    assert_eq!(parse_control_url(&addr, "/ctl/IPConn").unwrap(), "/ctl/IPConn");
+    assert_eq!(parse_control_url(&addr, "ctl/IPConn").unwrap(), "/ctl/IPConn");
+    assert_eq!(
+        parse_control_url(&addr, "http://192.168.1.1:5000/ctl/IPConn?x=1").unwrap(),
+        "/ctl/IPConn?x=1"
+    );
+
+    let addr = "[2001:db8::1]:5000".parse().unwrap();
+    assert_eq!(
+        parse_control_url(&addr, "http://[2001:db8::1]:5000/ctl/IPConn").unwrap(),
+        "/ctl/IPConn"
+    );
+    assert!(parse_control_url(&addr, "http://192.168.1.1:5000/ctl/IPConn").is_err());
+}
+
+#[test]
+fn test_parse_control_url_fail() {
+    let addr = SocketAddr::from((Ipv4Addr::new(192, 168, 1, 1), 5000));
+    assert!(parse_control_url(&addr, "").is_err());
+    assert!(parse_control_url(&addr, "http://10.0.0.1:5000/ctl/IPConn").is_err());
+    assert!(parse_control_url(&addr, "http://192.168.1.1:80/ctl/IPConn").is_err());
 }
 
 #[test]
@@ -500,9 +1460,10 @@ fn test_parse_device1() {
    </device>
 </root>"#;
 
-    let (control_schema_url, control_url) = parse_control_urls(text.as_bytes()).unwrap();
+    let (control_schema_url, control_url, service_type) = parse_control_urls(text.as_bytes()).unwrap();
     assert_eq!(control_url, "/ctl/IPConn");
     assert_eq!(control_schema_url, "/WANIPCn.xml");
+    assert_eq!(service_type, messages::SERVICE_TYPE);
 }
 
 #[test]
@@ -608,9 +1569,91 @@ fn test_parse_device2() {
     "#;
     let result = parse_control_urls(text.as_bytes());
     assert!(result.is_ok());
-    let (control_schema_url, control_url) = result.unwrap();
+    let (control_schema_url, control_url, service_type) = result.unwrap();
     assert_eq!(control_url, "/igdupnp/control/WANIPConn1");
+    assert_eq!(service_type, messages::SERVICE_TYPE);
     assert_eq!(control_schema_url, "/igdconnSCPD.xml");
+
+    let device_info = parse_device_info(text.as_bytes()).unwrap();
+    assert_eq!(device_info.friendly_name, "FRITZ!Box 7430");
+    assert_eq!(device_info.manufacturer, "AVM Berlin");
+    assert_eq!(device_info.model_name, "FRITZ!Box 7430");
+    assert_eq!(device_info.model_number, "avm");
+}
+
+#[test]
+fn test_parse_device_without_connection_service() {
+    // A router in bridge mode only keeps its configuration services.
+    let text = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+  <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+  <serviceList>
+    <service>
+      <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+      <serviceId>urn:upnp-org:serviceId:L3Forwarding1</serviceId>
+      <controlURL>/ctl/L3F</controlURL>
+      <eventSubURL>/evt/L3F</eventSubURL>
+      <SCPDURL>/L3F.xml</SCPDURL>
+    </service>
+  </serviceList>
+  <deviceList>
+    <device>
+      <deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+    </device>
+  </deviceList>
+</device>
+</root>"#;
+    assert!(matches!(
+        parse_control_urls(text.as_bytes()),
+        Err(SearchError::NoConnectionService)
+    ));
+    let text = r#"<?xml version="1.0"?><root xmlns="urn:schemas-upnp-org:device-1-0"></root>"#;
+    assert!(matches!(
+        parse_control_urls(text.as_bytes()),
+        Err(SearchError::InvalidResponse)
+    ));
+}
+
+#[test]
+fn test_parse_igd_v2_control_urls() {
+    let text = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+  <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:2</deviceType>
+  <deviceList>
+    <device>
+      <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:2</deviceType>
+      <serviceList>
+        <service>
+          <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+          <controlURL>/ctl/IPConn</controlURL>
+          <SCPDURL>/WANIPCn.xml</SCPDURL>
+        </service>
+        <service>
+          <serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>
+          <controlURL>/ctl/IPConn2</controlURL>
+          <SCPDURL>/WANIPCn2.xml</SCPDURL>
+        </service>
+      </serviceList>
+    </device>
+  </deviceList>
+</device>
+</root>"#;
+    assert_eq!(
+        parse_control_urls(text.as_bytes()).unwrap(),
+        (
+            "/WANIPCn.xml".to_string(),
+            "/ctl/IPConn".to_string(),
+            messages::SERVICE_TYPE
+        )
+    );
+    assert_eq!(
+        parse_igd_v2_control_urls(text.as_bytes()).unwrap(),
+        Some(("/WANIPCn2.xml".to_string(), "/ctl/IPConn2".to_string()))
+    );
+    let v1 = text.replace("WANIPConnection:2", "WANIPConnection:1");
+    assert_eq!(parse_igd_v2_control_urls(v1.as_bytes()).unwrap(), None);
 }
 
 #[test]
@@ -695,7 +1738,805 @@ fn test_parse_device3() {
 </device>
 </root>"#;
 
-    let (control_schema_url, control_url) = parse_control_urls(text.as_bytes()).unwrap();
+    let (control_schema_url, control_url, service_type) = parse_control_urls(text.as_bytes()).unwrap();
     assert_eq!(control_url, "/upnp/control/WANIPConn1");
     assert_eq!(control_schema_url, "/332b484d/wanipconnSCPD.xml");
+    assert_eq!(service_type, messages::SERVICE_TYPE);
+}
+
+#[test]
+fn test_parse_get_specific_port_mapping_entry() {
+    let text = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:GetSpecificPortMappingEntryResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+<NewInternalPort>8080</NewInternalPort>
+<NewInternalClient>192.168.1.10</NewInternalClient>
+<NewEnabled>1</NewEnabled>
+<NewPortMappingDescription>test</NewPortMappingDescription>
+<NewLeaseDuration>3600</NewLeaseDuration>
+</u:GetSpecificPortMappingEntryResponse>
+</s:Body>
+</s:Envelope>"#;
+    let result = parse_response(text.into(), "GetSpecificPortMappingEntryResponse");
+    let entry =
+        parse_get_specific_port_mapping_entry(result, RemoteHost::Wildcard, PortMappingProtocol::TCP, 80).unwrap();
+    assert_eq!(entry.external_port, 80);
+    assert_eq!(entry.protocol, PortMappingProtocol::TCP);
+    assert_eq!(entry.internal_port, 8080);
+    assert_eq!(entry.internal_client, "192.168.1.10");
+    assert!(entry.enabled);
+    assert_eq!(entry.port_mapping_description, "test");
+    assert_eq!(entry.lease_duration, 3600);
+}
+
+#[test]
+fn test_parse_get_specific_port_mapping_entry_missing() {
+    let text = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<s:Fault>
+<faultcode>s:Client</faultcode>
+<faultstring>UPnPError</faultstring>
+<detail>
+<UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+<errorCode>714</errorCode>
+<errorDescription>NoSuchEntryInArray</errorDescription>
+</UPnPError>
+</detail>
+</s:Fault>
+</s:Body>
+</s:Envelope>"#;
+    let result = parse_response(text.into(), "GetSpecificPortMappingEntryResponse");
+    assert!(matches!(
+        parse_get_specific_port_mapping_entry(result, RemoteHost::Wildcard, PortMappingProtocol::TCP, 80),
+        Err(GetSpecificPortMappingEntryError::NoSuchEntryInArray)
+    ));
+}
+
+#[test]
+fn test_parse_max_lease_duration() {
+    let text = r#"<?xml version="1.0"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+<actionList>
+<action>
+<name>AddPortMapping</name>
+<argumentList>
+<argument>
+<name>NewLeaseDuration</name>
+<direction>in</direction>
+<relatedStateVariable>PortMappingLeaseDuration</relatedStateVariable>
+</argument>
+</argumentList>
+</action>
+</actionList>
+<serviceStateTable>
+<stateVariable sendEvents="no">
+<name>PortMappingLeaseDuration</name>
+<dataType>ui4</dataType>
+<allowedValueRange>
+<minimum>0</minimum>
+<maximum>604800</maximum>
+</allowedValueRange>
+</stateVariable>
+</serviceStateTable>
+</scpd>"#;
+    assert_eq!(parse_max_lease_duration(text).unwrap(), Some(604800));
+
+    let text = r#"<?xml version="1.0"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+<serviceStateTable>
+<stateVariable sendEvents="no">
+<name>PortMappingLeaseDuration</name>
+<dataType>ui4</dataType>
+</stateVariable>
+</serviceStateTable>
+</scpd>"#;
+    assert_eq!(parse_max_lease_duration(text).unwrap(), None);
+}
+
+#[test]
+fn test_parse_response_not_soap() {
+    let login = r#"<!DOCTYPE html>
+<html><head><meta http-equiv="refresh" content="0; url=/login.htm"></head></html>"#;
+    assert!(matches!(
+        parse_response(login.into(), "GetExternalIPAddressResponse"),
+        Err(RequestError::NotSoap(_))
+    ));
+    let error = "\r\n<HTML><BODY>500 Internal Server Error</BODY></HTML>";
+    assert!(matches!(
+        parse_response(error.into(), "GetExternalIPAddressResponse"),
+        Err(RequestError::NotSoap(_))
+    ));
+    let xml = r#"<?xml version="1.0"?><error>Not Authorized</error>"#;
+    assert!(matches!(
+        parse_response(xml.into(), "GetExternalIPAddressResponse"),
+        Err(RequestError::NotSoap(_))
+    ));
+    // A SOAP envelope without a body is malformed.
+    let no_body = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Header/></s:Envelope>"#;
+    assert!(matches!(
+        parse_response(no_body.into(), "GetExternalIPAddressResponse"),
+        Err(RequestError::InvalidResponse(_))
+    ));
+    assert!(matches!(
+        parse_response("<s:Envelope><s:Body>".into(), "GetExternalIPAddressResponse"),
+        Err(RequestError::InvalidResponse(_))
+    ));
+
+    let prefixed = r#"<?xml version="1.0"?>
+<SOAP-ENV:Envelope xmlns:SOAP-ENV="http://schemas.xmlsoap.org/soap/envelope/">
+<SOAP-ENV:Body>
+<m:GetExternalIPAddressResponse xmlns:m="urn:schemas-upnp-org:service:WANIPConnection:1">
+<NewExternalIPAddress>203.0.113.1</NewExternalIPAddress>
+</m:GetExternalIPAddressResponse>
+</SOAP-ENV:Body>
+</SOAP-ENV:Envelope>"#;
+    let result = parse_response(prefixed.into(), "GetExternalIPAddressResponse");
+    assert_eq!(
+        parse_get_external_ip_response(result).unwrap(),
+        Ipv4Addr::new(203, 0, 113, 1)
+    );
+    let unprefixed = prefixed.replace("SOAP-ENV:", "").replace("xmlns:SOAP-ENV", "xmlns");
+    let result = parse_response(unprefixed, "GetExternalIPAddressResponse");
+    assert_eq!(
+        parse_get_external_ip_response(result).unwrap(),
+        Ipv4Addr::new(203, 0, 113, 1)
+    );
+}
+
+#[test]
+fn test_parse_http_response_status() {
+    let fault = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<s:Fault>
+<faultcode>s:Client</faultcode>
+<faultstring>UPnPError</faultstring>
+<detail>
+<UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+<errorCode>718</errorCode>
+<errorDescription>ConflictInMappingEntry</errorDescription>
+</UPnPError>
+</detail>
+</s:Fault>
+</s:Body>
+</s:Envelope>"#;
+    match parse_http_response(500, fault.into(), "AddPortMappingResponse") {
+        Err(RequestError::ErrorCode {
+            action,
+            code,
+            description,
+        }) => assert_eq!(
+            (&*action, code, &*description),
+            ("AddPortMapping", 718, "ConflictInMappingEntry")
+        ),
+        result => panic!("unexpected result: {:?}", result.err()),
+    }
+    assert!(matches!(
+        parse_http_response(404, "<html>Not Found</html>".into(), "AddPortMappingResponse"),
+        Err(RequestError::ControlUrlUnreachable(404))
+    ));
+    assert!(matches!(
+        parse_http_response(503, "".into(), "AddPortMappingResponse"),
+        Err(RequestError::Http(attohttpc::StatusCode::SERVICE_UNAVAILABLE))
+    ));
+    assert!(matches!(
+        parse_http_response(200, "".into(), "AddPortMappingResponse"),
+        Err(RequestError::InvalidResponse(_))
+    ));
+    assert!(matches!(
+        parse_http_response(403, "Forbidden".into(), "AddPortMappingResponse"),
+        Err(RequestError::Http(attohttpc::StatusCode::FORBIDDEN))
+    ));
+}
+
+#[test]
+fn test_parse_get_dns_servers_response() {
+    let text = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:GetDNSServersResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+<NewDNSServers>192.168.1.1, 8.8.8.8</NewDNSServers>
+</u:GetDNSServersResponse>
+</s:Body>
+</s:Envelope>"#;
+    let result = parse_response(text.into(), "GetDNSServersResponse");
+    assert_eq!(
+        parse_get_dns_servers_response(result).unwrap(),
+        vec![Ipv4Addr::new(192, 168, 1, 1), Ipv4Addr::new(8, 8, 8, 8)]
+    );
+
+    let text = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<s:Fault>
+<faultcode>s:Client</faultcode>
+<faultstring>UPnPError</faultstring>
+<detail>
+<UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+<errorCode>401</errorCode>
+<errorDescription>Invalid Action</errorDescription>
+</UPnPError>
+</detail>
+</s:Fault>
+</s:Body>
+</s:Envelope>"#;
+    let result = parse_response(text.into(), "GetDNSServersResponse");
+    assert!(matches!(
+        parse_get_dns_servers_response(result),
+        Err(GetDnsServersError::NotSupported)
+    ));
+}
+
+#[test]
+fn test_parse_service_control_url() {
+    let text = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+<deviceList>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+<deviceList>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANDSLLinkConfig:1</serviceType>
+<controlURL>/upnp/control/dsl</controlURL>
+</service>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<controlURL>/upnp/control/ip</controlURL>
+</service>
+</serviceList>
+</device>
+</deviceList>
+</device>
+</deviceList>
+</device>
+</root>"#;
+    let addr = SocketAddr::from((Ipv4Addr::new(192, 168, 1, 1), 49000));
+    assert_eq!(
+        parse_service_control_url(&addr, text, "urn:schemas-upnp-org:service:WANDSLLinkConfig:1").unwrap(),
+        Some("/upnp/control/dsl".to_string())
+    );
+    assert_eq!(
+        parse_service_control_url(&addr, text, "urn:schemas-upnp-org:service:Layer3Forwarding:1").unwrap(),
+        None
+    );
+}
+
+#[test]
+fn test_parse_dsl_link_info() {
+    let response = |action: &str, args: &str| {
+        let text = format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:{action}Response xmlns:u="urn:schemas-upnp-org:service:WANDSLLinkConfig:1">
+{args}
+</u:{action}Response>
+</s:Body>
+</s:Envelope>"#,
+            action = action,
+            args = args
+        );
+        parse_response(text, &format!("{}Response", action))
+    };
+    let info = parse_dsl_link_info(
+        response(
+            "GetDSLLinkInfo",
+            "<NewLinkType>PPPoE</NewLinkType><NewLinkStatus>Up</NewLinkStatus>",
+        ),
+        response("GetAutoConfig", "<NewAutoConfig>1</NewAutoConfig>"),
+        Err(RequestError::error_code(
+            "GetModulationType",
+            602,
+            "Optional Action Not Implemented",
+        )),
+    )
+    .unwrap();
+    assert_eq!(
+        info,
+        DslLinkInfo {
+            link_type: "PPPoE".into(),
+            link_status: "Up".into(),
+            auto_config: true,
+            modulation_type: None,
+        }
+    );
+}
+
+#[test]
+fn test_parse_active_connection() {
+    let description = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+<UDN>uuid:wan-device</UDN>
+<deviceList>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+<UDN>uuid:wan-connection-1</UDN>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+<controlURL>/upnp/control/ip1</controlURL>
+</service>
+</serviceList>
+</device>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+<UDN>uuid:wan-connection-2</UDN>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>
+<serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+<controlURL>/upnp/control/ppp2</controlURL>
+</service>
+</serviceList>
+</device>
+</deviceList>
+</device>
+</root>"#;
+    let response = |args: &str| {
+        let text = format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:GetActiveConnectionResponse xmlns:u="urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1">
+{}
+</u:GetActiveConnectionResponse>
+</s:Body>
+</s:Envelope>"#,
+            args
+        );
+        parse_response(text, "GetActiveConnectionResponse")
+    };
+    let addr = SocketAddr::from((Ipv4Addr::new(192, 168, 1, 1), 49000));
+
+    let connection = parse_active_connection(
+        &addr,
+        description,
+        response(
+            "<NewActiveConnDeviceContainer>uuid:wan-connection-2:WANConnectionDevice:1</NewActiveConnDeviceContainer>
+<NewActiveConnectionServiceID>uuid:wan-connection-2:WANConnectionDevice:1,urn:upnp-org:serviceId:WANIPConn1</NewActiveConnectionServiceID>",
+        ),
+    )
+    .unwrap();
+    assert_eq!(
+        connection,
+        Some(ActiveConnection {
+            device_container: "uuid:wan-connection-2:WANConnectionDevice:1".into(),
+            service_id: "uuid:wan-connection-2:WANConnectionDevice:1,urn:upnp-org:serviceId:WANIPConn1".into(),
+            service_type: "urn:schemas-upnp-org:service:WANPPPConnection:1".into(),
+            control_url: "/upnp/control/ppp2".into(),
+        })
+    );
+
+    let end = Err(RequestError::error_code(
+        "GetActiveConnection",
+        713,
+        "SpecifiedArrayIndexInvalid",
+    ));
+    assert_eq!(parse_active_connection(&addr, description, end).unwrap(), None);
+}
+
+#[test]
+fn test_parse_check_authorized_response() {
+    let text = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:GetExternalIPAddressResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+<NewExternalIPAddress></NewExternalIPAddress>
+</u:GetExternalIPAddressResponse>
+</s:Body>
+</s:Envelope>"#;
+    let response = parse_response(text.to_string(), "GetExternalIPAddressResponse");
+    assert!(parse_check_authorized_response(response).unwrap());
+    let unauthorized = Err(RequestError::error_code(
+        "GetExternalIPAddress",
+        606,
+        "Action not authorized",
+    ));
+    assert!(!parse_check_authorized_response(unauthorized).unwrap());
+    let other = Err(RequestError::error_code("GetExternalIPAddress", 501, "Action Failed"));
+    assert!(parse_check_authorized_response(other).is_err());
+}
+
+#[test]
+fn test_parse_counter() {
+    let text = |value: &str| {
+        format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:GetTotalPacketsSentResponse xmlns:u="urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1">
+<NewTotalPacketsSent>{}</NewTotalPacketsSent>
+</u:GetTotalPacketsSentResponse>
+</s:Body>
+</s:Envelope>"#,
+            value
+        )
+    };
+    let parse = |value: &str| {
+        parse_counter(
+            parse_response(text(value), "GetTotalPacketsSentResponse"),
+            "NewTotalPacketsSent",
+        )
+    };
+    assert_eq!(parse(" 4294967295 ").unwrap(), u32::MAX);
+    assert!(matches!(parse("-1"), Err(RequestError::InvalidResponse(_))));
+    assert!(matches!(
+        parse_counter(
+            parse_response(text("12"), "GetTotalPacketsSentResponse"),
+            "NewTotalPacketsReceived"
+        ),
+        Err(RequestError::InvalidResponse(_))
+    ));
+}
+
+#[test]
+fn test_parse_services() {
+    let text = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+  <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+  <serviceList>
+    <service>
+      <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+      <serviceId>urn:upnp-org:serviceId:L3Forwarding1</serviceId>
+      <controlURL>/ctl/L3F</controlURL>
+      <eventSubURL>/evt/L3F</eventSubURL>
+      <SCPDURL>/L3F.xml</SCPDURL>
+    </service>
+  </serviceList>
+  <deviceList>
+    <device>
+      <deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+      <deviceList>
+        <device>
+          <deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+          <serviceList>
+            <service>
+              <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+              <serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+              <controlURL>/ctl/IPConn</controlURL>
+              <eventSubURL>/evt/IPConn</eventSubURL>
+              <SCPDURL>/WANIPCn.xml</SCPDURL>
+            </service>
+          </serviceList>
+        </device>
+      </deviceList>
+    </device>
+  </deviceList>
+</device>
+</root>"#;
+    let services = parse_services(text).unwrap();
+    assert_eq!(
+        services,
+        vec![
+            ServiceInfo {
+                device_type: "urn:schemas-upnp-org:device:InternetGatewayDevice:1".into(),
+                service_type: "urn:schemas-upnp-org:service:Layer3Forwarding:1".into(),
+                service_id: "urn:upnp-org:serviceId:L3Forwarding1".into(),
+                control_url: "/ctl/L3F".into(),
+                event_sub_url: "/evt/L3F".into(),
+                scpd_url: "/L3F.xml".into(),
+            },
+            ServiceInfo {
+                device_type: "urn:schemas-upnp-org:device:WANConnectionDevice:1".into(),
+                service_type: "urn:schemas-upnp-org:service:WANIPConnection:1".into(),
+                service_id: "urn:upnp-org:serviceId:WANIPConn1".into(),
+                control_url: "/ctl/IPConn".into(),
+                event_sub_url: "/evt/IPConn".into(),
+                scpd_url: "/WANIPCn.xml".into(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_parse_get_external_ip_response() {
+    let text = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:GetExternalIPAddressResponse xmlns:u="urn:schemas-upnp-org:service:WANPPPConnection:1">
+<NewExternalIPAddress>203.0.113.1</NewExternalIPAddress>
+</u:GetExternalIPAddressResponse>
+</s:Body>
+</s:Envelope>"#;
+    let parse = |text: String| parse_get_external_ip_response(parse_response(text, "GetExternalIPAddressResponse"));
+    assert_eq!(parse(text.to_string()).unwrap(), Ipv4Addr::new(203, 0, 113, 1));
+
+    let empty = text.replace(
+        "<NewExternalIPAddress>203.0.113.1</NewExternalIPAddress>",
+        "<NewExternalIPAddress/>",
+    );
+    assert!(matches!(parse(empty), Err(GetExternalIpError::NotConnected)));
+    let unspecified = text.replace("203.0.113.1", "0.0.0.0");
+    assert!(matches!(parse(unspecified), Err(GetExternalIpError::NotConnected)));
+    let malformed = text.replace("203.0.113.1", "203.0.113");
+    assert!(matches!(
+        parse(malformed),
+        Err(GetExternalIpError::RequestError(RequestError::InvalidResponse(_)))
+    ));
+}
+
+#[test]
+fn test_parse_get_external_ipv6_response() {
+    let text = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:X_AVM_DE_GetExternalIPv6AddressResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">
+<NewExternalIPv6Address>2001:db8::7</NewExternalIPv6Address>
+<NewPrefixLength>64</NewPrefixLength>
+<NewValidLifetime>7200</NewValidLifetime>
+<NewPreferedLifetime>3600</NewPreferedLifetime>
+</u:X_AVM_DE_GetExternalIPv6AddressResponse>
+</s:Body>
+</s:Envelope>"#;
+    let result = parse_response(text.to_string(), "X_AVM_DE_GetExternalIPv6AddressResponse");
+    assert_eq!(
+        parse_get_external_ipv6_response(result).unwrap(),
+        "2001:db8::7".parse::<Ipv6Addr>().unwrap()
+    );
+
+    let text = text.replace("2001:db8::7", "");
+    let result = parse_response(text, "X_AVM_DE_GetExternalIPv6AddressResponse");
+    assert!(matches!(
+        parse_get_external_ipv6_response(result),
+        Err(GetExternalIpv6Error::NotSupported)
+    ));
+
+    let unknown = Err(RequestError::error_code(
+        "X_AVM_DE_GetExternalIPv6Address",
+        401,
+        "Invalid Action",
+    ));
+    assert!(matches!(
+        parse_get_external_ipv6_response(unknown),
+        Err(GetExternalIpv6Error::NotSupported)
+    ));
+}
+
+#[test]
+fn test_check_mapping_entry() {
+    let entry = |lease_duration: u32, description: &str| PortMappingEntry {
+        remote_host: String::new(),
+        external_port: 8080,
+        protocol: PortMappingProtocol::TCP,
+        internal_port: 8080,
+        internal_client: "192.168.1.2".into(),
+        enabled: true,
+        port_mapping_description: description.into(),
+        lease_duration,
+    };
+
+    assert_eq!(check_mapping_entry(&entry(3600, "game"), 3600, "game"), vec![]);
+    assert_eq!(check_mapping_entry(&entry(3598, "game"), 3600, "game"), vec![]);
+    assert_eq!(check_mapping_entry(&entry(0, "game"), 0, "game"), vec![]);
+    assert_eq!(
+        check_mapping_entry(&entry(1800, "gam"), 3600, "game"),
+        vec![
+            MappingWarning::LeaseClamped {
+                requested: 3600,
+                granted: 1800
+            },
+            MappingWarning::DescriptionTruncated { stored: "gam".into() },
+        ]
+    );
+    assert_eq!(
+        check_mapping_entry(&entry(604800, ""), 0, "game"),
+        vec![
+            MappingWarning::LeaseClamped {
+                requested: 0,
+                granted: 604800
+            },
+            MappingWarning::DescriptionChanged { stored: "".into() },
+        ]
+    );
+    let disabled = PortMappingEntry {
+        enabled: false,
+        ..entry(0, "game")
+    };
+    assert_eq!(
+        check_mapping_entry(&disabled, 0, "game"),
+        vec![MappingWarning::Disabled]
+    );
+}
+
+#[test]
+fn test_parse_remote_host() {
+    assert_eq!(parse_remote_host(""), Some(RemoteHost::Wildcard));
+    assert_eq!(
+        parse_remote_host("203.0.113.7"),
+        Some(RemoteHost::Specific(Ipv4Addr::new(203, 0, 113, 7)))
+    );
+    assert_eq!(parse_remote_host("peer.example.com"), None);
+}
+
+#[test]
+fn test_parse_query_state_variable_response() {
+    let text = r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:QueryStateVariableResponse xmlns:u="urn:schemas-upnp-org:control-1-0">
+<return>3</return>
+</u:QueryStateVariableResponse>
+</s:Body>
+</s:Envelope>"#;
+    let result = parse_response(text.to_string(), "QueryStateVariableResponse");
+    assert_eq!(parse_query_state_variable_response(result).unwrap(), "3");
+
+    let text = text.replace("<return>3</return>", "<return/>");
+    let result = parse_response(text, "QueryStateVariableResponse");
+    assert_eq!(parse_query_state_variable_response(result).unwrap(), "");
+
+    let unknown = Err(RequestError::error_code("QueryStateVariable", 404, "Invalid Var"));
+    assert!(parse_query_state_variable_response(unknown).is_err());
+}
+
+#[test]
+fn test_parse_response_non_standard_fault() {
+    let fault = |fault: &str| {
+        format!(
+            r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+<s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>{}</s:Fault></s:Body>
+</s:Envelope>"#,
+            fault
+        )
+    };
+    let error_of = |text: String| match parse_response(text, "AddPortMappingResponse") {
+        Err(RequestError::ErrorCode { code, description, .. }) => (code, description),
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("unexpected success"),
+    };
+
+    // A vendor element in place of UPnPError.
+    let vendor = fault(
+        r#"<detail><VendorError xmlns="urn:vendor-com:control-1-0"><errorCode>718</errorCode>
+<errorDescription>ConflictInMappingEntry</errorDescription></VendorError></detail>"#,
+    );
+    assert_eq!(error_of(vendor), (718, "ConflictInMappingEntry".to_string()));
+    // The code right under Fault, with whitespace and without description.
+    let flat = fault("<errorCode> 606 </errorCode>");
+    assert_eq!(error_of(flat), (606, String::new()));
+
+    let no_code = fault("<detail><UPnPError><errorDescription>Error</errorDescription></UPnPError></detail>");
+    assert!(matches!(
+        parse_response(no_code, "AddPortMappingResponse"),
+        Err(RequestError::InvalidResponse(_))
+    ));
+}
+
+#[test]
+fn test_parse_ppp_connection_service() {
+    let text = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+<deviceList>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+<deviceList>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>
+<serviceId>urn:upnp-org:serviceId:WANPPPConn1</serviceId>
+<controlURL>/upnp/control/WANPPPConn1</controlURL>
+<eventSubURL>/upnp/event/WANPPPConn1</eventSubURL>
+<SCPDURL>/wanpppconnSCPD.xml</SCPDURL>
+</service>
+</serviceList>
+</device>
+</deviceList>
+</device>
+</deviceList>
+</device>
+</root>"#;
+    assert_eq!(
+        parse_control_urls(text.as_bytes()).unwrap(),
+        (
+            "/wanpppconnSCPD.xml".to_string(),
+            "/upnp/control/WANPPPConn1".to_string(),
+            messages::WAN_PPP_CONNECTION
+        )
+    );
+}
+
+#[test]
+fn test_parse_notify() {
+    let alive = "NOTIFY * HTTP/1.1\r
+HOST: 239.255.255.250:1900\r
+CACHE-CONTROL: max-age=120\r
+LOCATION: http://192.168.1.1:5000/rootDesc.xml\r
+SERVER: Linux UPnP/1.1 MiniUPnPd/2.2\r
+NT: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+USN: uuid:bc6e5f3c::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+NTS: ssdp:alive\r\n\r\n";
+    match parse_notify(alive) {
+        Some(Announcement::Alive {
+            location,
+            discovery_info,
+        }) => {
+            assert_eq!(location, "http://192.168.1.1:5000/rootDesc.xml");
+            assert_eq!(discovery_info.st, "urn:schemas-upnp-org:device:InternetGatewayDevice:1");
+            assert_eq!(discovery_info.uuid(), Some("bc6e5f3c"));
+            assert_eq!(discovery_info.max_age, Some(Duration::from_secs(120)));
+        }
+        announcement => panic!("unexpected announcement: {:?}", announcement),
+    }
+
+    let byebye = "NOTIFY * HTTP/1.1\r
+NT: urn:schemas-upnp-org:device:InternetGatewayDevice:2\r
+USN: uuid:bc6e5f3c::urn:schemas-upnp-org:device:InternetGatewayDevice:2\r
+NTS: ssdp:byebye\r\n\r\n";
+    match parse_notify(byebye) {
+        Some(Announcement::ByeBye { discovery_info }) => assert_eq!(discovery_info.uuid(), Some("bc6e5f3c")),
+        announcement => panic!("unexpected announcement: {:?}", announcement),
+    }
+
+    // Services, search responses and locations without an address are not gateway announcements.
+    let service = alive.replace("device:InternetGatewayDevice:1", "service:WANIPConnection:1");
+    assert_eq!(parse_notify(&service), None);
+    let response = alive.replace("NOTIFY * HTTP/1.1", "HTTP/1.1 200 OK");
+    assert_eq!(parse_notify(&response), None);
+    let named = alive.replace("192.168.1.1", "gateway.local");
+    assert_eq!(parse_notify(&named), None);
+    let ipv6 = alive.replace("192.168.1.1", "[fe80::1]");
+    assert!(matches!(parse_notify(&ipv6), Some(Announcement::Alive { .. })));
+}
+
+#[test]
+fn test_parse_get_list_of_port_mappings_response() {
+    let listing = r#"<?xml version="1.0" encoding="UTF-8"?>
+<p:PortMappingList xmlns:p="urn:schemas-upnp-org:gw:WANIPConnection">
+<p:PortMappingEntry>
+<p:NewRemoteHost></p:NewRemoteHost>
+<p:NewExternalPort>8080</p:NewExternalPort>
+<p:NewProtocol>TCP</p:NewProtocol>
+<p:NewInternalPort>80</p:NewInternalPort>
+<p:NewInternalClient>192.168.1.2</p:NewInternalClient>
+<p:NewEnabled>1</p:NewEnabled>
+<p:NewDescription>web &amp; more</p:NewDescription>
+<p:NewLeaseTime>3600</p:NewLeaseTime>
+</p:PortMappingEntry>
+</p:PortMappingList>"#;
+    let text = format!(
+        r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body>
+<u:GetListOfPortMappingsResponse xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:2">
+<NewPortListing>{}</NewPortListing>
+</u:GetListOfPortMappingsResponse></s:Body></s:Envelope>"#,
+        crate::soap::escape(listing)
+    );
+    let entries =
+        parse_get_list_of_port_mappings_response(parse_response(text, "GetListOfPortMappingsResponse")).unwrap();
+    assert_eq!(
+        entries,
+        vec![PortMappingEntry {
+            remote_host: String::new(),
+            external_port: 8080,
+            protocol: PortMappingProtocol::TCP,
+            internal_port: 80,
+            internal_client: "192.168.1.2".to_string(),
+            enabled: true,
+            port_mapping_description: "web & more".to_string(),
+            lease_duration: 3600,
+        }]
+    );
+
+    let not_found = Err(RequestError::error_code(
+        "GetListOfPortMappings",
+        730,
+        "PortMappingNotFound",
+    ));
+    assert_eq!(parse_get_list_of_port_mappings_response(not_found).unwrap(), Vec::new());
 }
@@ -1,6 +1,7 @@
 use std::error;
 use std::fmt;
 use std::io;
+use std::net::SocketAddr;
 use std::str;
 #[cfg(feature = "aio")]
 use std::string::FromUtf8Error;
@@ -13,14 +14,37 @@ use tokio::time::error::Elapsed;
 pub enum RequestError {
     /// attohttp error
     AttoHttpError(attohttpc::Error),
-    /// IO Error
+    /// The connection to the gateway could not be established.
+    ///
+    /// This is usually transient, or means the gateway went away, and the request can be retried.
+    Connect(Box<dyn error::Error + Send + Sync>),
+    /// IO Error, while the connection was established
     IoError(io::Error),
+    /// The gateway answered with this HTTP error status instead of a SOAP response.
+    Http(attohttpc::StatusCode),
     /// The response from the gateway could not be parsed.
     InvalidResponse(String),
-    /// The gateway returned an unhandled error code and description.
-    ErrorCode(u16, String),
+    /// The response body was larger than this limit, see `GatewayOptions::max_response_size`.
+    ResponseTooLarge(usize),
+    /// The gateway returned an unhandled UPnP error code and description in response to an action.
+    ErrorCode {
+        /// Name of the action that failed, such as `AddPortMapping`
+        action: String,
+        /// The UPnP error code, such as 718 (ConflictInMappingEntry)
+        code: u16,
+        /// The description of the error given by the gateway
+        description: String,
+    },
     /// Action is not supported by the gateway
     UnsupportedAction(String),
+    /// The gateway answered with a document that is not SOAP, usually an HTML error or login page.
+    NotSoap(String),
+    /// The gateway requires HTTP authentication, and no or wrong credentials were given.
+    HttpUnauthorized,
+    /// The control url answered with an HTTP 404 instead of a SOAP response.
+    ///
+    /// The gateway probably changed, for example after a firmware update, and should be searched again.
+    ControlUrlUnreachable(u16),
     /// When using the aio feature.
     #[cfg(feature = "aio")]
     HyperError(hyper::Error),
@@ -34,15 +58,61 @@ pub enum RequestError {
     Utf8Error(FromUtf8Error),
 }
 
+impl RequestError {
+    pub(crate) fn error_code(action: &str, code: u16, description: &str) -> RequestError {
+        RequestError::ErrorCode {
+            action: action.to_string(),
+            code,
+            description: description.to_string(),
+        }
+    }
+
+    /// The HTTP status the gateway answered with instead of a SOAP response, if that is the error.
+    ///
+    /// This tells a gateway failing internally (500) from a wrong control url (404) or missing credentials (401),
+    /// whichever variant reports them. UPnP errors are reported in a SOAP response, so they have no status here
+    /// even though gateways send them with a 500.
+    pub fn http_status(&self) -> Option<u16> {
+        match *self {
+            RequestError::Http(status) => Some(status.as_u16()),
+            RequestError::ControlUrlUnreachable(status) => Some(status),
+            RequestError::HttpUnauthorized => Some(401),
+            _ => None,
+        }
+    }
+
+    /// Whether the request may succeed if it is sent again after a delay.
+    ///
+    /// Connection and IO errors, HTTP 5xx statuses, and UPnP error 501 (Action Failed) are transient. Other errors will fail again until something changes on either side.
+    pub fn is_retriable(&self) -> bool {
+        match *self {
+            RequestError::Connect(..) | RequestError::IoError(..) => true,
+            RequestError::Http(status) => status.is_server_error(),
+            RequestError::ErrorCode { code, .. } => code == 501,
+            #[cfg(feature = "aio")]
+            RequestError::HyperError(..) => true,
+            _ => false,
+        }
+    }
+}
+
 impl From<attohttpc::Error> for RequestError {
     fn from(err: attohttpc::Error) -> RequestError {
-        RequestError::AttoHttpError(err)
+        match err.into_kind() {
+            attohttpc::ErrorKind::Io(e) => RequestError::from(e),
+            kind => RequestError::AttoHttpError(kind.into()),
+        }
     }
 }
 
 impl From<io::Error> for RequestError {
     fn from(err: io::Error) -> RequestError {
-        RequestError::IoError(err)
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::AddrNotAvailable | io::ErrorKind::NotConnected => {
+                RequestError::Connect(Box::new(err))
+            }
+            _ => RequestError::IoError(err),
+        }
     }
 }
 
@@ -56,7 +126,11 @@ impl From<http::Error> for RequestError {
 #[cfg(feature = "aio")]
 impl From<hyper::Error> for RequestError {
     fn from(err: hyper::Error) -> RequestError {
-        RequestError::HyperError(err)
+        if err.is_connect() {
+            RequestError::Connect(Box::new(err))
+        } else {
+            RequestError::HyperError(err)
+        }
     }
 }
 
@@ -77,17 +151,27 @@ impl From<Elapsed> for RequestError {
 impl fmt::Display for RequestError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            RequestError::AttoHttpError(ref e) => write!(f, "HTTP error {}", e),
+            RequestError::AttoHttpError(..) => write!(f, "HTTP error"),
             RequestError::InvalidResponse(ref e) => write!(f, "Invalid response from gateway: {}", e),
-            RequestError::IoError(ref e) => write!(f, "IO error. {}", e),
-            RequestError::ErrorCode(n, ref e) => write!(f, "Gateway response error {}: {}", n, e),
+            RequestError::ResponseTooLarge(n) => write!(f, "Response from gateway is larger than {} bytes", n),
+            RequestError::Connect(..) => write!(f, "Could not connect to the gateway"),
+            RequestError::IoError(..) => write!(f, "IO error"),
+            RequestError::Http(status) => write!(f, "Gateway answered with HTTP {}", status),
+            RequestError::ErrorCode {
+                ref action,
+                code,
+                ref description,
+            } => write!(f, "Gateway response error {} to {}: {}", code, action, description),
             RequestError::UnsupportedAction(ref e) => write!(f, "Gateway does not support action: {}", e),
+            RequestError::NotSoap(ref e) => write!(f, "Gateway answered with a page that is not SOAP: {}", e),
+            RequestError::HttpUnauthorized => write!(f, "Gateway requires HTTP authentication"),
+            RequestError::ControlUrlUnreachable(n) => write!(f, "Gateway control url is unreachable: HTTP {}", n),
             #[cfg(feature = "aio")]
-            RequestError::HyperError(ref e) => write!(f, "Hyper Error: {}", e),
+            RequestError::HyperError(..) => write!(f, "Hyper error"),
             #[cfg(feature = "aio")]
-            RequestError::HttpError(ref e) => write!(f, "Http  Error: {}", e),
+            RequestError::HttpError(..) => write!(f, "HTTP error"),
             #[cfg(feature = "aio")]
-            RequestError::Utf8Error(ref e) => write!(f, "Utf8Error Error: {}", e),
+            RequestError::Utf8Error(..) => write!(f, "Response from gateway is not valid UTF-8"),
         }
     }
 }
@@ -97,9 +181,15 @@ impl std::error::Error for RequestError {
         match *self {
             RequestError::AttoHttpError(ref e) => Some(e),
             RequestError::InvalidResponse(..) => None,
+            RequestError::ResponseTooLarge(..) => None,
+            RequestError::Connect(ref e) => Some(&**e),
             RequestError::IoError(ref e) => Some(e),
-            RequestError::ErrorCode(..) => None,
+            RequestError::Http(..) => None,
+            RequestError::ErrorCode { .. } => None,
             RequestError::UnsupportedAction(..) => None,
+            RequestError::NotSoap(..) => None,
+            RequestError::HttpUnauthorized => None,
+            RequestError::ControlUrlUnreachable(..) => None,
             #[cfg(feature = "aio")]
             RequestError::HyperError(ref e) => Some(e),
             #[cfg(feature = "aio")]
@@ -115,6 +205,11 @@ impl std::error::Error for RequestError {
 pub enum GetExternalIpError {
     /// The client is not authorized to perform the operation.
     ActionNotAuthorized,
+    /// The gateway has no external IP address yet and reported an empty address or `0.0.0.0`, as a PPP link
+    /// does while it is still coming up. Try again later.
+    NotConnected,
+    /// The gateway did not connect before the timeout of `Gateway::wait_for_connection`.
+    Timeout,
     /// Some other error occured performing the request.
     RequestError(RequestError),
 }
@@ -137,6 +232,11 @@ pub enum AddAnyPortError {
     ActionNotAuthorized,
     /// Can not add a mapping for local port 0.
     InternalPortZeroInvalid,
+    /// The internal client is not an address of this host, see `GatewayOptions::only_local_clients`.
+    InternalClientNotLocal,
+    /// The socket to forward is bound to `0.0.0.0` or to an IPv6 address, so the internal client is ambiguous.
+    /// Bind it to the IPv4 address of an interface instead.
+    InternalClientUnspecified,
     /// The gateway does not have any free ports.
     NoPortsAvailable,
     /// The gateway can only map internal ports to same-numbered external ports
@@ -146,10 +246,39 @@ pub enum AddAnyPortError {
     OnlyPermanentLeasesSupported,
     /// The description was too long for the gateway to handle.
     DescriptionTooLong,
+    /// The description is empty, which `GatewayOptions::strict_descriptions` rejects.
+    DescriptionEmpty,
+    /// The description has characters other than printable ASCII, which `GatewayOptions::strict_descriptions`
+    /// rejects.
+    DescriptionNotAscii,
+    /// The `GatewayOptions::add_any_port_timeout` expired before a port could be mapped.
+    Timeout,
+    /// The gateway has no external IP address yet, see `GetExternalIpError::NotConnected`.
+    NotConnected,
+    /// The gateway rejected the arguments as inconsistent, such as an internal client other than the host
+    /// sending the request (IGDv2).
+    InconsistentParameters,
     /// Some other error occured performing the request.
     RequestError(RequestError),
 }
 
+impl AddAnyPortError {
+    /// Whether `add_any_port` may succeed if it is called again after a delay.
+    ///
+    /// It may once the gateway frees ports or the request error is gone, but not for invalid arguments or
+    /// when the gateway refuses the action.
+    pub fn is_retriable(&self) -> bool {
+        match *self {
+            AddAnyPortError::NoPortsAvailable
+            | AddAnyPortError::ExternalPortInUse
+            | AddAnyPortError::Timeout
+            | AddAnyPortError::NotConnected => true,
+            AddAnyPortError::RequestError(ref e) => e.is_retriable(),
+            _ => false,
+        }
+    }
+}
+
 impl From<RequestError> for AddAnyPortError {
     fn from(err: RequestError) -> AddAnyPortError {
         AddAnyPortError::RequestError(err)
@@ -160,11 +289,24 @@ impl From<GetExternalIpError> for AddAnyPortError {
     fn from(err: GetExternalIpError) -> AddAnyPortError {
         match err {
             GetExternalIpError::ActionNotAuthorized => AddAnyPortError::ActionNotAuthorized,
+            GetExternalIpError::NotConnected | GetExternalIpError::Timeout => AddAnyPortError::NotConnected,
             GetExternalIpError::RequestError(e) => AddAnyPortError::RequestError(e),
         }
     }
 }
 
+impl From<GetSpecificPortMappingEntryError> for AddAnyPortError {
+    fn from(err: GetSpecificPortMappingEntryError) -> AddAnyPortError {
+        match err {
+            GetSpecificPortMappingEntryError::ActionNotAuthorized => AddAnyPortError::ActionNotAuthorized,
+            GetSpecificPortMappingEntryError::NoSuchEntryInArray => AddAnyPortError::RequestError(
+                RequestError::error_code("GetSpecificPortMappingEntry", 714, "NoSuchEntryInArray"),
+            ),
+            GetSpecificPortMappingEntryError::RequestError(e) => AddAnyPortError::RequestError(e),
+        }
+    }
+}
+
 /// Errors returned by `Gateway::add_port`
 #[derive(Debug)]
 pub enum AddPortError {
@@ -172,6 +314,8 @@ pub enum AddPortError {
     ActionNotAuthorized,
     /// Can not add a mapping for local port 0.
     InternalPortZeroInvalid,
+    /// The internal client is not an address of this host, see `GatewayOptions::only_local_clients`.
+    InternalClientNotLocal,
     /// External port number 0 (any port) is considered invalid by the gateway.
     ExternalPortZeroInvalid,
     /// The requested mapping conflicts with a mapping assigned to another client.
@@ -180,17 +324,61 @@ pub enum AddPortError {
     SamePortValuesRequired,
     /// The gateway only supports permanent leases (ie. a `lease_duration` of 0).
     OnlyPermanentLeasesSupported,
+    /// A temporary mapping was requested with a `lease_duration` of 0, which would make it permanent.
+    LeaseDurationZeroInvalid,
     /// The description was too long for the gateway to handle.
     DescriptionTooLong,
+    /// The description is empty, which `GatewayOptions::strict_descriptions` rejects.
+    DescriptionEmpty,
+    /// The description has characters other than printable ASCII, which `GatewayOptions::strict_descriptions`
+    /// rejects.
+    DescriptionNotAscii,
+    /// The gateway only supports the wildcard remote host with a specific external port.
+    RemoteHostOnlySupportsWildcard,
+    /// The port is reserved by another mechanism of the gateway, such as one of its own services (IGDv2).
+    ConflictWithOtherMechanisms,
+    /// The gateway rejected the arguments as inconsistent, such as an internal client other than the host
+    /// sending the request (IGDv2).
+    InconsistentParameters,
     /// Some other error occured performing the request.
     RequestError(RequestError),
 }
 
+impl AddPortError {
+    /// Whether `add_port` may succeed if it is called again after a delay.
+    ///
+    /// It may once the other client's mapping of the port expires or the request error is gone, but not for
+    /// invalid arguments or when the gateway refuses the action.
+    pub fn is_retriable(&self) -> bool {
+        match *self {
+            AddPortError::PortInUse => true,
+            AddPortError::RequestError(ref e) => e.is_retriable(),
+            _ => false,
+        }
+    }
+}
+
+impl From<GetSpecificPortMappingEntryError> for AddPortError {
+    fn from(err: GetSpecificPortMappingEntryError) -> AddPortError {
+        match err {
+            GetSpecificPortMappingEntryError::ActionNotAuthorized => AddPortError::ActionNotAuthorized,
+            GetSpecificPortMappingEntryError::NoSuchEntryInArray => AddPortError::RequestError(
+                RequestError::error_code("GetSpecificPortMappingEntry", 714, "NoSuchEntryInArray"),
+            ),
+            GetSpecificPortMappingEntryError::RequestError(e) => AddPortError::RequestError(e),
+        }
+    }
+}
+
 impl fmt::Display for GetExternalIpError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            GetExternalIpError::ActionNotAuthorized => write!(f, "The client is not authorized to remove the port"),
-            GetExternalIpError::RequestError(ref e) => write!(f, "Request Error. {}", e),
+            GetExternalIpError::ActionNotAuthorized => {
+                write!(f, "The client is not authorized to get the external IP address")
+            }
+            GetExternalIpError::NotConnected => write!(f, "The gateway has no external IP address yet"),
+            GetExternalIpError::Timeout => write!(f, "The gateway did not connect before the timeout"),
+            GetExternalIpError::RequestError(ref e) => e.fmt(f),
         }
     }
 }
@@ -203,7 +391,30 @@ impl From<io::Error> for GetExternalIpError {
 
 impl std::error::Error for GetExternalIpError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match *self {
+            GetExternalIpError::RequestError(ref e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl RemovePortError {
+    /// Whether `remove_port` may succeed if it is called again after a delay, only for a transient request error.
+    pub fn is_retriable(&self) -> bool {
+        match *self {
+            RemovePortError::RequestError(ref e) => e.is_retriable(),
+            _ => false,
+        }
+    }
+}
+
+impl From<GetSpecificPortMappingEntryError> for RemovePortError {
+    fn from(err: GetSpecificPortMappingEntryError) -> RemovePortError {
+        match err {
+            GetSpecificPortMappingEntryError::ActionNotAuthorized => RemovePortError::ActionNotAuthorized,
+            GetSpecificPortMappingEntryError::NoSuchEntryInArray => RemovePortError::NoSuchPortMapping,
+            GetSpecificPortMappingEntryError::RequestError(e) => RemovePortError::RequestError(e),
+        }
     }
 }
 
@@ -212,14 +423,17 @@ impl fmt::Display for RemovePortError {
         match *self {
             RemovePortError::ActionNotAuthorized => write!(f, "The client is not authorized to remove the port"),
             RemovePortError::NoSuchPortMapping => write!(f, "The port was not mapped"),
-            RemovePortError::RequestError(ref e) => write!(f, "Request error. {}", e),
+            RemovePortError::RequestError(ref e) => e.fmt(f),
         }
     }
 }
 
 impl std::error::Error for RemovePortError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match *self {
+            RemovePortError::RequestError(ref e) => e.source(),
+            _ => None,
+        }
     }
 }
 
@@ -227,11 +441,17 @@ impl fmt::Display for AddAnyPortError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             AddAnyPortError::ActionNotAuthorized => {
-                write!(f, "The client is not authorized to remove the port")
+                write!(f, "The client is not authorized to map a port")
             }
             AddAnyPortError::InternalPortZeroInvalid => {
                 write!(f, "Can not add a mapping for local port 0")
             }
+            AddAnyPortError::InternalClientNotLocal => {
+                write!(f, "The internal client is not an address of this host")
+            }
+            AddAnyPortError::InternalClientUnspecified => {
+                write!(f, "The socket must be bound to a specific IPv4 address")
+            }
             AddAnyPortError::NoPortsAvailable => {
                 write!(f, "The gateway does not have any free ports")
             }
@@ -250,14 +470,26 @@ impl fmt::Display for AddAnyPortError {
             AddAnyPortError::DescriptionTooLong => {
                 write!(f, "The description was too long for the gateway to handle.")
             }
-            AddAnyPortError::RequestError(ref e) => write!(f, "Request error. {}", e),
+            AddAnyPortError::DescriptionEmpty => write!(f, "The description is empty."),
+            AddAnyPortError::DescriptionNotAscii => {
+                write!(f, "The description has characters other than printable ASCII.")
+            }
+            AddAnyPortError::Timeout => {
+                write!(f, "The gateway could not map a port in time")
+            }
+            AddAnyPortError::NotConnected => write!(f, "The gateway has no external IP address yet"),
+            AddAnyPortError::InconsistentParameters => write!(f, "The gateway rejected the arguments as inconsistent."),
+            AddAnyPortError::RequestError(ref e) => e.fmt(f),
         }
     }
 }
 
 impl std::error::Error for AddAnyPortError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match *self {
+            AddAnyPortError::RequestError(ref e) => e.source(),
+            _ => None,
+        }
     }
 }
 
@@ -266,6 +498,7 @@ impl fmt::Display for AddPortError {
         match *self {
             AddPortError::ActionNotAuthorized => write!(f, "The client is not authorized to map this port."),
             AddPortError::InternalPortZeroInvalid => write!(f, "Can not add a mapping for local port 0"),
+            AddPortError::InternalClientNotLocal => write!(f, "The internal client is not an address of this host"),
             AddPortError::ExternalPortZeroInvalid => write!(
                 f,
                 "External port number 0 (any port) is considered invalid by the gateway."
@@ -282,15 +515,32 @@ impl fmt::Display for AddPortError {
                 f,
                 "The gateway only supports permanent leases (ie. a `lease_duration` of 0),"
             ),
+            AddPortError::LeaseDurationZeroInvalid => {
+                write!(f, "A temporary mapping can not have a `lease_duration` of 0.")
+            }
             AddPortError::DescriptionTooLong => write!(f, "The description was too long for the gateway to handle."),
-            AddPortError::RequestError(ref e) => write!(f, "Request error. {}", e),
+            AddPortError::DescriptionEmpty => write!(f, "The description is empty."),
+            AddPortError::DescriptionNotAscii => {
+                write!(f, "The description has characters other than printable ASCII.")
+            }
+            AddPortError::RemoteHostOnlySupportsWildcard => {
+                write!(f, "The gateway only supports the wildcard remote host for this port.")
+            }
+            AddPortError::ConflictWithOtherMechanisms => {
+                write!(f, "The port is reserved by another mechanism of the gateway.")
+            }
+            AddPortError::InconsistentParameters => write!(f, "The gateway rejected the arguments as inconsistent."),
+            AddPortError::RequestError(ref e) => e.fmt(f),
         }
     }
 }
 
 impl std::error::Error for AddPortError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        match *self {
+            AddPortError::RequestError(ref e) => e.source(),
+            _ => None,
+        }
     }
 }
 
@@ -301,12 +551,30 @@ pub enum SearchError {
     HttpError(attohttpc::Error),
     /// Unable to process the response
     InvalidResponse,
+    /// The description of the gateway was larger than this limit, see `GatewayOptions::max_response_size`.
+    ResponseTooLarge(usize),
+    /// The gateway requires HTTP authentication for its description, and no or wrong credentials were given, see
+    /// `GatewayOptions::credentials`.
+    HttpUnauthorized,
+    /// The gateway answered the request for its description or control schema with this HTTP error status.
+    HttpStatus(u16),
     /// IO Error
     IoError(io::Error),
     /// UTF-8 decoding error
     Utf8Error(str::Utf8Error),
     /// XML processing error
     XmlError(xmltree::ParseError),
+    /// The control url is not a valid path on the gateway
+    InvalidControlUrl(String),
+    /// The search address is not a multicast group or a loopback address, see `SearchOptions::broadcast_address`
+    InvalidBroadcastAddress(SocketAddr),
+    /// The search socket could not be bound to `SearchOptions::bind_addr`, as when its port is already in use
+    BindFailed(SocketAddr, io::Error),
+    /// The device has no `WANIPConnection` or `WANPPPConnection` service to map ports with, as when the router is
+    /// in bridge mode
+    ///
+    /// `search_gateway` returns it when the search times out after only finding such devices.
+    NoConnectionService,
     /// When using the aio feature.
     #[cfg(feature = "aio")]
     HyperError(hyper::Error),
@@ -362,15 +630,25 @@ impl From<Elapsed> for SearchError {
 impl fmt::Display for SearchError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            SearchError::HttpError(ref e) => write!(f, "HTTP error {}", e),
+            SearchError::HttpError(..) => write!(f, "HTTP error"),
             SearchError::InvalidResponse => write!(f, "Invalid response"),
-            SearchError::IoError(ref e) => write!(f, "IO error: {}", e),
-            SearchError::Utf8Error(ref e) => write!(f, "UTF-8 error: {}", e),
-            SearchError::XmlError(ref e) => write!(f, "XML error: {}", e),
+            SearchError::ResponseTooLarge(n) => write!(f, "Response is larger than {} bytes", n),
+            SearchError::HttpUnauthorized => write!(f, "The gateway requires HTTP authentication"),
+            SearchError::HttpStatus(status) => write!(f, "Gateway answered with HTTP {}", status),
+            SearchError::IoError(..) => write!(f, "IO error"),
+            SearchError::Utf8Error(..) => write!(f, "Response is not valid UTF-8"),
+            SearchError::XmlError(..) => write!(f, "XML error"),
+            SearchError::InvalidControlUrl(ref e) => write!(f, "Invalid control url: {}", e),
+            SearchError::InvalidBroadcastAddress(addr) => write!(f, "Invalid search address: {}", addr),
+            SearchError::BindFailed(addr, _) => write!(f, "Could not bind the search socket to {}", addr),
+            SearchError::NoConnectionService => write!(
+                f,
+                "The device has no connection service to map ports, the router may be in bridge mode"
+            ),
             #[cfg(feature = "aio")]
-            SearchError::HyperError(ref e) => write!(f, "Hyper Error: {}", e),
+            SearchError::HyperError(..) => write!(f, "Hyper error"),
             #[cfg(feature = "aio")]
-            SearchError::InvalidUri(ref e) => write!(f, "InvalidUri Error: {}", e),
+            SearchError::InvalidUri(..) => write!(f, "Invalid URI"),
         }
     }
 }
@@ -380,9 +658,16 @@ impl error::Error for SearchError {
         match *self {
             SearchError::HttpError(ref e) => Some(e),
             SearchError::InvalidResponse => None,
+            SearchError::ResponseTooLarge(..) => None,
+            SearchError::HttpUnauthorized => None,
+            SearchError::HttpStatus(..) => None,
             SearchError::IoError(ref e) => Some(e),
             SearchError::Utf8Error(ref e) => Some(e),
             SearchError::XmlError(ref e) => Some(e),
+            SearchError::InvalidControlUrl(..) => None,
+            SearchError::InvalidBroadcastAddress(..) => None,
+            SearchError::BindFailed(_, ref e) => Some(e),
+            SearchError::NoConnectionService => None,
             #[cfg(feature = "aio")]
             SearchError::HyperError(ref e) => Some(e),
             #[cfg(feature = "aio")]
@@ -405,15 +690,56 @@ pub enum GetGenericPortMappingEntryError {
 impl From<RequestError> for GetGenericPortMappingEntryError {
     fn from(err: RequestError) -> GetGenericPortMappingEntryError {
         match err {
-            RequestError::ErrorCode(code, _) if code == 606 => GetGenericPortMappingEntryError::ActionNotAuthorized,
-            RequestError::ErrorCode(code, _) if code == 713 => {
-                GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid
-            }
+            RequestError::ErrorCode { code: 606, .. } => GetGenericPortMappingEntryError::ActionNotAuthorized,
+            RequestError::ErrorCode { code: 713, .. } => GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid,
             other => GetGenericPortMappingEntryError::RequestError(other),
         }
     }
 }
 
+// Restore the error code reported by the gateway, for operations returning a plain `RequestError`.
+impl From<GetGenericPortMappingEntryError> for RequestError {
+    fn from(err: GetGenericPortMappingEntryError) -> RequestError {
+        match err {
+            GetGenericPortMappingEntryError::ActionNotAuthorized => {
+                RequestError::error_code("GetGenericPortMappingEntry", 606, "ActionNotAuthorized")
+            }
+            GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid => {
+                RequestError::error_code("GetGenericPortMappingEntry", 713, "SpecifiedArrayIndexInvalid")
+            }
+            GetGenericPortMappingEntryError::RequestError(e) => e,
+        }
+    }
+}
+
+impl From<GetSpecificPortMappingEntryError> for RequestError {
+    fn from(err: GetSpecificPortMappingEntryError) -> RequestError {
+        match err {
+            GetSpecificPortMappingEntryError::ActionNotAuthorized => {
+                RequestError::error_code("GetSpecificPortMappingEntry", 606, "ActionNotAuthorized")
+            }
+            GetSpecificPortMappingEntryError::NoSuchEntryInArray => {
+                RequestError::error_code("GetSpecificPortMappingEntry", 714, "NoSuchEntryInArray")
+            }
+            GetSpecificPortMappingEntryError::RequestError(e) => e,
+        }
+    }
+}
+
+impl From<RemovePortError> for RequestError {
+    fn from(err: RemovePortError) -> RequestError {
+        match err {
+            RemovePortError::ActionNotAuthorized => {
+                RequestError::error_code("DeletePortMapping", 606, "ActionNotAuthorized")
+            }
+            RemovePortError::NoSuchPortMapping => {
+                RequestError::error_code("DeletePortMapping", 714, "NoSuchEntryInArray")
+            }
+            RemovePortError::RequestError(e) => e,
+        }
+    }
+}
+
 impl fmt::Display for GetGenericPortMappingEntryError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -428,7 +754,194 @@ impl fmt::Display for GetGenericPortMappingEntryError {
     }
 }
 
-impl std::error::Error for GetGenericPortMappingEntryError {}
+impl std::error::Error for GetGenericPortMappingEntryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            GetGenericPortMappingEntryError::RequestError(ref e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Errors than can occur while getting a port mapping by its protocol and external port
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum GetSpecificPortMappingEntryError {
+    /// The client is not authorized to perform the operation.
+    ActionNotAuthorized,
+    /// There is no port mapping for this protocol and external port.
+    NoSuchEntryInArray,
+    /// Some other error occured performing the request.
+    RequestError(RequestError),
+}
+
+impl From<RequestError> for GetSpecificPortMappingEntryError {
+    fn from(err: RequestError) -> GetSpecificPortMappingEntryError {
+        match err {
+            RequestError::ErrorCode { code: 606, .. } => GetSpecificPortMappingEntryError::ActionNotAuthorized,
+            RequestError::ErrorCode { code: 714, .. } => GetSpecificPortMappingEntryError::NoSuchEntryInArray,
+            other => GetSpecificPortMappingEntryError::RequestError(other),
+        }
+    }
+}
+
+impl fmt::Display for GetSpecificPortMappingEntryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GetSpecificPortMappingEntryError::ActionNotAuthorized => {
+                write!(f, "The client is not authorized to look up port mappings.")
+            }
+            GetSpecificPortMappingEntryError::NoSuchEntryInArray => write!(f, "The port mapping does not exist."),
+            GetSpecificPortMappingEntryError::RequestError(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for GetSpecificPortMappingEntryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            GetSpecificPortMappingEntryError::RequestError(ref e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Errors returned by `Gateway::get_dns_servers`
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum GetDnsServersError {
+    /// The gateway does not implement the `GetDNSServers` action.
+    NotSupported,
+    /// Some other error occured performing the request.
+    RequestError(RequestError),
+}
+
+impl From<RequestError> for GetDnsServersError {
+    fn from(err: RequestError) -> GetDnsServersError {
+        match err {
+            RequestError::ErrorCode { code: 401, .. } | RequestError::ErrorCode { code: 602, .. } => {
+                GetDnsServersError::NotSupported
+            }
+            other => GetDnsServersError::RequestError(other),
+        }
+    }
+}
+
+impl fmt::Display for GetDnsServersError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GetDnsServersError::NotSupported => write!(f, "The gateway does not report its DNS servers."),
+            GetDnsServersError::RequestError(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for GetDnsServersError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            GetDnsServersError::RequestError(ref e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Errors returned by `Gateway::get_external_ipv6`
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum GetExternalIpv6Error {
+    /// The gateway does not report an external IPv6 address, or has no IPv6 connection.
+    NotSupported,
+    /// The client is not authorized to perform the operation.
+    ActionNotAuthorized,
+    /// Some other error occured performing the request.
+    RequestError(RequestError),
+}
+
+impl From<RequestError> for GetExternalIpv6Error {
+    fn from(err: RequestError) -> GetExternalIpv6Error {
+        match err {
+            RequestError::ErrorCode { code: 401, .. } | RequestError::ErrorCode { code: 602, .. } => {
+                GetExternalIpv6Error::NotSupported
+            }
+            RequestError::ErrorCode { code: 606, .. } => GetExternalIpv6Error::ActionNotAuthorized,
+            other => GetExternalIpv6Error::RequestError(other),
+        }
+    }
+}
+
+impl fmt::Display for GetExternalIpv6Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GetExternalIpv6Error::NotSupported => write!(f, "The gateway does not report an external IPv6 address."),
+            GetExternalIpv6Error::ActionNotAuthorized => {
+                write!(f, "The client is not authorized to get the external IPv6 address")
+            }
+            GetExternalIpv6Error::RequestError(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for GetExternalIpv6Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            GetExternalIpv6Error::RequestError(ref e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Errors returned by `Gateway::renew_port`
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RenewPortError {
+    /// The client is not authorized to perform the operation.
+    ActionNotAuthorized,
+    /// The port mapping does not exist, the gateway may have dropped it.
+    NoSuchPortMapping,
+    /// The gateway only supports permanent leases (ie. a `lease_duration` of 0).
+    OnlyPermanentLeasesSupported,
+    /// Some other error occured performing the request.
+    RequestError(RequestError),
+}
+
+impl From<RequestError> for RenewPortError {
+    fn from(err: RequestError) -> RenewPortError {
+        RenewPortError::RequestError(err)
+    }
+}
+
+impl From<GetSpecificPortMappingEntryError> for RenewPortError {
+    fn from(err: GetSpecificPortMappingEntryError) -> RenewPortError {
+        match err {
+            GetSpecificPortMappingEntryError::ActionNotAuthorized => RenewPortError::ActionNotAuthorized,
+            GetSpecificPortMappingEntryError::NoSuchEntryInArray => RenewPortError::NoSuchPortMapping,
+            GetSpecificPortMappingEntryError::RequestError(e) => RenewPortError::RequestError(e),
+        }
+    }
+}
+
+impl fmt::Display for RenewPortError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RenewPortError::ActionNotAuthorized => write!(f, "The client is not authorized to renew the port."),
+            RenewPortError::NoSuchPortMapping => write!(f, "The port was not mapped"),
+            RenewPortError::OnlyPermanentLeasesSupported => write!(
+                f,
+                "The gateway only supports permanent leases (ie. a `lease_duration` of 0),"
+            ),
+            RenewPortError::RequestError(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RenewPortError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            RenewPortError::RequestError(ref e) => e.source(),
+            _ => None,
+        }
+    }
+}
 
 /// An error type that emcompasses all possible errors.
 #[derive(Debug)]
@@ -437,10 +950,20 @@ pub enum Error {
     AddAnyPortError(AddAnyPortError),
     /// `AddPortError`
     AddPortError(AddPortError),
+    /// `GetDnsServersError`
+    GetDnsServersError(GetDnsServersError),
     /// `GetExternalIpError`
     GetExternalIpError(GetExternalIpError),
+    /// `GetExternalIpv6Error`
+    GetExternalIpv6Error(GetExternalIpv6Error),
+    /// `GetGenericPortMappingEntryError`
+    GetGenericPortMappingEntryError(GetGenericPortMappingEntryError),
+    /// `GetSpecificPortMappingEntryError`
+    GetSpecificPortMappingEntryError(GetSpecificPortMappingEntryError),
     /// `RemovePortError`
     RemovePortError(RemovePortError),
+    /// `RenewPortError`
+    RenewPortError(RenewPortError),
     /// `RequestError`
     RequestError(RequestError),
     /// `SearchError`
@@ -455,8 +978,13 @@ impl fmt::Display for Error {
         match *self {
             Error::AddAnyPortError(ref e) => e.fmt(f),
             Error::AddPortError(ref e) => e.fmt(f),
+            Error::GetDnsServersError(ref e) => e.fmt(f),
             Error::GetExternalIpError(ref e) => e.fmt(f),
+            Error::GetExternalIpv6Error(ref e) => e.fmt(f),
+            Error::GetGenericPortMappingEntryError(ref e) => e.fmt(f),
+            Error::GetSpecificPortMappingEntryError(ref e) => e.fmt(f),
             Error::RemovePortError(ref e) => e.fmt(f),
+            Error::RenewPortError(ref e) => e.fmt(f),
             Error::RequestError(ref e) => e.fmt(f),
             Error::SearchError(ref e) => e.fmt(f),
         }
@@ -466,12 +994,17 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
-            Error::AddAnyPortError(ref e) => Some(e),
-            Error::AddPortError(ref e) => Some(e),
-            Error::GetExternalIpError(ref e) => Some(e),
-            Error::RemovePortError(ref e) => Some(e),
-            Error::RequestError(ref e) => Some(e),
-            Error::SearchError(ref e) => Some(e),
+            Error::AddAnyPortError(ref e) => e.source(),
+            Error::AddPortError(ref e) => e.source(),
+            Error::GetDnsServersError(ref e) => e.source(),
+            Error::GetExternalIpError(ref e) => e.source(),
+            Error::GetExternalIpv6Error(ref e) => e.source(),
+            Error::GetGenericPortMappingEntryError(ref e) => e.source(),
+            Error::GetSpecificPortMappingEntryError(ref e) => e.source(),
+            Error::RemovePortError(ref e) => e.source(),
+            Error::RenewPortError(ref e) => e.source(),
+            Error::RequestError(ref e) => e.source(),
+            Error::SearchError(ref e) => e.source(),
         }
     }
 }
@@ -488,18 +1021,48 @@ impl From<AddPortError> for Error {
     }
 }
 
+impl From<GetDnsServersError> for Error {
+    fn from(err: GetDnsServersError) -> Error {
+        Error::GetDnsServersError(err)
+    }
+}
+
 impl From<GetExternalIpError> for Error {
     fn from(err: GetExternalIpError) -> Error {
         Error::GetExternalIpError(err)
     }
 }
 
+impl From<GetExternalIpv6Error> for Error {
+    fn from(err: GetExternalIpv6Error) -> Error {
+        Error::GetExternalIpv6Error(err)
+    }
+}
+
+impl From<GetGenericPortMappingEntryError> for Error {
+    fn from(err: GetGenericPortMappingEntryError) -> Error {
+        Error::GetGenericPortMappingEntryError(err)
+    }
+}
+
+impl From<GetSpecificPortMappingEntryError> for Error {
+    fn from(err: GetSpecificPortMappingEntryError) -> Error {
+        Error::GetSpecificPortMappingEntryError(err)
+    }
+}
+
 impl From<RemovePortError> for Error {
     fn from(err: RemovePortError) -> Error {
         Error::RemovePortError(err)
     }
 }
 
+impl From<RenewPortError> for Error {
+    fn from(err: RenewPortError) -> Error {
+        Error::RenewPortError(err)
+    }
+}
+
 impl From<RequestError> for Error {
     fn from(err: RequestError) -> Error {
         Error::RequestError(err)
@@ -511,3 +1074,57 @@ impl From<SearchError> for Error {
         Error::SearchError(err)
     }
 }
+
+#[test]
+fn test_error_source_chain() {
+    use std::error::Error as _;
+
+    let err = AddPortError::RequestError(RequestError::IoError(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "timeout",
+    )));
+    // The wrappers of `RequestError` display it as is, so their source is the source of the request error.
+    assert_eq!(err.to_string(), "IO error");
+    let io = err.source().unwrap();
+    assert_eq!(io.to_string(), "timeout");
+    assert!(io.source().is_none());
+    assert!(AddPortError::PortInUse.source().is_none());
+
+    let err = Error::from(GetGenericPortMappingEntryError::RequestError(RequestError::error_code(
+        "GetGenericPortMappingEntry",
+        501,
+        "Action Failed",
+    )));
+    assert_eq!(
+        err.to_string(),
+        "Gateway response error 501 to GetGenericPortMappingEntry: Action Failed"
+    );
+    assert!(err.source().is_none());
+    // Errors converted back to a code are described with the UPnP name of the error.
+    assert_eq!(
+        RequestError::from(RemovePortError::NoSuchPortMapping).to_string(),
+        "Gateway response error 714 to DeletePortMapping: NoSuchEntryInArray"
+    );
+}
+
+#[test]
+fn test_is_retriable() {
+    let timeout = || RequestError::from(io::Error::new(io::ErrorKind::TimedOut, "timeout"));
+    assert!(timeout().is_retriable());
+    assert!(RequestError::from(io::Error::from(io::ErrorKind::ConnectionRefused)).is_retriable());
+    assert!(RequestError::Http(attohttpc::StatusCode::SERVICE_UNAVAILABLE).is_retriable());
+    assert!(!RequestError::Http(attohttpc::StatusCode::BAD_REQUEST).is_retriable());
+    assert!(!RequestError::ControlUrlUnreachable(404).is_retriable());
+    assert!(RequestError::error_code("AddPortMapping", 501, "Action Failed").is_retriable());
+    assert!(!RequestError::error_code("AddPortMapping", 402, "Invalid Args").is_retriable());
+    assert!(!RequestError::HttpUnauthorized.is_retriable());
+
+    assert!(AddAnyPortError::NoPortsAvailable.is_retriable());
+    assert!(AddAnyPortError::RequestError(timeout()).is_retriable());
+    assert!(!AddAnyPortError::ActionNotAuthorized.is_retriable());
+    assert!(!AddAnyPortError::DescriptionTooLong.is_retriable());
+    assert!(AddPortError::PortInUse.is_retriable());
+    assert!(!AddPortError::SamePortValuesRequired.is_retriable());
+    assert!(RemovePortError::RequestError(timeout()).is_retriable());
+    assert!(!RemovePortError::NoSuchPortMapping.is_retriable());
+}
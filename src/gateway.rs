@@ -1,16 +1,33 @@
 use std::collections::HashMap;
 use std::fmt;
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::common::{self, messages, parsing, parsing::RequestResult};
-use crate::errors::{self, AddAnyPortError, AddPortError, GetExternalIpError, RemovePortError, RequestError};
-use crate::PortMappingProtocol;
+use crate::common::parsing::{
+    self, ActiveConnection, DeviceInfo, DiscoveryInfo, DslLinkInfo, MappingResult, MappingWarning, RequestResult,
+    ServiceInfo, StatusInfo,
+};
+use crate::common::{
+    self, messages, AnyPort, Capabilities, ConnectionService, GatewayOptions, PruneResults, SearchOptions,
+};
+use crate::errors::{
+    self, AddAnyPortError, AddPortError, GetDnsServersError, GetExternalIpError, GetExternalIpv6Error,
+    GetGenericPortMappingEntryError, GetSpecificPortMappingEntryError, RemovePortError, RenewPortError, RequestError,
+    SearchError,
+};
+use crate::soap::{self, Action};
+use crate::transport::{AttoHttpTransport, HttpRequest, Transport};
+use crate::{PortMappingProtocol, RemoteHost};
 
 /// This structure represents a gateway found by the search functions.
 #[derive(Clone, Debug)]
 pub struct Gateway {
-    /// Socket address of the gateway
-    pub addr: SocketAddrV4,
+    /// Socket address of the gateway, an IPv6 one if its description was found at an IPv6 location
+    pub addr: SocketAddr,
     /// Root url of the device
     pub root_url: String,
     /// Control url of the device
@@ -19,54 +36,475 @@ pub struct Gateway {
     pub control_schema_url: String,
     /// Control schema for all actions
     pub control_schema: HashMap<String, Vec<String>>,
+    /// Options for the requests made to the gateway
+    pub options: GatewayOptions,
+    /// Headers of the search response, or `None` if the gateway was not found by a search
+    pub discovery_info: Option<DiscoveryInfo>,
+    /// Names of the root device from its description, or `None` if the description was not fetched
+    pub device_info: Option<DeviceInfo>,
+    /// HTTP client sending the requests
+    pub transport: Arc<dyn Transport>,
+    // Shared by the clones, so they all learn the service type answered by the gateway.
+    pub(crate) connection_service: Arc<Mutex<ConnectionService>>,
+    // Whether `AddAnyPortMapping` worked, shared by the clones like the service type.
+    pub(crate) add_any_port_support: Arc<Mutex<Option<bool>>>,
+    // What the description tells, fetched once for the clones.
+    pub(crate) capabilities: Arc<Mutex<Option<Capabilities>>>,
 }
 
 impl Gateway {
-    fn perform_request(&self, header: &str, body: &str, ok: &str) -> RequestResult {
-        let url = format!("http://{}{}", self.addr, self.control_url);
+    /// Create a gateway from a known address and control url, without searching for it.
+    ///
+    /// The control url can be a path (`/ctl/IPConn`) or a full url on `addr`, and is normalized to a path.
+    /// Because the control schema is not fetched, the standard `WANIPConnection:1` arguments are assumed.
+    pub fn new(addr: SocketAddr, control_url: String) -> Result<Gateway, SearchError> {
+        Gateway::with_transport(addr, control_url, Arc::new(AttoHttpTransport))
+    }
+
+    /// Create a gateway from the url of its description, such as `http://192.168.1.1:5000/rootDesc.xml`.
+    ///
+    /// The description and control schema are fetched like for a gateway found by `search_gateway` with
+    /// `options`, but without a search: this reconnects at once to a gateway found before, whose
+    /// `DiscoveryInfo` is then `None`. The url must have an IPv4 address, or an IPv6 address in brackets.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use igd::{Gateway, Result};
+    ///
+    /// fn main() -> Result {
+    ///     let gateway = Gateway::from_location("http://192.168.1.1:5000/rootDesc.xml", Default::default())?;
+    ///     println!("External IP address: {}", gateway.get_external_ip()?);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn from_location(location: &str, options: SearchOptions) -> Result<Gateway, SearchError> {
+        crate::search::gateway_from_location(location, &options)
+    }
+
+    /// Create a gateway like `Gateway::new`, sending its requests through `transport`.
+    pub fn with_transport(
+        addr: SocketAddr,
+        control_url: String,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Gateway, SearchError> {
+        Ok(Gateway {
+            addr,
+            root_url: String::new(),
+            control_url: parsing::parse_control_url(&addr, &control_url)?,
+            control_schema_url: String::new(),
+            control_schema: messages::default_control_schema(),
+            options: GatewayOptions::default(),
+            discovery_info: None,
+            device_info: None,
+            transport,
+            connection_service: Default::default(),
+            add_any_port_support: Default::default(),
+            capabilities: Default::default(),
+        })
+    }
+
+    fn perform_request(&self, action: Action, body: &str) -> RequestResult {
+        self.perform_request_at(&self.control_url, None, action, body)
+    }
+
+    // Actions of the connection service are sent with its service type, which is probed until confirmed.
+    fn perform_request_at(
+        &self,
+        control_url: &str,
+        deadline: Option<Instant>,
+        action: Action,
+        body: &str,
+    ) -> RequestResult {
+        if action.service_type != messages::SERVICE_TYPE {
+            return self.send_action(control_url, deadline, action, body);
+        }
+
+        let (service_type, other) = {
+            let connection_service = self.connection_service.lock().unwrap_or_else(|e| e.into_inner());
+            (connection_service.service_type, connection_service.other(action.name))
+        };
+        let send = |service_type: &'static str| {
+            let body = messages::rebind_service_type(body, service_type);
+            let result = self.send_action(control_url, deadline, Action::new(service_type, action.name), &body);
+            if parsing::is_service_type_accepted(&result) {
+                self.connection_service
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .confirm(service_type);
+            }
+            result
+        };
+
+        let result = send(service_type);
+        match (&result, other) {
+            (Err(e), Some(other)) if parsing::is_service_type_rejected(e) => {
+                common::record_retry(&self.options, action.name);
+                let retry = send(other);
+                match retry {
+                    Err(ref e) if parsing::is_service_type_rejected(e) => result,
+                    retry => retry,
+                }
+            }
+            _ => result,
+        }
+    }
+
+    fn send_action(&self, control_url: &str, deadline: Option<Instant>, action: Action, body: &str) -> RequestResult {
+        let send = |header: &str| {
+            let url = format!("http://{}{}", self.addr, control_url);
+            let mut request = HttpRequest::post(url, header, body.to_string(), &self.options);
+            request.timeout = common::time_left(deadline)?;
+            let response = self.transport.send(request)?;
+            let body = common::filter_response(&self.options, response.body);
+            parsing::parse_http_response(response.status, body, &action.response_name())
+        };
+        let send = |header: &str| {
+            let result = send(header);
+            common::record_request(&self.options, action.name, &result);
+            result
+        };
+
+        match send(&action.header()) {
+            // Some gateways only understand the header without quotes.
+            Err(ref e) if parsing::is_soap_action_rejected(e, action.name) => {
+                common::record_retry(&self.options, action.name);
+                send(&action.unquoted_header())
+            }
+            result => result,
+        }
+    }
+
+    /// Type of the connection service the actions are sent to.
+    ///
+    /// A gateway found by a search uses the connection service of its description, such as
+    /// `urn:schemas-upnp-org:service:WANPPPConnection:1` for DSL gateways. One created with `Gateway::new` starts
+    /// with `urn:schemas-upnp-org:service:WANIPConnection:1`: when the gateway rejects an action every connection
+    /// service has, with UPnP error 401 (Invalid Action) or an HTTP 404, and accepts it from the other one, the
+    /// service type that worked is kept, for this gateway and its clones.
+    /// A gateway found with `SearchOptions::prefer_igd_v2` starts with
+    /// `urn:schemas-upnp-org:service:WANIPConnection:2` when it has it.
+    pub fn service_type(&self) -> &'static str {
+        self.connection_service
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .service_type
+    }
+
+    /// Whether the gateway implements `AddAnyPortMapping`, or `None` until `add_any_port` tried it.
+    ///
+    /// This is `Some(false)` once the gateway answered that it does not implement the action, and right away
+    /// if the control schema does not list it, as `add_any_port` then falls back to `AddPortMapping` without
+    /// asking. The answer is kept for this gateway and its clones, so later calls go straight to the fallback.
+    pub fn supports_add_any_port(&self) -> Option<bool> {
+        if !self.control_schema.contains_key("AddAnyPortMapping") {
+            return Some(false);
+        }
+        *self.add_any_port_support.lock().unwrap_or_else(|e| e.into_inner())
+    }
 
-        let response = attohttpc::post(&url)
-            .header("SOAPAction", header)
-            .header("Content-Type", "text/xml")
-            .text(body)
-            .send()?;
+    /// What the gateway can do, from its device description and control schema
+    ///
+    /// The description and control schema are fetched on the first call only, and the result is kept for this
+    /// gateway and its clones. The service version and `AddAnyPortMapping` support follow what the gateway
+    /// answered since.
+    pub fn capabilities(&self) -> Result<Capabilities, RequestError> {
+        let cached = self.capabilities.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let mut capabilities = match cached {
+            Some(capabilities) => capabilities,
+            None => {
+                let capabilities =
+                    Capabilities::from_description(&self.control_schema, &self.services()?, self.max_lease_duration()?);
+                *self.capabilities.lock().unwrap_or_else(|e| e.into_inner()) = Some(capabilities.clone());
+                capabilities
+            }
+        };
+        capabilities.update(self.service_type(), self.supports_add_any_port());
+        Ok(capabilities)
+    }
+
+    /// Check that a TCP connection to the gateway can be opened within `timeout`.
+    ///
+    /// This is a pure connectivity probe, independent of UPnP, to fail fast before sending actions to a gateway
+    /// that may be gone, for example one loaded from a cache. A `true` result does not tell the gateway still
+    /// answers UPnP requests at its control url.
+    pub fn is_reachable(&self, timeout: Duration) -> bool {
+        TcpStream::connect_timeout(&self.addr, timeout).is_ok()
+    }
+
+    /// Address of this host on the network that reaches the gateway, the internal client of its mappings.
+    ///
+    /// For a gateway found by a search, this is the address its response was sent to, see
+    /// `DiscoveryInfo::local_ip`. Otherwise it is the source address the system would route packets to the
+    /// gateway from, found without sending any, so it picks the right interface on a host with several. For a
+    /// gateway reached over IPv6, it is the IPv4 address routing to the SSDP multicast group instead.
+    pub fn local_ip(&self) -> io::Result<Ipv4Addr> {
+        match self.discovery_info.as_ref().and_then(|info| info.local_ip) {
+            Some(ip) => Ok(ip),
+            None => common::local_ipv4_for(self.addr),
+        }
+    }
 
-        parsing::parse_response(response.text()?, ok)
+    /// Send a SOAP envelope, usually built with `soap::build_envelope`, to the control url.
+    ///
+    /// This is an escape hatch for actions the other methods do not cover. On success the `{action}Response`
+    /// element is returned, and UPnP errors reported by the gateway are returned as `RequestError::ErrorCode`.
+    pub fn send_envelope(
+        &self,
+        service_type: &str,
+        action: &str,
+        envelope: &str,
+    ) -> Result<xmltree::Element, RequestError> {
+        let response = self.perform_request(Action::new(service_type, action), envelope)?;
+        Ok(response.into_xml())
     }
 
     /// Get the external IP address of the gateway.
+    ///
+    /// Returns `GetExternalIpError::NotConnected` while the gateway has no external address yet.
     pub fn get_external_ip(&self) -> Result<Ipv4Addr, GetExternalIpError> {
         parsing::parse_get_external_ip_response(self.perform_request(
-            messages::GET_EXTERNAL_IP_HEADER,
+            messages::GET_EXTERNAL_IP_ACTION,
+            &messages::format_get_external_ip_message(),
+        ))
+    }
+
+    /// Get the state of the WAN connection of the gateway.
+    ///
+    /// This tells a gateway whose WAN link is down, which can still map ports, from one refusing to. `GetStatusInfo`
+    /// is required by IGD, but some gateways lack it and return a `RequestError::ErrorCode`.
+    pub fn get_status_info(&self) -> Result<StatusInfo, RequestError> {
+        parsing::parse_get_status_info_response(self.perform_request(
+            messages::GET_STATUS_INFO_ACTION,
+            &messages::format_get_status_info_message(),
+        ))
+    }
+
+    /// Wait until the gateway is connected to the internet, and return its external IP address.
+    ///
+    /// The gateway is polled every `interval`. It is connected once `GetStatusInfo` reports `Connected` and its
+    /// external address is not `0.0.0.0`, so a gateway behind another NAT, with a private external address, is
+    /// connected too. For gateways without `GetStatusInfo`, the external address must also not be private, as
+    /// some report a LAN address while the WAN link comes up. Request errors that are retriable are ignored, as
+    /// the gateway may be booting too. If the timeout expires first, `GetExternalIpError::Timeout` is returned.
+    pub fn wait_for_connection(&self, timeout: Duration, interval: Duration) -> Result<Ipv4Addr, GetExternalIpError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.connected_ip() {
+                Ok(Some(ip)) => return Ok(ip),
+                Ok(None) => {}
+                Err(GetExternalIpError::RequestError(ref e)) if e.is_retriable() => {}
+                Err(e) => return Err(e),
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(GetExternalIpError::Timeout);
+            }
+            thread::sleep(interval.min(left));
+        }
+    }
+
+    // The external IP address if the gateway is connected.
+    fn connected_ip(&self) -> Result<Option<Ipv4Addr>, GetExternalIpError> {
+        let connected = match self.get_status_info() {
+            Ok(status) if !status.is_connected() => return Ok(None),
+            Ok(_) => true,
+            // Gateways without `GetStatusInfo`, which the address alone tells.
+            Err(RequestError::ErrorCode {
+                code: 401 | 602 | 606, ..
+            }) => false,
+            Err(e) => return Err(GetExternalIpError::RequestError(e)),
+        };
+        match self.get_external_ip() {
+            Ok(ip) if connected => Ok(Some(ip).filter(|ip| !ip.is_unspecified())),
+            Ok(ip) => Ok(Some(ip).filter(|ip| common::is_connected_address(*ip))),
+            Err(GetExternalIpError::NotConnected) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get the external IPv6 address of a dual-stack gateway.
+    ///
+    /// IGD has no standard action for this: `WANIPv6FirewallControl:1` only opens pinholes, and
+    /// `WANIPConnection:2` reports IPv4 addresses. This calls the `X_AVM_DE_GetExternalIPv6Address` action of the
+    /// connection service, which AVM FRITZ!Box gateways provide. Other gateways, and gateways without an IPv6
+    /// connection, return `GetExternalIpv6Error::NotSupported`.
+    pub fn get_external_ipv6(&self) -> Result<Ipv6Addr, GetExternalIpv6Error> {
+        parsing::parse_get_external_ipv6_response(self.perform_request(
+            messages::GET_EXTERNAL_IPV6_ACTION,
+            &messages::format_get_external_ipv6_message(),
+        ))
+    }
+
+    /// Check whether the gateway lets this client perform actions, before mapping any port.
+    ///
+    /// This calls the harmless `GetExternalIPAddress` action and returns `false` when the gateway answers with
+    /// error 606, which usually means UPnP is disabled in the router settings, or the client is not on its
+    /// allowed list. Other errors are returned as is.
+    pub fn check_authorized(&self) -> Result<bool, RequestError> {
+        parsing::parse_check_authorized_response(self.perform_request(
+            messages::GET_EXTERNAL_IP_ACTION,
             &messages::format_get_external_ip_message(),
-            "GetExternalIPAddressResponse",
         ))
     }
-    
+
+    /// Read a state variable of the connection service, such as `PortMappingNumberOfEntries`.
+    ///
+    /// This uses the deprecated `QueryStateVariable` action, which many gateways still answer even for variables
+    /// without a getter. Unknown variables are usually reported as `RequestError::ErrorCode { code: 404, .. }`.
+    pub fn query_state_variable(&self, name: &str) -> Result<String, RequestError> {
+        parsing::parse_query_state_variable_response(self.perform_request(
+            messages::QUERY_STATE_VARIABLE_ACTION,
+            &messages::format_query_state_variable_message(name),
+        ))
+    }
+
+    /// Get the DNS servers the gateway obtained for its WAN connection.
+    ///
+    /// Gateways without the optional `GetDNSServers` action return `GetDnsServersError::NotSupported`.
+    pub fn get_dns_servers(&self) -> Result<Vec<Ipv4Addr>, GetDnsServersError> {
+        parsing::parse_get_dns_servers_response(self.perform_request(
+            messages::GET_DNS_SERVERS_ACTION,
+            &messages::format_get_dns_servers_message(),
+        ))
+    }
+
+    /// Get the line attributes of a DSL modem, from its `WANDSLLinkConfig:1` service.
+    ///
+    /// The service is looked up in the device description, so `RequestError::UnsupportedAction` is returned when
+    /// the gateway has no such service, or was created with `Gateway::new`.
+    pub fn get_dsl_link_info(&self) -> Result<DslLinkInfo, RequestError> {
+        let control_url = self
+            .find_service_control_url(messages::WAN_DSL_LINK_CONFIG)?
+            .ok_or_else(|| RequestError::UnsupportedAction("GetDSLLinkInfo".to_string()))?;
+        let request = |action: &str| {
+            self.perform_request_at(
+                &control_url,
+                None,
+                Action::new(messages::WAN_DSL_LINK_CONFIG, action),
+                &soap::build_envelope(messages::WAN_DSL_LINK_CONFIG, action, ""),
+            )
+        };
+        parsing::parse_dsl_link_info(
+            request("GetDSLLinkInfo"),
+            request("GetAutoConfig"),
+            request("GetModulationType"),
+        )
+    }
+
+    /// Get the WAN connections the gateway reports as active, from its `WANCommonInterfaceConfig:1` service.
+    ///
+    /// On gateways with several connection services, this tells which one is actually live. The number of
+    /// active connections is only a state variable, read with `QueryStateVariable`, and the connections are
+    /// requested by index until that number, or until the gateway reports the end of the list or repeats a
+    /// connection, at most 16. `RequestError::UnsupportedAction` is returned when the gateway has no such service,
+    /// or was created with `Gateway::new`.
+    pub fn active_connections(&self) -> Result<Vec<ActiveConnection>, RequestError> {
+        let unsupported = || RequestError::UnsupportedAction("GetActiveConnection".to_string());
+        let description = self.get_description()?.ok_or_else(unsupported)?;
+        let control_url =
+            parsing::parse_service_control_url(&self.addr, &description, messages::WAN_COMMON_INTERFACE_CONFIG)?
+                .ok_or_else(unsupported)?;
+        let count = parsing::parse_number_of_active_connections(self.perform_request_at(
+            &control_url,
+            None,
+            messages::QUERY_STATE_VARIABLE_ACTION,
+            &messages::format_query_state_variable_message("NumberOfActiveConnections"),
+        ));
+
+        let mut connections = Vec::new();
+        for index in 0..count
+            .unwrap_or(common::MAX_ACTIVE_CONNECTIONS)
+            .min(common::MAX_ACTIVE_CONNECTIONS)
+        {
+            let result = self.perform_request_at(
+                &control_url,
+                None,
+                messages::GET_ACTIVE_CONNECTION_ACTION,
+                &messages::format_get_active_connection_message(index),
+            );
+            match parsing::parse_active_connection(&self.addr, &description, result)? {
+                Some(connection) if !connections.contains(&connection) => connections.push(connection),
+                _ => break,
+            }
+        }
+        Ok(connections)
+    }
+
+    /// Get the number of packets the gateway sent on its WAN interface, from its `WANCommonInterfaceConfig:1`
+    /// service.
+    ///
+    /// The counter is 32 bits and wraps around, so compare successive readings modulo `2^32`.
+    /// `RequestError::UnsupportedAction` is returned when the gateway has no such service, or was created with
+    /// `Gateway::new`.
+    pub fn get_total_packets_sent(&self) -> Result<u32, RequestError> {
+        self.get_counter(messages::GET_TOTAL_PACKETS_SENT_ACTION, "NewTotalPacketsSent")
+    }
+
+    /// Get the number of packets the gateway received on its WAN interface, like `get_total_packets_sent`.
+    pub fn get_total_packets_received(&self) -> Result<u32, RequestError> {
+        self.get_counter(messages::GET_TOTAL_PACKETS_RECEIVED_ACTION, "NewTotalPacketsReceived")
+    }
+
+    fn get_counter(&self, action: Action, field: &str) -> Result<u32, RequestError> {
+        let control_url = self
+            .find_service_control_url(messages::WAN_COMMON_INTERFACE_CONFIG)?
+            .ok_or_else(|| RequestError::UnsupportedAction(action.name.to_string()))?;
+        parsing::parse_counter(
+            self.perform_request_at(
+                &control_url,
+                None,
+                action,
+                &soap::build_envelope(messages::WAN_COMMON_INTERFACE_CONFIG, action.name, ""),
+            ),
+            field,
+        )
     }
 
-impl Frame<()> {
-    pub(crate) fn into_data(self) -> Frame<Data> {
-        Frame {
-            header: self.header.into_data(),
-            body: self.body,
+    // Look up the control url of another service of the gateway in its description.
+    fn find_service_control_url(&self, service_type: &str) -> Result<Option<String>, RequestError> {
+        match self.get_description()? {
+            Some(text) => parsing::parse_service_control_url(&self.addr, &text, service_type),
+            None => Ok(None),
         }
     }
 
-    pub(crate) fn into_window_update(self) -> Frame<WindowUpdate> {
-        Frame {
-            header: self.header.into_window_update(),
-            body: self.body,
+    // Fetch the device description, unless the gateway was created without its root url.
+    fn get_description(&self) -> Result<Option<String>, RequestError> {
+        if self.root_url.is_empty() {
+            return Ok(None);
         }
+
+        let url = format!("http://{}{}", self.addr, self.root_url);
+        let response = self.transport.send(HttpRequest::get(url, &self.options))?;
+        parsing::parse_http_status(response.status, response.body).map(Some)
     }
 
-    pub(crate) fn into_ping(self) -> Frame<Ping> {
-        Frame {
-            header: self.header.into_ping(),
-            body: self.body,
+    /// List all the services advertised in the device description, including those of the nested devices.
+    ///
+    /// Returns an empty list when the gateway was created with `Gateway::new`, since the description is unknown.
+    pub fn services(&self) -> Result<Vec<ServiceInfo>, RequestError> {
+        match self.get_description()? {
+            Some(text) => parsing::parse_services(&text),
+            None => Ok(Vec::new()),
         }
     }
-}
+
+    /// Get the longest lease duration in seconds the gateway accepts for port mappings.
+    ///
+    /// The limit is read from the `allowedValueRange` declared in the control schema, as IGDv2 gateways do.
+    /// Returns `None` when the lease duration is not constrained, or when the control schema url is unknown
+    /// because the gateway was created with `Gateway::new`.
+    pub fn max_lease_duration(&self) -> Result<Option<u32>, RequestError> {
+        if self.control_schema_url.is_empty() {
+            return Ok(None);
+        }
+
+        let url = format!("http://{}{}", self.addr, self.control_schema_url);
+        let response = self.transport.send(HttpRequest::get(url, &self.options))?;
+        let text = parsing::parse_http_status(response.status, response.body)?;
+
+        parsing::parse_max_lease_duration(&text)
+    }
 
     /// Get an external socket address with our external ip and any port. This is a convenience
     /// function that calls `get_external_ip` followed by `add_any_port`
@@ -85,8 +523,92 @@ impl Frame<()> {
         description: &str,
     ) -> Result<SocketAddrV4, AddAnyPortError> {
         let ip = self.get_external_ip()?;
-        let port = self.add_any_port(protocol, local_addr, lease_duration, description)?;
-        Ok(SocketAddrV4::new(ip, port))
+        let any_port = self.add_any_port_with_fallback(protocol, local_addr, lease_duration, description)?;
+        Ok(SocketAddrV4::new(ip, any_port.port))
+    }
+
+    /// Add a port mapping with any external port, like `get_any_address`, and describe how it was added.
+    ///
+    /// Besides the external address, the result tells the lease to renew the mapping before, whether the
+    /// gateway lacked `AddAnyPortMapping` so that random ports were tried with `AddPortMapping`, and whether
+    /// the gateway forced the external port to be the internal port. The lease is read back from the gateway
+    /// with `GetSpecificPortMappingEntry`, which costs a request.
+    pub fn get_any_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<MappingResult, AddAnyPortError> {
+        let ip = self.get_external_ip()?;
+        let any_port = self.add_any_port_with_fallback(protocol, local_addr, lease_duration, description)?;
+        let lease_duration = common::lease_duration(&self.options, lease_duration);
+        let lease_duration = if self.options.dry_run {
+            lease_duration
+        } else {
+            self.get_specific_port_mapping_entry(protocol, any_port.port)
+                .map_or(lease_duration, |entry| entry.lease_duration)
+        };
+        Ok(MappingResult {
+            external_addr: SocketAddrV4::new(ip, any_port.port),
+            lease_duration,
+            used_legacy_fallback: any_port.used_legacy_fallback,
+            same_port_forced: any_port.same_port_forced,
+        })
+    }
+
+    /// Map an external port of the gateway to a bound TCP listener, with any external port.
+    ///
+    /// This calls `get_any_address` with the local address of the listener, which must be bound to the IPv4
+    /// address of an interface rather than `0.0.0.0`, so the gateway knows where to send the traffic.
+    /// The lease_duration parameter is in seconds. A value of 0 is infinite.
+    ///
+    /// # Returns
+    ///
+    /// The external address that was mapped on success. Otherwise an error.
+    pub fn forward_listener(
+        &self,
+        listener: &TcpListener,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<SocketAddrV4, AddAnyPortError> {
+        let local_addr = common::forwarded_addr(listener.local_addr())?;
+        self.get_any_address(PortMappingProtocol::TCP, local_addr, lease_duration, description)
+    }
+
+    /// Map an external port of the gateway to a bound UDP socket, like `forward_listener` does for TCP.
+    pub fn forward_udp_socket(
+        &self,
+        socket: &UdpSocket,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<SocketAddrV4, AddAnyPortError> {
+        let local_addr = common::forwarded_addr(socket.local_addr())?;
+        self.get_any_address(PortMappingProtocol::UDP, local_addr, lease_duration, description)
+    }
+
+    /// Map `external_port` to `local_port` on this host, at the address given by `local_ip`.
+    ///
+    /// This is `add_port` without having to know which local address reaches the gateway. The lease_duration
+    /// parameter is in seconds. A value of 0 is infinite.
+    ///
+    /// # Returns
+    ///
+    /// The internal address that was mapped on success. Otherwise an error.
+    pub fn forward_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_port: u16,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<SocketAddrV4, AddPortError> {
+        let local_ip = self
+            .local_ip()
+            .map_err(|e| AddPortError::RequestError(RequestError::IoError(e)))?;
+        let local_addr = SocketAddrV4::new(local_ip, local_port);
+        self.add_port(protocol, external_port, local_addr, lease_duration, description)?;
+        Ok(local_addr)
     }
 
     /// Add a port mapping.with any external port.
@@ -97,6 +619,7 @@ impl Frame<()> {
     /// # Returns
     ///
     /// The external port that was mapped on success. Otherwise an error.
+    /// This is the internal port if the gateway requires both to be the same, which `get_any_mapping` reports.
     pub fn add_any_port(
         &self,
         protocol: PortMappingProtocol,
@@ -104,82 +627,216 @@ impl Frame<()> {
         lease_duration: u32,
         description: &str,
     ) -> Result<u16, AddAnyPortError> {
+        self.add_any_port_with_fallback(protocol, local_addr, lease_duration, description)
+            .map(|any_port| any_port.port)
+    }
+
+    fn add_any_port_with_fallback(
+        &self,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<AnyPort, AddAnyPortError> {
         // This function first attempts to call AddAnyPortMapping on the IGD with a random port
         // number. If that fails due to the method being unknown it attempts to call AddPortMapping
-        // instead with a random port number. If that fails due to ConflictInMappingEntry it retrys
-        // with another port up to a maximum of 20 times. If it fails due to SamePortValuesRequired
+        // instead with a random port number. If that fails due to ConflictInMappingEntry or a retriable
+        // request error it retrys with another port up to a maximum of 20 times. If it fails due to SamePortValuesRequired
         // it retrys once with the same port values.
 
         if local_addr.port() == 0 {
             return Err(AddAnyPortError::InternalPortZeroInvalid);
         }
+        if self.options.only_local_clients && !common::is_local_address(*local_addr.ip()) {
+            return Err(AddAnyPortError::InternalClientNotLocal);
+        }
+        common::check_description(
+            &self.options,
+            description,
+            AddAnyPortError::DescriptionEmpty,
+            AddAnyPortError::DescriptionNotAscii,
+        )?;
+
+        if self.options.dry_run {
+            return Ok(AnyPort {
+                port: self.check_any_port(protocol, local_addr)?,
+                used_legacy_fallback: !self.control_schema.contains_key("AddAnyPortMapping"),
+                same_port_forced: false,
+            });
+        }
+
+        let deadline = self
+            .options
+            .add_any_port_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let result = self.add_any_port_before(deadline, protocol, local_addr, lease_duration, description);
+        match result {
+            Err(AddAnyPortError::RequestError(..)) if common::is_expired(deadline) => Err(AddAnyPortError::Timeout),
+            result => result,
+        }
+    }
 
+    fn add_any_port_before(
+        &self,
+        deadline: Option<Instant>,
+        protocol: PortMappingProtocol,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<AnyPort, AddAnyPortError> {
         let schema = self.control_schema.get("AddAnyPortMapping");
-        if let Some(schema) = schema {
-            let external_port = common::random_port();
+        if let (Some(schema), Some(true) | None) = (schema, self.supports_add_any_port()) {
+            let external_port = common::random_port(&self.options);
 
-            parsing::parse_add_any_port_mapping_response(self.perform_request(
-                messages::ADD_ANY_PORT_MAPPING_HEADER,
+            let resp = self.perform_request_at(
+                &self.control_url,
+                deadline,
+                messages::ADD_ANY_PORT_MAPPING_ACTION,
                 &messages::format_add_any_port_mapping_message(
                     schema,
                     protocol,
                     external_port,
                     local_addr,
-                    lease_duration,
+                    common::lease_duration(&self.options, lease_duration),
                     description,
                 ),
-                "AddAnyPortMappingResponse",
-            ))
+            );
+            if let Some(supported) = parsing::is_add_any_port_supported(&resp) {
+                *self.add_any_port_support.lock().unwrap_or_else(|e| e.into_inner()) = Some(supported);
+            }
+            match parsing::parse_add_any_port_mapping_response(resp) {
+                // The gateway lists the action in its schema but does not implement it.
+                Err(AddAnyPortError::RequestError(RequestError::ErrorCode { code: 401, .. })) => {
+                    self.retry_add_random_port_mapping(deadline, protocol, local_addr, lease_duration, description)
+                }
+                result => result.map(|port| AnyPort {
+                    port,
+                    used_legacy_fallback: false,
+                    same_port_forced: false,
+                }),
+            }
         } else {
-            self.retry_add_random_port_mapping(protocol, local_addr, lease_duration, description)
+            self.retry_add_random_port_mapping(deadline, protocol, local_addr, lease_duration, description)
+        }
+    }
+
+    fn check_any_port(&self, protocol: PortMappingProtocol, local_addr: SocketAddrV4) -> Result<u16, AddAnyPortError> {
+        const ATTEMPTS: usize = 20;
+
+        for _ in 0..ATTEMPTS {
+            let external_port = common::random_port(&self.options);
+            if self.is_port_available(RemoteHost::Wildcard, protocol, external_port, local_addr)? {
+                return Ok(external_port);
+            }
+        }
+
+        Err(AddAnyPortError::NoPortsAvailable)
+    }
+
+    // The port is available if it is not mapped, or already mapped to local_addr.
+    fn is_port_available(
+        &self,
+        remote_host: RemoteHost,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+    ) -> Result<bool, GetSpecificPortMappingEntryError> {
+        match self.get_specific_port_mapping_entry_with_remote_host(remote_host, protocol, external_port) {
+            Ok(entry) => {
+                Ok(entry.internal_client == local_addr.ip().to_string() && entry.internal_port == local_addr.port())
+            }
+            Err(GetSpecificPortMappingEntryError::NoSuchEntryInArray) => Ok(true),
+            Err(e) => Err(e),
         }
     }
 
     fn retry_add_random_port_mapping(
         &self,
+        deadline: Option<Instant>,
         protocol: PortMappingProtocol,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
-    ) -> Result<u16, AddAnyPortError> {
-        const ATTEMPTS: usize = 20;
+    ) -> Result<AnyPort, AddAnyPortError> {
+        const ATTEMPTS: u32 = 20;
 
-        for _ in 0..ATTEMPTS {
-            if let Ok(port) = self.add_random_port_mapping(protocol, local_addr, lease_duration, &description) {
-                return Ok(port);
+        let mut last_error = AddAnyPortError::NoPortsAvailable;
+        for attempt in 0..ATTEMPTS {
+            if attempt > 0 {
+                thread::sleep(common::backoff_delay(&self.options, attempt, deadline));
+            }
+            if common::is_expired(deadline) {
+                return Err(AddAnyPortError::Timeout);
+            }
+            match self.add_random_port_mapping(deadline, protocol, local_addr, lease_duration, description) {
+                Ok(any_port) => return Ok(any_port),
+                Err(e @ AddAnyPortError::NoPortsAvailable) => last_error = e,
+                // A transient failure may be over for the next port.
+                Err(AddAnyPortError::RequestError(e)) if e.is_retriable() => {
+                    last_error = AddAnyPortError::RequestError(e)
+                }
+                e => return e,
             }
         }
 
-        Err(AddAnyPortError::NoPortsAvailable)
+        Err(last_error)
     }
 
     fn add_random_port_mapping(
         &self,
+        deadline: Option<Instant>,
         protocol: PortMappingProtocol,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
-    ) -> Result<u16, AddAnyPortError> {
-        let external_port = common::random_port();
+    ) -> Result<AnyPort, AddAnyPortError> {
+        let external_port = common::random_port(&self.options);
 
-        if let Err(err) = self.add_port_mapping(protocol, external_port, local_addr, lease_duration, &description) {
+        if let Err(err) = self.add_wildcard_port_mapping(
+            deadline,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        ) {
             match parsing::convert_add_random_port_mapping_error(err) {
                 Some(err) => return Err(err),
-                None => return self.add_same_port_mapping(protocol, local_addr, lease_duration, description),
+                None => {
+                    let port =
+                        self.add_same_port_mapping(deadline, protocol, local_addr, lease_duration, description)?;
+                    return Ok(AnyPort {
+                        port,
+                        used_legacy_fallback: true,
+                        same_port_forced: true,
+                    });
+                }
             }
         }
 
-        Ok(external_port)
+        Ok(AnyPort {
+            port: external_port,
+            used_legacy_fallback: true,
+            same_port_forced: false,
+        })
     }
 
     fn add_same_port_mapping(
         &self,
+        deadline: Option<Instant>,
         protocol: PortMappingProtocol,
         local_addr: SocketAddrV4,
         lease_duration: u32,
         description: &str,
     ) -> Result<u16, AddAnyPortError> {
-        match self.add_port_mapping(protocol, local_addr.port(), local_addr, lease_duration, description) {
+        match self.add_wildcard_port_mapping(
+            deadline,
+            protocol,
+            local_addr.port(),
+            local_addr,
+            lease_duration,
+            description,
+        ) {
             Ok(_) => Ok(local_addr.port()),
             Err(e) => Err(parsing::convert_add_same_port_mapping_error(e)),
         }
@@ -187,6 +844,7 @@ impl Frame<()> {
 
     fn add_port_mapping(
         &self,
+        remote_host: RemoteHost,
         protocol: PortMappingProtocol,
         external_port: u16,
         local_addr: SocketAddrV4,
@@ -194,23 +852,69 @@ impl Frame<()> {
         description: &str,
     ) -> Result<(), RequestError> {
         self.perform_request(
-            messages::ADD_PORT_MAPPING_HEADER,
-            &messages::format_add_port_mapping_message(
-                self.control_schema
-                    .get("AddPortMapping")
-                    .ok_or_else(|| RequestError::UnsupportedAction("AddPortMapping".to_string()))?,
+            messages::ADD_PORT_MAPPING_ACTION,
+            &self.format_add_port_mapping(
+                remote_host,
                 protocol,
                 external_port,
                 local_addr,
                 lease_duration,
                 description,
-            ),
-            "AddPortMappingResponse",
+            )?,
+        )?;
+
+        Ok(())
+    }
+
+    // The mappings tried by `add_any_port`, which must complete before its deadline.
+    fn add_wildcard_port_mapping(
+        &self,
+        deadline: Option<Instant>,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(), RequestError> {
+        self.perform_request_at(
+            &self.control_url,
+            deadline,
+            messages::ADD_PORT_MAPPING_ACTION,
+            &self.format_add_port_mapping(
+                RemoteHost::Wildcard,
+                protocol,
+                external_port,
+                local_addr,
+                lease_duration,
+                description,
+            )?,
         )?;
 
         Ok(())
     }
 
+    fn format_add_port_mapping(
+        &self,
+        remote_host: RemoteHost,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<String, RequestError> {
+        Ok(messages::format_add_port_mapping_message(
+            self.control_schema
+                .get("AddPortMapping")
+                .ok_or_else(|| RequestError::UnsupportedAction("AddPortMapping".to_string()))?,
+            remote_host,
+            protocol,
+            external_port,
+            local_addr,
+            common::lease_duration(&self.options, lease_duration),
+            description,
+        ))
+    }
+
     /// Add a port mapping.
     ///
     /// The local_addr is the address where the traffic is sent to.
@@ -223,51 +927,1726 @@ impl Frame<()> {
         lease_duration: u32,
         description: &str,
     ) -> Result<(), AddPortError> {
-        if external_port == 0 {
-            return Err(AddPortError::ExternalPortZeroInvalid);
-        }
-        if local_addr.port() == 0 {
-            return Err(AddPortError::InternalPortZeroInvalid);
-        }
-
-        self.add_port_mapping(protocol, external_port, local_addr, lease_duration, description)
-            .map_err(parsing::convert_add_port_error)
+        self.add_port_with_remote_host(
+            RemoteHost::Wildcard,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        )
     }
 
-    /// Remove a port mapping.
-    pub fn remove_port(&self, protocol: PortMappingProtocol, external_port: u16) -> Result<(), RemovePortError> {
-        parsing::parse_delete_port_mapping_response(self.perform_request(
-            messages::DELETE_PORT_MAPPING_HEADER,
-            &messages::format_delete_port_message(
-                self.control_schema.get("DeletePortMapping").ok_or_else(|| {
-                    RemovePortError::RequestError(RequestError::UnsupportedAction("DeletePortMapping".to_string()))
-                })?,
-                protocol,
-                external_port,
-            ),
-            "DeletePortMappingResponse",
-        ))
+    /// Add a port mapping like `add_port`, then read it back to report how the gateway changed it.
+    ///
+    /// Gateways may silently shorten the lease, truncate or drop the description, or not list the mapping at all,
+    /// which `add_port` does not tell. This strict mode costs a `GetSpecificPortMappingEntry` request, and returns
+    /// the differences found, if any. Nothing is read back with `GatewayOptions::dry_run`.
+    pub fn add_port_verified(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<Vec<MappingWarning>, AddPortError> {
+        self.add_port(protocol, external_port, local_addr, lease_duration, description)?;
+        if self.options.dry_run {
+            return Ok(Vec::new());
+        }
+        match self.get_specific_port_mapping_entry(protocol, external_port) {
+            Ok(entry) => {
+                let lease_duration = common::lease_duration(&self.options, lease_duration);
+                Ok(parsing::check_mapping_entry(&entry, lease_duration, description))
+            }
+            Err(GetSpecificPortMappingEntryError::NoSuchEntryInArray) => Ok(vec![MappingWarning::NotListed]),
+            Err(e) => Err(e.into()),
+        }
     }
 
-    /// Get one port mapping entry
+    /// Add a permanent port mapping, which stays until it is removed or the gateway restarts.
     ///
-    /// Gets one port mapping entry by its index.
-    /// Not all existing port mappings might be visible to this client.
-    /// If the index is out of bound, GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid will be returned
-    pub fn get_generic_port_mapping_entry(
+    /// This is `add_port` with a `lease_duration` of 0. Some gateways only accept such leases, and return
+    /// `AddPortError::OnlyPermanentLeasesSupported` to `add_port_temporary`. A permanent mapping outlives the
+    /// application if it is not removed, so prefer a temporary one that is renewed when the gateway allows it.
+    pub fn add_port_permanent(
         &self,
-        index: u32,
-    ) -> Result<parsing::PortMappingEntry, errors::GetGenericPortMappingEntryError> {
-        parsing::parse_get_generic_port_mapping_entry(self.perform_request(
-            messages::GET_GENERIC_PORT_MAPPING_ENTRY,
-            &messages::formate_get_generic_port_mapping_entry_message(index),
-            "GetGenericPortMappingEntryResponse",
-        ))
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        description: &str,
+    ) -> Result<(), AddPortError> {
+        self.add_port(protocol, external_port, local_addr, 0, description)
     }
-}
+
+    /// Add a port mapping that expires after `lease_duration` seconds.
+    ///
+    /// Unlike `add_port`, a `lease_duration` of 0 is rejected with `AddPortError::LeaseDurationZeroInvalid`
+    /// rather than making the mapping permanent. Gateways that only support permanent leases return
+    /// `AddPortError::OnlyPermanentLeasesSupported`, after which the caller can fall back to `add_port_permanent`.
+    pub fn add_port_temporary(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(), AddPortError> {
+        if lease_duration == 0 {
+            return Err(AddPortError::LeaseDurationZeroInvalid);
+        }
+        self.add_port(protocol, external_port, local_addr, lease_duration, description)
+    }
+
+    /// Find the protocols the gateway accepts mappings of `external_port` for.
+    ///
+    /// Some gateways reject UDP, or TCP, mappings on some ports. For each protocol, a mapping of `external_port`
+    /// to the same port of this host is added with a lease of 30 seconds, or a permanent one if the gateway
+    /// requires it, and removed at once. The mapping is also removed when adding it failed without an answer
+    /// from the gateway. A protocol that already has a mapping of the port, be it listed or reported as a
+    /// conflict (718), is supported without probing further, and that mapping is left untouched.
+    ///
+    /// The gateway answering the probe with another error code means the protocol is not supported. A probe that
+    /// fails otherwise, such as on a lost connection, is skipped and its protocol is not reported.
+    pub fn supported_protocols_for(&self, external_port: u16) -> Result<Vec<PortMappingProtocol>, RequestError> {
+        let local_addr = SocketAddrV4::new(self.local_ip().map_err(RequestError::IoError)?, external_port);
+        let mut supported = Vec::new();
+        for &protocol in &[PortMappingProtocol::TCP, PortMappingProtocol::UDP] {
+            match self.probe_protocol(protocol, local_addr) {
+                Ok(true) => supported.push(protocol),
+                Ok(false) => {}
+                Err(e) => debug!("could not probe {} mappings of port {}: {}", protocol, external_port, e),
+            }
+        }
+        Ok(supported)
+    }
+
+    fn probe_protocol(&self, protocol: PortMappingProtocol, local_addr: SocketAddrV4) -> Result<bool, RequestError> {
+        let external_port = local_addr.port();
+        match self.get_specific_port_mapping_entry(protocol, external_port) {
+            Ok(_) => return Ok(true),
+            Err(GetSpecificPortMappingEntryError::NoSuchEntryInArray) => {}
+            // A conflict when adding the probe still tells about a mapping the lookup missed.
+            Err(e) => debug!(
+                "could not look up the {} mapping of port {}: {}",
+                protocol, external_port, e
+            ),
+        }
+
+        let add = |lease_duration| {
+            self.add_port(
+                protocol,
+                external_port,
+                local_addr,
+                lease_duration,
+                common::PROBE_DESCRIPTION,
+            )
+        };
+        let result = match add(common::PROBE_LEASE_DURATION) {
+            Err(AddPortError::OnlyPermanentLeasesSupported) => add(0),
+            result => result,
+        };
+        match result {
+            Ok(()) => {}
+            Err(AddPortError::PortInUse) => return Ok(true),
+            Err(AddPortError::RequestError(RequestError::ErrorCode { .. })) => return Ok(false),
+            Err(AddPortError::RequestError(e)) => {
+                // The gateway may have added the mapping before the request failed.
+                let _ = self.remove_port(protocol, external_port);
+                return Err(e);
+            }
+            Err(_) => return Ok(false),
+        }
+        match self.remove_port(protocol, external_port) {
+            Ok(()) | Err(RemovePortError::NoSuchPortMapping) => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Add a port mapping that only applies to traffic from `remote_host`.
+    ///
+    /// `add_port` is the same as this function with `RemoteHost::Wildcard`.
+    pub fn add_port_with_remote_host(
+        &self,
+        remote_host: RemoteHost,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(), AddPortError> {
+        if external_port == 0 {
+            return Err(AddPortError::ExternalPortZeroInvalid);
+        }
+        if local_addr.port() == 0 {
+            return Err(AddPortError::InternalPortZeroInvalid);
+        }
+        if self.options.only_local_clients && !common::is_local_address(*local_addr.ip()) {
+            return Err(AddPortError::InternalClientNotLocal);
+        }
+        common::check_description(
+            &self.options,
+            description,
+            AddPortError::DescriptionEmpty,
+            AddPortError::DescriptionNotAscii,
+        )?;
+
+        if self.options.dry_run {
+            if !self.control_schema.contains_key("AddPortMapping") {
+                return Err(AddPortError::RequestError(RequestError::UnsupportedAction(
+                    "AddPortMapping".to_string(),
+                )));
+            }
+            return if self.is_port_available(remote_host, protocol, external_port, local_addr)? {
+                Ok(())
+            } else {
+                Err(AddPortError::PortInUse)
+            };
+        }
+
+        self.add_port_mapping(
+            remote_host,
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        )
+        .map_err(parsing::convert_add_port_error)
+    }
+
+    /// Renew the lease of an existing port mapping.
+    ///
+    /// The mapping is read with `get_specific_port_mapping_entry` and added again with the same internal
+    /// client, internal port, description, remote host and enabled state, for `lease_duration` seconds. A value
+    /// of 0 is infinite.
+    /// `RenewPortError::NoSuchPortMapping` is returned if the gateway dropped the mapping.
+    pub fn renew_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        lease_duration: u32,
+    ) -> Result<(), RenewPortError> {
+        let entry = self.get_specific_port_mapping_entry(protocol, external_port)?;
+        let internal_client = entry
+            .internal_client
+            .parse()
+            .map_err(|_| RequestError::InvalidResponse(format!("Invalid internal client {}", entry.internal_client)))?;
+        if self.options.dry_run {
+            return Ok(());
+        }
+
+        self.add_entry_again(
+            &entry,
+            SocketAddrV4::new(internal_client, entry.internal_port),
+            lease_duration,
+        )
+        .map_err(parsing::convert_renew_port_error)
+    }
+
+    /// Point an existing port mapping to `new_local_addr`, keeping its description, remaining lease, remote host
+    /// and enabled state.
+    ///
+    /// IGD has no action to update a mapping, so the mapping is read with `get_specific_port_mapping_entry` and
+    /// added again with the new internal client. `AddPortMapping` overwrites the entry of the same external port
+    /// at once on most gateways, without the window of a removal followed by an addition during which inbound
+    /// traffic is dropped. Gateways that refuse to give the port to another client answer
+    /// `AddPortError::PortInUse`, and the mapping is left unchanged. A missing mapping is reported as
+    /// `RequestError::ErrorCode` with code 714 (NoSuchEntryInArray).
+    pub fn repoint_mapping(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        new_local_addr: SocketAddrV4,
+    ) -> Result<(), AddPortError> {
+        if new_local_addr.port() == 0 {
+            return Err(AddPortError::InternalPortZeroInvalid);
+        }
+        if self.options.only_local_clients && !common::is_local_address(*new_local_addr.ip()) {
+            return Err(AddPortError::InternalClientNotLocal);
+        }
+        let entry = self.get_specific_port_mapping_entry(protocol, external_port)?;
+        if self.options.dry_run {
+            return Ok(());
+        }
+
+        self.add_entry_again(&entry, new_local_addr, entry.lease_duration)
+            .map_err(parsing::convert_add_port_error)
+    }
+
+    // Add `entry` again for `local_addr` and `lease_duration`, keeping its remote host and whether it is enabled.
+    fn add_entry_again(
+        &self,
+        entry: &parsing::PortMappingEntry,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+    ) -> Result<(), RequestError> {
+        // A remote host name can not be sent back, the mapping then applies to any host.
+        let remote_host = parsing::parse_remote_host(&entry.remote_host).unwrap_or_default();
+        let message = self.format_add_port_mapping(
+            remote_host,
+            entry.protocol,
+            entry.external_port,
+            local_addr,
+            lease_duration,
+            &entry.port_mapping_description,
+        )?;
+        let message = if entry.enabled {
+            message
+        } else {
+            messages::disable_mapping(&message)
+        };
+        self.perform_request(messages::ADD_PORT_MAPPING_ACTION, &message)?;
+        Ok(())
+    }
+
+    /// Remove a port mapping.
+    ///
+    /// A gateway answering with an empty body is taken to have removed it, even though some gateways answer so
+    /// when there was no such mapping.
+    pub fn remove_port(&self, protocol: PortMappingProtocol, external_port: u16) -> Result<(), RemovePortError> {
+        self.remove_port_with_remote_host(RemoteHost::Wildcard, protocol, external_port)
+    }
+
+    /// Remove a port mapping added for `remote_host`.
+    pub fn remove_port_with_remote_host(
+        &self,
+        remote_host: RemoteHost,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<(), RemovePortError> {
+        if self.options.dry_run {
+            if !self.control_schema.contains_key("DeletePortMapping") {
+                return Err(RemovePortError::RequestError(RequestError::UnsupportedAction(
+                    "DeletePortMapping".to_string(),
+                )));
+            }
+            self.get_specific_port_mapping_entry_with_remote_host(remote_host, protocol, external_port)?;
+            return Ok(());
+        }
+
+        parsing::parse_delete_port_mapping_response(self.perform_request(
+            messages::DELETE_PORT_MAPPING_ACTION,
+            &messages::format_delete_port_message(
+                self.control_schema.get("DeletePortMapping").ok_or_else(|| {
+                    RemovePortError::RequestError(RequestError::UnsupportedAction("DeletePortMapping".to_string()))
+                })?,
+                remote_host,
+                protocol,
+                external_port,
+            ),
+        ))
+    }
+
+    /// Remove the port mapping of an entry listed by `port_mappings`, with its remote host.
+    ///
+    /// A mapping that disappeared since it was listed is taken as removed. An entry whose remote host is a host
+    /// name can not be passed back to the gateway, and is reported as `RequestError::InvalidResponse`.
+    pub fn remove_entry(&self, entry: &parsing::PortMappingEntry) -> Result<(), RemovePortError> {
+        let remote_host = parsing::parse_remote_host(&entry.remote_host).ok_or_else(|| {
+            RemovePortError::RequestError(RequestError::InvalidResponse(format!(
+                "remote host {} is not an IP address",
+                entry.remote_host
+            )))
+        })?;
+        match self.remove_port_with_remote_host(remote_host, entry.protocol, entry.external_port) {
+            Err(RemovePortError::NoSuchPortMapping) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Remove the mappings of `protocol` whose external port is between `start_port` and `end_port`, in one
+    /// request
+    ///
+    /// `DeletePortMappingRange` is an action of `WANIPConnection:2`, like `get_list_of_port_mappings`: other
+    /// gateways answer with a `RequestError`. With `manage`, the mappings of all the clients are removed, which
+    /// the gateway may not authorize, otherwise only those of this host. A range without any mapping is
+    /// reported as `RemovePortError::NoSuchPortMapping`.
+    pub fn remove_port_range(
+        &self,
+        protocol: PortMappingProtocol,
+        start_port: u16,
+        end_port: u16,
+        manage: bool,
+    ) -> Result<(), RemovePortError> {
+        if self.options.dry_run {
+            return match self
+                .get_list_of_port_mappings(protocol, start_port, end_port, manage, 1)
+                .map_err(RemovePortError::RequestError)?
+            {
+                entries if entries.is_empty() => Err(RemovePortError::NoSuchPortMapping),
+                _ => Ok(()),
+            };
+        }
+
+        parsing::parse_delete_port_mapping_response(self.perform_request(
+            messages::DELETE_PORT_MAPPING_RANGE_ACTION,
+            &messages::format_delete_port_range_message(protocol, start_port, end_port, manage),
+        ))
+    }
+
+    /// Get one port mapping entry
+    ///
+    /// Gets one port mapping entry by its index.
+    /// Not all existing port mappings might be visible to this client.
+    /// If the index is out of bound, GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid will be returned
+    pub fn get_generic_port_mapping_entry(
+        &self,
+        index: u32,
+    ) -> Result<parsing::PortMappingEntry, errors::GetGenericPortMappingEntryError> {
+        parsing::parse_get_generic_port_mapping_entry(self.perform_request(
+            messages::GET_GENERIC_PORT_MAPPING_ENTRY_ACTION,
+            &messages::formate_get_generic_port_mapping_entry_message(index),
+        ))
+    }
+
+    /// Iterate over the port mapping entries
+    ///
+    /// Entries are fetched one by one with `get_generic_port_mapping_entry`, until the gateway reports the
+    /// end of the list. The iteration stops after the first error. It also stops when the gateway answers the
+    /// previous entry again, as some ignore the index, and after 131072 entries, two per external port.
+    pub fn port_mappings(&self) -> PortMappingEntries<'_> {
+        PortMappingEntries {
+            gateway: self,
+            index: 0,
+            last: None,
+            done: false,
+        }
+    }
+
+    /// Get all the port mapping entries accepted by `filter`
+    pub fn find_mappings<F>(&self, filter: F) -> Result<Vec<parsing::PortMappingEntry>, GetGenericPortMappingEntryError>
+    where
+        F: Fn(&parsing::PortMappingEntry) -> bool,
+    {
+        let mut entries = Vec::new();
+        for entry in self.port_mappings() {
+            let entry = entry?;
+            if filter(&entry) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Get all the port mapping entries whose internal client is `ip`
+    pub fn find_mappings_for(
+        &self,
+        ip: Ipv4Addr,
+    ) -> Result<Vec<parsing::PortMappingEntry>, GetGenericPortMappingEntryError> {
+        let ip = ip.to_string();
+        self.find_mappings(|entry| entry.internal_client == ip)
+    }
+
+    /// Remove all the port mappings whose internal client is `internal_client`, and return how many were removed.
+    ///
+    /// This cleans up the mappings left under the previous address of a host whose DHCP lease changed. The
+    /// mappings are removed like with `prune_mappings`, which tells the mappings that could not be removed: here
+    /// they are only logged and not counted. An error is returned if the mappings could not be listed.
+    pub fn remove_mappings_for(&self, internal_client: Ipv4Addr) -> Result<usize, RequestError> {
+        let internal_client = internal_client.to_string();
+        let results = self.prune_mappings(|entry| entry.internal_client == internal_client)?;
+        for (entry, e) in &results.failures {
+            warn!(
+                "could not remove the {} mapping of port {}: {}",
+                entry.protocol, entry.external_port, e
+            );
+        }
+        Ok(results.removed)
+    }
+
+    /// Remove all the port mappings accepted by `predicate`, such as `PortMappingEntry::description_starts_with`.
+    ///
+    /// This reclaims the mappings leaked by crashed processes, before they fill the table of the gateway. The
+    /// mappings are listed first, then removed one by one. Those that disappeared in between are not counted,
+    /// and those with a host name as remote host are skipped, as they can not be passed back to the gateway.
+    /// The other removal errors do not stop the pruning, and are returned in `PruneResults::failures`.
+    pub fn prune_mappings<F>(&self, predicate: F) -> Result<PruneResults, GetGenericPortMappingEntryError>
+    where
+        F: Fn(&parsing::PortMappingEntry) -> bool,
+    {
+        let mut results = PruneResults {
+            removed: 0,
+            failures: Vec::new(),
+        };
+        for entry in self.find_mappings(predicate)? {
+            let remote_host = match parsing::parse_remote_host(&entry.remote_host) {
+                Some(remote_host) => remote_host,
+                None => {
+                    debug!("not removing mapping with remote host {}", entry.remote_host);
+                    continue;
+                }
+            };
+            match self.remove_port_with_remote_host(remote_host, entry.protocol, entry.external_port) {
+                Ok(()) => results.removed += 1,
+                Err(RemovePortError::NoSuchPortMapping) => {}
+                Err(e) => results.failures.push((entry, e)),
+            }
+        }
+        Ok(results)
+    }
+
+    /// Whether the port mapping of a protocol and external port is enabled, from its `NewEnabled` field.
+    ///
+    /// The mappings added by this crate are enabled, but some firmware disables them, or builds its own table
+    /// ignoring the flag. A missing mapping is reported as `RequestError::ErrorCode` with code 714
+    /// (NoSuchEntryInArray).
+    pub fn is_mapping_enabled(&self, protocol: PortMappingProtocol, external_port: u16) -> Result<bool, RequestError> {
+        Ok(self.get_specific_port_mapping_entry(protocol, external_port)?.enabled)
+    }
+
+    /// Find the lowest external port of `range` that has no mapping for `protocol`
+    ///
+    /// The ports are asked one by one with `get_specific_port_mapping_entry`, so this costs a request per mapped
+    /// port, and port 0 is skipped. Returns `None` when all the ports of the range are mapped. This is racy:
+    /// another client may map the port before it is added, so the `add_port` that follows must handle
+    /// `AddPortError::PortInUse`.
+    pub fn find_free_port(
+        &self,
+        protocol: PortMappingProtocol,
+        range: Range<u16>,
+    ) -> Result<Option<u16>, RequestError> {
+        for port in range.filter(|&port| port != 0) {
+            match self.get_specific_port_mapping_entry(protocol, port) {
+                Ok(_) => {}
+                Err(GetSpecificPortMappingEntryError::NoSuchEntryInArray) => return Ok(Some(port)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get the port mapping entry of a protocol and external port
+    ///
+    /// If there is no such port mapping, GetSpecificPortMappingEntryError::NoSuchEntryInArray will be returned
+    pub fn get_specific_port_mapping_entry(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<parsing::PortMappingEntry, GetSpecificPortMappingEntryError> {
+        self.get_specific_port_mapping_entry_with_remote_host(RemoteHost::Wildcard, protocol, external_port)
+    }
+
+    /// Get the port mapping entry of a remote host, protocol and external port
+    pub fn get_specific_port_mapping_entry_with_remote_host(
+        &self,
+        remote_host: RemoteHost,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<parsing::PortMappingEntry, GetSpecificPortMappingEntryError> {
+        let schema = self
+            .control_schema
+            .get("GetSpecificPortMappingEntry")
+            .ok_or_else(|| RequestError::UnsupportedAction("GetSpecificPortMappingEntry".to_string()))?;
+        parsing::parse_get_specific_port_mapping_entry(
+            self.perform_request(
+                messages::GET_SPECIFIC_PORT_MAPPING_ENTRY_ACTION,
+                &messages::format_get_specific_port_mapping_entry_message(schema, remote_host, protocol, external_port),
+            ),
+            remote_host,
+            protocol,
+            external_port,
+        )
+    }
+
+    /// Get the mappings of `protocol` whose external port is between `start_port` and `end_port`, in one request
+    ///
+    /// `GetListOfPortMappings` is an action of `WANIPConnection:2`, see `SearchOptions::prefer_igd_v2`: other
+    /// gateways answer with a `RequestError`, and the mappings are listed with `port_mappings` instead. With
+    /// `manage`, the mappings of all the clients are listed, which the gateway may not authorize, otherwise
+    /// only those of this host. At most `max_entries` are returned: the next page starts after the last external
+    /// port returned.
+    pub fn get_list_of_port_mappings(
+        &self,
+        protocol: PortMappingProtocol,
+        start_port: u16,
+        end_port: u16,
+        manage: bool,
+        max_entries: u16,
+    ) -> Result<Vec<parsing::PortMappingEntry>, RequestError> {
+        parsing::parse_get_list_of_port_mappings_response(self.perform_request(
+            messages::GET_LIST_OF_PORT_MAPPINGS_ACTION,
+            &messages::format_get_list_of_port_mappings_message(protocol, start_port, end_port, manage, max_entries),
+        ))
+    }
+}
+
+/// Iterator over the port mapping entries of a gateway, see `Gateway::port_mappings`
+pub struct PortMappingEntries<'a> {
+    gateway: &'a Gateway,
+    index: u32,
+    last: Option<parsing::PortMappingEntry>,
+    done: bool,
+}
+
+impl<'a> Iterator for PortMappingEntries<'a> {
+    type Item = Result<parsing::PortMappingEntry, GetGenericPortMappingEntryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.index >= common::MAX_PORT_MAPPING_ENTRIES {
+            return None;
+        }
+
+        match self.gateway.get_generic_port_mapping_entry(self.index) {
+            Ok(entry) if self.last.as_ref() == Some(&entry) => {
+                self.done = true;
+                None
+            }
+            Ok(entry) => {
+                self.index += 1;
+                self.last = Some(entry.clone());
+                Some(Ok(entry))
+            }
+            Err(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
 
 impl fmt::Display for Gateway {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "http://{}{}", self.addr, self.control_url)
     }
 }
+
+#[cfg(test)]
+use crate::transport::HttpResponse;
+
+// Transport answering the requests with canned responses, in order.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct MockTransport {
+    responses: std::sync::Mutex<std::collections::VecDeque<Result<HttpResponse, RequestError>>>,
+    headers: std::sync::Mutex<Vec<String>>,
+    bodies: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub(crate) fn new(responses: Vec<HttpResponse>) -> Arc<MockTransport> {
+        MockTransport::with_results(responses.into_iter().map(Ok).collect())
+    }
+
+    // Like `new`, and the errors are returned as if the request failed on the way.
+    pub(crate) fn with_results(results: Vec<Result<HttpResponse, RequestError>>) -> Arc<MockTransport> {
+        Arc::new(MockTransport {
+            responses: std::sync::Mutex::new(results.into()),
+            headers: Default::default(),
+            bodies: Default::default(),
+        })
+    }
+
+    pub(crate) fn ok(action: &str, args: &str) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            body: format!(
+                r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<u:{action}Response xmlns:u="urn:schemas-upnp-org:service:WANIPConnection:1">{args}</u:{action}Response>
+</s:Body>
+</s:Envelope>"#,
+                action = action,
+                args = args
+            ),
+        }
+    }
+
+    pub(crate) fn fault(code: u16) -> HttpResponse {
+        HttpResponse {
+            status: 500,
+            body: format!(
+                r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<s:Fault>
+<faultcode>s:Client</faultcode>
+<faultstring>UPnPError</faultstring>
+<detail>
+<UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+<errorCode>{}</errorCode>
+<errorDescription>Error</errorDescription>
+</UPnPError>
+</detail>
+</s:Fault>
+</s:Body>
+</s:Envelope>"#,
+                code
+            ),
+        }
+    }
+
+    // An entry of the mapping table, as listed by `GetGenericPortMappingEntry`.
+    pub(crate) fn generic_entry(
+        protocol: PortMappingProtocol,
+        port: u16,
+        internal_client: &str,
+        description: &str,
+    ) -> HttpResponse {
+        MockTransport::ok(
+            "GetGenericPortMappingEntry",
+            &format!(
+                "<NewRemoteHost></NewRemoteHost><NewExternalPort>{port}</NewExternalPort>\
+                 <NewProtocol>{protocol}</NewProtocol><NewInternalPort>{port}</NewInternalPort><NewInternalClient>{internal_client}</NewInternalClient>\
+                 <NewEnabled>1</NewEnabled><NewPortMappingDescription>{description}</NewPortMappingDescription>\
+                 <NewLeaseDuration>0</NewLeaseDuration>",
+            ),
+        )
+    }
+
+    // The answer of `GetSpecificPortMappingEntry` for a mapping to port 8080 of `internal_client`.
+    pub(crate) fn entry(internal_client: &str, enabled: bool, description: &str, lease_duration: u32) -> HttpResponse {
+        MockTransport::ok(
+            "GetSpecificPortMappingEntry",
+            &format!(
+                "<NewInternalPort>8080</NewInternalPort><NewInternalClient>{}</NewInternalClient>\
+                 <NewEnabled>{}</NewEnabled><NewPortMappingDescription>{}</NewPortMappingDescription>\
+                 <NewLeaseDuration>{}</NewLeaseDuration>",
+                internal_client, enabled as u8, description, lease_duration
+            ),
+        )
+    }
+
+    // The actions requested so far, without their service type.
+    pub(crate) fn actions(&self) -> Vec<String> {
+        let headers = self.headers.lock().unwrap();
+        headers
+            .iter()
+            .map(|header| {
+                header
+                    .trim_matches('"')
+                    .rsplit('#')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    // The SOAPAction headers sent so far.
+    fn headers(&self) -> Vec<String> {
+        self.headers.lock().unwrap().clone()
+    }
+
+    // The bodies of the actions sent so far.
+    fn bodies(&self) -> Vec<String> {
+        self.bodies.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Transport for MockTransport {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, RequestError> {
+        if let Some((_, header)) = request.headers.iter().find(|(name, _)| *name == "SOAPAction") {
+            self.headers.lock().unwrap().push(header.clone());
+            self.bodies.lock().unwrap().push(request.body.clone());
+        }
+        self.responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| Err(RequestError::InvalidResponse("no more responses".into())))
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn mock_gateway(transport: Arc<MockTransport>) -> Gateway {
+    Gateway::with_transport("192.168.1.1:5000".parse().unwrap(), "/ctl/IPConn".into(), transport).unwrap()
+}
+
+// A gateway answering `responses`, with its transport to check the requests.
+#[cfg(test)]
+fn mock_gateway_with(responses: Vec<HttpResponse>) -> (Arc<MockTransport>, Gateway) {
+    let transport = MockTransport::new(responses);
+    (transport.clone(), mock_gateway(transport))
+}
+
+#[test]
+fn test_add_any_port_retries_conflicts() {
+    let (transport, mut gateway) = mock_gateway_with(vec![
+        MockTransport::fault(718),
+        MockTransport::fault(718),
+        MockTransport::ok("AddPortMapping", ""),
+    ]);
+    gateway.options.port_rng = Some(crate::PortRng::seed_from_u64(1));
+
+    let port = gateway
+        .add_any_port(PortMappingProtocol::TCP, "192.168.1.2:8080".parse().unwrap(), 0, "test")
+        .unwrap();
+
+    let expected = crate::PortRng::seed_from_u64(1);
+    let expected: Vec<u16> = (0..3).map(|_| expected.random_port()).collect();
+    assert_eq!(port, expected[2]);
+    assert_eq!(transport.actions(), vec!["AddPortMapping"; 3]);
+}
+
+#[test]
+fn test_add_any_port_falls_back_to_same_port() {
+    let (transport, gateway) =
+        mock_gateway_with(vec![MockTransport::fault(724), MockTransport::ok("AddPortMapping", "")]);
+
+    let port = gateway
+        .add_any_port(PortMappingProtocol::UDP, "192.168.1.2:8080".parse().unwrap(), 0, "test")
+        .unwrap();
+
+    assert_eq!(port, 8080);
+    assert_eq!(transport.actions(), vec!["AddPortMapping"; 2]);
+}
+
+#[test]
+fn test_add_any_port_only_permanent_leases() {
+    let (transport, gateway) = mock_gateway_with(vec![MockTransport::fault(725)]);
+
+    let result = gateway.add_any_port(
+        PortMappingProtocol::TCP,
+        "192.168.1.2:8080".parse().unwrap(),
+        3600,
+        "test",
+    );
+
+    assert!(matches!(result, Err(AddAnyPortError::OnlyPermanentLeasesSupported)));
+    assert_eq!(transport.actions(), vec!["AddPortMapping"]);
+}
+
+#[test]
+fn test_add_any_port_falls_back_from_invalid_action() {
+    let (transport, mut gateway) =
+        mock_gateway_with(vec![MockTransport::fault(401), MockTransport::ok("AddPortMapping", "")]);
+    gateway.control_schema.insert(
+        "AddAnyPortMapping".into(),
+        gateway.control_schema["AddPortMapping"].clone(),
+    );
+
+    gateway
+        .add_any_port(PortMappingProtocol::TCP, "192.168.1.2:8080".parse().unwrap(), 0, "test")
+        .unwrap();
+
+    assert_eq!(transport.actions(), vec!["AddAnyPortMapping", "AddPortMapping"]);
+}
+
+#[test]
+fn test_supports_add_any_port() {
+    let (transport, mut gateway) = mock_gateway_with(vec![
+        MockTransport::ok("AddAnyPortMapping", "<NewReservedPort>40000</NewReservedPort>"),
+        MockTransport::fault(401),
+        MockTransport::ok("AddPortMapping", ""),
+        MockTransport::ok("AddPortMapping", ""),
+    ]);
+    let local_addr = "192.168.1.2:8080".parse().unwrap();
+    assert_eq!(gateway.supports_add_any_port(), Some(false));
+
+    gateway.control_schema.insert(
+        "AddAnyPortMapping".into(),
+        gateway.control_schema["AddPortMapping"].clone(),
+    );
+    assert_eq!(gateway.supports_add_any_port(), None);
+    gateway
+        .add_any_port(PortMappingProtocol::TCP, local_addr, 0, "test")
+        .unwrap();
+    assert_eq!(gateway.supports_add_any_port(), Some(true));
+
+    // A gateway that stopped implementing the action, as after a firmware update, is probed only once.
+    *gateway.add_any_port_support.lock().unwrap() = None;
+    let clone = gateway.clone();
+    clone
+        .add_any_port(PortMappingProtocol::TCP, local_addr, 0, "test")
+        .unwrap();
+    gateway
+        .add_any_port(PortMappingProtocol::TCP, local_addr, 0, "test")
+        .unwrap();
+    assert_eq!(gateway.supports_add_any_port(), Some(false));
+    assert_eq!(
+        transport.actions(),
+        vec![
+            "AddAnyPortMapping",
+            "AddAnyPortMapping",
+            "AddPortMapping",
+            "AddPortMapping"
+        ]
+    );
+}
+
+#[test]
+fn test_add_port_temporary_rejects_zero_lease() {
+    let (transport, gateway) = mock_gateway_with(vec![MockTransport::ok("AddPortMapping", "")]);
+    let local_addr = "192.168.1.2:8080".parse().unwrap();
+
+    let result = gateway.add_port_temporary(PortMappingProtocol::TCP, 8080, local_addr, 0, "test");
+    assert!(matches!(result, Err(AddPortError::LeaseDurationZeroInvalid)));
+    assert!(transport.actions().is_empty());
+
+    gateway
+        .add_port_permanent(PortMappingProtocol::TCP, 8080, local_addr, "test")
+        .unwrap();
+    assert_eq!(transport.actions(), vec!["AddPortMapping"]);
+}
+
+#[test]
+fn test_remove_mappings_for() {
+    let entry = |port, client| MockTransport::generic_entry(PortMappingProtocol::TCP, port, client, "test");
+    let (transport, gateway) = mock_gateway_with(vec![
+        entry(8080, "192.168.1.2"),
+        entry(8081, "192.168.1.3"),
+        entry(8082, "192.168.1.2"),
+        MockTransport::fault(713),
+        MockTransport::ok("DeletePortMapping", ""),
+        MockTransport::fault(606),
+    ]);
+
+    // The mapping that could not be removed is not counted, and does not lose the count of the others.
+    assert_eq!(gateway.remove_mappings_for(Ipv4Addr::new(192, 168, 1, 2)).unwrap(), 1);
+    let mut expected = vec!["GetGenericPortMappingEntry"; 4];
+    expected.extend(["DeletePortMapping"; 2]);
+    assert_eq!(transport.actions(), expected);
+}
+
+#[test]
+fn test_prune_mappings() {
+    let entry =
+        |port, description| MockTransport::generic_entry(PortMappingProtocol::UDP, port, "192.168.1.2", description);
+    let (_, gateway) = mock_gateway_with(vec![
+        entry(8080, "myapp session 1"),
+        entry(8081, "other"),
+        entry(8082, "myapp session 2"),
+        MockTransport::fault(713),
+        MockTransport::fault(606),
+        MockTransport::ok("DeletePortMapping", ""),
+    ]);
+
+    let results = gateway
+        .prune_mappings(parsing::PortMappingEntry::description_starts_with("myapp"))
+        .unwrap();
+
+    assert_eq!(results.removed, 1);
+    assert_eq!(results.failures.len(), 1);
+    let (entry, error) = &results.failures[0];
+    assert_eq!(entry.external_port, 8080);
+    assert!(matches!(error, RemovePortError::ActionNotAuthorized));
+}
+
+#[test]
+fn test_add_port_verified_reports_clamped_lease() {
+    let (transport, gateway) = mock_gateway_with(vec![
+        MockTransport::ok("AddPortMapping", ""),
+        MockTransport::entry("192.168.1.2", true, "test", 600),
+    ]);
+
+    let warnings = gateway
+        .add_port_verified(
+            PortMappingProtocol::TCP,
+            8080,
+            "192.168.1.2:8080".parse().unwrap(),
+            3600,
+            "test",
+        )
+        .unwrap();
+
+    assert_eq!(
+        warnings,
+        vec![MappingWarning::LeaseClamped {
+            requested: 3600,
+            granted: 600
+        }]
+    );
+    assert_eq!(
+        transport.actions(),
+        vec!["AddPortMapping", "GetSpecificPortMappingEntry"]
+    );
+}
+
+#[test]
+fn test_supported_protocols_for() {
+    let transport = MockTransport::new(vec![
+        MockTransport::fault(714),
+        MockTransport::fault(725),
+        MockTransport::ok("AddPortMapping", ""),
+        MockTransport::ok("DeletePortMapping", ""),
+        // A failed lookup, then a conflict: UDP is mapped already.
+        MockTransport::fault(501),
+        MockTransport::fault(718),
+    ]);
+    let gateway = Gateway::with_transport(
+        "127.0.0.1:5000".parse().unwrap(),
+        "/ctl/IPConn".into(),
+        transport.clone(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        gateway.supported_protocols_for(4433).unwrap(),
+        vec![PortMappingProtocol::TCP, PortMappingProtocol::UDP]
+    );
+    assert_eq!(
+        transport.actions(),
+        vec![
+            "GetSpecificPortMappingEntry",
+            "AddPortMapping",
+            "AddPortMapping",
+            "DeletePortMapping",
+            "GetSpecificPortMappingEntry",
+            "AddPortMapping",
+        ]
+    );
+    // Without any answer left, the probes fail and are skipped.
+    assert_eq!(gateway.supported_protocols_for(4433).unwrap(), vec![]);
+}
+
+#[test]
+fn test_get_any_mapping_reports_fallback() {
+    let (_, gateway) = mock_gateway_with(vec![
+        MockTransport::ok(
+            "GetExternalIPAddress",
+            "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>",
+        ),
+        MockTransport::fault(724),
+        MockTransport::ok("AddPortMapping", ""),
+        MockTransport::entry("192.168.1.2", true, "test", 600),
+    ]);
+
+    let mapping = gateway
+        .get_any_mapping(
+            PortMappingProtocol::TCP,
+            "192.168.1.2:8080".parse().unwrap(),
+            3600,
+            "test",
+        )
+        .unwrap();
+
+    assert_eq!(
+        mapping,
+        MappingResult {
+            external_addr: "203.0.113.7:8080".parse().unwrap(),
+            lease_duration: 600,
+            used_legacy_fallback: true,
+            same_port_forced: true,
+        }
+    );
+}
+
+#[test]
+fn test_get_any_mapping_reports_random_port() {
+    let (_, mut gateway) = mock_gateway_with(vec![
+        MockTransport::ok(
+            "GetExternalIPAddress",
+            "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>",
+        ),
+        MockTransport::ok("AddPortMapping", ""),
+        MockTransport::fault(714),
+    ]);
+    gateway.options.port_rng = Some(crate::PortRng::seed_from_u64(1));
+    gateway.options.permanent_lease_duration = 604800;
+
+    let mapping = gateway
+        .get_any_mapping(PortMappingProtocol::UDP, "192.168.1.2:8080".parse().unwrap(), 0, "test")
+        .unwrap();
+
+    let expected = crate::PortRng::seed_from_u64(1).random_port();
+    assert_eq!(
+        mapping.external_addr,
+        SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), expected)
+    );
+    assert_eq!(mapping.lease_duration, 604800);
+    assert!(mapping.used_legacy_fallback);
+    assert!(!mapping.same_port_forced);
+}
+
+#[test]
+fn test_soap_action_retried_without_quotes() {
+    let (transport, gateway) = mock_gateway_with(vec![
+        HttpResponse {
+            status: 500,
+            body: "Invalid SOAPAction".into(),
+        },
+        MockTransport::ok(
+            "GetExternalIPAddress",
+            "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>",
+        ),
+        HttpResponse {
+            status: 500,
+            body: "Internal error".into(),
+        },
+    ]);
+
+    assert_eq!(gateway.get_external_ip().unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+    // The gateway may have added the mapping before failing, so it is not added twice.
+    assert!(gateway
+        .add_port(
+            PortMappingProtocol::TCP,
+            8080,
+            "192.168.1.2:8080".parse().unwrap(),
+            0,
+            "test"
+        )
+        .is_err());
+    assert_eq!(
+        transport.headers(),
+        vec![
+            "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress\"",
+            "urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress",
+            "\"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping\"",
+        ]
+    );
+}
+
+#[test]
+fn test_is_reachable() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let gateway = Gateway::new(listener.local_addr().unwrap(), "/ctl/IPConn".into()).unwrap();
+    assert!(gateway.is_reachable(Duration::from_secs(1)));
+
+    drop(listener);
+    assert!(!gateway.is_reachable(Duration::from_secs(1)));
+}
+
+#[test]
+fn test_service_type_ip_connection() {
+    let (transport, gateway) = mock_gateway_with(vec![
+        MockTransport::ok(
+            "GetExternalIPAddress",
+            "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>",
+        ),
+        MockTransport::fault(401),
+    ]);
+
+    gateway.get_external_ip().unwrap();
+    // The service type is confirmed, so the gateway is not probed for WANPPPConnection:1 anymore.
+    assert!(gateway
+        .add_port(
+            PortMappingProtocol::TCP,
+            8080,
+            "192.168.1.2:8080".parse().unwrap(),
+            0,
+            "test"
+        )
+        .is_err());
+
+    let ip_connection = "\"urn:schemas-upnp-org:service:WANIPConnection:1#";
+    assert_eq!(transport.headers().len(), 2);
+    assert!(transport
+        .headers()
+        .iter()
+        .all(|header| header.starts_with(ip_connection)));
+    assert_eq!(gateway.service_type(), messages::SERVICE_TYPE);
+}
+
+#[test]
+fn test_service_type_ppp_connection() {
+    let (transport, gateway) = mock_gateway_with(vec![
+        MockTransport::fault(401),
+        MockTransport::ok(
+            "GetExternalIPAddress",
+            "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>",
+        ),
+        MockTransport::ok("AddPortMapping", ""),
+    ]);
+
+    assert_eq!(gateway.get_external_ip().unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+    assert_eq!(gateway.service_type(), messages::WAN_PPP_CONNECTION);
+    // Clones share the service type that worked.
+    gateway
+        .clone()
+        .add_port(
+            PortMappingProtocol::TCP,
+            8080,
+            "192.168.1.2:8080".parse().unwrap(),
+            0,
+            "test",
+        )
+        .unwrap();
+
+    assert_eq!(
+        transport.headers(),
+        vec![
+            "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress\"",
+            "\"urn:schemas-upnp-org:service:WANPPPConnection:1#GetExternalIPAddress\"",
+            "\"urn:schemas-upnp-org:service:WANPPPConnection:1#AddPortMapping\"",
+        ]
+    );
+}
+
+#[test]
+fn test_service_type_fallback_on_not_found() {
+    let (transport, gateway) = mock_gateway_with(vec![
+        HttpResponse {
+            status: 404,
+            body: "<html>Not Found</html>".into(),
+        },
+        MockTransport::ok(
+            "GetExternalIPAddress",
+            "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>",
+        ),
+    ]);
+
+    assert_eq!(gateway.get_external_ip().unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+    assert_eq!(gateway.service_type(), messages::WAN_PPP_CONNECTION);
+    assert_eq!(
+        transport.headers(),
+        vec![
+            "\"urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress\"",
+            "\"urn:schemas-upnp-org:service:WANPPPConnection:1#GetExternalIPAddress\"",
+        ]
+    );
+}
+
+#[test]
+fn test_remove_port_empty_response() {
+    let empty = |body: &str| HttpResponse {
+        status: 200,
+        body: body.into(),
+    };
+    let (_, gateway) = mock_gateway_with(vec![empty(""), empty(" \r\n"), empty("<html></html>")]);
+
+    gateway.remove_port(PortMappingProtocol::TCP, 8080).unwrap();
+    gateway.remove_port(PortMappingProtocol::UDP, 8080).unwrap();
+    assert!(matches!(
+        gateway.remove_port(PortMappingProtocol::TCP, 8080),
+        Err(RemovePortError::RequestError(RequestError::NotSoap(_)))
+    ));
+}
+
+#[test]
+fn test_response_filter() {
+    // An unescaped ampersand in a vendor field makes the whole response invalid XML.
+    let broken = MockTransport::ok(
+        "GetExternalIPAddress",
+        "<NewExternalIPAddress>1.2.3.4</NewExternalIPAddress><X_Vendor>AT & T</X_Vendor>",
+    );
+    let (_, mut gateway) = mock_gateway_with(vec![broken.clone(), broken]);
+
+    assert!(gateway.get_external_ip().is_err());
+    gateway.options.response_filter = Some(crate::ResponseFilter::new(|text| text.replace(" & ", " &amp; ")));
+    assert_eq!(gateway.get_external_ip().unwrap(), Ipv4Addr::new(1, 2, 3, 4));
+}
+
+#[test]
+fn test_forward_port() {
+    let transport = MockTransport::new(vec![MockTransport::ok("AddPortMapping", "")]);
+    let gateway = Gateway::with_transport(
+        "127.0.0.1:5000".parse().unwrap(),
+        "/ctl/IPConn".into(),
+        transport.clone(),
+    )
+    .unwrap();
+
+    let local_addr = gateway
+        .forward_port(PortMappingProtocol::TCP, 8080, 9000, 0, "test")
+        .unwrap();
+    assert_eq!(local_addr, SocketAddrV4::new(gateway.local_ip().unwrap(), 9000));
+    assert_eq!(local_addr, "127.0.0.1:9000".parse().unwrap());
+    assert_eq!(transport.actions(), vec!["AddPortMapping"]);
+}
+
+#[test]
+fn test_is_mapping_enabled() {
+    let (_, gateway) = mock_gateway_with(vec![
+        MockTransport::entry("192.168.1.2", true, "test", 0),
+        MockTransport::entry("192.168.1.2", false, "test", 0),
+        MockTransport::fault(714),
+    ]);
+
+    assert!(gateway.is_mapping_enabled(PortMappingProtocol::TCP, 8080).unwrap());
+    assert!(!gateway.is_mapping_enabled(PortMappingProtocol::TCP, 8080).unwrap());
+    assert!(matches!(
+        gateway.is_mapping_enabled(PortMappingProtocol::TCP, 8080),
+        Err(RequestError::ErrorCode { code: 714, .. })
+    ));
+}
+
+#[test]
+fn test_wait_for_connection() {
+    let status = |status: &str| {
+        MockTransport::ok(
+            "GetStatusInfo",
+            &format!(
+                "<NewConnectionStatus>{}</NewConnectionStatus><NewLastConnectionError>ERROR_NONE</NewLastConnectionError>\
+                 <NewUptime>0</NewUptime>",
+                status
+            ),
+        )
+    };
+    let ip = |ip: &str| {
+        MockTransport::ok(
+            "GetExternalIPAddress",
+            &format!("<NewExternalIPAddress>{}</NewExternalIPAddress>", ip),
+        )
+    };
+    let (_, gateway) = mock_gateway_with(vec![
+        status("Connecting"),
+        status("Connected"),
+        ip("0.0.0.0"),
+        // Behind another NAT.
+        status("Connected"),
+        ip("100.64.0.2"),
+        // Without GetStatusInfo, the address alone tells.
+        MockTransport::fault(401),
+        ip("192.168.0.2"),
+        MockTransport::fault(401),
+        ip("203.0.113.1"),
+        MockTransport::fault(401),
+        ip(""),
+    ]);
+    let interval = Duration::from_millis(1);
+
+    let ip = gateway.wait_for_connection(Duration::from_secs(5), interval).unwrap();
+    assert_eq!(ip, Ipv4Addr::new(100, 64, 0, 2));
+    let ip = gateway.wait_for_connection(Duration::from_secs(5), interval).unwrap();
+    assert_eq!(ip, Ipv4Addr::new(203, 0, 113, 1));
+    assert!(matches!(
+        gateway.wait_for_connection(Duration::from_secs(0), interval),
+        Err(GetExternalIpError::Timeout)
+    ));
+}
+
+#[test]
+fn test_http_status() {
+    let status = |status: u16| HttpResponse {
+        status,
+        body: "<html>Error</html>".into(),
+    };
+    // A 500 is sent again with the unquoted SOAPAction header.
+    let (_, gateway) = mock_gateway_with(vec![
+        status(500),
+        status(500),
+        status(503),
+        status(401),
+        MockTransport::fault(718),
+    ]);
+    let err = |result: Result<Ipv4Addr, GetExternalIpError>| match result {
+        Err(GetExternalIpError::RequestError(e)) => e,
+        result => panic!("unexpected result: {:?}", result),
+    };
+
+    let e = err(gateway.get_external_ip());
+    assert!(matches!(e, RequestError::Http(s) if s.as_u16() == 500));
+    assert_eq!(e.http_status(), Some(500));
+    assert!(e.is_retriable());
+    assert!(matches!(err(gateway.get_external_ip()), RequestError::Http(s) if s.as_u16() == 503));
+    assert_eq!(err(gateway.get_external_ip()).http_status(), Some(401));
+    assert_eq!(err(gateway.get_external_ip()).http_status(), None);
+}
+
+#[test]
+fn test_find_free_port() {
+    let entry = MockTransport::entry("192.168.1.3", true, "test", 0);
+    let (transport, gateway) = mock_gateway_with(vec![
+        entry.clone(),
+        entry.clone(),
+        MockTransport::fault(714),
+        entry.clone(),
+        entry,
+    ]);
+
+    assert_eq!(
+        gateway.find_free_port(PortMappingProtocol::TCP, 8080..8090).unwrap(),
+        Some(8082)
+    );
+    assert_eq!(gateway.find_free_port(PortMappingProtocol::TCP, 0..2).unwrap(), None);
+    assert_eq!(transport.actions().len(), 4);
+    assert_eq!(
+        gateway.find_free_port(PortMappingProtocol::TCP, 9000..9000).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn test_permanent_lease_duration() {
+    let entry = MockTransport::entry("192.168.1.2", true, "test", 604800);
+    let (_, mut gateway) = mock_gateway_with(vec![MockTransport::ok("AddPortMapping", ""), entry]);
+    gateway.options.permanent_lease_duration = 604800;
+    assert_eq!(common::lease_duration(&gateway.options, 3600), 3600);
+
+    // The week sent instead of 0 is what the gateway granted.
+    let warnings = gateway
+        .add_port_verified(
+            PortMappingProtocol::TCP,
+            8080,
+            "192.168.1.2:8080".parse().unwrap(),
+            0,
+            "test",
+        )
+        .unwrap();
+    assert!(warnings.is_empty());
+}
+
+#[test]
+fn test_igd_v2_service_type() {
+    let (transport, mut gateway) = mock_gateway_with(vec![
+        MockTransport::fault(401),
+        MockTransport::ok(
+            "GetExternalIPAddress",
+            "<NewExternalIPAddress>203.0.113.1</NewExternalIPAddress>",
+        ),
+    ]);
+    gateway.connection_service = Arc::new(Mutex::new(ConnectionService::new(messages::WAN_IP_CONNECTION_2)));
+    assert_eq!(gateway.service_type(), messages::WAN_IP_CONNECTION_2);
+
+    // The control url is the one of WANIPConnection:2, so WANIPConnection:1 is not tried there.
+    assert!(gateway.get_external_ip().is_err());
+    gateway.get_external_ip().unwrap();
+    assert_eq!(
+        transport.headers(),
+        ["\"urn:schemas-upnp-org:service:WANIPConnection:2#GetExternalIPAddress\""; 2]
+    );
+    assert_eq!(gateway.service_type(), messages::WAN_IP_CONNECTION_2);
+}
+
+#[test]
+fn test_capabilities() {
+    let description = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:2</deviceType>
+<deviceList>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:2</deviceType>
+<deviceList>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:2</deviceType>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>
+<serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+<controlURL>/ctl/IPConn</controlURL>
+<eventSubURL>/evt/IPConn</eventSubURL>
+<SCPDURL>/WANIPCn.xml</SCPDURL>
+</service>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANIPv6FirewallControl:1</serviceType>
+<serviceId>urn:upnp-org:serviceId:WANIP6FC1</serviceId>
+<controlURL>/ctl/IP6FCtl</controlURL>
+<eventSubURL>/evt/IP6FCtl</eventSubURL>
+<SCPDURL>/WANIP6FC.xml</SCPDURL>
+</service>
+</serviceList>
+</device>
+</deviceList>
+</device>
+</deviceList>
+</device>
+</root>"#;
+    let (_, mut gateway) = mock_gateway_with(vec![HttpResponse {
+        status: 200,
+        body: description.into(),
+    }]);
+    gateway.root_url = "/rootDesc.xml".into();
+    gateway
+        .control_schema
+        .insert("GetListOfPortMappings".into(), Vec::new());
+    gateway.connection_service = Arc::new(Mutex::new(ConnectionService::new(messages::WAN_IP_CONNECTION_2)));
+
+    let expected = Capabilities {
+        service_version: 2,
+        supports_add_any_port: false,
+        supports_list_mappings: true,
+        has_ipv6_firewall: true,
+        max_lease_duration: None,
+    };
+    assert_eq!(gateway.capabilities().unwrap(), expected);
+    // The description is not fetched again, the transport has no more responses.
+    assert_eq!(gateway.clone().capabilities().unwrap(), expected);
+}
+
+#[test]
+fn test_remove_entry() {
+    let (transport, gateway) = mock_gateway_with(vec![
+        MockTransport::ok("DeletePortMapping", ""),
+        MockTransport::fault(714),
+    ]);
+    let mut entry = parsing::PortMappingEntry {
+        remote_host: "203.0.113.7".into(),
+        external_port: 8080,
+        protocol: PortMappingProtocol::TCP,
+        internal_port: 8080,
+        internal_client: "192.168.1.2".into(),
+        enabled: true,
+        port_mapping_description: "test".into(),
+        lease_duration: 0,
+    };
+
+    gateway.remove_entry(&entry).unwrap();
+    // The mapping disappeared since it was listed.
+    gateway.remove_entry(&entry).unwrap();
+    assert_eq!(transport.actions(), vec!["DeletePortMapping"; 2]);
+
+    entry.remote_host = "peer.example.com".into();
+    match gateway.remove_entry(&entry) {
+        Err(RemovePortError::RequestError(RequestError::InvalidResponse(_))) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(transport.actions().len(), 2);
+}
+
+#[test]
+fn test_metrics() {
+    #[derive(Debug, Default)]
+    struct Events(Mutex<Vec<String>>);
+
+    impl crate::Metrics for Events {
+        fn request(&self, action: &str) {
+            self.0.lock().unwrap().push(format!("request {}", action));
+        }
+
+        fn retry(&self, action: &str) {
+            self.0.lock().unwrap().push(format!("retry {}", action));
+        }
+
+        fn error_code(&self, action: &str, code: u16) {
+            self.0.lock().unwrap().push(format!("error {} {}", action, code));
+        }
+
+        fn timeout(&self, action: &str) {
+            self.0.lock().unwrap().push(format!("timeout {}", action));
+        }
+    }
+
+    let transport = MockTransport::with_results(vec![
+        Ok(MockTransport::fault(401)),
+        Ok(MockTransport::ok(
+            "GetExternalIPAddress",
+            "<NewExternalIPAddress>203.0.113.1</NewExternalIPAddress>",
+        )),
+        // The error of a timed out read on some platforms.
+        Err(RequestError::IoError(std::io::ErrorKind::WouldBlock.into())),
+    ]);
+    let events = Arc::new(Events::default());
+    let mut gateway = mock_gateway(transport);
+    gateway.options.metrics = Some(events.clone());
+
+    gateway.get_external_ip().unwrap();
+    assert!(gateway.get_external_ip().is_err());
+    assert_eq!(
+        *events.0.lock().unwrap(),
+        [
+            "request GetExternalIPAddress",
+            "error GetExternalIPAddress 401",
+            "retry GetExternalIPAddress",
+            "request GetExternalIPAddress",
+            "request GetExternalIPAddress",
+            "timeout GetExternalIPAddress",
+        ]
+    );
+}
+
+#[test]
+fn test_repoint_mapping() {
+    let (transport, gateway) = mock_gateway_with(vec![
+        MockTransport::entry("192.168.1.2", true, "web", 600),
+        MockTransport::ok("AddPortMapping", ""),
+    ]);
+
+    gateway
+        .repoint_mapping(PortMappingProtocol::TCP, 80, "192.168.1.3:8081".parse().unwrap())
+        .unwrap();
+
+    assert_eq!(transport.actions(), ["GetSpecificPortMappingEntry", "AddPortMapping"]);
+    let body = &transport.bodies()[1];
+    assert!(body.contains("<NewInternalClient>192.168.1.3</NewInternalClient>"));
+    assert!(body.contains("<NewInternalPort>8081</NewInternalPort>"));
+    assert!(body.contains("<NewLeaseDuration>600</NewLeaseDuration>"));
+    assert!(body.contains("<NewPortMappingDescription>web</NewPortMappingDescription>"));
+}
+
+#[test]
+fn test_add_port_igd_v2_errors() {
+    let (transport, mut gateway) = mock_gateway_with(vec![
+        MockTransport::fault(729),
+        MockTransport::ok("AddPortMapping", ""),
+        MockTransport::fault(729),
+        MockTransport::fault(733),
+        MockTransport::fault(716),
+        MockTransport::fault(732),
+    ]);
+    gateway.options.port_rng = Some(crate::PortRng::seed_from_u64(1));
+    let local_addr = "192.168.1.2:8080".parse().unwrap();
+
+    assert!(gateway
+        .add_any_port(PortMappingProtocol::TCP, local_addr, 0, "test")
+        .is_ok());
+    let result = gateway.add_port(PortMappingProtocol::TCP, 8080, local_addr, 0, "test");
+    assert!(matches!(result, Err(AddPortError::ConflictWithOtherMechanisms)));
+    let result = gateway.add_port(PortMappingProtocol::TCP, 8080, local_addr, 0, "test");
+    assert!(matches!(result, Err(AddPortError::InconsistentParameters)));
+    let result = gateway.add_port(PortMappingProtocol::TCP, 8080, local_addr, 0, "test");
+    assert!(matches!(result, Err(AddPortError::ExternalPortZeroInvalid)));
+    let result = gateway.add_port(PortMappingProtocol::TCP, 8080, local_addr, 0, "test");
+    assert!(matches!(result, Err(AddPortError::InternalPortZeroInvalid)));
+    assert_eq!(transport.actions(), vec!["AddPortMapping"; 6]);
+}
+
+#[test]
+fn test_remove_port_range() {
+    let (transport, gateway) = mock_gateway_with(vec![
+        MockTransport::ok("DeletePortMappingRange", ""),
+        MockTransport::fault(730),
+    ]);
+
+    gateway
+        .remove_port_range(PortMappingProtocol::UDP, 40000, 40099, false)
+        .unwrap();
+    assert!(matches!(
+        gateway.remove_port_range(PortMappingProtocol::UDP, 40000, 40099, false),
+        Err(RemovePortError::NoSuchPortMapping)
+    ));
+    assert_eq!(transport.actions(), vec!["DeletePortMappingRange"; 2]);
+    let body = &transport.bodies()[0];
+    assert!(body.contains("<NewStartPort>40000</NewStartPort><NewEndPort>40099</NewEndPort>"));
+    assert!(body.contains("<NewProtocol>UDP</NewProtocol><NewManage>0</NewManage>"));
+}
+
+#[test]
+fn test_get_status_info() {
+    let (_, gateway) = mock_gateway_with(vec![
+        MockTransport::ok(
+            "GetStatusInfo",
+            "<NewConnectionStatus>Disconnected</NewConnectionStatus>\
+             <NewLastConnectionError>ERROR_NO_CARRIER</NewLastConnectionError><NewUptime>0</NewUptime>",
+        ),
+        MockTransport::ok(
+            "GetStatusInfo",
+            "<NewConnectionStatus>Connected</NewConnectionStatus>\
+             <NewLastConnectionError>ERROR_NONE</NewLastConnectionError><NewUptime>3600</NewUptime>",
+        ),
+        MockTransport::ok("GetStatusInfo", "<NewConnectionStatus>Connected</NewConnectionStatus>"),
+        MockTransport::ok("GetStatusInfo", "<NewUptime>3600</NewUptime>"),
+    ]);
+
+    let status = gateway.get_status_info().unwrap();
+    assert!(!status.is_connected());
+    assert_eq!(status.last_connection_error.as_deref(), Some("ERROR_NO_CARRIER"));
+    assert_eq!(
+        gateway.get_status_info().unwrap(),
+        StatusInfo {
+            connection_status: "Connected".to_string(),
+            last_connection_error: Some("ERROR_NONE".to_string()),
+            uptime: Some(3600),
+        }
+    );
+    assert_eq!(
+        gateway.get_status_info().unwrap(),
+        StatusInfo {
+            connection_status: "Connected".to_string(),
+            last_connection_error: None,
+            uptime: None,
+        }
+    );
+    assert!(matches!(
+        gateway.get_status_info(),
+        Err(RequestError::InvalidResponse(_))
+    ));
+}
+
+#[test]
+fn test_active_connections() {
+    let description = HttpResponse {
+        status: 200,
+        body: r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+<UDN>uuid:wan-device</UDN>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType>
+<serviceId>urn:upnp-org:serviceId:WANCommonIFC1</serviceId>
+<controlURL>/ctl/CmnIfCfg</controlURL>
+</service>
+</serviceList>
+<deviceList>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+<UDN>uuid:wan-connection</UDN>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+<serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+<controlURL>/ctl/IPConn</controlURL>
+</service>
+</serviceList>
+</device>
+</deviceList>
+</device>
+</root>"#
+            .into(),
+    };
+    let connection = || {
+        MockTransport::ok(
+            "GetActiveConnection",
+            "<NewActiveConnDeviceContainer>uuid:wan-connection</NewActiveConnDeviceContainer>\
+             <NewActiveConnectionServiceID>urn:upnp-org:serviceId:WANIPConn1</NewActiveConnectionServiceID>",
+        )
+    };
+    let (transport, mut gateway) = mock_gateway_with(vec![
+        description.clone(),
+        MockTransport::ok("QueryStateVariable", "<return>1</return>"),
+        connection(),
+        // Without the number, the gateway repeating the connection ends the list.
+        description,
+        MockTransport::fault(404),
+        connection(),
+        connection(),
+    ]);
+    gateway.root_url = "/rootDesc.xml".into();
+
+    assert_eq!(gateway.active_connections().unwrap().len(), 1);
+    assert_eq!(gateway.active_connections().unwrap().len(), 1);
+    assert_eq!(
+        transport.actions(),
+        [
+            "QueryStateVariable",
+            "GetActiveConnection",
+            "QueryStateVariable",
+            "GetActiveConnection",
+            "GetActiveConnection"
+        ]
+    );
+}
+
+#[test]
+fn test_renew_port_keeps_disabled_mapping() {
+    let entry = || MockTransport::entry("192.168.1.2", false, "test", 600);
+    let (transport, gateway) = mock_gateway_with(vec![
+        entry(),
+        MockTransport::ok("AddPortMapping", ""),
+        entry(),
+        MockTransport::ok("AddPortMapping", ""),
+    ]);
+
+    gateway.renew_port(PortMappingProtocol::TCP, 8080, 3600).unwrap();
+    gateway
+        .repoint_mapping(PortMappingProtocol::TCP, 8080, "192.168.1.3:8080".parse().unwrap())
+        .unwrap();
+    let bodies = transport.bodies();
+    assert!(bodies[1].contains("<NewEnabled>0</NewEnabled>"));
+    assert!(bodies[1].contains("<NewLeaseDuration>3600</NewLeaseDuration>"));
+    assert!(bodies[3].contains("<NewEnabled>0</NewEnabled>"));
+    assert!(bodies[3].contains("<NewInternalClient>192.168.1.3</NewInternalClient>"));
+}
+
+#[test]
+fn test_add_any_port_retries_request_errors() {
+    let transport = MockTransport::with_results(vec![
+        Err(RequestError::from(io::Error::from(io::ErrorKind::ConnectionReset))),
+        Ok(MockTransport::ok("AddPortMapping", "")),
+    ]);
+    let gateway = mock_gateway(transport.clone());
+
+    gateway
+        .add_any_port(PortMappingProtocol::TCP, "192.168.1.2:8080".parse().unwrap(), 0, "test")
+        .unwrap();
+    assert_eq!(transport.actions(), vec!["AddPortMapping"; 2]);
+}
+
+#[test]
+fn test_port_mappings_stops_on_repeated_entry() {
+    let entry = || MockTransport::generic_entry(PortMappingProtocol::UDP, 8080, "192.168.1.2", "test");
+    let (transport, gateway) = mock_gateway_with(vec![entry(), entry()]);
+
+    assert_eq!(gateway.port_mappings().count(), 1);
+    assert_eq!(transport.actions(), vec!["GetGenericPortMappingEntry"; 2]);
+}
@@ -3,6 +3,17 @@
 //! This library allows you to communicate with an IGD enabled device.
 //! Use one of the `search_gateway` functions to obtain a `Gateway` object.
 //! You can then communicate with the device via this object.
+//!
+//! # Features
+//!
+//! Without any feature, the API is blocking: discovery uses a plain UDP socket and the SOAP requests are sent
+//! with `attohttpc`, so neither futures nor an async runtime are compiled in.
+//! A blocking `Gateway` owns no reactor or runtime: each call only opens the connection its request needs. An
+//! application searches once and reuses the `Gateway`, or its clones, for all its calls, from any thread.
+//!
+//! - `aio` adds the async API in the `aio` module, with requests sent through hyper on tokio.
+//! - `aio_futures` only adds the async `aio::Gateway` and its `aio::Transport` trait, for another runtime.
+//! - `test-server` adds the fake gateway of the `test_server` module, for tests.
 
 extern crate attohttpc;
 #[macro_use]
@@ -12,9 +23,10 @@ extern crate bytes;
 
 extern crate rand;
 extern crate url;
-extern crate xmltree;
+/// XML elements returned by `Gateway::send_envelope`
+pub extern crate xmltree;
 
-#[cfg(feature = "aio")]
+#[cfg(feature = "aio_futures")]
 extern crate futures;
 #[cfg(feature = "aio")]
 extern crate http;
@@ -24,26 +36,44 @@ extern crate hyper;
 extern crate tokio;
 
 // data structures
-pub use self::common::parsing::PortMappingEntry;
-pub use self::common::SearchOptions;
+pub use self::common::parsing::{
+    ActiveConnection, Announcement, DeviceInfo, DiscoveryInfo, DslLinkInfo, MappingResult, MappingWarning,
+    PortMappingEntry, ServiceInfo, StatusInfo,
+};
+pub use self::common::{
+    Capabilities, Credentials, GatewayOptions, GatewaySelector, Metrics, PortRng, PruneResults, ResponseFilter,
+    SearchOptions, SearchResults,
+};
 pub use self::errors::{
-    AddAnyPortError, AddPortError, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError, RequestError,
+    AddAnyPortError, AddPortError, GetDnsServersError, GetExternalIpError, GetExternalIpv6Error,
+    GetGenericPortMappingEntryError, GetSpecificPortMappingEntryError, RemovePortError, RenewPortError, RequestError,
     SearchError,
 };
 pub use self::errors::{Error, Result};
-pub use self::gateway::Gateway;
+pub use self::gateway::{Gateway, PortMappingEntries};
+pub use self::manager::{GatewayStatus, ManagedMapping, MappingHealth, MappingManager};
+pub use self::transport::{AttoHttpTransport, Transport};
 
 // search of gateway
-pub use self::search::search_gateway;
+pub use self::search::{
+    listen_announcements, map_port, search_gateway, search_gateway_at, search_gateways, search_gateways_on_interfaces,
+    unmap_port, Announcements,
+};
 
-#[cfg(feature = "aio")]
+#[cfg(feature = "aio_futures")]
 pub mod aio;
 mod common;
 mod errors;
 mod gateway;
+mod manager;
 mod search;
+pub mod soap;
+#[cfg(feature = "test-server")]
+pub mod test_server;
+pub mod transport;
 
 use std::fmt;
+use std::net::Ipv4Addr;
 
 /// Represents the protocols available for port mapping.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -66,3 +96,25 @@ impl fmt::Display for PortMappingProtocol {
         )
     }
 }
+
+/// The remote hosts a port mapping applies to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RemoteHost {
+    /// Any remote host, sent to the gateway as an empty `NewRemoteHost`.
+    #[default]
+    Wildcard,
+    /// Only this remote host.
+    ///
+    /// Some gateways only support the wildcard and reject specific hosts with
+    /// `AddPortError::RemoteHostOnlySupportsWildcard`.
+    Specific(Ipv4Addr),
+}
+
+impl fmt::Display for RemoteHost {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RemoteHost::Wildcard => Ok(()),
+            RemoteHost::Specific(ip) => write!(f, "{}", ip),
+        }
+    }
+}
@@ -0,0 +1,574 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::common;
+use crate::errors::{AddPortError, GetExternalIpError, RemovePortError, RequestError};
+use crate::{Gateway, PortMappingProtocol};
+
+/// Health of a mapping kept by a `MappingManager`
+#[derive(Clone, Debug, PartialEq)]
+pub enum MappingHealth {
+    /// The mapping was added or last renewed at this instant.
+    Active(Instant),
+    /// The last attempt to add or renew the mapping failed with this error. It is tried again on the next renewal.
+    Failed(String),
+}
+
+/// A port mapping kept by a `MappingManager`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManagedMapping {
+    /// Protocol of the mapping
+    pub protocol: PortMappingProtocol,
+    /// External port of the mapping
+    pub external_port: u16,
+    /// Address the traffic is sent to
+    pub local_addr: SocketAddrV4,
+    /// Lease duration in seconds, 0 is infinite
+    pub lease_duration: u32,
+    /// Description of the mapping
+    pub description: String,
+    /// Whether the mapping is on the gateway, as of the last renewal
+    pub health: MappingHealth,
+}
+
+/// State of the gateway of a `MappingManager`, as of the last renewal
+#[derive(Clone, Debug, PartialEq)]
+pub struct GatewayStatus {
+    /// Whether the gateway answered
+    pub reachable: bool,
+    /// Last external IP address reported by the gateway
+    pub external_ip: Option<Ipv4Addr>,
+    /// Number of reboots detected, when the gateway answered again after being unreachable or reported another
+    /// external IP address
+    pub reboots: u32,
+    /// When the last reboot was detected
+    pub last_reboot: Option<Instant>,
+}
+
+impl Default for GatewayStatus {
+    fn default() -> GatewayStatus {
+        GatewayStatus {
+            reachable: true,
+            external_ip: None,
+            reboots: 0,
+            last_reboot: None,
+        }
+    }
+}
+
+impl GatewayStatus {
+    // Records the answer to `GetExternalIPAddress`, and tells whether the gateway rebooted.
+    fn update(&mut self, probe: &Result<Ipv4Addr, GetExternalIpError>) -> bool {
+        let reachable = unreachable_error(probe).is_none();
+        let mut rebooted = reachable && !self.reachable;
+        if let Ok(ip) = *probe {
+            rebooted |= matches!(self.external_ip, Some(old) if old != ip);
+            self.external_ip = Some(ip);
+        }
+        self.reachable = reachable;
+        if rebooted {
+            self.reboots += 1;
+            self.last_reboot = Some(Instant::now());
+        }
+        rebooted
+    }
+}
+
+// The error of the probe if the request did not reach the UPnP service of the gateway. A rebooting gateway may
+// answer with an HTTP 404 or 5xx before its UPnP daemon is up. A gateway answering with a UPnP error, even to
+// `GetExternalIPAddress`, is up and can still renew the mappings.
+fn unreachable_error(probe: &Result<Ipv4Addr, GetExternalIpError>) -> Option<&RequestError> {
+    match *probe {
+        Err(GetExternalIpError::RequestError(ref e)) => match *e {
+            RequestError::Connect(..) | RequestError::IoError(..) | RequestError::ControlUrlUnreachable(..) => Some(e),
+            RequestError::Http(status) if status.is_server_error() => Some(e),
+            #[cfg(feature = "aio")]
+            RequestError::HyperError(..) | RequestError::HttpError(..) => Some(e),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl ManagedMapping {
+    fn is(&self, protocol: PortMappingProtocol, external_port: u16) -> bool {
+        self.protocol == protocol && self.external_port == external_port
+    }
+}
+
+/// Keeps a set of port mappings on a gateway.
+///
+/// A background thread renews all the mappings every `interval`, or every half of the shortest lease if that
+/// is sooner, and adds again those the gateway dropped, as routers do when they reboot. Each renewal first asks
+/// the gateway its external IP address: while the gateway is unreachable it is asked again every few seconds,
+/// and all the mappings are added again as soon as it answers, see `MappingManager::status`. The requests are
+/// blocking. The thread is stopped when the manager is dropped, leaving the mappings on the gateway until their
+/// leases expire; `remove` them first to delete them.
+#[derive(Debug)]
+pub struct MappingManager {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    gateway: Gateway,
+    interval: Duration,
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    mappings: Vec<ManagedMapping>,
+    status: GatewayStatus,
+    stopped: bool,
+}
+
+// How often an unreachable gateway is asked whether it is back.
+const UNREACHABLE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Lease of `add_port_persistent` when the gateway does not declare its longest one: a week, the limit of IGDv2.
+const PERSISTENT_LEASE: u32 = 604800;
+
+impl MappingManager {
+    /// Manage mappings on `gateway`, renewing them every `interval`.
+    pub fn new(gateway: Gateway, interval: Duration) -> MappingManager {
+        let shared = Arc::new(Shared {
+            gateway,
+            interval,
+            state: Mutex::new(State::default()),
+            wake: Condvar::new(),
+        });
+        let thread = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.run())
+        };
+        MappingManager {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// The gateway the mappings are added to.
+    pub fn gateway(&self) -> &Gateway {
+        &self.shared.gateway
+    }
+
+    /// Add a port mapping like `Gateway::add_port` and keep it.
+    ///
+    /// A mapping with the same protocol and external port is replaced. The mapping is kept even if adding it
+    /// failed, so that it is tried again on the next renewal; its health tells the error.
+    pub fn add(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(), AddPortError> {
+        let mut mapping = ManagedMapping {
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description: description.to_string(),
+            health: MappingHealth::Failed(String::new()),
+        };
+        let result = add_mapping(&self.shared.gateway, &mapping);
+        mapping.health = health(&result);
+        self.keep(mapping);
+        result
+    }
+
+    /// Add a port mapping that stays until it is removed, and keep it.
+    ///
+    /// Gateways cap the lease even of permanent mappings, or refuse them, so the longest lease declared in the
+    /// control schema is requested instead, or a week if it is not declared, and the mapping is renewed before
+    /// it expires. A permanent lease is requested from the gateways that only support those, and the lease
+    /// the gateway actually granted is read back. Returns that lease in seconds, 0 being infinite.
+    pub fn add_port_persistent(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddrV4,
+        description: &str,
+    ) -> Result<u32, AddPortError> {
+        let gateway = &self.shared.gateway;
+        let lease_duration = match gateway.max_lease_duration() {
+            Ok(Some(max)) if max > 0 => max,
+            _ => PERSISTENT_LEASE,
+        };
+        let mut mapping = ManagedMapping {
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description: description.to_string(),
+            health: MappingHealth::Failed(String::new()),
+        };
+        let mut result = add_mapping(gateway, &mapping);
+        if let Err(AddPortError::OnlyPermanentLeasesSupported) = result {
+            mapping.lease_duration = 0;
+            result = add_mapping(gateway, &mapping);
+        }
+        if result.is_ok() && mapping.lease_duration > 0 {
+            // Renew the mapping in time if the gateway shortened the lease.
+            if let Ok(entry) = gateway.get_specific_port_mapping_entry(protocol, external_port) {
+                if entry.lease_duration > 0 && entry.lease_duration < mapping.lease_duration {
+                    mapping.lease_duration = entry.lease_duration;
+                }
+            }
+        }
+        mapping.health = health(&result);
+        let lease_duration = mapping.lease_duration;
+        self.keep(mapping);
+        result.map(|()| lease_duration)
+    }
+
+    fn keep(&self, mapping: ManagedMapping) {
+        let mut state = self.shared.lock();
+        state
+            .mappings
+            .retain(|m| !m.is(mapping.protocol, mapping.external_port));
+        state.mappings.push(mapping);
+        // The renewal may be due sooner with a shorter lease.
+        self.shared.wake.notify_all();
+    }
+
+    /// Stop keeping a port mapping and remove it from the gateway.
+    ///
+    /// A mapping the gateway already dropped is not an error.
+    pub fn remove(&self, protocol: PortMappingProtocol, external_port: u16) -> Result<(), RemovePortError> {
+        self.shared.lock().mappings.retain(|m| !m.is(protocol, external_port));
+        match self.shared.gateway.remove_port(protocol, external_port) {
+            Err(RemovePortError::NoSuchPortMapping) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// The mappings kept, with their health.
+    pub fn mappings(&self) -> Vec<ManagedMapping> {
+        self.shared.lock().mappings.clone()
+    }
+
+    /// Whether the gateway is reachable, and the reboots detected so far.
+    pub fn status(&self) -> GatewayStatus {
+        self.shared.lock().status.clone()
+    }
+}
+
+impl Drop for MappingManager {
+    fn drop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run(&self) {
+        let mut renewed = Instant::now();
+        let mut state = self.lock();
+        while !state.stopped {
+            let mut interval = self.renew_interval(&state.mappings);
+            if !state.status.reachable {
+                interval = interval.min(UNREACHABLE_INTERVAL);
+            }
+            let due = renewed + interval;
+            let now = Instant::now();
+            if now < due {
+                state = self
+                    .wake
+                    .wait_timeout(state, due - now)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+                continue;
+            }
+            drop(state);
+            self.renew();
+            renewed = Instant::now();
+            state = self.lock();
+        }
+    }
+
+    fn renew_interval(&self, mappings: &[ManagedMapping]) -> Duration {
+        // A permanent mapping may be sent with a finite lease, see `GatewayOptions::permanent_lease_duration`.
+        mappings
+            .iter()
+            .map(|m| common::lease_duration(&self.gateway.options, m.lease_duration))
+            .filter(|&lease_duration| lease_duration > 0)
+            .map(|lease_duration| Duration::from_secs(u64::from(lease_duration) / 2).max(Duration::from_secs(1)))
+            .fold(self.interval, Duration::min)
+    }
+
+    // Adds all the mappings again, without holding the lock during the requests.
+    fn renew(&self) {
+        let probe = self.gateway.get_external_ip();
+        let mappings = {
+            let mut state = self.lock();
+            if state.status.update(&probe) {
+                debug!("gateway {} rebooted, adding the mappings again", self.gateway.addr);
+            }
+            if let Some(e) = unreachable_error(&probe) {
+                for m in &mut state.mappings {
+                    m.health = MappingHealth::Failed(format!("The gateway is unreachable. {}", e));
+                }
+                return;
+            }
+            state.mappings.clone()
+        };
+        for mapping in mappings {
+            let health = health(&add_mapping(&self.gateway, &mapping));
+            let mut state = self.lock();
+            // Skip the mapping if it was removed or replaced meanwhile.
+            if let Some(m) = state.mappings.iter_mut().find(|m| **m == mapping) {
+                m.health = health;
+            }
+        }
+    }
+}
+
+// Adding a mapping again renews its lease, and re-creates it if the gateway dropped it. Some gateways refuse to
+// add a mapping that exists, even for the same client.
+fn add_mapping(gateway: &Gateway, mapping: &ManagedMapping) -> Result<(), AddPortError> {
+    let result = gateway.add_port(
+        mapping.protocol,
+        mapping.external_port,
+        mapping.local_addr,
+        mapping.lease_duration,
+        &mapping.description,
+    );
+    match result {
+        Err(AddPortError::PortInUse) => {
+            match gateway.get_specific_port_mapping_entry(mapping.protocol, mapping.external_port) {
+                Ok(entry)
+                    if entry.internal_client == mapping.local_addr.ip().to_string()
+                        && entry.internal_port == mapping.local_addr.port() =>
+                {
+                    Ok(())
+                }
+                _ => Err(AddPortError::PortInUse),
+            }
+        }
+        result => result,
+    }
+}
+
+fn health(result: &Result<(), AddPortError>) -> MappingHealth {
+    match *result {
+        Ok(()) => MappingHealth::Active(Instant::now()),
+        Err(ref e) => MappingHealth::Failed(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+use crate::gateway::{mock_gateway, MockTransport};
+#[cfg(test)]
+use crate::transport::HttpResponse;
+
+#[test]
+fn test_mapping_manager() {
+    let entry = |client: &str| MockTransport::entry(client, true, "test", 0);
+    let transport = MockTransport::new(vec![
+        MockTransport::ok("AddPortMapping", ""),
+        // Another client has the port.
+        MockTransport::fault(718),
+        entry("192.168.1.3"),
+        MockTransport::ok(
+            "GetExternalIPAddress",
+            "<NewExternalIPAddress>203.0.113.1</NewExternalIPAddress>",
+        ),
+        // The gateway refuses to add the existing mapping again.
+        MockTransport::fault(718),
+        entry("192.168.1.2"),
+        // The other client released the port.
+        MockTransport::ok("AddPortMapping", ""),
+        MockTransport::ok("DeletePortMapping", ""),
+    ]);
+    let manager = MappingManager::new(mock_gateway(transport.clone()), Duration::from_secs(3600));
+    let local_addr = "192.168.1.2:8080".parse().unwrap();
+
+    manager
+        .add(PortMappingProtocol::TCP, 8080, local_addr, 0, "test")
+        .unwrap();
+    assert!(matches!(
+        manager.add(PortMappingProtocol::UDP, 8080, local_addr, 0, "test"),
+        Err(AddPortError::PortInUse)
+    ));
+    let mappings = manager.mappings();
+    assert_eq!(mappings.len(), 2);
+    assert!(matches!(mappings[0].health, MappingHealth::Active(_)));
+    assert!(matches!(mappings[1].health, MappingHealth::Failed(_)));
+
+    manager.shared.renew();
+    assert!(manager
+        .mappings()
+        .iter()
+        .all(|m| matches!(m.health, MappingHealth::Active(_))));
+
+    manager.remove(PortMappingProtocol::TCP, 8080).unwrap();
+    let mappings = manager.mappings();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].protocol, PortMappingProtocol::UDP);
+    assert_eq!(
+        transport.actions(),
+        [
+            "AddPortMapping",
+            "AddPortMapping",
+            "GetSpecificPortMappingEntry",
+            "GetExternalIPAddress",
+            "AddPortMapping",
+            "GetSpecificPortMappingEntry",
+            "AddPortMapping",
+            "DeletePortMapping",
+        ]
+    );
+}
+
+#[test]
+fn test_mapping_manager_detects_reboots() {
+    let ip = |ip: &str| {
+        MockTransport::ok(
+            "GetExternalIPAddress",
+            &format!("<NewExternalIPAddress>{}</NewExternalIPAddress>", ip),
+        )
+    };
+    let unreachable = || {
+        Err(RequestError::from(std::io::Error::from(
+            std::io::ErrorKind::ConnectionRefused,
+        )))
+    };
+    let transport = MockTransport::with_results(vec![
+        Ok(MockTransport::ok("AddPortMapping", "")),
+        Ok(ip("203.0.113.1")),
+        Ok(MockTransport::ok("AddPortMapping", "")),
+        unreachable(),
+        Ok(ip("203.0.113.1")),
+        Ok(MockTransport::ok("AddPortMapping", "")),
+        // The web server of the gateway is up before its UPnP daemon.
+        Ok(HttpResponse {
+            status: 503,
+            body: "<html>Service Unavailable</html>".into(),
+        }),
+        Ok(ip("203.0.113.1")),
+        Ok(MockTransport::ok("AddPortMapping", "")),
+        Ok(ip("203.0.113.2")),
+        Ok(MockTransport::ok("AddPortMapping", "")),
+    ]);
+    let manager = MappingManager::new(mock_gateway(transport), Duration::from_secs(3600));
+    let is_active = |manager: &MappingManager| matches!(manager.mappings()[0].health, MappingHealth::Active(_));
+    manager
+        .add(
+            PortMappingProtocol::TCP,
+            8080,
+            "192.168.1.2:8080".parse().unwrap(),
+            0,
+            "test",
+        )
+        .unwrap();
+
+    manager.shared.renew();
+    let status = manager.status();
+    assert!(status.reachable);
+    assert_eq!(status.external_ip, Some(Ipv4Addr::new(203, 0, 113, 1)));
+    assert_eq!(status.reboots, 0);
+
+    manager.shared.renew();
+    assert!(!manager.status().reachable);
+    assert!(!is_active(&manager));
+
+    manager.shared.renew();
+    let status = manager.status();
+    assert!(status.reachable);
+    assert_eq!(status.reboots, 1);
+    assert!(status.last_reboot.is_some());
+    assert!(is_active(&manager));
+
+    manager.shared.renew();
+    assert!(!manager.status().reachable);
+    assert!(!is_active(&manager));
+    manager.shared.renew();
+    assert_eq!(manager.status().reboots, 2);
+    assert!(is_active(&manager));
+
+    manager.shared.renew();
+    let status = manager.status();
+    assert_eq!(status.external_ip, Some(Ipv4Addr::new(203, 0, 113, 2)));
+    assert_eq!(status.reboots, 3);
+}
+
+#[test]
+fn test_add_port_persistent() {
+    let entry = |lease: u32| MockTransport::entry("192.168.1.2", true, "test", lease);
+    let transport = MockTransport::new(vec![
+        // The gateway caps the lease to an hour.
+        MockTransport::ok("AddPortMapping", ""),
+        entry(3600),
+        MockTransport::fault(725),
+        MockTransport::ok("AddPortMapping", ""),
+    ]);
+    let manager = MappingManager::new(mock_gateway(transport.clone()), Duration::from_secs(3600));
+    let local_addr = "192.168.1.2:8080".parse().unwrap();
+
+    let lease = manager
+        .add_port_persistent(PortMappingProtocol::TCP, 8080, local_addr, "test")
+        .unwrap();
+    assert_eq!(lease, 3600);
+    assert_eq!(
+        manager.shared.renew_interval(&manager.mappings()),
+        Duration::from_secs(1800)
+    );
+    let lease = manager
+        .add_port_persistent(PortMappingProtocol::UDP, 8080, local_addr, "test")
+        .unwrap();
+    assert_eq!(lease, 0);
+
+    let leases: Vec<_> = manager.mappings().iter().map(|m| m.lease_duration).collect();
+    assert_eq!(leases, [3600, 0]);
+    assert_eq!(
+        transport.actions(),
+        [
+            "AddPortMapping",
+            "GetSpecificPortMappingEntry",
+            "AddPortMapping",
+            "AddPortMapping"
+        ]
+    );
+}
+
+#[test]
+fn test_mapping_manager_renews_after_fault() {
+    // The gateway answers, but refuses `GetExternalIPAddress`.
+    let transport = MockTransport::new(vec![
+        MockTransport::ok("AddPortMapping", ""),
+        MockTransport::fault(501),
+        MockTransport::ok("AddPortMapping", ""),
+    ]);
+    let manager = MappingManager::new(mock_gateway(transport.clone()), Duration::from_secs(3600));
+    manager
+        .add(
+            PortMappingProtocol::TCP,
+            8080,
+            "192.168.1.2:8080".parse().unwrap(),
+            0,
+            "test",
+        )
+        .unwrap();
+
+    manager.shared.renew();
+    assert!(manager.status().reachable);
+    assert!(matches!(manager.mappings()[0].health, MappingHealth::Active(_)));
+    assert_eq!(
+        transport.actions(),
+        ["AddPortMapping", "GetExternalIPAddress", "AddPortMapping"]
+    );
+}
@@ -1,10 +1,16 @@
-use std::collections::HashMap;
-use std::net::{SocketAddrV4, UdpSocket};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
 use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::common::{messages, parsing, SearchOptions};
-use crate::errors::SearchError;
+use crate::common::parsing::{self, Announcement, DeviceInfo, DiscoveryInfo};
+use crate::common::{self, messages, ConnectionService, GatewayOptions, SearchOptions, SearchResults};
+use crate::errors::{self, SearchError};
 use crate::gateway::Gateway;
+use crate::PortMappingProtocol;
 
 /// Search gateway, using the given `SearchOptions`.
 ///
@@ -23,46 +29,949 @@ use crate::gateway::Gateway;
 /// }
 /// ```
 pub fn search_gateway(options: SearchOptions) -> Result<Gateway, SearchError> {
-    let socket = UdpSocket::bind(options.bind_addr)?;
-    socket.set_read_timeout(options.timeout)?;
+    common::check_broadcast_address(options.broadcast_address)?;
+    if let Some(gateway) = CACHE.get(&options).filter(|gateway| is_selected(gateway, &options)) {
+        debug!("reusing the cached gateway {}", gateway.addr);
+        return Ok(Gateway {
+            options: options.gateway_options,
+            ..gateway
+        });
+    }
+    let gateway = search_with_retries(&options)?;
+    let max_age = gateway.discovery_info.as_ref().and_then(|info| info.max_age);
+    CACHE.insert(&options, &gateway, max_age);
+    Ok(gateway)
+}
 
-    socket.send_to(messages::SEARCH_REQUEST.as_bytes(), options.broadcast_address)?;
+// The gateways of `SearchOptions::use_cache`.
+static CACHE: common::GatewayCache<Gateway> = common::GatewayCache::new();
 
-    loop {
-        let mut buf = [0u8; 1500];
-        let (read, _) = socket.recv_from(&mut buf)?;
-        let text = str::from_utf8(&buf[..read])?;
+/// Search the gateway at `ip` with a unicast M-SEARCH, using the given `SearchOptions`.
+///
+/// The request is sent to `ip` on the port of `options.broadcast_address`, 1900 by default, instead of the
+/// multicast group: this finds a gateway whose address is known, such as the default route, on networks that
+/// filter multicast. `options.bind_addr` must be an IPv4 address. The gateway is then used like with
+/// `search_gateway`.
+///
+/// # Example
+/// ```no_run
+/// use igd::{search_gateway_at, Result};
+///
+/// fn main() -> Result {
+///     let gateway = search_gateway_at("192.168.1.1".parse().unwrap(), Default::default())?;
+///     println!("External IP address: {}", gateway.get_external_ip()?);
+///     Ok(())
+/// }
+/// ```
+pub fn search_gateway_at(ip: Ipv4Addr, options: SearchOptions) -> Result<Gateway, SearchError> {
+    search_with_retries(&common::unicast_search_options(ip, options))
+}
 
-        let (addr, root_url) = parsing::parse_search_result(text)?;
+fn search_with_retries(options: &SearchOptions) -> Result<Gateway, SearchError> {
+    let start = Instant::now();
+    let mut result = find_gateway(options);
+    for retry in 1..=options.retries {
+        match result {
+            Err(ref e) if common::is_search_timeout(e) => {
+                debug!("no gateway found, searching again ({}/{})", retry, options.retries);
+                result = find_gateway(options);
+            }
+            _ => break,
+        }
+    }
+    common::record_search(options, start, result.is_ok() as usize);
+    result
+}
+
+fn find_gateway(options: &SearchOptions) -> Result<Gateway, SearchError> {
+    let socket = bind_search_socket(options)?;
 
-        let (control_schema_url, control_url) = match get_control_urls(&addr, &root_url) {
+    send_search_requests(&socket, options)?;
+
+    let mut retransmissions = common::Retransmissions::new(options);
+    let mut buf = vec![0u8; options.response_buffer_size];
+    let mut no_connection_service = false;
+    let mut tried = HashSet::new();
+    loop {
+        let retransmit_at = retransmissions.next();
+        socket.set_read_timeout(retransmit_at.map(common::read_timeout_until).or(options.timeout))?;
+        let (read, from) = match socket.recv_from(&mut buf) {
             Ok(o) => o,
-            Err(..) => continue,
+            Err(ref e) if retransmit_at.is_some() && common::is_timeout(e) => {
+                send_search_requests(&socket, options)?;
+                retransmissions.sent();
+                continue;
+            }
+            Err(ref e) if no_connection_service && common::is_timeout(e) => {
+                return Err(SearchError::NoConnectionService)
+            }
+            Err(e) => return Err(e.into()),
         };
+        common::warn_if_truncated(read, buf.len(), from);
+        let (addr, root_url, discovery_info) = match parse_search_response(&buf[..read]) {
+            Ok(responder) => responder,
+            Err(e) => {
+                debug!("ignoring search response from {}: {}", from, e);
+                continue;
+            }
+        };
+        // The other responses of a device that could not be used have the same location.
+        if !tried.insert(common::device_key(addr, &root_url, &discovery_info)) {
+            continue;
+        }
+
+        match get_gateway(addr, root_url, discovery_info, None, options) {
+            Ok(gateway) if is_selected(&gateway, options) => return Ok(gateway),
+            Ok(_) => debug!("ignoring {}, which the selector rejects", addr),
+            Err(SearchError::NoConnectionService) => {
+                debug!("ignoring {}, which has no connection service", addr);
+                no_connection_service = true;
+            }
+            Err(e) => debug!("could not fetch the description of {}: {}", addr, e),
+        }
+    }
+}
+
+/// Search all the gateways answering within `options.search_window`, using the given `SearchOptions`.
+///
+/// Responses are collected until the window expires, which is `options.timeout` when it is `None`, or 3 seconds.
+/// A device sends a response per device and service it has: one response is kept per device, identified by the
+/// UUID of its USN or by its address, preferring the one whose `ST` is the gateway device. The descriptions of
+/// the gateways are then fetched in parallel, each within the timeout. A gateway whose description can not be
+/// fetched or parsed does not fail the search: its address and error are returned in `SearchResults::failures`
+/// instead.
+pub fn search_gateways(options: SearchOptions) -> Result<SearchResults, SearchError> {
+    let start = Instant::now();
+    let result = find_gateways(&options);
+    let gateways = result.as_ref().map_or(0, |results| results.gateways.len());
+    common::record_search(&options, start, gateways);
+    result
+}
+
+fn find_gateways(options: &SearchOptions) -> Result<SearchResults, SearchError> {
+    common::check_broadcast_address(options.broadcast_address)?;
+    let socket = bind_search_socket(options)?;
+
+    send_search_requests(&socket, options)?;
+
+    let deadline = Instant::now() + common::search_window(options);
+    let mut retransmissions = common::Retransmissions::new(options);
+    let mut responders = Vec::new();
+    let mut buf = vec![0u8; options.response_buffer_size];
+    loop {
+        if Instant::now() >= deadline {
+            break;
+        }
+        let retransmit_at = retransmissions.next().filter(|at| *at < deadline);
+        socket.set_read_timeout(Some(common::read_timeout_until(retransmit_at.unwrap_or(deadline))))?;
 
-        let control_schema = match get_schemas(&addr, &control_schema_url) {
+        let (read, from) = match socket.recv_from(&mut buf) {
             Ok(o) => o,
-            Err(..) => continue,
+            Err(ref e) if common::is_timeout(e) => {
+                if retransmit_at.is_some() {
+                    send_search_requests(&socket, options)?;
+                    retransmissions.sent();
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
         };
+        common::warn_if_truncated(read, buf.len(), from);
+
+        // A device sends a response per device and service, some without a location.
+        match parse_search_response(&buf[..read]) {
+            Ok(responder) => common::add_responder(&mut responders, responder),
+            Err(e) => debug!("ignoring search response from {}: {}", from, e),
+        }
+    }
+
+    Ok(fetch_gateways(responders, options))
+}
+
+/// Search all the gateways like `search_gateways`, out of each IPv4 interface of this host at the same time.
+///
+/// On a host with several networks, such as Ethernet, Wi-Fi and a VPN, the multicast M-SEARCH otherwise
+/// leaves through a single interface, which may not be the one leading to the gateway. The interfaces that
+/// are up, other than loopback, are each searched from their address with the port of `options.bind_addr`,
+/// and the results are merged: the interface a gateway was found from is its `DiscoveryInfo::local_ip`, so a
+/// gateway reachable from two interfaces is returned for each. An interface whose search fails is in
+/// `SearchResults::failures` with its address.
+///
+/// Only Unix systems list their interfaces: elsewhere, the interface routing to the SSDP group is searched.
+pub fn search_gateways_on_interfaces(options: SearchOptions) -> Result<SearchResults, SearchError> {
+    common::check_broadcast_address(options.broadcast_address)?;
+    if !options.broadcast_address.is_ipv4() {
+        return Err(SearchError::InvalidBroadcastAddress(options.broadcast_address));
+    }
+    let start = Instant::now();
+    let interfaces = common::ipv4_interfaces()?;
+    let results = thread::scope(|scope| {
+        let searches: Vec<_> = interfaces
+            .iter()
+            .map(|ip| {
+                let options = common::interface_search_options(*ip, &options);
+                scope.spawn(move || (options.bind_addr, find_gateways(&options)))
+            })
+            .collect();
+        searches.into_iter().map(|search| search.join().unwrap()).collect()
+    });
+    let results = common::merge_search_results(results);
+    common::record_search(&options, start, results.gateways.len());
+    Ok(results)
+}
+
+/// Announcements of the gateways joining and leaving the network, see `listen_announcements`
+#[derive(Debug)]
+pub struct Announcements {
+    socket: UdpSocket,
+    buf: Vec<u8>,
+}
+
+impl Iterator for Announcements {
+    type Item = Result<Announcement, SearchError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (read, from) = match self.socket.recv_from(&mut self.buf) {
+                Ok(o) => o,
+                Err(e) => return Some(Err(e.into())),
+            };
+            common::warn_if_truncated(read, self.buf.len(), from);
+            match str::from_utf8(&self.buf[..read]).ok().and_then(parsing::parse_notify) {
+                Some(announcement) => return Some(Ok(announcement)),
+                None => trace!("ignoring SSDP message from {}", from),
+            }
+        }
+    }
+}
+
+/// Listen for the `ssdp:alive` and `ssdp:byebye` announcements of gateways, using the given `SearchOptions`.
+///
+/// The socket joins the multicast group of `options.broadcast_address` on the interface of
+/// `options.bind_addr`, and receives on the port of the group along with the other SSDP listeners of the host.
+/// Gateways announce themselves when they join the network and before their `max_age` expires, so
+/// applications learn about the gateways appearing and disappearing without searching again.
+///
+/// The iterator blocks until the next announcement. With `options.timeout`, it yields an `IoError` each time
+/// nothing was announced for that long, and it can be iterated again.
+///
+/// # Example
+/// ```no_run
+/// use igd::{listen_announcements, Announcement, Gateway, SearchError};
+///
+/// fn main() -> Result<(), SearchError> {
+///     for announcement in listen_announcements(Default::default())? {
+///         match announcement? {
+///             Announcement::Alive { location, .. } => {
+///                 let gateway = Gateway::from_location(&location, Default::default())?;
+///                 println!("Gateway at {}", gateway.addr);
+///             }
+///             Announcement::ByeBye { discovery_info } => println!("Gateway {} left", discovery_info.usn),
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn listen_announcements(options: SearchOptions) -> Result<Announcements, SearchError> {
+    let socket = common::bind_announcement_socket(&options)?;
+    socket.set_read_timeout(options.timeout)?;
+    Ok(Announcements {
+        socket,
+        buf: vec![0u8; options.response_buffer_size],
+    })
+}
+
+/// Search a gateway with `options` and map any external port to `local_addr`, in one call.
+///
+/// This is `search_gateway` followed by `Gateway::get_any_address`, for programs that only need a port
+/// forwarded. The lease_duration parameter is in seconds. A value of 0 is infinite.
+///
+/// # Returns
+///
+/// The external address that was mapped on success. Otherwise an error.
+///
+/// # Example
+/// ```no_run
+/// use igd::{map_port, unmap_port, PortMappingProtocol, Result};
+///
+/// fn main() -> Result {
+///     let local_addr = "192.168.1.2:8080".parse().unwrap();
+///     let external_addr = map_port(PortMappingProtocol::TCP, local_addr, 3600, "my server", Default::default())?;
+///     println!("Reachable at {}", external_addr);
+///     unmap_port(PortMappingProtocol::TCP, external_addr.port(), Default::default())
+/// }
+/// ```
+pub fn map_port(
+    protocol: PortMappingProtocol,
+    local_addr: SocketAddrV4,
+    lease_duration: u32,
+    description: &str,
+    options: SearchOptions,
+) -> errors::Result<SocketAddrV4> {
+    let gateway = search_gateway(options)?;
+    Ok(gateway.get_any_address(protocol, local_addr, lease_duration, description)?)
+}
+
+/// Search a gateway with `options` and remove the mapping of `external_port`, like `map_port` added it.
+pub fn unmap_port(protocol: PortMappingProtocol, external_port: u16, options: SearchOptions) -> errors::Result {
+    let gateway = search_gateway(options)?;
+    Ok(gateway.remove_port(protocol, external_port)?)
+}
+
+fn parse_search_response(data: &[u8]) -> Result<(SocketAddr, String, DiscoveryInfo), SearchError> {
+    let text = str::from_utf8(data)?;
+    let (addr, root_url) = parsing::parse_search_result(text)?;
+    Ok((addr, root_url, parsing::parse_discovery_info(text)))
+}
+
+// Number of gateway descriptions fetched at the same time by `search_gateways`.
+const MAX_PARALLEL_FETCHES: usize = 8;
+
+fn fetch_gateways(responders: Vec<(SocketAddr, String, DiscoveryInfo)>, options: &SearchOptions) -> SearchResults {
+    let workers = responders.len().min(MAX_PARALLEL_FETCHES);
+    let queue = Mutex::new(responders.into_iter());
+    let results = Mutex::new(SearchResults {
+        gateways: Vec::new(),
+        failures: Vec::new(),
+    });
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let (addr, root_url, discovery_info) = match next {
+                    Some(responder) => responder,
+                    None => break,
+                };
+                match get_gateway(addr, root_url, discovery_info, options.timeout, options) {
+                    Ok(gateway) if is_selected(&gateway, options) => results.lock().unwrap().gateways.push(gateway),
+                    Ok(_) => debug!("ignoring {}, which the selector rejects", addr),
+                    Err(e) => {
+                        debug!("could not fetch the description of {}: {}", addr, e);
+                        results.lock().unwrap().failures.push((addr, e));
+                    }
+                }
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+fn send_search_requests(socket: &UdpSocket, options: &SearchOptions) -> io::Result<()> {
+    for search_target in common::search_targets(options) {
+        let request = messages::format_search_request(options.broadcast_address, search_target, options.mx);
+        socket.send_to(request.as_bytes(), options.broadcast_address)?;
+    }
+    Ok(())
+}
+
+fn bind_search_socket(options: &SearchOptions) -> Result<UdpSocket, SearchError> {
+    let socket = common::bind_search_socket(options.bind_addr, options.reuse_address)
+        .map_err(|e| SearchError::BindFailed(options.bind_addr, e))?;
+    if options.bind_addr.is_ipv4() {
+        socket.set_multicast_loop_v4(options.multicast_loop)?;
+        if let Some(ttl) = options.multicast_ttl {
+            socket.set_multicast_ttl_v4(ttl)?;
+        }
+    } else {
+        socket.set_multicast_loop_v6(options.multicast_loop)?;
+    }
+    Ok(socket)
+}
+
+fn is_selected(gateway: &Gateway, options: &SearchOptions) -> bool {
+    common::is_selected(
+        options,
+        gateway.addr,
+        gateway.discovery_info.as_ref(),
+        gateway.device_info.as_ref(),
+    )
+}
+
+// The gateway of `Gateway::from_location`, fetched like a gateway found by a search.
+pub(crate) fn gateway_from_location(location: &str, options: &SearchOptions) -> Result<Gateway, SearchError> {
+    let (addr, root_url) = parsing::parse_location(location)?;
+    let gateway = get_gateway(addr, root_url, DiscoveryInfo::default(), options.timeout, options)?;
+    Ok(Gateway {
+        discovery_info: None,
+        ..gateway
+    })
+}
 
+// Fetch the description and control schema of a gateway found by a search, unless its control url is known.
+fn get_gateway(
+    addr: SocketAddr,
+    root_url: String,
+    discovery_info: DiscoveryInfo,
+    timeout: Option<Duration>,
+    search_options: &SearchOptions,
+) -> Result<Gateway, SearchError> {
+    let options = &search_options.gateway_options;
+    let discovery_info = DiscoveryInfo {
+        local_ip: common::search_local_ip(search_options, addr),
+        ..discovery_info
+    };
+    if let Some(ref control_url) = search_options.control_url {
         return Ok(Gateway {
-            addr,
             root_url,
-            control_url,
-            control_schema_url,
-            control_schema,
+            options: options.clone(),
+            discovery_info: Some(discovery_info),
+            ..Gateway::new(addr, control_url.clone())?
         });
     }
+
+    let (control_schema_url, control_url, service_type, device_info) =
+        get_control_urls(&addr, &root_url, timeout, search_options)?;
+    let gateway = Gateway::new(addr, control_url)?;
+    let control_schema = get_schemas(&addr, &control_schema_url, timeout, options)?;
+
+    Ok(Gateway {
+        root_url,
+        control_schema_url,
+        control_schema,
+        options: options.clone(),
+        discovery_info: Some(discovery_info),
+        device_info: Some(device_info),
+        connection_service: Arc::new(Mutex::new(ConnectionService::confirmed(service_type))),
+        ..gateway
+    })
+}
+
+fn get_control_urls(
+    addr: &SocketAddr,
+    root_url: &str,
+    timeout: Option<Duration>,
+    search_options: &SearchOptions,
+) -> Result<(String, String, &'static str, DeviceInfo), SearchError> {
+    let url = format!("http://{}{}", addr, root_url);
+    let body = get(&url, timeout, &search_options.gateway_options)?;
+    let device_info = parsing::parse_device_info(&body[..])?;
+    if search_options.prefer_igd_v2 {
+        if let Some((control_schema_url, control_url)) = parsing::parse_igd_v2_control_urls(&body[..])? {
+            return Ok((
+                control_schema_url,
+                control_url,
+                messages::WAN_IP_CONNECTION_2,
+                device_info,
+            ));
+        }
+    }
+    let (control_schema_url, control_url, service_type) = parsing::parse_control_urls(&body[..])?;
+    Ok((control_schema_url, control_url, service_type, device_info))
+}
+
+fn get_schemas(
+    addr: &SocketAddr,
+    control_schema_url: &str,
+    timeout: Option<Duration>,
+    options: &GatewayOptions,
+) -> Result<HashMap<String, Vec<String>>, SearchError> {
+    let url = format!("http://{}{}", addr, control_schema_url);
+    let body = get(&url, timeout, options)?;
+    parsing::parse_schemas(&body[..])
+}
+
+fn get(url: &str, timeout: Option<Duration>, options: &GatewayOptions) -> Result<Vec<u8>, SearchError> {
+    let mut request = attohttpc::get(url);
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    if let Some(ref credentials) = options.credentials {
+        request = request.header("Authorization", credentials.authorization());
+    }
+    let (status, _, reader) = request.send()?.split();
+    if let Some(e) = common::fetch_status_error(status.as_u16()) {
+        return Err(e);
+    }
+    common::read_limited(reader, options.max_response_size)?
+        .ok_or(SearchError::ResponseTooLarge(options.max_response_size))
+}
+
+#[test]
+fn test_search_gateway_from_source_port() {
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = responder.local_addr().unwrap();
+    let source = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let bind_addr = source.local_addr().unwrap();
+    let options = || SearchOptions {
+        bind_addr,
+        broadcast_address,
+        timeout: Some(Duration::from_secs(5)),
+        control_url: Some("/ctl/IPConn".to_string()),
+        ..Default::default()
+    };
+
+    // The port is still taken.
+    match search_gateway(options()) {
+        Err(SearchError::BindFailed(addr, _)) => assert_eq!(addr, bind_addr),
+        result => panic!("unexpected result: {:?}", result),
+    }
+
+    drop(source);
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        let response = "HTTP/1.1 200 OK\r
+LOCATION: http://127.0.0.1:9/rootDesc.xml\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        responder.send_to(response.as_bytes(), from).unwrap();
+        from
+    });
+    let gateway = search_gateway(options()).unwrap();
+    assert_eq!(thread.join().unwrap(), bind_addr);
+    assert_eq!(gateway.discovery_info.unwrap().local_ip, Some(Ipv4Addr::LOCALHOST));
+}
+
+#[test]
+fn test_search_gateway_with_control_url() {
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = responder.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        // Nothing listens on port 9, so fetching the description would fail.
+        let response = "HTTP/1.1 200 OK\r
+LOCATION: http://127.0.0.1:9/rootDesc.xml\r
+SERVER: Linux UPnP/1.0 miniupnpd/2.2\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        responder.send_to(response.as_bytes(), from).unwrap();
+    });
+
+    let gateway = search_gateway(SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address,
+        timeout: Some(Duration::from_secs(5)),
+        control_url: Some("/ctl/IPConn".to_string()),
+        ..Default::default()
+    })
+    .unwrap();
+    thread.join().unwrap();
+
+    assert_eq!(gateway.addr, "127.0.0.1:9".parse().unwrap());
+    assert_eq!(gateway.root_url, "/rootDesc.xml");
+    assert_eq!(gateway.control_url, "/ctl/IPConn");
+    assert_eq!(gateway.discovery_info.unwrap().server, "Linux UPnP/1.0 miniupnpd/2.2");
+}
+
+#[test]
+fn test_search_gateway_over_ipv6() {
+    let responder = UdpSocket::bind("[::1]:0").unwrap();
+    let broadcast_address = responder.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let (read, from) = responder.recv_from(&mut buf).unwrap();
+        let request = str::from_utf8(&buf[..read]).unwrap().to_string();
+        let response = "HTTP/1.1 200 OK\r
+LOCATION: http://[::1]:9/rootDesc.xml\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        responder.send_to(response.as_bytes(), from).unwrap();
+        request
+    });
+
+    let gateway = search_gateway(SearchOptions {
+        bind_addr: "[::1]:0".parse().unwrap(),
+        broadcast_address,
+        timeout: Some(Duration::from_secs(5)),
+        control_url: Some("/ctl/IPConn".to_string()),
+        ..SearchOptions::ipv6(0)
+    })
+    .unwrap();
+    let request = thread.join().unwrap();
+
+    assert!(request.contains(&format!("\r\nHost:[::1]:{}\r\n", broadcast_address.port())));
+    // The gateway is used over IPv6 too.
+    assert_eq!(gateway.addr, "[::1]:9".parse().unwrap());
+    assert_eq!(gateway.to_string(), "http://[::1]:9/ctl/IPConn");
+}
+
+#[test]
+fn test_search_gateways_aggregates_responses() {
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = responder.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        // A response for another service, without a location.
+        let other = "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:service:Layer3Forwarding:1\r\n\r\n";
+        responder.send_to(other.as_bytes(), from).unwrap();
+        // A response longer than an Ethernet frame, with the location last.
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nSERVER: {}\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+             LOCATION: http://127.0.0.1:9/rootDesc.xml\r\n\r\n",
+            "x".repeat(2000)
+        );
+        responder.send_to(response.as_bytes(), from).unwrap();
+    });
+
+    let results = search_gateways(SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address,
+        search_window: Some(Duration::from_millis(500)),
+        control_url: Some("/ctl/IPConn".to_string()),
+        ..Default::default()
+    })
+    .unwrap();
+    thread.join().unwrap();
+
+    assert_eq!(results.gateways.len(), 1);
+    assert_eq!(results.gateways[0].root_url, "/rootDesc.xml");
+    assert_eq!(results.gateways[0].discovery_info.as_ref().unwrap().server.len(), 2000);
+}
+
+#[test]
+fn test_concurrent_searches_bind() {
+    let bind_addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let options = |reuse_address| SearchOptions {
+        bind_addr,
+        broadcast_address: "127.0.0.1:1900".parse().unwrap(),
+        reuse_address,
+        ..Default::default()
+    };
+
+    let first = bind_search_socket(&options(true)).unwrap();
+    let second = bind_search_socket(&options(true)).unwrap();
+    assert_eq!(first.local_addr().unwrap(), second.local_addr().unwrap());
+
+    match bind_search_socket(&options(false)) {
+        Err(SearchError::BindFailed(addr, _)) => assert_eq!(addr, bind_addr),
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_search_gateways_dedupes_device_responses() {
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = responder.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        let uuid = "uuid:824ff22b-8c7d-41c5-a131-44f534e12555";
+        for st in [
+            "upnp:rootdevice",
+            "urn:schemas-upnp-org:service:WANIPConnection:1",
+            "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+            "urn:schemas-upnp-org:device:WANDevice:1",
+        ] {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nLOCATION: http://127.0.0.1:9/rootDesc.xml\r\nST: {st}\r\nUSN: {uuid}::{st}\r\n\r\n",
+                st = st,
+                uuid = uuid
+            );
+            responder.send_to(response.as_bytes(), from).unwrap();
+        }
+    });
+
+    let results = search_gateways(SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address,
+        search_window: Some(Duration::from_millis(500)),
+        control_url: Some("/ctl/IPConn".to_string()),
+        ..Default::default()
+    })
+    .unwrap();
+    thread.join().unwrap();
+
+    assert_eq!(results.gateways.len(), 1);
+    let discovery_info = results.gateways[0].discovery_info.as_ref().unwrap();
+    assert_eq!(discovery_info.st, "urn:schemas-upnp-org:device:InternetGatewayDevice:1");
+    assert_eq!(discovery_info.uuid(), Some("824ff22b-8c7d-41c5-a131-44f534e12555"));
+}
+
+#[test]
+fn test_search_gateway_retries() {
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = responder.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        // The first M-SEARCH is lost.
+        responder.recv_from(&mut buf).unwrap();
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        let response = "HTTP/1.1 200 OK\r
+LOCATION: http://127.0.0.1:9/rootDesc.xml\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        responder.send_to(response.as_bytes(), from).unwrap();
+    });
+
+    let options = || SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address,
+        timeout: Some(Duration::from_millis(300)),
+        control_url: Some("/ctl/IPConn".to_string()),
+        ..Default::default()
+    };
+    search_gateway(SearchOptions {
+        retries: 1,
+        ..options()
+    })
+    .unwrap();
+    thread.join().unwrap();
+
+    // Nothing answers anymore.
+    match search_gateway(options()) {
+        Err(ref e) if common::is_search_timeout(e) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_search_socket_multicast_interface() {
+    let options = |bind_addr: &str| SearchOptions {
+        bind_addr: bind_addr.parse().unwrap(),
+        ..Default::default()
+    };
+
+    let socket = bind_search_socket(&options("127.0.0.1:0")).unwrap();
+    let multicast_if = socket2::SockRef::from(&socket).multicast_if_v4().unwrap();
+    assert_eq!(multicast_if, std::net::Ipv4Addr::LOCALHOST);
+
+    let socket = bind_search_socket(&options("0.0.0.0:0")).unwrap();
+    let multicast_if = socket2::SockRef::from(&socket).multicast_if_v4().unwrap();
+    assert_eq!(multicast_if, std::net::Ipv4Addr::UNSPECIFIED);
+}
+
+#[test]
+fn test_search_gateway_at() {
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = responder.local_addr().unwrap().port();
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let (read, from) = responder.recv_from(&mut buf).unwrap();
+        let request = str::from_utf8(&buf[..read]).unwrap().to_string();
+        let response = "HTTP/1.1 200 OK\r
+LOCATION: http://127.0.0.1:9/rootDesc.xml\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        responder.send_to(response.as_bytes(), from).unwrap();
+        request
+    });
+
+    let gateway = search_gateway_at(
+        Ipv4Addr::LOCALHOST,
+        SearchOptions {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            broadcast_address: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), port)),
+            timeout: Some(Duration::from_secs(1)),
+            control_url: Some("/ctl/IPConn".to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(gateway.addr, "127.0.0.1:9".parse().unwrap());
+    assert!(thread
+        .join()
+        .unwrap()
+        .contains(&format!("\r\nHost:127.0.0.1:{}\r\n", port)));
+}
+
+#[test]
+fn test_search_gateway_retransmissions() {
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = responder.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        // The first M-SEARCH is lost, the retransmission is answered.
+        let (read, _) = responder.recv_from(&mut buf).unwrap();
+        let request = str::from_utf8(&buf[..read]).unwrap().to_string();
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        let response = "HTTP/1.1 200 OK\r
+LOCATION: http://127.0.0.1:9/rootDesc.xml\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        responder.send_to(response.as_bytes(), from).unwrap();
+        request
+    });
+
+    let start = Instant::now();
+    search_gateway(SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address,
+        timeout: Some(Duration::from_secs(5)),
+        retransmissions: 2,
+        retransmit_interval: Duration::from_millis(50),
+        mx: 1,
+        control_url: Some("/ctl/IPConn".to_string()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(thread.join().unwrap().contains("\r\nMX:1\r\n"));
+}
+
+#[test]
+fn test_search_gateway_cache() {
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = responder.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        let response = "HTTP/1.1 200 OK\r
+CACHE-CONTROL: max-age=60\r
+LOCATION: http://127.0.0.1:9/rootDesc.xml\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        responder.send_to(response.as_bytes(), from).unwrap();
+    });
+
+    let options = || SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address,
+        timeout: Some(Duration::from_millis(300)),
+        control_url: Some("/ctl/IPConn".to_string()),
+        use_cache: true,
+        ..Default::default()
+    };
+    search_gateway(options()).unwrap();
+    thread.join().unwrap();
+
+    // Nothing answers anymore, but the gateway is still valid.
+    let gateway = search_gateway(SearchOptions {
+        gateway_options: GatewayOptions {
+            dry_run: true,
+            ..Default::default()
+        },
+        ..options()
+    })
+    .unwrap();
+    assert_eq!(gateway.addr, "127.0.0.1:9".parse().unwrap());
+    assert!(gateway.options.dry_run);
+    // The gateway was found without asking for IGDv2.
+    match search_gateway(SearchOptions {
+        prefer_igd_v2: true,
+        ..options()
+    }) {
+        Err(ref e) if common::is_search_timeout(e) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match search_gateway(SearchOptions {
+        force_refresh: true,
+        ..options()
+    }) {
+        Err(ref e) if common::is_search_timeout(e) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn test_search_gateways_selector() {
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let broadcast_address = responder.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        for port in &[9, 10] {
+            let response = format!(
+                "HTTP/1.1 200 OK\r
+LOCATION: http://127.0.0.1:{}/rootDesc.xml\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+                port
+            );
+            responder.send_to(response.as_bytes(), from).unwrap();
+        }
+    });
+
+    let results = search_gateways(SearchOptions {
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        broadcast_address,
+        search_window: Some(Duration::from_millis(300)),
+        control_url: Some("/ctl/IPConn".to_string()),
+        selector: Some(crate::GatewaySelector::new(|addr, _, _| addr.port() == 10)),
+        ..Default::default()
+    })
+    .unwrap();
+    thread.join().unwrap();
+
+    let addrs: Vec<_> = results.gateways.iter().map(|gateway| gateway.addr).collect();
+    assert_eq!(addrs, vec!["127.0.0.1:10".parse().unwrap()]);
+    assert!(results.failures.is_empty());
 }
 
-fn get_control_urls(addr: &SocketAddrV4, root_url: &str) -> Result<(String, String), SearchError> {
-    let url = format!("http://{}:{}{}", addr.ip(), addr.port(), root_url);
-    let response = attohttpc::get(&url).send()?;
-    parsing::parse_control_urls(&response.bytes()?[..])
+#[cfg(feature = "test-server")]
+#[test]
+fn test_search_gateways_reports_failed_descriptions() {
+    use crate::test_server::{search_responder, unusable_and_server_locations, TestServer};
+
+    let server = TestServer::start().unwrap();
+    let (broadcast_address, thread) = search_responder(unusable_and_server_locations(&server));
+
+    let results = search_gateways(SearchOptions {
+        broadcast_address,
+        search_window: Some(Duration::from_millis(500)),
+        ..server.search_options()
+    })
+    .unwrap();
+    thread.join().unwrap();
+
+    let addrs: Vec<_> = results.gateways.iter().map(|gateway| gateway.addr).collect();
+    assert_eq!(addrs, vec![server.addr()]);
+    assert_eq!(results.failures.len(), 1);
+    assert_eq!(results.failures[0].0, "127.0.0.1:1".parse::<SocketAddr>().unwrap());
 }
 
-fn get_schemas(addr: &SocketAddrV4, control_schema_url: &str) -> Result<HashMap<String, Vec<String>>, SearchError> {
-    let url = format!("http://{}:{}{}", addr.ip(), addr.port(), control_schema_url);
-    let response = attohttpc::get(&url).send()?;
-    parsing::parse_schemas(&response.bytes()?[..])
+#[cfg(feature = "test-server")]
+#[test]
+fn test_map_port_unmap_port() {
+    use crate::errors::RemovePortError;
+    use crate::test_server::TestServer;
+
+    let server = TestServer::start().unwrap();
+    let local_addr = "192.168.1.2:8080".parse().unwrap();
+
+    let external_addr = map_port(
+        PortMappingProtocol::TCP,
+        local_addr,
+        60,
+        "test",
+        server.search_options(),
+    )
+    .unwrap();
+    assert_eq!(*external_addr.ip(), Ipv4Addr::new(203, 0, 113, 1));
+    let mappings = server.mappings();
+    assert_eq!(mappings.len(), 1);
+    assert_eq!(mappings[0].external_port, external_addr.port());
+    assert_eq!(
+        (mappings[0].internal_client.as_str(), mappings[0].internal_port),
+        ("192.168.1.2", 8080)
+    );
+
+    unmap_port(PortMappingProtocol::TCP, external_addr.port(), server.search_options()).unwrap();
+    assert!(server.mappings().is_empty());
+    assert!(matches!(
+        unmap_port(PortMappingProtocol::TCP, external_addr.port(), server.search_options()),
+        Err(errors::Error::RemovePortError(RemovePortError::NoSuchPortMapping))
+    ));
+}
+
+#[test]
+fn test_gateway_from_location_http_status() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let location = format!("http://{}/rootDesc.xml", listener.local_addr().unwrap());
+    let thread = thread::spawn(move || {
+        for status in ["401 Unauthorized", "503 Service Unavailable"] {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Length: 13\r\nConnection: close\r\n\r\n<html></html>",
+                status
+            );
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    assert!(matches!(
+        Gateway::from_location(&location, Default::default()),
+        Err(SearchError::HttpUnauthorized)
+    ));
+    assert!(matches!(
+        Gateway::from_location(&location, Default::default()),
+        Err(SearchError::HttpStatus(503))
+    ));
+    thread.join().unwrap();
 }
@@ -0,0 +1,135 @@
+//! Helpers to build SOAP requests for actions the `Gateway` methods do not cover.
+//!
+//! An envelope built here can be sent with `Gateway::send_envelope`, which takes care of the HTTP request and
+//! of the UPnP errors in the response.
+
+const MESSAGE_HEAD: &str = r#"<?xml version="1.0"?>
+<s:Envelope s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/" xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+<s:Body>"#;
+
+const MESSAGE_TAIL: &str = r#"</s:Body>
+</s:Envelope>"#;
+
+/// The `SOAPAction` header of a request, naming an action and the service it belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Action<'a> {
+    /// Type of the service, such as `urn:schemas-upnp-org:service:WANIPConnection:1`
+    pub service_type: &'a str,
+    /// Name of the action, such as `GetExternalIPAddress`
+    pub name: &'a str,
+}
+
+impl<'a> Action<'a> {
+    /// Name the action `name` of the service `service_type`.
+    pub const fn new(service_type: &'a str, name: &'a str) -> Action<'a> {
+        Action { service_type, name }
+    }
+
+    /// Value of the header, quoted as the UPnP specification requires.
+    ///
+    /// # Example
+    /// ```
+    /// let action = igd::soap::Action::new("urn:schemas-upnp-org:service:WANIPConnection:1", "GetExternalIPAddress");
+    /// assert_eq!(action.header(), r#""urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress""#);
+    /// ```
+    pub fn header(&self) -> String {
+        format!(r#""{}""#, self.unquoted_header())
+    }
+
+    /// Value of the header without quotes, which some gateways expect instead.
+    pub fn unquoted_header(&self) -> String {
+        format!("{}#{}", self.service_type.trim(), self.name.trim())
+    }
+
+    /// Name of the element holding the result of the action in the response.
+    pub fn response_name(&self) -> String {
+        format!("{}Response", self.name.trim())
+    }
+}
+
+/// Build the SOAP envelope calling `action` on the service `service_type`.
+///
+/// `body_xml` is inserted as is in the action element, so it must already be escaped. It usually holds the
+/// arguments of the action, like `<NewExternalPort>80</NewExternalPort>`, but can be any nested XML.
+///
+/// # Example
+/// ```
+/// let envelope = igd::soap::build_envelope(
+///     "urn:schemas-upnp-org:service:WANIPConnection:1",
+///     "GetExternalIPAddress",
+///     "",
+/// );
+/// assert!(envelope.contains("<u:GetExternalIPAddress"));
+/// ```
+pub fn build_envelope(service_type: &str, action: &str, body_xml: &str) -> String {
+    format!(
+        r#"{head}<u:{action} xmlns:u="{service_type}">
+{body}
+</u:{action}>{tail}"#,
+        head = MESSAGE_HEAD,
+        action = action,
+        service_type = service_type,
+        body = body_xml,
+        tail = MESSAGE_TAIL,
+    )
+}
+
+/// Escape text for use as the value of an argument in `build_envelope`.
+///
+/// # Example
+/// ```
+/// assert_eq!(igd::soap::escape("R&D <web>"), "R&amp;D &lt;web&gt;");
+/// ```
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_action_header_quoting() {
+    let action = Action::new("urn:schemas-upnp-org:service:WANIPConnection:1 ", "AddPortMapping");
+    assert_eq!(
+        action.header(),
+        "\"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping\""
+    );
+    assert_eq!(
+        action.unquoted_header(),
+        "urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping"
+    );
+    assert_eq!(action.response_name(), "AddPortMappingResponse");
+}
+
+#[test]
+fn test_build_envelope() {
+    let envelope = build_envelope(
+        "urn:schemas-upnp-org:service:WANIPConnection:1",
+        "GetGenericPortMappingEntry",
+        "<NewPortMappingIndex>0</NewPortMappingIndex>",
+    );
+    let xml = xmltree::Element::parse(envelope.as_bytes()).unwrap();
+    let action = xml
+        .get_child("Body")
+        .and_then(|b| b.get_child("GetGenericPortMappingEntry"))
+        .unwrap();
+    assert_eq!(
+        action.namespace.as_deref(),
+        Some("urn:schemas-upnp-org:service:WANIPConnection:1")
+    );
+    assert_eq!(
+        action
+            .get_child("NewPortMappingIndex")
+            .and_then(|i| i.get_text())
+            .as_deref(),
+        Some("0")
+    );
+}
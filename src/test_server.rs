@@ -0,0 +1,632 @@
+//! A fake gateway on localhost, to test code using this crate without a router.
+//!
+//! The `TestServer` answers SSDP searches sent to it, serves a device description with a `WANIPConnection:1`
+//! service, and keeps the port mappings added through it in memory. The answer to any action can be replaced
+//! with `TestServer::set_response`, to reproduce the errors and quirks of a particular gateway.
+//!
+//! # Example
+//! ```
+//! use igd::test_server::TestServer;
+//! use igd::PortMappingProtocol;
+//!
+//! let server = TestServer::start().unwrap();
+//! let gateway = igd::search_gateway(server.search_options()).unwrap();
+//!
+//! let local_addr = "127.0.0.1:8080".parse().unwrap();
+//! gateway.add_port(PortMappingProtocol::TCP, 80, local_addr, 0, "test").unwrap();
+//! assert_eq!(server.mappings()[0].external_port, 80);
+//! ```
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use xmltree::Element;
+
+use crate::common::messages;
+use crate::{soap, PortMappingEntry, PortMappingProtocol, SearchOptions};
+
+const CONTROL_URL: &str = "/ctl/IPConn";
+const SCPD_URL: &str = "/WANIPCn.xml";
+const ROOT_URL: &str = "/rootDesc.xml";
+
+// Input arguments of the actions served, listed in the control schema.
+const ACTIONS: &[(&str, &[&str])] = &[
+    ("GetExternalIPAddress", &[]),
+    (
+        "AddPortMapping",
+        &[
+            "NewRemoteHost",
+            "NewExternalPort",
+            "NewProtocol",
+            "NewInternalPort",
+            "NewInternalClient",
+            "NewEnabled",
+            "NewPortMappingDescription",
+            "NewLeaseDuration",
+        ],
+    ),
+    (
+        "AddAnyPortMapping",
+        &[
+            "NewRemoteHost",
+            "NewExternalPort",
+            "NewProtocol",
+            "NewInternalPort",
+            "NewInternalClient",
+            "NewEnabled",
+            "NewPortMappingDescription",
+            "NewLeaseDuration",
+        ],
+    ),
+    (
+        "DeletePortMapping",
+        &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+    ),
+    ("GetGenericPortMappingEntry", &["NewPortMappingIndex"]),
+    (
+        "GetSpecificPortMappingEntry",
+        &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+    ),
+];
+
+/// Answer of the `TestServer` to an action, see `TestServer::set_response`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TestResponse {
+    /// A successful response with these output arguments, such as
+    /// `<NewExternalIPAddress>1.2.3.4</NewExternalIPAddress>`
+    Ok(String),
+    /// A UPnP error with this code, such as 718 (ConflictInMappingEntry)
+    Fault(u16),
+    /// An HTTP response with this status and body, sent as is
+    Raw(u16, String),
+}
+
+#[derive(Debug)]
+struct State {
+    external_ip: Ipv4Addr,
+    mappings: Vec<PortMappingEntry>,
+    responses: HashMap<String, TestResponse>,
+    actions: Vec<String>,
+}
+
+/// A fake gateway answering searches and actions on localhost, until it is dropped
+#[derive(Debug)]
+pub struct TestServer {
+    ssdp_addr: SocketAddr,
+    http_addr: SocketAddrV4,
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl TestServer {
+    /// Start a server on ephemeral ports of `127.0.0.1`, with the external address `203.0.113.1` and no mappings.
+    pub fn start() -> io::Result<TestServer> {
+        let ssdp = UdpSocket::bind("127.0.0.1:0")?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let ssdp_addr = ssdp.local_addr()?;
+        let http_addr = match listener.local_addr()? {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(..) => unreachable!("listener bound to an IPv4 address"),
+        };
+        let state = Arc::new(Mutex::new(State {
+            external_ip: Ipv4Addr::new(203, 0, 113, 1),
+            mappings: Vec::new(),
+            responses: HashMap::new(),
+            actions: Vec::new(),
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let ssdp_stop = stop.clone();
+        let ssdp_thread = thread::spawn(move || serve_ssdp(ssdp, http_addr, &ssdp_stop));
+        let http_state = state.clone();
+        let http_stop = stop.clone();
+        let http_thread = thread::spawn(move || serve_http(listener, &http_state, &http_stop));
+
+        Ok(TestServer {
+            ssdp_addr,
+            http_addr,
+            state,
+            stop,
+            threads: vec![ssdp_thread, http_thread],
+        })
+    }
+
+    /// Options for `search_gateway` and `search_gateways` to find this server.
+    pub fn search_options(&self) -> SearchOptions {
+        SearchOptions {
+            bind_addr: "127.0.0.1:0".parse().unwrap(),
+            broadcast_address: self.ssdp_addr,
+            timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        }
+    }
+
+    /// Address of the HTTP server, for `Gateway::new` with `control_url`.
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::V4(self.http_addr)
+    }
+
+    /// Control url of the `WANIPConnection:1` service.
+    pub fn control_url(&self) -> &'static str {
+        CONTROL_URL
+    }
+
+    /// Answer every later request of `action` with `response`, instead of the built-in behaviour.
+    ///
+    /// Actions the server does not implement, such as `GetDNSServers`, can be answered this way too; without
+    /// a response they fail with error 401 (Invalid Action).
+    pub fn set_response(&self, action: &str, response: TestResponse) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .insert(action.to_string(), response);
+    }
+
+    /// Go back to the built-in behaviour for `action`.
+    pub fn clear_response(&self, action: &str) {
+        self.state.lock().unwrap().responses.remove(action);
+    }
+
+    /// Change the address returned by `GetExternalIPAddress`.
+    pub fn set_external_ip(&self, ip: Ipv4Addr) {
+        self.state.lock().unwrap().external_ip = ip;
+    }
+
+    /// The port mappings added and not removed so far, in the order they were added.
+    pub fn mappings(&self) -> Vec<PortMappingEntry> {
+        self.state.lock().unwrap().mappings.clone()
+    }
+
+    /// The names of the actions requested so far, including those answered by `set_response`.
+    pub fn actions(&self) -> Vec<String> {
+        self.state.lock().unwrap().actions.clone()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake up both threads, blocked waiting for a packet and a connection.
+        if let Ok(socket) = UdpSocket::bind("127.0.0.1:0") {
+            let _ = socket.send_to(b"", self.ssdp_addr);
+        }
+        let _ = TcpStream::connect(self.http_addr);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve_ssdp(socket: UdpSocket, http_addr: SocketAddrV4, stop: &AtomicBool) {
+    let mut buf = [0u8; 1500];
+    while let Ok((read, from)) = socket.recv_from(&mut buf) {
+        if stop.load(Ordering::SeqCst) {
+            return;
+        }
+        if !buf[..read].starts_with(b"M-SEARCH") {
+            continue;
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r
+CACHE-CONTROL: max-age=120\r
+LOCATION: http://{}{}\r
+SERVER: igd test server\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+USN: uuid:00000000-0000-0000-0000-000000000000::urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+            http_addr, ROOT_URL
+        );
+        let _ = socket.send_to(response.as_bytes(), from);
+    }
+}
+
+fn serve_http(listener: TcpListener, state: &Mutex<State>, stop: &AtomicBool) {
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Ok(stream) = stream {
+            // A client that goes away only fails its own request.
+            let _ = serve_connection(stream, state);
+        }
+    }
+}
+
+fn serve_connection(stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let (status, body) = match (method.as_str(), path.as_str()) {
+        ("GET", ROOT_URL) => (200, description()),
+        ("GET", SCPD_URL) => (200, control_schema()),
+        ("POST", CONTROL_URL) => match str::from_utf8(&body) {
+            Ok(body) => answer(state, body),
+            Err(..) => (400, String::new()),
+        },
+        _ => (404, String::new()),
+    };
+    respond(stream, status, &body)
+}
+
+fn respond(mut stream: TcpStream, status: u16, body: &str) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        500 => "Internal Server Error",
+        _ => "Status",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+// Answer a SOAP request with the response set for its action, or with the built-in behaviour.
+fn answer(state: &Mutex<State>, body: &str) -> (u16, String) {
+    let (action, args) = match parse_request(body) {
+        Some(request) => request,
+        None => return (400, String::new()),
+    };
+    let mut state = state.lock().unwrap();
+    state.actions.push(action.clone());
+    let response = match state.responses.get(&action) {
+        Some(response) => response.clone(),
+        None => perform(&mut state, &action, &args),
+    };
+    match response {
+        TestResponse::Ok(output) => (
+            200,
+            soap::build_envelope(messages::SERVICE_TYPE, &format!("{}Response", action), &output),
+        ),
+        TestResponse::Fault(code) => (500, fault(code)),
+        TestResponse::Raw(status, body) => (status, body),
+    }
+}
+
+fn parse_request(body: &str) -> Option<(String, HashMap<String, String>)> {
+    let envelope = Element::parse(body.as_bytes()).ok()?;
+    let action = envelope
+        .get_child("Body")?
+        .children
+        .iter()
+        .find_map(|child| child.as_element())?;
+    let args = action
+        .children
+        .iter()
+        .filter_map(|child| child.as_element())
+        .map(|arg| {
+            let value = arg.get_text().unwrap_or_default().trim().to_string();
+            (arg.name.clone(), value)
+        })
+        .collect();
+    Some((action.name.clone(), args))
+}
+
+fn perform(state: &mut State, action: &str, args: &HashMap<String, String>) -> TestResponse {
+    let arg = |name: &str| args.get(name).map(String::as_str).unwrap_or_default();
+    let protocol = match arg("NewProtocol") {
+        "TCP" => Some(PortMappingProtocol::TCP),
+        "UDP" => Some(PortMappingProtocol::UDP),
+        _ => None,
+    };
+    let external_port = arg("NewExternalPort").parse::<u16>().ok();
+    let find = |state: &State, port: u16, protocol: PortMappingProtocol| {
+        state
+            .mappings
+            .iter()
+            .position(|m| m.external_port == port && m.protocol == protocol)
+    };
+
+    match action {
+        "GetExternalIPAddress" => TestResponse::Ok(format!(
+            "<NewExternalIPAddress>{}</NewExternalIPAddress>",
+            state.external_ip
+        )),
+        "AddPortMapping" | "AddAnyPortMapping" => {
+            let (external_port, protocol, internal_port) =
+                match (external_port, protocol, arg("NewInternalPort").parse()) {
+                    (Some(external_port), Some(protocol), Ok(internal_port)) => {
+                        (external_port, protocol, internal_port)
+                    }
+                    _ => return TestResponse::Fault(402),
+                };
+            let entry = PortMappingEntry {
+                remote_host: arg("NewRemoteHost").to_string(),
+                external_port,
+                protocol,
+                internal_port,
+                internal_client: arg("NewInternalClient").to_string(),
+                enabled: arg("NewEnabled") != "0",
+                port_mapping_description: arg("NewPortMappingDescription").to_string(),
+                lease_duration: arg("NewLeaseDuration").parse().unwrap_or(0),
+            };
+            if action == "AddAnyPortMapping" {
+                let free = (external_port..=u16::MAX)
+                    .chain(1024..external_port)
+                    .find(|&port| port != 0 && find(state, port, protocol).is_none());
+                return match free {
+                    Some(port) => {
+                        state.mappings.push(PortMappingEntry {
+                            external_port: port,
+                            ..entry
+                        });
+                        TestResponse::Ok(format!("<NewReservedPort>{}</NewReservedPort>", port))
+                    }
+                    None => TestResponse::Fault(728),
+                };
+            }
+            if external_port == 0 {
+                return TestResponse::Fault(716);
+            }
+            match find(state, external_port, protocol) {
+                // Renewing one's own mapping replaces it.
+                Some(i) if state.mappings[i].internal_client == entry.internal_client => state.mappings[i] = entry,
+                Some(..) => return TestResponse::Fault(718),
+                None => state.mappings.push(entry),
+            }
+            TestResponse::Ok(String::new())
+        }
+        "DeletePortMapping" => match (external_port, protocol) {
+            (Some(port), Some(protocol)) => match find(state, port, protocol) {
+                Some(i) => {
+                    state.mappings.remove(i);
+                    TestResponse::Ok(String::new())
+                }
+                None => TestResponse::Fault(714),
+            },
+            _ => TestResponse::Fault(402),
+        },
+        "GetSpecificPortMappingEntry" => match (external_port, protocol) {
+            (Some(port), Some(protocol)) => match find(state, port, protocol) {
+                Some(i) => TestResponse::Ok(format_entry(&state.mappings[i], false)),
+                None => TestResponse::Fault(714),
+            },
+            _ => TestResponse::Fault(402),
+        },
+        "GetGenericPortMappingEntry" => match arg("NewPortMappingIndex").parse::<usize>() {
+            Ok(index) => match state.mappings.get(index) {
+                Some(entry) => TestResponse::Ok(format_entry(entry, true)),
+                None => TestResponse::Fault(713),
+            },
+            Err(..) => TestResponse::Fault(402),
+        },
+        _ => TestResponse::Fault(401),
+    }
+}
+
+// Output arguments of a mapping, with its key for `GetGenericPortMappingEntry`.
+fn format_entry(entry: &PortMappingEntry, with_key: bool) -> String {
+    let key = if with_key {
+        format!(
+            "<NewRemoteHost>{}</NewRemoteHost><NewExternalPort>{}</NewExternalPort><NewProtocol>{}</NewProtocol>",
+            soap::escape(&entry.remote_host),
+            entry.external_port,
+            entry.protocol
+        )
+    } else {
+        String::new()
+    };
+    format!(
+        "{}<NewInternalPort>{}</NewInternalPort><NewInternalClient>{}</NewInternalClient>\
+         <NewEnabled>{}</NewEnabled><NewPortMappingDescription>{}</NewPortMappingDescription>\
+         <NewLeaseDuration>{}</NewLeaseDuration>",
+        key,
+        entry.internal_port,
+        soap::escape(&entry.internal_client),
+        entry.enabled as u8,
+        soap::escape(&entry.port_mapping_description),
+        entry.lease_duration
+    )
+}
+
+fn fault(code: u16) -> String {
+    format!(
+        r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body>
+<s:Fault>
+<faultcode>s:Client</faultcode>
+<faultstring>UPnPError</faultstring>
+<detail>
+<UPnPError xmlns="urn:schemas-upnp-org:control-1-0">
+<errorCode>{}</errorCode>
+<errorDescription>Error {}</errorDescription>
+</UPnPError>
+</detail>
+</s:Fault>
+</s:Body>
+</s:Envelope>"#,
+        code, code
+    )
+}
+
+fn description() -> String {
+    format!(
+        r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+<friendlyName>igd test server</friendlyName>
+<UDN>uuid:00000000-0000-0000-0000-000000000000</UDN>
+<deviceList>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+<deviceList>
+<device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>
+<serviceList>
+<service>
+<serviceType>{}</serviceType>
+<serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+<controlURL>{}</controlURL>
+<eventSubURL>/evt/IPConn</eventSubURL>
+<SCPDURL>{}</SCPDURL>
+</service>
+</serviceList>
+</device>
+</deviceList>
+</device>
+</deviceList>
+</device>
+</root>"#,
+        messages::SERVICE_TYPE,
+        CONTROL_URL,
+        SCPD_URL
+    )
+}
+
+fn control_schema() -> String {
+    let actions: String = ACTIONS
+        .iter()
+        .map(|(name, args)| {
+            let args: String = args
+                .iter()
+                .map(|arg| format!("<argument><name>{}</name><direction>in</direction></argument>", arg))
+                .collect();
+            format!(
+                "<action><name>{}</name><argumentList>{}</argumentList></action>",
+                name, args
+            )
+        })
+        .collect();
+    format!(
+        r#"<?xml version="1.0"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<actionList>{}</actionList>
+</scpd>"#,
+        actions
+    )
+}
+
+#[test]
+fn test_server_discover_map_remove() {
+    use crate::{AddPortError, RemovePortError};
+
+    let server = TestServer::start().unwrap();
+    let gateway = crate::search_gateway(server.search_options()).unwrap();
+    assert_eq!(gateway.addr, server.addr());
+    assert_eq!(gateway.control_url, server.control_url());
+    assert!(gateway.control_schema.contains_key("AddAnyPortMapping"));
+    assert_eq!(gateway.get_external_ip().unwrap(), Ipv4Addr::new(203, 0, 113, 1));
+
+    let local_addr = "192.168.1.2:8080".parse().unwrap();
+    let port = gateway
+        .add_any_port(PortMappingProtocol::TCP, local_addr, 60, "test")
+        .unwrap();
+    let entry = gateway
+        .get_specific_port_mapping_entry(PortMappingProtocol::TCP, port)
+        .unwrap();
+    assert_eq!(
+        (entry.internal_client.as_str(), entry.internal_port),
+        ("192.168.1.2", 8080)
+    );
+    assert_eq!(gateway.find_mappings(|_| true).unwrap(), server.mappings());
+
+    // Another client keeps the port until it is removed.
+    let other = "192.168.1.3:8080".parse().unwrap();
+    assert!(matches!(
+        gateway.add_port(PortMappingProtocol::TCP, port, other, 60, "test"),
+        Err(AddPortError::PortInUse)
+    ));
+    gateway.remove_port(PortMappingProtocol::TCP, port).unwrap();
+    assert!(server.mappings().is_empty());
+    assert!(matches!(
+        gateway.remove_port(PortMappingProtocol::TCP, port),
+        Err(RemovePortError::NoSuchPortMapping)
+    ));
+
+    server.set_response("GetExternalIPAddress", TestResponse::Fault(606));
+    assert!(gateway.get_external_ip().is_err());
+    server.clear_response("GetExternalIPAddress");
+    server.set_external_ip(Ipv4Addr::new(198, 51, 100, 7));
+    assert_eq!(gateway.get_external_ip().unwrap(), Ipv4Addr::new(198, 51, 100, 7));
+    assert_eq!(server.actions()[0], "GetExternalIPAddress");
+}
+
+#[test]
+fn test_gateway_from_location() {
+    let server = TestServer::start().unwrap();
+    let location = format!("http://{}{}", server.addr(), ROOT_URL);
+    let gateway = crate::Gateway::from_location(&location, server.search_options()).unwrap();
+    assert_eq!(gateway.addr, server.addr());
+    assert_eq!(gateway.root_url, ROOT_URL);
+    assert_eq!(gateway.control_url, server.control_url());
+    assert!(gateway.control_schema.contains_key("AddAnyPortMapping"));
+    assert_eq!(gateway.discovery_info, None);
+    assert_eq!(gateway.get_external_ip().unwrap(), Ipv4Addr::new(203, 0, 113, 1));
+    // The service type of the description is not probed.
+    server.set_response("GetExternalIPAddress", TestResponse::Fault(401));
+    assert!(gateway.get_external_ip().is_err());
+    assert_eq!(server.actions(), ["GetExternalIPAddress", "GetExternalIPAddress"]);
+
+    let location = format!("http://gateway.local:{}{}", server.addr().port(), ROOT_URL);
+    assert!(matches!(
+        crate::Gateway::from_location(&location, server.search_options()),
+        Err(crate::SearchError::InvalidResponse)
+    ));
+}
+
+// A responder answering the first M-SEARCH sent to it with a gateway response per location, for the tests of the
+// searches. Returns the address to send the search to.
+#[cfg(test)]
+pub(crate) fn search_responder(locations: Vec<String>) -> (SocketAddr, JoinHandle<()>) {
+    let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = responder.local_addr().unwrap();
+    let thread = thread::spawn(move || {
+        let mut buf = [0u8; 1500];
+        let (_, from) = responder.recv_from(&mut buf).unwrap();
+        for location in locations {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nLOCATION: {}\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+                location
+            );
+            responder.send_to(response.as_bytes(), from).unwrap();
+        }
+    });
+    (addr, thread)
+}
+
+// The location of the description of `server`, and one where nothing listens, so its description can not be
+// fetched.
+#[cfg(test)]
+pub(crate) fn unusable_and_server_locations(server: &TestServer) -> Vec<String> {
+    vec![
+        "http://127.0.0.1:1/rootDesc.xml".to_string(),
+        format!("http://{}{}", server.addr(), ROOT_URL),
+    ]
+}
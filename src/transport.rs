@@ -0,0 +1,127 @@
+//! HTTP requests and responses exchanged with the gateway.
+//!
+//! These are the values handed to a `Transport`, or to an implementation of `aio::Transport`.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::common::{self, GatewayOptions};
+use crate::errors::RequestError;
+
+/// An HTTP request to the gateway
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    /// Method of the request, `GET` or `POST`
+    pub method: &'static str,
+    /// Full url of the request
+    pub url: String,
+    /// Headers of the request, besides `Host` and `Content-Length` which the transport sets
+    pub headers: Vec<(&'static str, String)>,
+    /// Body of the request, empty for a `GET`
+    pub body: String,
+    /// Maximum time the whole request may take, or `None` for no limit
+    pub timeout: Option<Duration>,
+    /// Largest response body to accept, larger ones fail with `RequestError::ResponseTooLarge`
+    pub max_response_size: usize,
+}
+
+impl HttpRequest {
+    pub(crate) fn get(url: String, options: &GatewayOptions) -> HttpRequest {
+        HttpRequest {
+            method: "GET",
+            url,
+            headers: Vec::new(),
+            body: String::new(),
+            timeout: None,
+            max_response_size: options.max_response_size,
+        }
+        .with_credentials(options)
+    }
+
+    pub(crate) fn post(url: String, soap_action: &str, body: String, options: &GatewayOptions) -> HttpRequest {
+        HttpRequest {
+            method: "POST",
+            url,
+            headers: vec![
+                ("SOAPAction", soap_action.to_string()),
+                ("Content-Type", "text/xml".to_string()),
+            ],
+            body,
+            timeout: None,
+            max_response_size: options.max_response_size,
+        }
+        .with_credentials(options)
+    }
+
+    // Whether the request only reads the state of the gateway, like a `GET` or a `GetExternalIPAddress` action.
+    #[cfg(feature = "aio")]
+    pub(crate) fn is_read_only(&self) -> bool {
+        match self.headers.iter().find(|(name, _)| *name == "SOAPAction") {
+            Some((_, header)) => {
+                common::messages::is_read_only(header.trim_matches('"').rsplit('#').next().unwrap_or_default())
+            }
+            None => self.method == "GET",
+        }
+    }
+
+    fn with_credentials(mut self, options: &GatewayOptions) -> HttpRequest {
+        if let Some(ref credentials) = options.credentials {
+            self.headers.push(("Authorization", credentials.authorization()));
+        }
+        self
+    }
+}
+
+/// An HTTP response from the gateway
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    /// Status code of the response
+    pub status: u16,
+    /// Body of the response
+    pub body: String,
+}
+
+/// HTTP client used by the blocking `Gateway` to send its requests.
+///
+/// Gateways use `AttoHttpTransport` by default. Implement this trait to use another HTTP client, or to test code
+/// using a `Gateway` with canned responses instead of a real gateway.
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// Send the request and return the response, whatever its status code.
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, RequestError>;
+}
+
+/// The default `Transport`, sending requests with attohttpc
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AttoHttpTransport;
+
+impl Transport for AttoHttpTransport {
+    fn send(&self, request: HttpRequest) -> Result<HttpResponse, RequestError> {
+        send_blocking(request)
+    }
+}
+
+fn send_blocking(request: HttpRequest) -> Result<HttpResponse, RequestError> {
+    let mut builder = match request.method {
+        "GET" => attohttpc::get(&request.url),
+        _ => attohttpc::post(&request.url),
+    };
+    for (name, value) in request.headers {
+        builder = builder.header(name, value);
+    }
+    if let Some(timeout) = request.timeout {
+        builder = builder.timeout(timeout);
+    }
+    let response = if request.body.is_empty() {
+        builder.send()?
+    } else {
+        builder.text(request.body).send()?
+    };
+    let (status, _, reader) = response.split();
+    let body = common::read_limited(reader, request.max_response_size)?
+        .ok_or(RequestError::ResponseTooLarge(request.max_response_size))?;
+
+    Ok(HttpResponse {
+        status: status.as_u16(),
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}